@@ -7,8 +7,13 @@ mod attestation {
     use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
     use poi_radio::{
         messages::poi::PublicPoiMessage,
-        operator::attestation::{
-            compare_attestations, local_comparison_point, update_blocks, Attestation,
+        operator::{
+            attestation::{
+                compare_attestations, local_comparison_point, update_blocks, Attestation,
+                QuorumThreshold,
+            },
+            consensus::ConsensusPolicy,
+            stake::Stake,
         },
     };
     use std::collections::HashMap;
@@ -27,7 +32,7 @@ mod attestation {
             42,
             vec![black_box(Attestation::new(
                 "default".to_string(),
-                0.0,
+                Stake::zero(),
                 Vec::new(),
                 Vec::new(),
             ))],
@@ -39,7 +44,7 @@ mod attestation {
                     42,
                     &blocks,
                     "awesome-npoi".to_string(),
-                    0.0,
+                    Stake::zero(),
                     "0xadd3".to_string(),
                     1,
                 )
@@ -50,13 +55,13 @@ mod attestation {
     fn update_attestations_bench(c: &mut Criterion) {
         let attestation = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![2],
         ));
 
         c.bench_function("update_attestation", |b| {
-            b.iter(|| Attestation::update(&attestation, "0xa2".to_string(), 1.0, 1))
+            b.iter(|| Attestation::update(&attestation, "0xa2".to_string(), Stake::from_grt(1), 1))
         });
     }
 
@@ -66,7 +71,7 @@ mod attestation {
 
         let remote = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![0],
         ));
@@ -74,7 +79,7 @@ mod attestation {
 
         let local = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             Vec::new(),
             vec![0],
         ));
@@ -95,6 +100,8 @@ mod attestation {
                     black_box(remote_attestations.clone()),
                     black_box(&local_attestations),
                     "my-awesome-hash",
+                    &QuorumThreshold::default(),
+                    &ConsensusPolicy::default(),
                 )
             })
         });
@@ -104,21 +111,21 @@ mod attestation {
         let mut local_blocks: HashMap<u64, Attestation> = black_box(HashMap::new());
         let attestation1 = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![2],
         ));
 
         let attestation2 = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa2".to_string()],
             vec![4],
         ));
 
         let attestation3 = black_box(Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xa3".to_string()],
             vec![6],
         ));
@@ -153,6 +160,7 @@ mod attestation {
                 block_number: 42,
                 block_hash: String::from("4dbba1ba9fb18b0034965712598be1368edcf91ae2c551d59462aab578dab9c5"),
                 graph_account: String::from("0xa1"),
+                version: 0,
             },
             signature: String::from("03b197380ab9ee3a9fcaea1301224ad1ff02e9e414275fd79d6ee463b21eb6957af7670a26b0a7f8a6316d95dba8497f2bd67b32b39be07073cf81beff0b37961b"),
         }]
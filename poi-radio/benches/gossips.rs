@@ -10,6 +10,8 @@ use std::collections::HashMap;
 use graphcast_sdk::networks::NetworkName;
 use graphcast_sdk::{BlockPointer, NetworkPointer};
 use poi_radio::config::Config;
+use poi_radio::operator::consensus::ConsensusPolicyType;
+use poi_radio::storage::StorageBackend;
 
 fn gossip_poi_bench(c: &mut Criterion) {
     let identifiers = black_box(vec!["identifier1".to_string(), "identifier2".to_string()]);
@@ -37,6 +39,27 @@ fn gossip_poi_bench(c: &mut Criterion) {
         )],
         coverage: poi_radio::config::CoverageLevel::Comprehensive,
         collect_message_duration: 10,
+        quorum_stake_ratio: 0.0,
+        quorum_min_senders: 1,
+        bisection_lookback_blocks: None,
+        comparison_history_limit: 1000,
+        sender_agreement_threshold: None,
+        stake_cache_ttl: 300,
+        consensus_policy: ConsensusPolicyType::StakeWeighted,
+        trusted_senders: vec![],
+        message_retention_secs: 86400,
+        max_messages_per_deployment: 1000,
+        storage_backend: StorageBackend::Json,
+        audit_log_path: None,
+        audit_log_max_bytes: 104857600,
+        audit_log_rotation_secs: 86400,
+        record_path: None,
+        server_admin_token: None,
+        poi_message_version: 0,
+        max_poi_batch_size: None,
+        heartbeat_interval: 0,
+        upgrade_auto_subscribe: None,
+        upgrade_reminder_leads: vec![],
         waku_host: None,
         waku_port: None,
         waku_node_key: None,
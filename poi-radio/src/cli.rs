@@ -0,0 +1,412 @@
+use clap::{Args, Parser, Subcommand};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::operator::consensus::ConsensusPolicyType;
+use crate::replay::{read_recording, replay, ReplayError};
+use crate::simulate::{simulate, ComparisonParameters, SimulationInputs};
+use crate::state::{PersistedState, StateSummary};
+use crate::storage::{
+    migration::versioned, open_store, JsonFileStore, StateStore, StorageBackend, StorageError,
+};
+
+/// Offline maintenance of the persisted radio state, runs without a Waku node or a wallet
+#[derive(Debug, Parser)]
+#[clap(
+    name = "state",
+    bin_name = "poi-radio state",
+    about = "Export, import and inspect the persisted state of a radio"
+)]
+pub struct StateCli {
+    #[clap(subcommand)]
+    pub command: StateCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StateCommand {
+    /// Write the persisted state as versioned JSON, which import reads back on any host
+    Export {
+        #[clap(flatten)]
+        location: StateLocation,
+        #[clap(
+            long,
+            short,
+            value_name = "FILE",
+            help = "File to export to, standard output if not set"
+        )]
+        output: Option<PathBuf>,
+    },
+    /// Replace the persisted state with an exported one
+    Import {
+        #[clap(flatten)]
+        location: StateLocation,
+        #[clap(value_name = "FILE", help = "Exported state to import")]
+        input: PathBuf,
+        #[clap(long, help = "Overwrite the persisted state if there is one")]
+        force: bool,
+    },
+    /// Summarize the persisted state: deployments, blocks awaiting comparison, divergent results and messages per sender
+    Inspect {
+        #[clap(flatten)]
+        location: StateLocation,
+        #[clap(long, help = "Print the summary as JSON")]
+        json: bool,
+    },
+}
+
+/// Where the radio persists its state, same options and environment variables as the radio itself
+#[derive(Debug, Args)]
+pub struct StateLocation {
+    #[clap(
+        long,
+        value_name = "PERSISTENCE_FILE_PATH",
+        env = "PERSISTENCE_FILE_PATH",
+        help = "Path of the persisted state"
+    )]
+    pub persistence_file_path: String,
+    #[clap(
+        long,
+        value_name = "STORAGE_BACKEND",
+        value_enum,
+        default_value = "json",
+        env = "STORAGE_BACKEND",
+        help = "Storage backend of the persisted state"
+    )]
+    pub storage_backend: StorageBackend,
+}
+
+impl StateLocation {
+    /// Read the state without modifying the stored copy, unreadable files are reported instead of quarantined
+    fn read(&self) -> Result<Option<PersistedState>, StateCliError> {
+        let state = match self.storage_backend {
+            StorageBackend::Json => JsonFileStore::new(&self.persistence_file_path).read()?,
+            backend => open_store(backend, &self.persistence_file_path)?.load()?,
+        };
+        Ok(state)
+    }
+
+    fn read_existing(&self) -> Result<PersistedState, StateCliError> {
+        self.read()?
+            .ok_or_else(|| StateCliError::MissingState(self.persistence_file_path.clone()))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateCliError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("Failed to write output: {0}")]
+    Io(#[from] io::Error),
+    #[error("No persisted state at {0}")]
+    MissingState(String),
+    #[error("A persisted state already exists at {0}, use --force to overwrite it")]
+    ExistingState(String),
+}
+
+/// Run a state subcommand
+pub fn run_state_command(cli: StateCli) -> Result<(), StateCliError> {
+    match cli.command {
+        StateCommand::Export { location, output } => {
+            let state = location.read_existing()?;
+            match output {
+                Some(path) => JsonFileStore::new(path).save(&state)?,
+                None => {
+                    let value =
+                        versioned(serde_json::to_value(&state).map_err(StorageError::from)?)?;
+                    let mut stdout = io::stdout().lock();
+                    serde_json::to_writer_pretty(&mut stdout, &value)
+                        .map_err(StorageError::from)?;
+                    writeln!(stdout)?;
+                }
+            }
+        }
+        StateCommand::Import {
+            location,
+            input,
+            force,
+        } => {
+            let state = JsonFileStore::new(&input)
+                .read()?
+                .ok_or_else(|| StateCliError::MissingState(input.display().to_string()))?;
+            if !force && location.read()?.is_some() {
+                return Err(StateCliError::ExistingState(location.persistence_file_path));
+            }
+            if let Some(parent) = Path::new(&location.persistence_file_path).parent() {
+                if !parent.as_os_str().is_empty() {
+                    std::fs::create_dir_all(parent)?;
+                }
+            }
+            open_store(location.storage_backend, &location.persistence_file_path)?
+                .replace(&state)?;
+            eprintln!(
+                "Imported {} into {}",
+                input.display(),
+                location.persistence_file_path
+            );
+        }
+        StateCommand::Inspect { location, json } => {
+            let summary = StateSummary::new(&location.read_existing()?);
+            if json {
+                let summary = serde_json::to_string_pretty(&summary).map_err(StorageError::from)?;
+                println!("{summary}");
+            } else {
+                print!("{summary}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replay of a recording made with RECORD_PATH, runs without a Waku node, a Graph node or a wallet
+#[derive(Debug, Parser)]
+#[clap(
+    name = "replay",
+    bin_name = "poi-radio replay",
+    about = "Feed a recording back through message validation and POI comparison with a simulated clock"
+)]
+pub struct ReplayCli {
+    #[clap(value_name = "FILE", help = "Recording to replay")]
+    pub recording: PathBuf,
+    #[clap(long, help = "Print every comparison result as a JSON line")]
+    pub json: bool,
+}
+
+/// Replay a recording and print the comparison results it produces
+pub async fn run_replay_command(cli: ReplayCli) -> Result<(), ReplayError> {
+    let comparisons = replay(read_recording(&cli.recording)?).await?.comparisons;
+    for comparison in &comparisons {
+        if cli.json {
+            let line = serde_json::to_string(comparison).expect("Comparison results serialize");
+            println!("{line}");
+        } else {
+            println!(
+                "{} {} at block {}: {}",
+                comparison.at,
+                comparison.result.deployment,
+                comparison.result.block_number,
+                comparison.result.result_type
+            );
+        }
+    }
+    eprintln!("Replayed {} comparison results", comparisons.len());
+    Ok(())
+}
+
+/// What-if comparison of stored inputs under other comparison parameters, runs without a Waku node or a wallet
+#[derive(Debug, Parser)]
+#[clap(
+    name = "simulate",
+    bin_name = "poi-radio simulate",
+    about = "Compare stored remote messages and local attestations under alternative comparison parameters, and show which outcomes change"
+)]
+pub struct SimulateCli {
+    #[clap(
+        long,
+        value_name = "FILE",
+        help = "Recording to take the inputs and baseline parameters from, instead of the persisted state",
+        long_help = "Recording to take the inputs and baseline parameters from, instead of the persisted state.\n
+            A recording covers every block the radio attested to, while the persisted state only holds blocks awaiting comparison"
+    )]
+    pub recording: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PERSISTENCE_FILE_PATH",
+        env = "PERSISTENCE_FILE_PATH",
+        required_unless_present = "recording",
+        help = "Path of the persisted state"
+    )]
+    pub persistence_file_path: Option<String>,
+    #[clap(
+        long,
+        value_name = "STORAGE_BACKEND",
+        value_enum,
+        default_value = "json",
+        env = "STORAGE_BACKEND",
+        help = "Storage backend of the persisted state"
+    )]
+    pub storage_backend: StorageBackend,
+    #[clap(flatten)]
+    pub baseline: BaselineParameters,
+    #[clap(flatten)]
+    pub alternative: AlternativeParameters,
+    #[clap(long, help = "Print the report, with unchanged outcomes, as JSON")]
+    pub json: bool,
+}
+
+/// Parameters the radio compares with, read from its environment variables. Recordings bring their own
+#[derive(Debug, Args)]
+pub struct BaselineParameters {
+    #[clap(
+        long,
+        value_name = "COLLECT_MESSAGE_DURATION",
+        default_value = "120",
+        env = "COLLECT_MESSAGE_DURATION",
+        help = "Baseline collect message duration"
+    )]
+    pub baseline_collect_message_duration: i64,
+    #[clap(
+        long,
+        value_name = "QUORUM_STAKE_RATIO",
+        value_parser = Config::parse_ratio,
+        default_value = "0",
+        env = "QUORUM_STAKE_RATIO",
+        help = "Baseline quorum stake ratio"
+    )]
+    pub baseline_quorum_stake_ratio: f32,
+    #[clap(
+        long,
+        value_name = "QUORUM_MIN_SENDERS",
+        default_value = "1",
+        env = "QUORUM_MIN_SENDERS",
+        help = "Baseline quorum minimum senders"
+    )]
+    pub baseline_quorum_min_senders: usize,
+    #[clap(
+        long,
+        value_name = "CONSENSUS_POLICY",
+        value_enum,
+        default_value = "stake-weighted",
+        env = "CONSENSUS_POLICY",
+        help = "Baseline consensus policy"
+    )]
+    pub baseline_consensus_policy: ConsensusPolicyType,
+    #[clap(
+        long,
+        value_name = "[TRUSTED_SENDER]",
+        value_delimiter = ',',
+        env = "TRUSTED_SENDERS",
+        help = "Baseline trusted senders"
+    )]
+    pub baseline_trusted_senders: Vec<String>,
+    #[clap(
+        long,
+        value_name = "SENDER_AGREEMENT_THRESHOLD",
+        value_parser = Config::parse_ratio,
+        env = "SENDER_AGREEMENT_THRESHOLD",
+        help = "Baseline sender agreement threshold"
+    )]
+    pub baseline_sender_agreement_threshold: Option<f32>,
+}
+
+impl From<BaselineParameters> for ComparisonParameters {
+    fn from(baseline: BaselineParameters) -> Self {
+        ComparisonParameters {
+            collect_message_duration: baseline.baseline_collect_message_duration,
+            quorum_stake_ratio: baseline.baseline_quorum_stake_ratio,
+            quorum_min_senders: baseline.baseline_quorum_min_senders,
+            consensus_policy: baseline.baseline_consensus_policy,
+            trusted_senders: baseline.baseline_trusted_senders,
+            sender_agreement_threshold: baseline.baseline_sender_agreement_threshold,
+        }
+    }
+}
+
+/// Parameters to simulate, the ones left out keep their baseline value
+#[derive(Debug, Args)]
+pub struct AlternativeParameters {
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        help = "Minimum duration to wait for messages after a local attestation"
+    )]
+    pub collect_message_duration: Option<i64>,
+    #[clap(
+        long,
+        value_name = "RATIO",
+        value_parser = Config::parse_ratio,
+        help = "Minimum share (0 to 1) of the attesting stake the top remote nPOI needs"
+    )]
+    pub quorum_stake_ratio: Option<f32>,
+    #[clap(
+        long,
+        value_name = "COUNT",
+        help = "Minimum number of senders attesting to the top remote nPOI"
+    )]
+    pub quorum_min_senders: Option<usize>,
+    #[clap(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        help = "Policy used to pick the consensus nPOI"
+    )]
+    pub consensus_policy: Option<ConsensusPolicyType>,
+    #[clap(
+        long,
+        value_name = "[TRUSTED_SENDER]",
+        value_delimiter = ',',
+        help = "Indexer addresses that count towards consensus under the trusted-set policy"
+    )]
+    pub trusted_senders: Option<Vec<String>>,
+    #[clap(
+        long,
+        value_name = "RATIO",
+        value_parser = Config::parse_ratio,
+        help = "Agreement ratio (0 to 1) below which sender stakes are scaled down, 0 turns weighting off"
+    )]
+    pub sender_agreement_threshold: Option<f32>,
+}
+
+impl AlternativeParameters {
+    pub fn apply(self, baseline: &ComparisonParameters) -> ComparisonParameters {
+        ComparisonParameters {
+            collect_message_duration: self
+                .collect_message_duration
+                .unwrap_or(baseline.collect_message_duration),
+            quorum_stake_ratio: self
+                .quorum_stake_ratio
+                .unwrap_or(baseline.quorum_stake_ratio),
+            quorum_min_senders: self
+                .quorum_min_senders
+                .unwrap_or(baseline.quorum_min_senders),
+            consensus_policy: self.consensus_policy.unwrap_or(baseline.consensus_policy),
+            trusted_senders: self
+                .trusted_senders
+                .unwrap_or_else(|| baseline.trusted_senders.clone()),
+            sender_agreement_threshold: self
+                .sender_agreement_threshold
+                .or(baseline.sender_agreement_threshold),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulateCliError {
+    #[error(transparent)]
+    State(#[from] StateCliError),
+    #[error(transparent)]
+    Replay(#[from] ReplayError),
+}
+
+/// Simulate the comparison parameters and print the outcomes that change
+pub async fn run_simulate_command(cli: SimulateCli) -> Result<(), SimulateCliError> {
+    let (inputs, baseline) = match (cli.recording, cli.persistence_file_path) {
+        (Some(recording), _) => {
+            let replayed = replay(read_recording(recording)?).await?;
+            let baseline = ComparisonParameters::from_config(&replayed.config);
+            (SimulationInputs::from_replay(replayed), baseline)
+        }
+        (None, persistence_file_path) => {
+            let location = StateLocation {
+                // Required by clap without a recording
+                persistence_file_path: persistence_file_path.unwrap_or_default(),
+                storage_backend: cli.storage_backend,
+            };
+            let state = location.read_existing()?;
+            (
+                SimulationInputs::from_state(state),
+                ComparisonParameters::from(cli.baseline),
+            )
+        }
+    };
+    let alternative = cli.alternative.apply(&baseline);
+
+    let report = simulate(&inputs, baseline, alternative).await;
+    if cli.json {
+        let report = serde_json::to_string_pretty(&report).expect("Simulation reports serialize");
+        println!("{report}");
+    } else {
+        print!("{report}");
+    }
+    Ok(())
+}
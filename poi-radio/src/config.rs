@@ -8,17 +8,25 @@ use graphcast_sdk::{
     graphcast_agent::{
         message_typing::IdentityValidation, GraphcastAgentConfig, GraphcastAgentError,
     },
-    graphql::{
-        client_network::query_network_subgraph, client_registry::query_registry, QueryError,
-    },
+    graphql::{client_registry::query_registry, QueryError},
     init_tracing, wallet_address,
 };
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use tracing::{debug, info, trace};
+use std::sync::Arc;
+use tracing::{debug, error, info, trace};
 
-use crate::state::{panic_hook, PersistedState};
+use crate::graphql::query_indexer_stake;
+use crate::messages::poi::POI_MESSAGE_VERSION;
+use crate::operator::{
+    attestation::QuorumThreshold,
+    consensus::{ConsensusPolicy, ConsensusPolicyType},
+    reputation::SenderWeights,
+    stake::Stake,
+};
+use crate::state::{panic_hook, MessageRetention, PersistedState};
+use crate::storage::{open_store, StateStore, StorageBackend, StorageError};
 use crate::{active_allocation_hashes, syncing_deployment_hashes};
 
 #[derive(clap::ValueEnum, Clone, Debug, Serialize, Deserialize, Default)]
@@ -124,6 +132,103 @@ pub struct Config {
         help = "Set the minimum duration to wait for a topic message collection"
     )]
     pub collect_message_duration: i64,
+    #[clap(
+        long,
+        value_name = "QUORUM_STAKE_RATIO",
+        value_parser = Config::parse_ratio,
+        default_value = "0",
+        env = "QUORUM_STAKE_RATIO",
+        help = "Minimum share (0 to 1) of the attesting stake that the top remote nPOI needs to be taken as consensus",
+        long_help = "Minimum share (0 to 1) of the attesting stake that the top remote nPOI needs to be taken as consensus.\n
+            Comparisons below the quorum are reported as Inconclusive instead of Match or Divergent. Default is 0 (disabled)"
+    )]
+    pub quorum_stake_ratio: f32,
+    #[clap(
+        long,
+        value_name = "QUORUM_MIN_SENDERS",
+        default_value = "1",
+        env = "QUORUM_MIN_SENDERS",
+        help = "Minimum number of senders attesting to the top remote nPOI for it to be taken as consensus",
+        long_help = "Minimum number of senders attesting to the top remote nPOI for it to be taken as consensus.\n
+            Comparisons below the quorum are reported as Inconclusive instead of Match or Divergent. Default is 1"
+    )]
+    pub quorum_min_senders: usize,
+    #[clap(
+        long,
+        value_name = "BISECTION_LOOKBACK_BLOCKS",
+        env = "BISECTION_LOOKBACK_BLOCKS",
+        help = "If set, the Radio bisects divergent deployments over up to this many earlier blocks to find where the divergence started",
+        long_help = "If set, the Radio bisects divergent deployments over up to this many earlier blocks to find where the divergence started.\n
+            Peers are asked for their nPOIs at each probed block and have collect_message_duration to respond. Off by default"
+    )]
+    pub bisection_lookback_blocks: Option<u64>,
+    #[clap(
+        long,
+        value_name = "COMPARISON_HISTORY_LIMIT",
+        default_value = "1000",
+        env = "COMPARISON_HISTORY_LIMIT",
+        help = "Maximum number of blocks kept per deployment in the comparison history",
+        long_help = "Maximum number of blocks kept per deployment in the comparison history.\n
+            Once a deployment exceeds the limit, results of its oldest blocks are dropped first. Default is 1000"
+    )]
+    pub comparison_history_limit: usize,
+    #[clap(
+        long,
+        value_name = "SENDER_AGREEMENT_THRESHOLD",
+        value_parser = Config::parse_ratio,
+        env = "SENDER_AGREEMENT_THRESHOLD",
+        help = "If set, senders agreeing with the remote majority less often than this ratio (0 to 1) have their stake weight scaled down",
+        long_help = "If set, senders agreeing with the remote majority less often than this ratio (0 to 1) have their stake weight scaled down.\n
+            Once a sender has taken part in enough comparisons, its stake is multiplied by its majority agreement rate. Off by default"
+    )]
+    pub sender_agreement_threshold: Option<f32>,
+    #[clap(
+        long,
+        value_name = "STAKE_CACHE_TTL",
+        default_value = "300",
+        env = "STAKE_CACHE_TTL",
+        help = "Number of seconds sender stakes and the current epoch are cached before they are fetched again from the network subgraph"
+    )]
+    pub stake_cache_ttl: i64,
+    #[clap(
+        long,
+        value_name = "CONSENSUS_POLICY",
+        value_enum,
+        default_value = "stake-weighted",
+        env = "CONSENSUS_POLICY",
+        help = "Policy used to pick the consensus nPOI among remote attestations",
+        long_help = "Policy used to pick the consensus nPOI among remote attestations\nstake-weighted: The nPOI backed by the most stake\n
+            sender-count: The nPOI sent by the most senders, stake only breaks ties\n
+            trusted-set: The nPOI sent by the most senders listed in trusted_senders, other senders are ignored.\n
+            Default is stake-weighted"
+    )]
+    pub consensus_policy: ConsensusPolicyType,
+    #[clap(
+        long,
+        value_name = "[TRUSTED_SENDER]",
+        value_delimiter = ',',
+        env = "TRUSTED_SENDERS",
+        help = "Comma separated list of indexer addresses that count towards consensus under the trusted-set policy"
+    )]
+    pub trusted_senders: Vec<String>,
+    #[clap(
+        long,
+        value_name = "MESSAGE_RETENTION_SECS",
+        default_value = "86400",
+        env = "MESSAGE_RETENTION_SECS",
+        help = "Number of seconds remote messages are kept in the cache, judged by their nonce timestamp",
+        long_help = "Number of seconds remote messages are kept in the cache, judged by their nonce timestamp.\n
+            Messages for deployments the Radio is no longer subscribed to are evicted regardless, equivocation evidence is kept as long as its latest message would be. Default is 86400 (a day)"
+    )]
+    pub message_retention_secs: i64,
+    #[clap(
+        long,
+        value_name = "MAX_MESSAGES_PER_DEPLOYMENT",
+        default_value = "1000",
+        env = "MAX_MESSAGES_PER_DEPLOYMENT",
+        help = "Maximum number of remote messages kept in the cache per deployment, the oldest ones are evicted first. Also bounds the equivocation evidence kept per deployment"
+    )]
+    pub max_messages_per_deployment: usize,
     #[clap(
         long,
         value_name = "WAKU_HOST",
@@ -255,6 +360,13 @@ pub struct Config {
         env = "SERVER_PORT"
     )]
     pub server_port: Option<u16>,
+    #[clap(
+        long,
+        value_name = "SERVER_ADMIN_TOKEN",
+        env = "SERVER_ADMIN_TOKEN",
+        help = "If set, the API service exposes state snapshot and restore at /api/v1/state, for requests bearing this token"
+    )]
+    pub server_admin_token: Option<String>,
     #[clap(
         long,
         value_name = "PERSISTENCE_FILE_PATH",
@@ -262,6 +374,48 @@ pub struct Config {
         env = "PERSISTENCE_FILE_PATH"
     )]
     pub persistence_file_path: Option<String>,
+    #[clap(
+        long,
+        value_name = "STORAGE_BACKEND",
+        value_enum,
+        default_value = "json",
+        env = "STORAGE_BACKEND",
+        help = "Storage backend for the persisted state at persistence_file_path",
+        long_help = "Storage backend for the persisted state at persistence_file_path\njson: The whole state is rewritten to a JSON file on every save\n
+            sqlite: An embedded SQLite database, only changes are written on every save and the data can be queried from outside the Radio.\n
+            Default is json"
+    )]
+    pub storage_backend: StorageBackend,
+    #[clap(
+        long,
+        value_name = "AUDIT_LOG_PATH",
+        env = "AUDIT_LOG_PATH",
+        help = "If set, every message received or sent is appended to this file in JSON Lines format, with the outcome of its validation"
+    )]
+    pub audit_log_path: Option<String>,
+    #[clap(
+        long,
+        value_name = "AUDIT_LOG_MAX_BYTES",
+        env = "AUDIT_LOG_MAX_BYTES",
+        default_value = "104857600",
+        help = "Size in bytes past which the audit log is rotated, rotated files are kept next to it"
+    )]
+    pub audit_log_max_bytes: u64,
+    #[clap(
+        long,
+        value_name = "AUDIT_LOG_ROTATION_SECS",
+        env = "AUDIT_LOG_ROTATION_SECS",
+        default_value = "86400",
+        help = "Age in seconds past which the audit log is rotated, rotated files are kept next to it"
+    )]
+    pub audit_log_rotation_secs: i64,
+    #[clap(
+        long,
+        value_name = "RECORD_PATH",
+        env = "RECORD_PATH",
+        help = "If set, gossip messages and Graph node responses consumed by the Radio are recorded to this file, to be replayed with `poi-radio replay`"
+    )]
+    pub record_path: Option<String>,
     #[clap(
         long,
         value_name = "LOG_FORMAT",
@@ -304,6 +458,52 @@ pub struct Config {
         default_value = "600"
     )]
     pub topic_update_interval: u64,
+    #[clap(
+        long,
+        value_name = "POI_MESSAGE_VERSION",
+        env = "POI_MESSAGE_VERSION",
+        default_value = "0",
+        value_parser = Config::parse_poi_message_version,
+        help = "Version of the POI messages to send, radios drop versions newer than they can decode. Keep the previous version until most radios of the network are upgraded"
+    )]
+    // Recordings made before the field was added sent the legacy version
+    #[serde(default)]
+    pub poi_message_version: u32,
+    #[clap(
+        long,
+        value_name = "MAX_POI_BATCH_SIZE",
+        env = "MAX_POI_BATCH_SIZE",
+        help = "If set, nPOIs are gossiped in batches of up to this many deployments on the poi-batch content topic, instead of one message per deployment. Radios that predate batches do not read them"
+    )]
+    pub max_poi_batch_size: Option<usize>,
+    #[clap(
+        long,
+        value_name = "HEARTBEAT_INTERVAL",
+        env = "HEARTBEAT_INTERVAL",
+        default_value = "300",
+        help = "Seconds between the heartbeats the Radio gossips with its versions, deployments and chainheads, 0 to not send heartbeats"
+    )]
+    // Recordings made before the field was added come from radios that did not send heartbeats
+    #[serde(default)]
+    pub heartbeat_interval: u64,
+    #[clap(
+        long,
+        value_name = "UPGRADE_AUTO_SUBSCRIBE",
+        env = "UPGRADE_AUTO_SUBSCRIBE",
+        help = "Subscribe to the new deployment of upgrades announced by subgraph owners, so cross-checking starts on it before the migrate time"
+    )]
+    pub upgrade_auto_subscribe: Option<bool>,
+    #[clap(
+        long,
+        value_name = "[UPGRADE_REMINDER_LEAD]",
+        value_delimiter = ',',
+        env = "UPGRADE_REMINDER_LEADS",
+        default_value = "86400,3600",
+        help = "Comma separated seconds before the migrate time of an announced upgrade at which to remind the indexer of it"
+    )]
+    // Recordings made before the field was added come from radios that did not send reminders
+    #[serde(default)]
+    pub upgrade_reminder_leads: Vec<i64>,
 }
 
 impl Config {
@@ -326,6 +526,61 @@ impl Config {
         Ok(String::from(value))
     }
 
+    /// Validate that a ratio is between 0 and 1
+    pub(crate) fn parse_ratio(value: &str) -> Result<f32, String> {
+        let ratio = value
+            .parse::<f32>()
+            .map_err(|e| format!("Ratio must be a number: {e}"))?;
+        if (0.0..=1.0).contains(&ratio) {
+            Ok(ratio)
+        } else {
+            Err(format!("Ratio must be between 0 and 1, got {ratio}"))
+        }
+    }
+
+    /// Validate that this radio can sign POI messages of a version
+    fn parse_poi_message_version(value: &str) -> Result<u32, String> {
+        let version = value
+            .parse::<u32>()
+            .map_err(|e| format!("Message version must be a number: {e}"))?;
+        if version <= POI_MESSAGE_VERSION {
+            Ok(version)
+        } else {
+            Err(format!(
+                "Message version must be at most {POI_MESSAGE_VERSION}, got {version}"
+            ))
+        }
+    }
+
+    /// Quorum the top remote nPOI must reach before comparisons are conclusive
+    pub fn quorum_threshold(&self) -> QuorumThreshold {
+        QuorumThreshold::new(self.quorum_stake_ratio, self.quorum_min_senders)
+    }
+
+    /// Retention policy for cached remote messages
+    pub fn message_retention(&self) -> MessageRetention {
+        MessageRetention {
+            max_age: self.message_retention_secs,
+            max_per_deployment: self.max_messages_per_deployment,
+        }
+    }
+
+    /// Consensus policy used to compare against remote attestations
+    pub fn consensus(&self) -> ConsensusPolicy {
+        match self.consensus_policy {
+            ConsensusPolicyType::StakeWeighted => ConsensusPolicy::StakeWeighted,
+            ConsensusPolicyType::SenderCount => ConsensusPolicy::SenderCount,
+            ConsensusPolicyType::TrustedSet => ConsensusPolicy::trusted_set(&self.trusted_senders),
+        }
+    }
+
+    /// Stake multipliers for remote senders, empty unless sender_agreement_threshold is set
+    pub fn sender_weights(&self, state: &PersistedState) -> SenderWeights {
+        self.sender_agreement_threshold
+            .map(|threshold| state.sender_weights(threshold))
+            .unwrap_or_default()
+    }
+
     /// Private key takes precedence over mnemonic
     pub fn wallet_input(&self) -> Result<&String, ConfigError> {
         match (&self.private_key, &self.mnemonic) {
@@ -365,7 +620,7 @@ impl Config {
         .await
     }
 
-    pub async fn basic_info(&self) -> Result<(String, f32), QueryError> {
+    pub async fn basic_info(&self) -> Result<(String, Stake), QueryError> {
         // Using unwrap directly as the query has been ran in the set-up validation
         let wallet = build_wallet(
             self.wallet_input()
@@ -375,37 +630,62 @@ impl Config {
         // The query here must be Ok but so it is okay to panic here
         // Alternatively, make validate_set_up return wallet, address, and stake
         let my_address = query_registry(self.registry_subgraph(), &wallet_address(&wallet)).await?;
-        let my_stake = query_network_subgraph(self.network_subgraph(), &my_address)
-            .await
-            .unwrap()
-            .indexer_stake();
+        let my_stake = query_indexer_stake(self.network_subgraph(), &my_address).await?;
         info!(
             my_address,
-            my_stake, "Initializing radio operator for indexer identity",
+            my_stake = my_stake.to_string(),
+            "Initializing radio operator for indexer identity",
         );
         Ok((my_address, my_stake))
     }
 
-    pub async fn init_radio_state(&self) -> PersistedState {
-        let file_path = &self.persistence_file_path.clone();
+    /// Store for the radio state, if a persistence file path is set
+    pub fn state_store(&self) -> Option<Arc<dyn StateStore>> {
+        self.persistence_file_path.as_ref().map(|path| {
+            open_store(self.storage_backend, path).unwrap_or_else(|e| {
+                panic!(
+                    "Could not open {:?} state storage at {path}: {e}",
+                    self.storage_backend
+                )
+            })
+        })
+    }
 
-        if let Some(path) = file_path {
-            //TODO: set up synchronous panic hook as part of PersistedState functions
-            // panic_hook(&path);
-            let state = PersistedState::load_cache(path);
-            trace!(
-                local_attestations = tracing::field::debug(&state.local_attestations()),
-                remote_messages = tracing::field::debug(&state.remote_messages()),
-                state = tracing::field::debug(&state),
-                "Loaded Persisted state cache"
-            );
+    pub async fn init_radio_state(&self, store: Option<&Arc<dyn StateStore>>) -> PersistedState {
+        let mut state = if let Some(store) = store {
+            let state = match store.load() {
+                Ok(Some(state)) => {
+                    trace!(
+                        local_attestations = tracing::field::debug(&state.local_attestations()),
+                        remote_messages = tracing::field::debug(&state.remote_messages()),
+                        state = tracing::field::debug(&state),
+                        "Loaded Persisted state cache"
+                    );
+                    state
+                }
+                Ok(None) => {
+                    debug!("No persisted state found, created new state");
+                    PersistedState::new(None, None, None)
+                }
+                // The unreadable state was kept aside, safe to start over
+                Err(e @ StorageError::Quarantined { .. }) => {
+                    error!(err = e.to_string(), "Created new state");
+                    PersistedState::new(None, None, None)
+                }
+                // Starting empty would overwrite the stored state on the next save
+                Err(e) => panic!(
+                    "Could not load persisted state: {e}. Fix or move away the persisted state to start with an empty state"
+                ),
+            };
 
-            panic_hook(path);
+            panic_hook(store.clone());
             state
         } else {
             debug!("Created new state");
             PersistedState::new(None, None, None)
-        }
+        };
+        state.set_stake_cache_ttl(self.stake_cache_ttl);
+        state
     }
 
     pub fn callbook(&self) -> CallBook {
@@ -1,6 +1,15 @@
 use graphcast_sdk::graphql::QueryError;
 use graphql_client::{GraphQLQuery, Response};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::operator::stake::{first_block_reaching, EpochStart, Stake};
+
+/// BigInt scalar of the network subgraph, a decimal string
+type BigInt = String;
+
+/// Maximum number of entities the network subgraph returns for a single query
+const NETWORK_QUERY_PAGE_SIZE: usize = 1000;
 
 // Maybe later on move graphql to SDK as the queries are pretty standarded
 
@@ -30,6 +39,51 @@ pub struct IndexingStatuses;
 )]
 pub struct BlockHashFromNumber;
 
+/// Derived GraphQL Query to the version of the Graph node
+#[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
+#[graphql(
+    schema_path = "src/graphql/schema_graph_node.graphql",
+    query_path = "src/graphql/query_graph_node_version.graphql",
+    response_derives = "Debug, Serialize, Deserialize"
+)]
+pub struct GraphNodeVersion;
+
+/// Derived GraphQL Query to the indexer stake in the network subgraph
+#[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
+#[graphql(
+    schema_path = "src/graphql/schema_network.graphql",
+    query_path = "src/graphql/query_indexer_stake.graphql",
+    response_derives = "Debug, Serialize, Deserialize"
+)]
+pub struct IndexerStake;
+
+/// Derived GraphQL Query to the stakes of a set of indexers at a block in the network subgraph
+#[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
+#[graphql(
+    schema_path = "src/graphql/schema_network.graphql",
+    query_path = "src/graphql/query_indexer_stakes_at.graphql",
+    response_derives = "Debug, Serialize, Deserialize"
+)]
+pub struct IndexerStakesAt;
+
+/// Derived GraphQL Query to the latest protocol epoch and the chain head of the network subgraph
+#[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
+#[graphql(
+    schema_path = "src/graphql/schema_network.graphql",
+    query_path = "src/graphql/query_current_epoch.graphql",
+    response_derives = "Debug, Serialize, Deserialize"
+)]
+pub struct CurrentEpoch;
+
+/// Derived GraphQL Query to the latest L1 block the protocol had seen at a block of the network subgraph chain
+#[derive(GraphQLQuery, Serialize, Deserialize, Debug)]
+#[graphql(
+    schema_path = "src/graphql/schema_network.graphql",
+    query_path = "src/graphql/query_l1_block_at.graphql",
+    response_derives = "Debug, Serialize, Deserialize"
+)]
+pub struct L1BlockAt;
+
 /// Query graph node for Proof of Indexing
 pub async fn perform_proof_of_indexing(
     graph_node_endpoint: String,
@@ -75,3 +129,180 @@ pub async fn query_graph_node_poi(
         ))
     }
 }
+
+/// Post a query to a GraphQL endpoint, such as the network subgraph, and return its response data
+async fn post_query<Q: GraphQLQuery>(
+    endpoint: &str,
+    variables: Q::Variables,
+) -> Result<Option<Q::ResponseData>, QueryError> {
+    let request_body = Q::build_query(variables);
+    let client = reqwest::Client::new();
+    let response = client
+        .post(endpoint)
+        .json(&request_body)
+        .send()
+        .await?
+        .error_for_status()?;
+    let response_body: Response<Q::ResponseData> = response.json().await?;
+
+    if let Some(errors) = response_body.errors.as_deref() {
+        return Err(QueryError::Other(anyhow::anyhow!("{}", errors[0].message)));
+    }
+    Ok(response_body.data)
+}
+
+fn parse_staked_tokens(indexer_address: &str, staked_tokens: &str) -> Result<Stake, QueryError> {
+    staked_tokens.parse().map_err(|e| {
+        QueryError::ParseResponseError(format!(
+            "Invalid staked tokens for indexer {indexer_address}: {e}"
+        ))
+    })
+}
+
+/// Query the network subgraph for the exact staked tokens of an indexer, zero if the indexer is not found
+pub async fn query_indexer_stake(
+    network_subgraph: &str,
+    indexer_address: &str,
+) -> Result<Stake, QueryError> {
+    let variables: indexer_stake::Variables = indexer_stake::Variables {
+        address: indexer_address.to_string(),
+    };
+    match post_query::<IndexerStake>(network_subgraph, variables).await? {
+        Some(data) => match data.indexer {
+            Some(indexer) => parse_staked_tokens(indexer_address, &indexer.staked_tokens),
+            None => Ok(Stake::zero()),
+        },
+        None => Err(QueryError::ParseResponseError(format!(
+            "Missing response data from network subgraph for {indexer_address}"
+        ))),
+    }
+}
+
+/// Query the network subgraph for the staked tokens of a set of indexers as of a block of the protocol chain,
+/// in pages of NETWORK_QUERY_PAGE_SIZE addresses. Indexers that did not exist at that block have zero stake
+pub async fn query_indexer_stakes_at(
+    network_subgraph: &str,
+    indexer_addresses: &[String],
+    block_number: u64,
+) -> Result<HashMap<String, Stake>, QueryError> {
+    let mut stakes: HashMap<String, Stake> = indexer_addresses
+        .iter()
+        .map(|address| (address.clone(), Stake::zero()))
+        .collect();
+    for page in indexer_addresses.chunks(NETWORK_QUERY_PAGE_SIZE) {
+        let variables: indexer_stakes_at::Variables = indexer_stakes_at::Variables {
+            // Indexer ids in the network subgraph are lowercase addresses
+            addresses: page.iter().map(|address| address.to_lowercase()).collect(),
+            block: block_number as i64,
+        };
+        let data = post_query::<IndexerStakesAt>(network_subgraph, variables)
+            .await?
+            .ok_or_else(|| {
+                QueryError::ParseResponseError(format!(
+                    "Missing response data from network subgraph for indexer stakes at block {block_number}"
+                ))
+            })?;
+        for indexer in data.indexers {
+            let stake = parse_staked_tokens(&indexer.id, &indexer.staked_tokens)?;
+            for address in page.iter().filter(|a| a.to_lowercase() == indexer.id) {
+                stakes.insert(address.clone(), stake.clone());
+            }
+        }
+    }
+    Ok(stakes)
+}
+
+/// Query the network subgraph for the latest L1 block the protocol had seen at a block of the network subgraph chain,
+/// None before the network was created
+async fn query_l1_block_at(network_subgraph: &str, block: u64) -> Result<Option<u64>, QueryError> {
+    let variables = l1_block_at::Variables {
+        block: block as i64,
+    };
+    let data = post_query::<L1BlockAt>(network_subgraph, variables)
+        .await?
+        .ok_or_else(|| {
+            QueryError::ParseResponseError(format!(
+                "Missing response data from network subgraph for the L1 block at {block}"
+            ))
+        })?;
+    data.graph_network
+        .and_then(|network| network.current_l1_block_number)
+        .map(|number| {
+            number.parse::<u64>().map_err(|e| {
+                QueryError::ParseResponseError(format!("Invalid L1 block number {number}: {e}"))
+            })
+        })
+        .transpose()
+}
+
+/// Query the network subgraph for the current protocol epoch and the block of the network subgraph chain its stakes
+/// are read at. Epochs are counted in blocks of the chain the protocol runs on, or in L1 blocks when it runs on L2,
+/// where the network subgraph indexes a different chain. Stakes are then read at the first L2 block at which the
+/// protocol had seen the L1 start block of the epoch, so every radio reads them at the same block no matter when
+/// it sees the epoch. `latest` is the latest epoch already seen, it is returned as is while still current and
+/// bounds the search for the stake block of the next one
+pub async fn query_current_epoch(
+    network_subgraph: &str,
+    latest: Option<EpochStart>,
+) -> Result<EpochStart, QueryError> {
+    let data = post_query::<CurrentEpoch>(network_subgraph, current_epoch::Variables)
+        .await?
+        .ok_or_else(|| {
+            QueryError::ParseResponseError(
+                "Missing response data from network subgraph for the current epoch".to_string(),
+            )
+        })?;
+    let epoch = data.epoches.first().ok_or_else(|| {
+        QueryError::ParseResponseError("No epoch found in the network subgraph".to_string())
+    })?;
+    let id = epoch.id.parse::<u64>().map_err(|e| {
+        QueryError::ParseResponseError(format!("Invalid epoch id {}: {e}", epoch.id))
+    })?;
+    let start_block = epoch.start_block as u64;
+    if let Some(latest) = latest.filter(|latest| latest.epoch == id) {
+        return Ok(latest);
+    }
+    let counts_l1_blocks = data
+        .graph_network
+        .is_some_and(|network| network.current_l1_block_number.is_some());
+    let stake_block = if counts_l1_blocks {
+        let head = data
+            .meta
+            .ok_or_else(|| {
+                QueryError::ParseResponseError(
+                    "Missing chain head of the network subgraph".to_string(),
+                )
+            })?
+            .block
+            .number as u64;
+        // The stake block of an earlier epoch is the first block at which an earlier L1 block was seen
+        let floor = latest
+            .filter(|latest| latest.epoch < id)
+            .map_or(0, |latest| latest.stake_block);
+        first_block_reaching(floor, head, |block| async move {
+            Ok(query_l1_block_at(network_subgraph, block)
+                .await?
+                .is_some_and(|l1_block| l1_block >= start_block))
+        })
+        .await?
+    } else {
+        start_block
+    };
+    Ok(EpochStart {
+        epoch: id,
+        start_block,
+        stake_block,
+    })
+}
+
+/// Query the Graph node for its version
+pub async fn query_graph_node_version(graph_node_endpoint: &str) -> Result<String, QueryError> {
+    let data = post_query::<GraphNodeVersion>(graph_node_endpoint, graph_node_version::Variables)
+        .await?
+        .ok_or_else(|| {
+            QueryError::ParseResponseError(
+                "Missing response data from Graph node for its version".to_string(),
+            )
+        })?;
+    Ok(data.version.version)
+}
@@ -23,13 +23,17 @@ use graphcast_sdk::{
 
 use crate::operator::{attestation::AttestationError, RadioOperator};
 
+pub mod cli;
 pub mod config;
 pub mod graphql;
 pub mod messages;
 pub mod metrics;
 pub mod operator;
+pub mod replay;
 pub mod server;
+pub mod simulate;
 pub mod state;
+pub mod storage;
 
 /// A global static (singleton) instance of GraphcastAgent. It is useful to ensure that we have only one GraphcastAgent
 /// per Radio instance, so that we can keep track of state and more easily test our Radio application.
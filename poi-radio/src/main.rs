@@ -1,12 +1,47 @@
+use clap::Parser;
 use dotenv::dotenv;
 
-use poi_radio::{config::Config, operator::RadioOperator, RADIO_OPERATOR};
+use poi_radio::{
+    cli::{
+        run_replay_command, run_simulate_command, run_state_command, ReplayCli, SimulateCli,
+        StateCli,
+    },
+    config::Config,
+    operator::RadioOperator,
+    RADIO_OPERATOR,
+};
 
 extern crate partial_application;
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    // State maintenance, replays and simulations run on their own, without the configuration needed to join the network
+    let result = match std::env::args().nth(1).as_deref() {
+        Some("state") => Some(
+            run_state_command(StateCli::parse_from(std::env::args_os().skip(1)))
+                .map_err(|e| e.to_string()),
+        ),
+        Some("replay") => Some(
+            run_replay_command(ReplayCli::parse_from(std::env::args_os().skip(1)))
+                .await
+                .map_err(|e| e.to_string()),
+        ),
+        Some("simulate") => Some(
+            run_simulate_command(SimulateCli::parse_from(std::env::args_os().skip(1)))
+                .await
+                .map_err(|e| e.to_string()),
+        ),
+        _ => None,
+    };
+    if let Some(result) = result {
+        if let Err(e) = result {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Parse basic configurations
     let radio_config = Config::args();
 
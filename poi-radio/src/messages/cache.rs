@@ -0,0 +1,344 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::messages::poi::PublicPoiMessage;
+
+type PoiMessage = GraphcastMessage<PublicPoiMessage>;
+
+/// Messages of one deployment by block number, each tagged with its sequence number in the cache
+#[derive(Clone, Default)]
+struct DeploymentMessages {
+    blocks: HashMap<u64, Vec<(u64, PoiMessage)>>,
+    len: usize,
+}
+
+impl DeploymentMessages {
+    fn sorted(&self) -> Vec<(u64, PoiMessage)> {
+        let mut msgs: Vec<(u64, PoiMessage)> = self.blocks.values().flatten().cloned().collect();
+        msgs.sort_unstable_by_key(|(seq, _)| *seq);
+        msgs
+    }
+}
+
+/// Keep the messages of a deployment matching the predicate, returns the number of dropped messages.
+/// The deployment is only copied if it is shared with a clone of the cache and loses messages
+fn retain(
+    entry: &mut Arc<DeploymentMessages>,
+    mut f: impl FnMut(u64, &PoiMessage) -> bool,
+) -> usize {
+    if entry
+        .blocks
+        .values()
+        .flatten()
+        .all(|(seq, msg)| f(*seq, msg))
+    {
+        return 0;
+    }
+    let entry = Arc::make_mut(entry);
+    let before = entry.len;
+    entry.blocks.retain(|_, msgs| {
+        msgs.retain(|(seq, msg)| f(*seq, msg));
+        !msgs.is_empty()
+    });
+    entry.len = entry.blocks.values().map(Vec::len).sum();
+    before - entry.len
+}
+
+/// Remote POI messages indexed by deployment and block.
+/// Deployments are shared between clones of the cache and copied on their first change, so a clone costs
+/// one pointer per deployment and a change only copies the deployment it touches.
+/// Messages keep the order they were received in, which is also the order they are serialized in
+#[derive(Clone, Default)]
+pub struct RemoteMessages {
+    deployments: HashMap<String, Arc<DeploymentMessages>>,
+    next_seq: u64,
+}
+
+impl RemoteMessages {
+    /// Add a message, returns the number of messages cached for its deployment
+    pub fn insert(&mut self, msg: PoiMessage) -> usize {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let block = msg.payload.block_number;
+        let entry = Arc::make_mut(self.deployments.entry(msg.identifier.clone()).or_default());
+        entry.blocks.entry(block).or_default().push((seq, msg));
+        entry.len += 1;
+        entry.len
+    }
+
+    /// Total number of cached messages
+    pub fn len(&self) -> usize {
+        self.deployments.values().map(|entry| entry.len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deployments.is_empty()
+    }
+
+    /// Number of messages cached for a deployment
+    pub fn deployment_len(&self, deployment: &str) -> usize {
+        match self.deployments.get(deployment) {
+            Some(entry) => entry.len,
+            None => 0,
+        }
+    }
+
+    /// Deployments with cached messages
+    pub fn deployments(&self) -> Vec<String> {
+        self.deployments.keys().cloned().collect()
+    }
+
+    /// Messages of a deployment in received order
+    pub fn deployment(&self, deployment: &str) -> Vec<PoiMessage> {
+        self.deployments
+            .get(deployment)
+            .map(|entry| entry.sorted().into_iter().map(|(_, msg)| msg).collect())
+            .unwrap_or_default()
+    }
+
+    /// Messages of a deployment at a block in received order
+    pub fn block(&self, deployment: &str, block: u64) -> Vec<PoiMessage> {
+        self.deployments
+            .get(deployment)
+            .and_then(|entry| entry.blocks.get(&block))
+            .map(|msgs| msgs.iter().map(|(_, msg)| msg.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// All messages in received order
+    pub fn all(&self) -> Vec<PoiMessage> {
+        let mut msgs: Vec<(u64, PoiMessage)> = self
+            .deployments
+            .values()
+            .flat_map(|entry| entry.sorted())
+            .collect();
+        msgs.sort_unstable_by_key(|(seq, _)| *seq);
+        msgs.into_iter().map(|(_, msg)| msg).collect()
+    }
+
+    /// Drop the messages of a deployment below a block, returns the number of dropped messages
+    pub fn remove_before(&mut self, deployment: &str, block: u64) -> usize {
+        self.retain_deployment(deployment, |msg| msg.payload.block_number >= block)
+    }
+
+    /// Keep the messages of a deployment matching the predicate, returns the number of dropped messages
+    pub fn retain_deployment(
+        &mut self,
+        deployment: &str,
+        mut f: impl FnMut(&PoiMessage) -> bool,
+    ) -> usize {
+        let removed = match self.deployments.get_mut(deployment) {
+            Some(entry) => retain(entry, |_, msg| f(msg)),
+            None => return 0,
+        };
+        self.drop_empty();
+        removed
+    }
+
+    /// Keep the messages matching the predicate, returns the number of dropped messages by deployment
+    pub fn retain(&mut self, mut f: impl FnMut(&PoiMessage) -> bool) -> HashMap<String, usize> {
+        let removed = self
+            .deployments
+            .iter_mut()
+            .filter_map(|(deployment, entry)| {
+                let removed = retain(entry, |_, msg| f(msg));
+                (removed > 0).then(|| (deployment.clone(), removed))
+            })
+            .collect();
+        self.drop_empty();
+        removed
+    }
+
+    /// Keep the most recently received messages of each deployment up to max,
+    /// returns the number of dropped messages by deployment
+    pub fn truncate(&mut self, max: usize) -> HashMap<String, usize> {
+        let removed = self
+            .deployments
+            .iter_mut()
+            .filter_map(|(deployment, entry)| {
+                if entry.len <= max {
+                    return None;
+                }
+                let sorted = entry.sorted();
+                let oldest_kept = sorted
+                    .get(sorted.len() - max)
+                    .map_or(u64::MAX, |(seq, _)| *seq);
+                let removed = retain(entry, |seq, _| seq >= oldest_kept);
+                Some((deployment.clone(), removed))
+            })
+            .collect();
+        self.drop_empty();
+        removed
+    }
+
+    /// Messages added since an earlier clone of the cache and the messages dropped since then,
+    /// deployments still shared with the clone are skipped without looking at their messages
+    pub fn changes_since<'a>(
+        &'a self,
+        earlier: &'a RemoteMessages,
+    ) -> (Vec<&'a PoiMessage>, Vec<&'a PoiMessage>) {
+        let mut added = vec![];
+        let mut dropped = vec![];
+        for (deployment, entry) in &self.deployments {
+            match earlier.deployments.get(deployment) {
+                Some(earlier_entry) if Arc::ptr_eq(entry, earlier_entry) => {}
+                Some(earlier_entry) => {
+                    let seqs = |entry: &DeploymentMessages| -> HashSet<u64> {
+                        entry
+                            .blocks
+                            .values()
+                            .flatten()
+                            .map(|(seq, _)| *seq)
+                            .collect()
+                    };
+                    let (kept, earlier_kept) = (seqs(entry), seqs(earlier_entry));
+                    added.extend(
+                        entry
+                            .blocks
+                            .values()
+                            .flatten()
+                            .filter(|(seq, _)| !earlier_kept.contains(seq))
+                            .map(|(_, msg)| msg),
+                    );
+                    dropped.extend(
+                        earlier_entry
+                            .blocks
+                            .values()
+                            .flatten()
+                            .filter(|(seq, _)| !kept.contains(seq))
+                            .map(|(_, msg)| msg),
+                    );
+                }
+                None => added.extend(entry.blocks.values().flatten().map(|(_, msg)| msg)),
+            }
+        }
+        for (deployment, earlier_entry) in &earlier.deployments {
+            if !self.deployments.contains_key(deployment) {
+                dropped.extend(earlier_entry.blocks.values().flatten().map(|(_, msg)| msg));
+            }
+        }
+        (added, dropped)
+    }
+
+    fn drop_empty(&mut self) {
+        self.deployments.retain(|_, entry| entry.len > 0);
+    }
+}
+
+impl fmt::Debug for RemoteMessages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.all()).finish()
+    }
+}
+
+impl Serialize for RemoteMessages {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.all().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RemoteMessages {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<PoiMessage>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl FromIterator<PoiMessage> for RemoteMessages {
+    fn from_iter<I: IntoIterator<Item = PoiMessage>>(iter: I) -> Self {
+        let mut cache = RemoteMessages::default();
+        for msg in iter {
+            cache.insert(msg);
+        }
+        cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(deployment: &str, block: u64) -> PoiMessage {
+        GraphcastMessage {
+            identifier: deployment.to_string(),
+            nonce: block as i64,
+            graph_account: String::from("0xa1"),
+            payload: PublicPoiMessage {
+                identifier: deployment.to_string(),
+                content: String::from("npoi-x"),
+                nonce: block as i64,
+                network: String::from("goerli"),
+                block_number: block,
+                block_hash: String::from("0xblahh"),
+                graph_account: String::from("0xa1"),
+                version: 0,
+            },
+            signature: format!("{deployment}-{block}"),
+        }
+    }
+
+    #[test]
+    fn test_remote_messages_index() {
+        let mut cache: RemoteMessages = [
+            message("Qm1", 2),
+            message("Qm2", 1),
+            message("Qm1", 1),
+            message("Qm1", 3),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.deployment_len("Qm1"), 3);
+        assert_eq!(cache.block("Qm1", 1).len(), 1);
+        let blocks: Vec<u64> = cache
+            .deployment("Qm1")
+            .iter()
+            .map(|m| m.payload.block_number)
+            .collect();
+        assert_eq!(blocks, vec![2, 1, 3]);
+
+        assert_eq!(cache.remove_before("Qm1", 2), 1);
+        assert_eq!(cache.truncate(1), HashMap::from([(String::from("Qm1"), 1)]));
+        assert_eq!(cache.retain(|m| m.identifier != "Qm2").len(), 1);
+        assert_eq!(cache.deployments(), vec![String::from("Qm1")]);
+
+        // Serialized in received order, like the Vec it replaces
+        let json = serde_json::to_string(&cache).unwrap();
+        let loaded: RemoteMessages = serde_json::from_str(&json).unwrap();
+        let signatures: Vec<String> = loaded.all().into_iter().map(|m| m.signature).collect();
+        assert_eq!(signatures, vec![String::from("Qm1-3")]);
+    }
+
+    #[test]
+    fn test_remote_messages_clone_on_write() {
+        let mut cache: RemoteMessages = [message("Qm1", 1), message("Qm2", 1), message("Qm3", 1)]
+            .into_iter()
+            .collect();
+        let published = cache.clone();
+        cache.insert(message("Qm1", 2));
+        assert_eq!(cache.remove_before("Qm2", 2), 1);
+        // Nothing to drop, so the deployment stays shared
+        assert_eq!(cache.remove_before("Qm3", 0), 0);
+
+        assert_eq!(published.len(), 3);
+        assert_eq!(published.deployment_len("Qm1"), 1);
+        assert_eq!(cache.deployment_len("Qm1"), 2);
+        assert_eq!(cache.deployment_len("Qm2"), 0);
+        assert!(Arc::ptr_eq(
+            &cache.deployments["Qm3"],
+            &published.deployments["Qm3"]
+        ));
+
+        let (added, dropped) = cache.changes_since(&published);
+        let signatures = |msgs: Vec<&PoiMessage>| -> Vec<String> {
+            msgs.into_iter().map(|m| m.signature.clone()).collect()
+        };
+        assert_eq!(signatures(added), vec![String::from("Qm1-2")]);
+        assert_eq!(signatures(dropped), vec![String::from("Qm2-1")]);
+    }
+}
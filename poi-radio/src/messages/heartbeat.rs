@@ -0,0 +1,320 @@
+use async_graphql::SimpleObject;
+use axum::async_trait;
+use ethers_core::types::transaction::eip712::{EIP712Domain, Eip712, Eip712Error};
+use graphcast_sdk::{
+    graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage},
+    networks::NetworkName,
+    BlockPointer,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::operator::audit::ValidationCheck;
+use crate::operator::dispatch::{
+    graphcast_validity, MessageContext, RadioMessageHandler, Validation,
+};
+
+/// Content topic heartbeats are gossiped on, radios subscribe to it along with their deployments
+pub const HEARTBEAT_TOPIC: &str = "heartbeat";
+
+/// Version of this radio, as reported in its heartbeats
+pub const RADIO_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Latest block of a network known to the Graph node of a radio
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, Eq, SimpleObject)]
+pub struct NetworkChainhead {
+    #[prost(string, tag = "1")]
+    pub network: String,
+    #[prost(uint64, tag = "2")]
+    pub block_number: u64,
+    #[prost(string, tag = "3")]
+    pub block_hash: String,
+}
+
+/// Periodic status of a radio: the software it runs, the deployments it cross-checks and the chainheads it sees
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+pub struct HeartbeatMessage {
+    /// Always the heartbeat content topic
+    #[prost(string, tag = "1")]
+    pub identifier: String,
+    /// nonce cached to check against the next incoming message, also the time the heartbeat was sent
+    #[prost(int64, tag = "2")]
+    pub nonce: i64,
+    /// Empty if the radio could not query its Graph node
+    #[prost(string, tag = "3")]
+    pub graph_node_version: String,
+    #[prost(string, tag = "4")]
+    pub radio_version: String,
+    /// Graph account sender
+    #[prost(string, tag = "5")]
+    pub graph_account: String,
+    /// Deployments the radio cross-checks
+    #[prost(string, repeated, tag = "6")]
+    pub deployments: Vec<String>,
+    #[prost(message, repeated, tag = "7")]
+    pub chainheads: Vec<NetworkChainhead>,
+}
+
+/// EIP-712 type of heartbeats, with the chainheads flattened to one array element per network
+mod signed {
+    use ethers_contract::EthAbiType;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+
+    #[derive(Eip712, EthAbiType, Clone)]
+    #[eip712(
+        name = "HeartbeatMessage",
+        version = "0",
+        chain_id = 1,
+        verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+    )]
+    pub struct HeartbeatMessage {
+        pub identifier: String,
+        pub nonce: i64,
+        pub graph_node_version: String,
+        pub radio_version: String,
+        pub graph_account: String,
+        pub deployments: Vec<String>,
+        pub networks: Vec<String>,
+        pub block_numbers: Vec<u64>,
+        pub block_hashes: Vec<String>,
+    }
+}
+
+impl From<&HeartbeatMessage> for signed::HeartbeatMessage {
+    fn from(msg: &HeartbeatMessage) -> Self {
+        signed::HeartbeatMessage {
+            identifier: msg.identifier.clone(),
+            nonce: msg.nonce,
+            graph_node_version: msg.graph_node_version.clone(),
+            radio_version: msg.radio_version.clone(),
+            graph_account: msg.graph_account.clone(),
+            deployments: msg.deployments.clone(),
+            networks: msg.chainheads.iter().map(|c| c.network.clone()).collect(),
+            block_numbers: msg.chainheads.iter().map(|c| c.block_number).collect(),
+            block_hashes: msg
+                .chainheads
+                .iter()
+                .map(|c| c.block_hash.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Eip712 for HeartbeatMessage {
+    type Error = Eip712Error;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        signed::HeartbeatMessage::from(self).domain()
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        signed::HeartbeatMessage::type_hash()
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        signed::HeartbeatMessage::from(self).struct_hash()
+    }
+}
+
+impl HeartbeatMessage {
+    /// Heartbeat of this radio, with the chainheads sorted by network
+    pub fn build(
+        nonce: i64,
+        graph_account: String,
+        graph_node_version: String,
+        deployments: Vec<String>,
+        network_chainhead_blocks: &HashMap<NetworkName, BlockPointer>,
+    ) -> Self {
+        let mut chainheads: Vec<NetworkChainhead> = network_chainhead_blocks
+            .iter()
+            .map(|(network, block)| NetworkChainhead {
+                network: network.to_string(),
+                block_number: block.number,
+                block_hash: block.hash.clone(),
+            })
+            .collect();
+        chainheads.sort_by(|a, b| a.network.cmp(&b.network));
+        HeartbeatMessage {
+            identifier: HEARTBEAT_TOPIC.to_string(),
+            nonce,
+            graph_node_version,
+            radio_version: RADIO_VERSION.to_string(),
+            graph_account,
+            deployments,
+            chainheads,
+        }
+    }
+
+    /// Chainhead reported for a network
+    pub fn chainhead(&self, network: &str) -> Option<&NetworkChainhead> {
+        self.chainheads.iter().find(|c| c.network == network)
+    }
+
+    /// Check duplicated fields: payload message has duplicated fields with GraphcastMessage, the values must be the same
+    pub fn valid_outer(&self, outer: &GraphcastMessage<Self>) -> Result<&Self, BuildMessageError> {
+        if self.nonce == outer.nonce
+            && self.graph_account == outer.graph_account
+            && self.identifier == outer.identifier
+            && self.identifier == HEARTBEAT_TOPIC
+        {
+            Ok(self)
+        } else {
+            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                "Heartbeat wrapped by inconsistent GraphcastMessage: nonce {} <- {}, account {} <- {}, identifier {} <- {}",
+                self.nonce,
+                outer.nonce,
+                self.graph_account,
+                outer.graph_account,
+                self.identifier,
+                outer.identifier,
+            )))
+        }
+    }
+}
+
+/// The latest heartbeat of each indexer is kept in the state
+pub struct HeartbeatHandler;
+
+#[async_trait]
+impl RadioMessageHandler for HeartbeatHandler {
+    type Payload = HeartbeatMessage;
+    const NAME: &'static str = "HeartbeatMessage";
+    const TOPIC: Option<&'static str> = Some(HEARTBEAT_TOPIC);
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<HeartbeatMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        graphcast_validity(msg, context).await?;
+        msg.payload
+            .valid_outer(msg)
+            .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+        Ok(())
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<HeartbeatMessage>, context: &MessageContext) {
+        context.state.add_heartbeat(msg.payload).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::LocalWallet;
+
+    use crate::messages::poi::PublicPoiMessage;
+    use crate::messages::poi_batch::PublicPoiBatchMessage;
+    use crate::messages::poi_request::{PoiRequestMessage, PoiResponseMessage};
+    use crate::messages::upgrade::VersionUpgradeMessage;
+
+    fn heartbeat() -> HeartbeatMessage {
+        let chainheads = HashMap::from([
+            (
+                NetworkName::Mainnet,
+                BlockPointer {
+                    number: 20,
+                    hash: String::from("0xmainnet"),
+                },
+            ),
+            (
+                NetworkName::Goerli,
+                BlockPointer {
+                    number: 10,
+                    hash: String::from("0xgoerli"),
+                },
+            ),
+        ]);
+        HeartbeatMessage::build(
+            1687448729,
+            String::from("0xa1"),
+            String::from("0.32.0"),
+            vec![String::from("Qm1"), String::from("Qm2")],
+            &chainheads,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_signature() {
+        let wallet: LocalWallet =
+            "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        let heartbeat = heartbeat();
+        assert_eq!(heartbeat.chainheads[0].network, "goerli");
+        assert_eq!(heartbeat.chainhead("mainnet").unwrap().block_number, 20);
+        let msg = GraphcastMessage::build(
+            &wallet,
+            heartbeat.identifier.clone(),
+            heartbeat.graph_account.clone(),
+            heartbeat.nonce,
+            heartbeat,
+        )
+        .await
+        .unwrap();
+        assert!(msg.payload.valid_outer(&msg).is_ok());
+        let signer = msg.recover_sender_address().unwrap();
+
+        let mut tampered = msg.clone();
+        tampered.payload.chainheads[1].block_number = 21;
+        assert_ne!(tampered.recover_sender_address().unwrap(), signer);
+    }
+
+    #[test]
+    fn test_heartbeat_not_interchangeable() {
+        let heartbeat_bytes = heartbeat().encode_to_vec();
+        let public_poi = PublicPoiMessage::new(
+            String::from("Qm1"),
+            String::from("0xnpoi"),
+            1,
+            String::from("goerli"),
+            10,
+            String::from("0xgoerli"),
+            String::from("0xa1"),
+        );
+        let others = [
+            public_poi.encode_to_vec(),
+            PublicPoiBatchMessage::build(1, String::from("0xa1"), &[public_poi]).encode_to_vec(),
+            PoiRequestMessage::new(
+                String::from("Qm1"),
+                1,
+                String::from("goerli"),
+                10,
+                String::from("0xgoerli"),
+                String::from("0xa1"),
+            )
+            .encode_to_vec(),
+            PoiResponseMessage::new(
+                String::from("Qm1"),
+                1,
+                String::from("goerli"),
+                String::from("0xgoerli"),
+                10,
+                String::from("0xnpoi"),
+                String::from("0xa1"),
+            )
+            .encode_to_vec(),
+            VersionUpgradeMessage::new(
+                String::from("Qm1"),
+                String::from("Qm2"),
+                String::from("0xsubgraph"),
+                1,
+                String::from("goerli"),
+                2,
+                String::from("0xa1"),
+            )
+            .encode_to_vec(),
+        ];
+
+        assert!(PublicPoiMessage::decode(heartbeat_bytes.as_slice()).is_err());
+        assert!(PublicPoiBatchMessage::decode(heartbeat_bytes.as_slice()).is_err());
+        assert!(PoiRequestMessage::decode(heartbeat_bytes.as_slice()).is_err());
+        assert!(PoiResponseMessage::decode(heartbeat_bytes.as_slice()).is_err());
+        assert!(VersionUpgradeMessage::decode(heartbeat_bytes.as_slice()).is_err());
+        for bytes in others {
+            assert!(HeartbeatMessage::decode(bytes.as_slice()).is_err());
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod cache;
+pub mod heartbeat;
 pub mod poi;
+pub mod poi_batch;
+pub mod poi_request;
 pub mod upgrade;
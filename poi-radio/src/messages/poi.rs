@@ -1,23 +1,36 @@
-use async_graphql::SimpleObject;
-use ethers_contract::EthAbiType;
-use ethers_core::types::transaction::eip712::Eip712;
-use ethers_derive_eip712::*;
+use async_graphql::{OutputType, SimpleObject};
+use axum::async_trait;
+use chrono::Utc;
+use ethers_core::types::transaction::eip712::{EIP712Domain, Eip712, Eip712Error};
 use graphcast_sdk::{
-    graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage},
+    callbook::CallBook,
+    graphcast_agent::{
+        message_typing::{BuildMessageError, GraphcastMessage, IdentityValidation},
+        MSG_REPLAY_LIMIT,
+    },
     graphql::client_graph_node::query_graph_node_network_block_hash,
     networks::NetworkName,
 };
 use prost::Message;
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex as AsyncMutex;
 use tracing::trace;
 
-#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
-#[eip712(
-    name = "PublicPoiMessage",
-    version = "0",
-    chain_id = 1,
-    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
-)]
+use crate::metrics::CACHED_MESSAGES;
+use crate::operator::audit::ValidationCheck;
+use crate::operator::dispatch::{MessageContext, RadioMessageHandler, Validation};
+
+/// Latest nonce by deployment and sender address
+pub type Nonces = Arc<AsyncMutex<HashMap<String, HashMap<String, i64>>>>;
+
+/// Version of the POI messages this radio builds, messages of every version up to this one are decoded
+pub const POI_MESSAGE_VERSION: u32 = 1;
+
+/// nPOI of a deployment at a block, as gossiped by a radio. Every version decodes from the same layout, fields
+/// added by later versions are empty in earlier ones. Each version is signed with its own EIP-712 type and domain,
+/// so radios verify the versions they know and drop newer ones
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
 pub struct PublicPoiMessage {
     #[prost(string, tag = "1")]
     pub identifier: String,
@@ -39,6 +52,114 @@ pub struct PublicPoiMessage {
     /// Graph account sender
     #[prost(string, tag = "7")]
     pub graph_account: String,
+    /// Message version, absent from version 0 payloads
+    #[prost(uint32, tag = "8")]
+    #[serde(default)]
+    pub version: u32,
+}
+
+/// EIP-712 types of the released message versions. Their fields and domains must not change,
+/// a new version gets a new module instead
+mod signed {
+    use ethers_contract::EthAbiType;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+
+    pub mod v0 {
+        use super::*;
+
+        #[derive(Eip712, EthAbiType, Clone)]
+        #[eip712(
+            name = "PublicPoiMessage",
+            version = "0",
+            chain_id = 1,
+            verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+        )]
+        pub struct PublicPoiMessage {
+            pub identifier: String,
+            pub content: String,
+            pub nonce: i64,
+            pub network: String,
+            pub block_number: u64,
+            pub block_hash: String,
+            pub graph_account: String,
+        }
+    }
+
+    pub mod v1 {
+        use super::*;
+
+        #[derive(Eip712, EthAbiType, Clone)]
+        #[eip712(
+            name = "PublicPoiMessage",
+            version = "1",
+            chain_id = 1,
+            verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+        )]
+        pub struct PublicPoiMessage {
+            pub identifier: String,
+            pub content: String,
+            pub nonce: i64,
+            pub network: String,
+            pub block_number: u64,
+            pub block_hash: String,
+            pub graph_account: String,
+            pub version: u32,
+        }
+    }
+}
+
+impl From<&PublicPoiMessage> for signed::v0::PublicPoiMessage {
+    fn from(msg: &PublicPoiMessage) -> Self {
+        signed::v0::PublicPoiMessage {
+            identifier: msg.identifier.clone(),
+            content: msg.content.clone(),
+            nonce: msg.nonce,
+            network: msg.network.clone(),
+            block_number: msg.block_number,
+            block_hash: msg.block_hash.clone(),
+            graph_account: msg.graph_account.clone(),
+        }
+    }
+}
+
+impl From<&PublicPoiMessage> for signed::v1::PublicPoiMessage {
+    fn from(msg: &PublicPoiMessage) -> Self {
+        signed::v1::PublicPoiMessage {
+            identifier: msg.identifier.clone(),
+            content: msg.content.clone(),
+            nonce: msg.nonce,
+            network: msg.network.clone(),
+            block_number: msg.block_number,
+            block_hash: msg.block_hash.clone(),
+            graph_account: msg.graph_account.clone(),
+            version: msg.version,
+        }
+    }
+}
+
+/// Messages are signed as the type of their version. Versions newer than this radio's are
+/// hashed as the latest one it knows, they are dropped by valid_version before their signature matters
+impl Eip712 for PublicPoiMessage {
+    type Error = Eip712Error;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        match self.version {
+            0 => signed::v0::PublicPoiMessage::from(self).domain(),
+            _ => signed::v1::PublicPoiMessage::from(self).domain(),
+        }
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        signed::v1::PublicPoiMessage::type_hash()
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        match self.version {
+            0 => signed::v0::PublicPoiMessage::from(self).struct_hash(),
+            _ => signed::v1::PublicPoiMessage::from(self).struct_hash(),
+        }
+    }
 }
 
 impl PublicPoiMessage {
@@ -59,6 +180,7 @@ impl PublicPoiMessage {
             block_number,
             block_hash,
             graph_account,
+            version: POI_MESSAGE_VERSION,
         }
     }
 
@@ -82,6 +204,11 @@ impl PublicPoiMessage {
         )
     }
 
+    /// Send the message as an earlier version, for radios that do not decode the latest one yet
+    pub fn with_version(self, version: u32) -> Self {
+        PublicPoiMessage { version, ..self }
+    }
+
     pub fn payload_content(&self) -> String {
         self.content.clone()
     }
@@ -114,6 +241,19 @@ impl PublicPoiMessage {
         }
     }
 
+    /// Check the message is of a version this radio can verify the signature of
+    pub fn valid_version(&self) -> Result<&Self, BuildMessageError> {
+        if self.version <= POI_MESSAGE_VERSION {
+            Ok(self)
+        } else {
+            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                "Message version {} is newer than the latest version this radio decodes ({}), drop message",
+                self.version,
+                POI_MESSAGE_VERSION
+            )))
+        }
+    }
+
     /// Check duplicated fields: payload message has duplicated fields with GraphcastMessage, the values must be the same
     pub fn valid_outer(&self, outer: &GraphcastMessage<Self>) -> Result<&Self, BuildMessageError> {
         if self.nonce == outer.nonce
@@ -146,3 +286,172 @@ impl PublicPoiMessage {
         Ok(self)
     }
 }
+
+/// Run every check a received POI message goes through before it is cached, as of `now`.
+/// The message version is checked first, then the same checks as check_message_validity of Graphcast followed by
+/// validity_check, except the message age is measured against the given time so recorded traffic can be replayed
+pub async fn validate_poi_message(
+    msg: &GraphcastMessage<PublicPoiMessage>,
+    nonces: &Nonces,
+    callbook: &CallBook,
+    local_sender: &str,
+    id_validation: &IdentityValidation,
+    now: i64,
+) -> Result<(), (ValidationCheck, BuildMessageError)> {
+    msg.payload
+        .valid_version()
+        .map_err(|e| (ValidationCheck::ValidVersion, e))?;
+    graphcast_validity_at(msg, nonces, callbook, local_sender, id_validation, now)
+        .await
+        .map_err(|e| (ValidationCheck::CheckMessageValidity, e))?;
+
+    msg.payload
+        .valid_hash(callbook.graph_node_status())
+        .await
+        .map_err(|e| (ValidationCheck::ValidHash, e))?
+        .valid_outer(msg)
+        .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+    Ok(())
+}
+
+/// Same checks as check_message_validity of Graphcast, with the message age measured against `now`
+pub(crate) async fn graphcast_validity_at<T>(
+    msg: &GraphcastMessage<T>,
+    nonces: &Nonces,
+    callbook: &CallBook,
+    local_sender: &str,
+    id_validation: &IdentityValidation,
+    now: i64,
+) -> Result<(), BuildMessageError>
+where
+    T: Message + Eip712 + Default + Clone + 'static + OutputType,
+{
+    msg.valid_sender(
+        callbook.graphcast_registry(),
+        callbook.graph_network(),
+        local_sender.to_string(),
+        id_validation,
+    )
+    .await?;
+    let message_age = now - msg.nonce;
+    if !(0..MSG_REPLAY_LIMIT).contains(&message_age) {
+        return Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+            "Message timestamp {} outside acceptable range {}, drop message",
+            message_age,
+            MSG_REPLAY_LIMIT
+        )));
+    }
+    msg.valid_nonce(nonces).await?;
+    Ok(())
+}
+
+/// Gossiped nPOIs are cached in the state until their comparison
+pub struct PublicPoiHandler;
+
+#[async_trait]
+impl RadioMessageHandler for PublicPoiHandler {
+    type Payload = PublicPoiMessage;
+    const NAME: &'static str = "PublicPoiMessage";
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<PublicPoiMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        // Recorded before validation, replays run the same checks
+        context.recorder.message(msg);
+        let agent = context.agent;
+        validate_poi_message(
+            msg,
+            &agent.nonces,
+            &agent.callbook,
+            &agent.graphcast_identity.graphcast_id,
+            &agent.id_validation,
+            Utc::now().timestamp(),
+        )
+        .await
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<PublicPoiMessage>, context: &MessageContext) {
+        let identifier = msg.identifier.clone();
+        let cached = context.state.add_remote_message(msg).await;
+        CACHED_MESSAGES
+            .with_label_values(&[&identifier])
+            .set(cached as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::LocalWallet;
+
+    /// Signed with the test key below by the last radio release without message versions
+    const GOLDEN_V0: &str = "0a2e516d574543675a645032594d63563952744b553431477863645738454759714d4e6f4739387562753552474e365512f7010a2e516d574543675a645032594d63563952744b553431477863645738454759714d4e6f4739387562753552474e365512423078326133613361316264386137316532653061346132643666623164396530663164386234376261336633613163396639336231653761366338623566366537641899d9d1a4062206676f65726c6928a8b4ac0432423078393562666137643662366539636637623965336239633661316665316239613166306335623564366531613262336334643565366637303831393261336234633a2a3078653961316361626435373730306231373934356664383166656566626138323334306439353638661899d9d1a406222a3078653961316361626435373730306231373934356664383166656566626138323334306439353638662a820161633965353633386463383536363837633261646562653063323736333638646363303465636435343464393162393866383132366263313162343739333538323636666630633235373339366466366533653133346234643462623537353032653365613966306238623666323132626632626565633232316163653833643163";
+    /// Signed with the test key below by a radio on message version 1
+    const GOLDEN_V1: &str = "0a2e516d574543675a645032594d63563952744b553431477863645738454759714d4e6f4739387562753552474e365512f9010a2e516d574543675a645032594d63563952744b553431477863645738454759714d4e6f4739387562753552474e365512423078326133613361316264386137316532653061346132643666623164396530663164386234376261336633613163396639336231653761366338623566366537641899d9d1a4062206676f65726c6928a8b4ac0432423078393562666137643662366539636637623965336239633661316665316239613166306335623564366531613262336334643565366637303831393261336234633a2a30786539613163616264353737303062313739343566643831666565666261383233343064393536386640011899d9d1a406222a3078653961316361626435373730306231373934356664383166656566626138323334306439353638662a820132306638333962623465356332623162363734356137363236366638396630393633616263653439306163303232356632376437303738666637633236643061313132313733633636613065306637346539353237303536346639333164333938353235666365333131656339323061363137613261616639636234636235623162";
+    const SIGNER_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const SIGNER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    fn payload(version: u32) -> PublicPoiMessage {
+        PublicPoiMessage::new(
+            String::from("QmWECgZdP2YMcV9RtKU41GxcdW8EGYqMNoG98ubu5RGN6U"),
+            String::from("0x2a3a3a1bd8a71e2e0a4a2d6fb1d9e0f1d8b47ba3f3a1c9f93b1e7a6c8b5f6e7d"),
+            1687448729,
+            String::from("goerli"),
+            9116200,
+            String::from("0x95bfa7d6b6e9cf7b9e3b9c6a1fe1b9a1f0c5b5d6e1a2b3c4d5e6f708192a3b4c"),
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        )
+        .with_version(version)
+    }
+
+    async fn signed(version: u32) -> GraphcastMessage<PublicPoiMessage> {
+        let wallet: LocalWallet = SIGNER_KEY.parse().unwrap();
+        let payload = payload(version);
+        GraphcastMessage::build(
+            &wallet,
+            payload.identifier.clone(),
+            payload.graph_account.clone(),
+            payload.nonce,
+            payload,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn decode(golden: &str) -> GraphcastMessage<PublicPoiMessage> {
+        GraphcastMessage::decode(hex::decode(golden).unwrap().as_slice()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_decode_previous_version() {
+        let msg = decode(GOLDEN_V0);
+        assert_eq!(msg.payload, payload(0));
+        assert_eq!(msg.recover_sender_address().unwrap(), SIGNER);
+        // Radios sending the previous version are understood by radios that predate versions
+        assert_eq!(hex::encode(signed(0).await.encode_to_vec()), GOLDEN_V0);
+    }
+
+    #[tokio::test]
+    async fn test_decode_current_version() {
+        let msg = decode(GOLDEN_V1);
+        assert_eq!(msg.payload, payload(POI_MESSAGE_VERSION));
+        assert_eq!(msg.recover_sender_address().unwrap(), SIGNER);
+        assert_eq!(hex::encode(signed(1).await.encode_to_vec()), GOLDEN_V1);
+    }
+
+    #[test]
+    fn test_version_is_signed() {
+        let mut msg = decode(GOLDEN_V1);
+        msg.payload.version = 0;
+        assert_ne!(msg.recover_sender_address().unwrap(), SIGNER);
+    }
+
+    #[test]
+    fn test_newer_version_dropped() {
+        assert!(payload(0).valid_version().is_ok());
+        assert!(payload(POI_MESSAGE_VERSION).valid_version().is_ok());
+        assert!(payload(POI_MESSAGE_VERSION + 1).valid_version().is_err());
+    }
+}
@@ -0,0 +1,463 @@
+use async_graphql::SimpleObject;
+use axum::async_trait;
+use chrono::Utc;
+use ethers_core::types::transaction::eip712::{EIP712Domain, Eip712, Eip712Error};
+use graphcast_sdk::{
+    callbook::CallBook,
+    graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage, IdentityValidation},
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::messages::poi::{graphcast_validity_at, Nonces, PublicPoiMessage, POI_MESSAGE_VERSION};
+use crate::metrics::CACHED_MESSAGES;
+use crate::operator::audit::ValidationCheck;
+use crate::operator::dispatch::{MessageContext, RadioMessageHandler, Validation};
+
+/// Content topic batches are gossiped on, radios subscribe to it along with their deployments
+pub const POI_BATCH_TOPIC: &str = "poi-batch";
+
+/// nPOI of a deployment within a batch
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+pub struct PoiBatchEntry {
+    #[prost(string, tag = "1")]
+    pub deployment: String,
+    #[prost(string, tag = "2")]
+    pub content: String,
+}
+
+/// Entries of the deployments indexing a network, at the same message block
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+pub struct PoiBatchGroup {
+    /// blockchain relevant to the entries
+    #[prost(string, tag = "1")]
+    pub network: String,
+    /// block relevant to the entries
+    #[prost(uint64, tag = "2")]
+    pub block_number: u64,
+    /// block hash generated from the block number
+    #[prost(string, tag = "3")]
+    pub block_hash: String,
+    #[prost(message, repeated, tag = "4")]
+    pub entries: Vec<PoiBatchEntry>,
+}
+
+/// nPOIs of many deployments under a single signature, grouped by network and block
+#[derive(Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+pub struct PublicPoiBatchMessage {
+    /// Always the batch content topic
+    #[prost(string, tag = "1")]
+    pub identifier: String,
+    /// Graph account sender
+    #[prost(string, tag = "2")]
+    pub graph_account: String,
+    #[prost(message, repeated, tag = "3")]
+    pub groups: Vec<PoiBatchGroup>,
+    /// nonce cached to check against the next incoming message
+    #[prost(int64, tag = "4")]
+    pub nonce: i64,
+}
+
+/// EIP-712 type of batches, with the groups flattened to one array element per entry
+mod signed {
+    use ethers_contract::EthAbiType;
+    use ethers_core::types::transaction::eip712::Eip712;
+    use ethers_derive_eip712::*;
+
+    #[derive(Eip712, EthAbiType, Clone)]
+    #[eip712(
+        name = "PublicPoiBatchMessage",
+        version = "0",
+        chain_id = 1,
+        verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+    )]
+    pub struct PublicPoiBatchMessage {
+        pub identifier: String,
+        pub nonce: i64,
+        pub graph_account: String,
+        pub networks: Vec<String>,
+        pub block_numbers: Vec<u64>,
+        pub block_hashes: Vec<String>,
+        pub deployments: Vec<String>,
+        pub contents: Vec<String>,
+    }
+}
+
+impl From<&PublicPoiBatchMessage> for signed::PublicPoiBatchMessage {
+    fn from(msg: &PublicPoiBatchMessage) -> Self {
+        let mut signed = signed::PublicPoiBatchMessage {
+            identifier: msg.identifier.clone(),
+            nonce: msg.nonce,
+            graph_account: msg.graph_account.clone(),
+            networks: vec![],
+            block_numbers: vec![],
+            block_hashes: vec![],
+            deployments: vec![],
+            contents: vec![],
+        };
+        for group in &msg.groups {
+            for entry in &group.entries {
+                signed.networks.push(group.network.clone());
+                signed.block_numbers.push(group.block_number);
+                signed.block_hashes.push(group.block_hash.clone());
+                signed.deployments.push(entry.deployment.clone());
+                signed.contents.push(entry.content.clone());
+            }
+        }
+        signed
+    }
+}
+
+impl Eip712 for PublicPoiBatchMessage {
+    type Error = Eip712Error;
+
+    fn domain(&self) -> Result<EIP712Domain, Self::Error> {
+        signed::PublicPoiBatchMessage::from(self).domain()
+    }
+
+    fn type_hash() -> Result<[u8; 32], Self::Error> {
+        signed::PublicPoiBatchMessage::type_hash()
+    }
+
+    fn struct_hash(&self) -> Result<[u8; 32], Self::Error> {
+        signed::PublicPoiBatchMessage::from(self).struct_hash()
+    }
+}
+
+impl PublicPoiBatchMessage {
+    /// Batch POI messages, grouped by network, block number and block hash in the order they come in.
+    /// The batch is sent with its own nonce, the nonces of the messages are dropped
+    pub fn build(nonce: i64, graph_account: String, messages: &[PublicPoiMessage]) -> Self {
+        let mut groups: Vec<PoiBatchGroup> = vec![];
+        for msg in messages {
+            let entry = PoiBatchEntry {
+                deployment: msg.identifier.clone(),
+                content: msg.content.clone(),
+            };
+            match groups.iter_mut().find(|group| {
+                group.network == msg.network
+                    && group.block_number == msg.block_number
+                    && group.block_hash == msg.block_hash
+            }) {
+                Some(group) => group.entries.push(entry),
+                None => groups.push(PoiBatchGroup {
+                    network: msg.network.clone(),
+                    block_number: msg.block_number,
+                    block_hash: msg.block_hash.clone(),
+                    entries: vec![entry],
+                }),
+            }
+        }
+        PublicPoiBatchMessage {
+            identifier: POI_BATCH_TOPIC.to_string(),
+            graph_account,
+            groups,
+            nonce,
+        }
+    }
+
+    /// Number of deployments in the batch
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| group.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Per-deployment messages of a group, as cached from single POI messages. They carry the signature of the
+    /// batch, which is kept in the state so the messages can be checked against the batch they were signed in
+    fn expand_group(
+        group: &PoiBatchGroup,
+        outer: &GraphcastMessage<Self>,
+    ) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+        group
+            .entries
+            .iter()
+            .map(|entry| GraphcastMessage {
+                identifier: entry.deployment.clone(),
+                nonce: outer.nonce,
+                graph_account: outer.graph_account.clone(),
+                payload: PublicPoiMessage {
+                    identifier: entry.deployment.clone(),
+                    content: entry.content.clone(),
+                    nonce: outer.nonce,
+                    network: group.network.clone(),
+                    block_number: group.block_number,
+                    block_hash: group.block_hash.clone(),
+                    graph_account: outer.graph_account.clone(),
+                    version: POI_MESSAGE_VERSION,
+                },
+                signature: outer.signature.clone(),
+            })
+            .collect()
+    }
+
+    /// Per-deployment messages of every entry of the batch
+    pub fn expand(
+        &self,
+        outer: &GraphcastMessage<Self>,
+    ) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+        self.groups
+            .iter()
+            .flat_map(|group| Self::expand_group(group, outer))
+            .collect()
+    }
+
+    /// Per-deployment messages of the groups whose block hash agrees with the local Graph node, other groups are dropped
+    pub async fn expand_valid(
+        &self,
+        outer: &GraphcastMessage<Self>,
+        graph_node_endpoint: &str,
+    ) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+        let mut messages = vec![];
+        for group in &self.groups {
+            let group_messages = Self::expand_group(group, outer);
+            let first = match group_messages.first() {
+                Some(msg) => msg,
+                None => continue,
+            };
+            if let Err(e) = first.payload.valid_hash(graph_node_endpoint).await {
+                debug!(
+                    network = group.network,
+                    block = group.block_number,
+                    err = tracing::field::debug(e),
+                    "Dropped batch group"
+                );
+                continue;
+            }
+            messages.extend(group_messages);
+        }
+        messages
+    }
+
+    /// Whether a per-deployment message is one of the entries of the batch, signature included
+    pub fn carries(
+        &self,
+        outer: &GraphcastMessage<Self>,
+        msg: &GraphcastMessage<PublicPoiMessage>,
+    ) -> bool {
+        self.expand(outer).iter().any(|entry| {
+            entry.identifier == msg.identifier
+                && entry.nonce == msg.nonce
+                && entry.graph_account == msg.graph_account
+                && entry.signature == msg.signature
+                && entry.payload == msg.payload
+        })
+    }
+
+    /// Check duplicated fields: payload message has duplicated fields with GraphcastMessage, the values must be the same
+    pub fn valid_outer(&self, outer: &GraphcastMessage<Self>) -> Result<&Self, BuildMessageError> {
+        if self.nonce == outer.nonce
+            && self.graph_account == outer.graph_account
+            && self.identifier == outer.identifier
+            && self.identifier == POI_BATCH_TOPIC
+        {
+            Ok(self)
+        } else {
+            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                "Batch wrapped by inconsistent GraphcastMessage: nonce {} <- {}, account {} <- {}, identifier {} <- {}",
+                self.nonce,
+                outer.nonce,
+                self.graph_account,
+                outer.graph_account,
+                self.identifier,
+                outer.identifier,
+            )))
+        }
+    }
+}
+
+/// Run the checks a received batch goes through before its groups are expanded, as of `now`.
+/// Block hashes are checked by group when the batch is expanded
+pub async fn validate_poi_batch(
+    msg: &GraphcastMessage<PublicPoiBatchMessage>,
+    nonces: &Nonces,
+    callbook: &CallBook,
+    local_sender: &str,
+    id_validation: &IdentityValidation,
+    now: i64,
+) -> Result<(), (ValidationCheck, BuildMessageError)> {
+    graphcast_validity_at(msg, nonces, callbook, local_sender, id_validation, now)
+        .await
+        .map_err(|e| (ValidationCheck::CheckMessageValidity, e))?;
+    msg.payload
+        .valid_outer(msg)
+        .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+    Ok(())
+}
+
+/// Batches are cached in the state as the per-deployment messages they carry
+pub struct PoiBatchHandler;
+
+#[async_trait]
+impl RadioMessageHandler for PoiBatchHandler {
+    type Payload = PublicPoiBatchMessage;
+    const NAME: &'static str = "PublicPoiBatchMessage";
+    const TOPIC: Option<&'static str> = Some(POI_BATCH_TOPIC);
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<PublicPoiBatchMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        // Recorded before validation, replays run the same checks
+        context.recorder.batch(msg);
+        let agent = context.agent;
+        validate_poi_batch(
+            msg,
+            &agent.nonces,
+            &agent.callbook,
+            &agent.graphcast_identity.graphcast_id,
+            &agent.id_validation,
+            Utc::now().timestamp(),
+        )
+        .await
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<PublicPoiBatchMessage>, context: &MessageContext) {
+        let expanded = msg.payload.expand_valid(&msg, &context.graph_node).await;
+        if expanded.is_empty() {
+            return;
+        }
+        // Kept as the evidence behind the per-deployment messages, which carry its signature
+        context.state.add_poi_batch(msg.clone()).await;
+        for expanded in expanded {
+            let identifier = expanded.identifier.clone();
+            let cached = context.state.add_remote_message(expanded).await;
+            CACHED_MESSAGES
+                .with_label_values(&[&identifier])
+                .set(cached as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::LocalWallet;
+
+    use crate::messages::poi_request::{PoiRequestMessage, PoiResponseMessage};
+    use crate::messages::upgrade::VersionUpgradeMessage;
+
+    fn message(deployment: &str, network: &str, block_number: u64) -> PublicPoiMessage {
+        PublicPoiMessage::new(
+            deployment.to_string(),
+            format!("0xnpoi-{deployment}"),
+            1,
+            network.to_string(),
+            block_number,
+            format!("0xblock-{block_number}"),
+            String::from("0xa1"),
+        )
+    }
+
+    fn batch() -> PublicPoiBatchMessage {
+        PublicPoiBatchMessage::build(
+            1687448729,
+            String::from("0xa1"),
+            &[
+                message("Qm1", "goerli", 10),
+                message("Qm2", "mainnet", 20),
+                message("Qm3", "goerli", 10),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_batch_expands_to_messages() {
+        let wallet: LocalWallet =
+            "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        let batch = batch();
+        assert_eq!(batch.groups.len(), 2);
+        assert_eq!(batch.len(), 3);
+        let msg = GraphcastMessage::build(
+            &wallet,
+            batch.identifier.clone(),
+            batch.graph_account.clone(),
+            batch.nonce,
+            batch,
+        )
+        .await
+        .unwrap();
+        assert!(msg.payload.valid_outer(&msg).is_ok());
+        let signer = msg.recover_sender_address().unwrap();
+
+        let expanded = msg.payload.expand(&msg);
+        let deployments: Vec<&str> = expanded.iter().map(|m| m.identifier.as_str()).collect();
+        assert_eq!(deployments, vec!["Qm1", "Qm3", "Qm2"]);
+        for expanded in &expanded {
+            assert!(expanded.payload.valid_outer(expanded).is_ok());
+            assert_eq!(expanded.nonce, 1687448729);
+        }
+        assert_eq!(expanded[2].payload.block_number, 20);
+        // Entries carry the batch signature and can be checked against the batch
+        for expanded in &expanded {
+            assert_eq!(expanded.signature, msg.signature);
+            assert!(msg.payload.carries(&msg, expanded));
+        }
+        let mut forged = expanded[0].clone();
+        forged.payload.content = String::from("0xforged");
+        assert!(!msg.payload.carries(&msg, &forged));
+
+        // Every entry is covered by the signature
+        let mut tampered = msg.clone();
+        tampered.payload.groups[1].entries[0].content = String::from("0xforged");
+        assert_ne!(tampered.recover_sender_address().unwrap(), signer);
+    }
+
+    #[test]
+    fn test_batch_not_interchangeable() {
+        let batch_bytes = batch().encode_to_vec();
+        let public_poi_bytes = message("Qm1", "goerli", 10).encode_to_vec();
+        let request_bytes = PoiRequestMessage::new(
+            String::from("Qm1"),
+            1,
+            String::from("goerli"),
+            10,
+            String::from("0xblock-10"),
+            String::from("0xa1"),
+        )
+        .encode_to_vec();
+        let response_bytes = PoiResponseMessage::new(
+            String::from("Qm1"),
+            1,
+            String::from("goerli"),
+            String::from("0xblock-10"),
+            10,
+            String::from("0xnpoi"),
+            String::from("0xa1"),
+        )
+        .encode_to_vec();
+        let upgrade_bytes = VersionUpgradeMessage::new(
+            String::from("Qm1"),
+            String::from("Qm2"),
+            String::from("0xsubgraph"),
+            1,
+            String::from("goerli"),
+            2,
+            String::from("0xa1"),
+        )
+        .encode_to_vec();
+
+        assert!(PublicPoiMessage::decode(batch_bytes.as_slice()).is_err());
+        assert!(PoiRequestMessage::decode(batch_bytes.as_slice()).is_err());
+        assert!(PoiResponseMessage::decode(batch_bytes.as_slice()).is_err());
+        assert!(VersionUpgradeMessage::decode(batch_bytes.as_slice()).is_err());
+        for bytes in [
+            public_poi_bytes,
+            request_bytes,
+            response_bytes,
+            upgrade_bytes,
+        ] {
+            assert!(PublicPoiBatchMessage::decode(bytes.as_slice()).is_err());
+        }
+        assert_eq!(
+            PublicPoiBatchMessage::decode(batch_bytes.as_slice()).unwrap(),
+            batch()
+        );
+    }
+}
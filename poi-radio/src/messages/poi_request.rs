@@ -0,0 +1,470 @@
+use async_graphql::SimpleObject;
+use axum::async_trait;
+use chrono::Utc;
+use ethers_contract::EthAbiType;
+use ethers_core::types::transaction::eip712::Eip712;
+use ethers_derive_eip712::*;
+use graphcast_sdk::{
+    graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage},
+    graphql::client_graph_node::query_graph_node_network_block_hash,
+    networks::NetworkName,
+};
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+use tracing::{debug, trace};
+
+use crate::operator::audit::ValidationCheck;
+use crate::operator::bisection::respond_poi_request;
+use crate::operator::dispatch::{
+    graphcast_validity, MessageContext, RadioMessageHandler, Validation,
+};
+
+/// Request for peers to share their nPOI of a deployment at a specific block
+/// Field tags and wire types are chosen so that requests, responses and PublicPoiMessage cannot be decoded as one another
+#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+#[eip712(
+    name = "PoiRequestMessage",
+    version = "0",
+    chain_id = 1,
+    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+)]
+pub struct PoiRequestMessage {
+    #[prost(string, tag = "1")]
+    pub identifier: String,
+    /// nonce cached to check against the next incoming message
+    #[prost(int64, tag = "2")]
+    pub nonce: i64,
+    /// blockchain relevant to the message
+    #[prost(string, tag = "3")]
+    pub network: String,
+    /// block to provide the nPOI for
+    #[prost(uint64, tag = "4")]
+    pub block_number: u64,
+    /// block hash generated from the block number
+    #[prost(string, tag = "5")]
+    pub block_hash: String,
+    /// Graph account sender
+    #[prost(string, tag = "6")]
+    pub graph_account: String,
+}
+
+impl PoiRequestMessage {
+    pub fn new(
+        identifier: String,
+        nonce: i64,
+        network: String,
+        block_number: u64,
+        block_hash: String,
+        graph_account: String,
+    ) -> Self {
+        PoiRequestMessage {
+            identifier,
+            nonce,
+            network,
+            block_number,
+            block_hash,
+            graph_account,
+        }
+    }
+
+    pub fn build(
+        identifier: String,
+        nonce: i64,
+        network: NetworkName,
+        block_number: u64,
+        block_hash: String,
+        graph_account: String,
+    ) -> Self {
+        PoiRequestMessage::new(
+            identifier,
+            nonce,
+            network.to_string(),
+            block_number,
+            block_hash,
+            graph_account,
+        )
+    }
+
+    /// Check duplicated fields: payload message has duplicated fields with GraphcastMessage, the values must be the same
+    pub fn valid_outer(&self, outer: &GraphcastMessage<Self>) -> Result<&Self, BuildMessageError> {
+        if self.nonce == outer.nonce
+            && self.graph_account == outer.graph_account
+            && self.identifier == outer.identifier
+        {
+            Ok(self)
+        } else {
+            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                "Radio message wrapped by inconsistent GraphcastMessage: {:#?} <- {:#?}",
+                &self,
+                &outer,
+            )))
+        }
+    }
+
+    /// Make sure the request refers to a block known by the local graph node
+    pub async fn validity_check(
+        &self,
+        gc_msg: &GraphcastMessage<Self>,
+        graph_node_endpoint: &str,
+    ) -> Result<&Self, BuildMessageError> {
+        self.valid_hash(graph_node_endpoint)
+            .await?
+            .valid_outer(gc_msg)
+    }
+
+    /// Check the block hash against the local graph node
+    pub async fn valid_hash(&self, graph_node_endpoint: &str) -> Result<&Self, BuildMessageError> {
+        valid_block_hash(
+            graph_node_endpoint,
+            &self.network,
+            self.block_number,
+            &self.block_hash,
+        )
+        .await?;
+        Ok(self)
+    }
+}
+
+/// A peer's nPOI of a deployment at the block asked for by a PoiRequestMessage
+#[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
+#[eip712(
+    name = "PoiResponseMessage",
+    version = "0",
+    chain_id = 1,
+    verifying_contract = "0xc944e90c64b2c07662a292be6244bdf05cda44a7"
+)]
+pub struct PoiResponseMessage {
+    #[prost(string, tag = "1")]
+    pub identifier: String,
+    /// nonce cached to check against the next incoming message
+    #[prost(int64, tag = "2")]
+    pub nonce: i64,
+    /// blockchain relevant to the message
+    #[prost(string, tag = "3")]
+    pub network: String,
+    /// block hash generated from the block number
+    #[prost(string, tag = "4")]
+    pub block_hash: String,
+    /// block the nPOI was requested for
+    #[prost(uint64, tag = "5")]
+    pub block_number: u64,
+    #[prost(string, tag = "6")]
+    pub content: String,
+    /// Graph account sender
+    #[prost(string, tag = "7")]
+    pub graph_account: String,
+}
+
+impl PoiResponseMessage {
+    pub fn new(
+        identifier: String,
+        nonce: i64,
+        network: String,
+        block_hash: String,
+        block_number: u64,
+        content: String,
+        graph_account: String,
+    ) -> Self {
+        PoiResponseMessage {
+            identifier,
+            nonce,
+            network,
+            block_hash,
+            block_number,
+            content,
+            graph_account,
+        }
+    }
+
+    /// Answer a request with the local nPOI
+    pub fn from_request(
+        request: &PoiRequestMessage,
+        nonce: i64,
+        content: String,
+        graph_account: String,
+    ) -> Self {
+        PoiResponseMessage::new(
+            request.identifier.clone(),
+            nonce,
+            request.network.clone(),
+            request.block_hash.clone(),
+            request.block_number,
+            content,
+            graph_account,
+        )
+    }
+
+    pub fn payload_content(&self) -> String {
+        self.content.clone()
+    }
+
+    /// Check duplicated fields: payload message has duplicated fields with GraphcastMessage, the values must be the same
+    pub fn valid_outer(&self, outer: &GraphcastMessage<Self>) -> Result<&Self, BuildMessageError> {
+        if self.nonce == outer.nonce
+            && self.graph_account == outer.graph_account
+            && self.identifier == outer.identifier
+        {
+            Ok(self)
+        } else {
+            Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                "Radio message wrapped by inconsistent GraphcastMessage: {:#?} <- {:#?}",
+                &self,
+                &outer,
+            )))
+        }
+    }
+
+    /// Make sure all messages stored are valid
+    pub async fn validity_check(
+        &self,
+        gc_msg: &GraphcastMessage<Self>,
+        graph_node_endpoint: &str,
+    ) -> Result<&Self, BuildMessageError> {
+        self.valid_hash(graph_node_endpoint)
+            .await?
+            .valid_outer(gc_msg)
+    }
+
+    /// Check the block hash against the local graph node
+    pub async fn valid_hash(&self, graph_node_endpoint: &str) -> Result<&Self, BuildMessageError> {
+        valid_block_hash(
+            graph_node_endpoint,
+            &self.network,
+            self.block_number,
+            &self.block_hash,
+        )
+        .await?;
+        Ok(self)
+    }
+}
+
+/// Check for the valid hash between local graph node and gossip
+async fn valid_block_hash(
+    graph_node_endpoint: &str,
+    network: &str,
+    block_number: u64,
+    block_hash: &str,
+) -> Result<(), BuildMessageError> {
+    let queried_hash: String =
+        query_graph_node_network_block_hash(graph_node_endpoint, network, block_number)
+            .await
+            .map_err(BuildMessageError::FieldDerivations)?;
+
+    trace!(
+        network,
+        block_number,
+        block_hash = queried_hash,
+        "Queried block hash from graph node",
+    );
+
+    if block_hash == queried_hash {
+        Ok(())
+    } else {
+        Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+            "Message hash ({}) differ from trusted provider response ({}), drop message",
+            block_hash,
+            queried_hash
+        )))
+    }
+}
+
+/// Seconds during which a deployment and block is answered once, and a sender's requests are counted
+const POI_REQUEST_WINDOW: i64 = 60;
+/// Requests answered per sender within a window, a bisection asks for one block at a time per deployment
+const MAX_POI_REQUESTS_PER_SENDER: usize = 20;
+
+/// Recent requests, responses are gossiped so one answer serves every requester of a deployment and block
+#[derive(Default)]
+struct PoiRequestLimiter {
+    answered: HashMap<(String, u64), i64>,
+    by_sender: HashMap<String, VecDeque<i64>>,
+}
+
+impl PoiRequestLimiter {
+    /// Whether to answer a request, which is then counted.
+    /// A deployment and block answered within the window, or a sender over its limit, is not answered
+    fn admit(&mut self, request: &PoiRequestMessage, sender: &str, now: i64) -> bool {
+        let since = now - POI_REQUEST_WINDOW;
+        self.answered.retain(|_, at| *at > since);
+        self.by_sender.retain(|_, times| {
+            while times.front().is_some_and(|at| *at <= since) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let key = (request.identifier.clone(), request.block_number);
+        if self.answered.contains_key(&key) {
+            return false;
+        }
+        let times = self.by_sender.entry(sender.to_string()).or_default();
+        if times.len() >= MAX_POI_REQUESTS_PER_SENDER {
+            return false;
+        }
+        times.push_back(now);
+        self.answered.insert(key, now);
+        true
+    }
+}
+
+/// Requests for a block are answered with the local nPOI, unless it cannot be generated.
+/// Repeated requests and senders over their limit are dropped before the Graph node is queried
+#[derive(Default)]
+pub struct PoiRequestHandler {
+    limiter: Mutex<PoiRequestLimiter>,
+}
+
+#[async_trait]
+impl RadioMessageHandler for PoiRequestHandler {
+    type Payload = PoiRequestMessage;
+    const NAME: &'static str = "PoiRequestMessage";
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<PoiRequestMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        graphcast_validity(msg, context).await?;
+        msg.payload
+            .valid_hash(&context.graph_node)
+            .await
+            .map_err(|e| (ValidationCheck::ValidHash, e))?
+            .valid_outer(msg)
+            .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+        Ok(())
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<PoiRequestMessage>, context: &MessageContext) {
+        let admitted = self.limiter.lock().unwrap().admit(
+            &msg.payload,
+            &msg.graph_account,
+            Utc::now().timestamp(),
+        );
+        if !admitted {
+            trace!(
+                deployment = msg.payload.identifier,
+                block = msg.payload.block_number,
+                sender = msg.graph_account,
+                "Skip repeated or rate limited nPOI request"
+            );
+            return;
+        }
+        let agent = context.agent;
+        let audit = context.audit.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond_poi_request(msg.payload, agent, &audit).await {
+                debug!(
+                    err = tracing::field::debug(&e),
+                    "Failed to respond to nPOI request"
+                );
+            }
+        });
+    }
+}
+
+/// Responses are collected in the state for the bisection that requested them
+pub struct PoiResponseHandler;
+
+#[async_trait]
+impl RadioMessageHandler for PoiResponseHandler {
+    type Payload = PoiResponseMessage;
+    const NAME: &'static str = "PoiResponseMessage";
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<PoiResponseMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        graphcast_validity(msg, context).await?;
+        msg.payload
+            .valid_hash(&context.graph_node)
+            .await
+            .map_err(|e| (ValidationCheck::ValidHash, e))?
+            .valid_outer(msg)
+            .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+        Ok(())
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<PoiResponseMessage>, context: &MessageContext) {
+        context.state.add_poi_response(msg).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::poi::PublicPoiMessage;
+
+    fn request() -> PoiRequestMessage {
+        PoiRequestMessage::build(
+            String::from("QmHash"),
+            1,
+            NetworkName::Goerli,
+            42,
+            String::from("0xblock"),
+            String::from("0xa1"),
+        )
+    }
+
+    #[test]
+    fn test_request_response_not_interchangeable() {
+        let request = request();
+        let response = PoiResponseMessage::from_request(
+            &request,
+            2,
+            String::from("0xnpoi"),
+            String::from("0xa2"),
+        );
+        let public_poi = PublicPoiMessage::new(
+            String::from("QmHash"),
+            String::from("0xnpoi"),
+            3,
+            String::from("goerli"),
+            42,
+            String::from("0xblock"),
+            String::from("0xa3"),
+        );
+
+        let request_bytes = request.encode_to_vec();
+        let response_bytes = response.encode_to_vec();
+        let public_poi_bytes = public_poi.encode_to_vec();
+
+        assert!(PoiResponseMessage::decode(request_bytes.as_slice()).is_err());
+        assert!(PublicPoiMessage::decode(request_bytes.as_slice()).is_err());
+        assert!(PoiRequestMessage::decode(response_bytes.as_slice()).is_err());
+        assert!(PublicPoiMessage::decode(response_bytes.as_slice()).is_err());
+        assert!(PoiRequestMessage::decode(public_poi_bytes.as_slice()).is_err());
+        assert!(PoiResponseMessage::decode(public_poi_bytes.as_slice()).is_err());
+
+        assert_eq!(
+            PoiResponseMessage::decode(response_bytes.as_slice()).unwrap(),
+            response
+        );
+    }
+
+    #[test]
+    fn test_request_limiter() {
+        let mut limiter = PoiRequestLimiter::default();
+        let request = request();
+        assert!(limiter.admit(&request, "0xa1", 0));
+        // Answered already, the response serves every requester
+        assert!(!limiter.admit(&request, "0xa2", 30));
+        assert!(limiter.admit(&request, "0xa2", POI_REQUEST_WINDOW));
+
+        let mut limiter = PoiRequestLimiter::default();
+        let blocks = |block_number| PoiRequestMessage {
+            block_number,
+            ..request.clone()
+        };
+        for block in 0..MAX_POI_REQUESTS_PER_SENDER as u64 {
+            assert!(limiter.admit(&blocks(block), "0xa1", 10));
+        }
+        assert!(!limiter.admit(&blocks(1000), "0xa1", 20));
+        assert!(limiter.admit(&blocks(1000), "0xa2", 20));
+        assert!(limiter.admit(&blocks(1001), "0xa1", 10 + POI_REQUEST_WINDOW));
+    }
+}
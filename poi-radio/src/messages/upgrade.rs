@@ -1,4 +1,7 @@
 use async_graphql::SimpleObject;
+use axum::async_trait;
+use chrono::Utc;
+use tracing::debug;
 
 use ethers_contract::EthAbiType;
 use ethers_core::types::transaction::eip712::Eip712;
@@ -11,6 +14,12 @@ use graphcast_sdk::{
 use prost::Message;
 use serde::{Deserialize, Serialize};
 
+use crate::operator::audit::ValidationCheck;
+use crate::operator::dispatch::{
+    graphcast_validity, MessageContext, RadioMessageHandler, Validation,
+};
+use crate::operator::upgrade::UpgradePlan;
+
 #[derive(Eip712, EthAbiType, Clone, Message, Serialize, Deserialize, PartialEq, SimpleObject)]
 #[eip712(
     name = "VersionUpgradeMessage",
@@ -126,3 +135,67 @@ impl VersionUpgradeMessage {
         Ok(self)
     }
 }
+
+/// Upgrade plans shared by subgraph owners are kept in the state until their migrate time and forwarded to the
+/// indexer through the notifier. If configured, the radio subscribes to the new deployment right away
+pub struct VersionUpgradeHandler;
+
+#[async_trait]
+impl RadioMessageHandler for VersionUpgradeHandler {
+    type Payload = VersionUpgradeMessage;
+    const NAME: &'static str = "VersionUpgradeMessage";
+
+    async fn validate(
+        &self,
+        msg: &GraphcastMessage<VersionUpgradeMessage>,
+        context: &MessageContext,
+    ) -> Validation {
+        graphcast_validity(msg, context).await?;
+        msg.payload
+            .valid_owner(&context.graph_node)
+            .await
+            .map_err(|e| (ValidationCheck::ValidOwner, e))?
+            .valid_outer(msg)
+            .map_err(|e| (ValidationCheck::ValidOuter, e))?;
+        Ok(())
+    }
+
+    async fn handle(&self, msg: GraphcastMessage<VersionUpgradeMessage>, context: &MessageContext) {
+        let plan = UpgradePlan::from(msg.payload);
+        if !plan.is_pending(Utc::now().timestamp()) {
+            debug!(
+                deployment = plan.deployment,
+                migrate_time = plan.migrate_time,
+                "Upgrade plan past its migrate time, skipped"
+            );
+            return;
+        }
+        if !context.state.add_upgrade_plan(plan.clone()).await {
+            debug!(
+                deployment = plan.deployment,
+                "A later upgrade plan is known for the deployment, skipped"
+            );
+            return;
+        }
+        let subscribed = context.upgrade_auto_subscribe && subscribe(&plan.new_hash, context).await;
+        context.notifier.notify(format!(
+            "Subgraph owner for a deployment has shared version upgrade info:\nold deployment: {}\nnew deployment: {}\nplanned migrate time: {}\nnetwork: {}{}",
+            plan.deployment,
+            plan.new_hash,
+            plan.migrate_time,
+            plan.network,
+            if subscribed { "\nsubscribed to the new deployment, cross-checking starts now" } else { "" }
+        )).await;
+    }
+}
+
+/// Add a deployment to the content topics of the agent, returns false if it was already subscribed to
+async fn subscribe(deployment: &str, context: &MessageContext) -> bool {
+    let mut topics = context.agent.content_identifiers().await;
+    if topics.iter().any(|topic| topic == deployment) {
+        return false;
+    }
+    topics.push(deployment.to_string());
+    context.agent.update_content_topics(topics).await;
+    true
+}
@@ -111,6 +111,55 @@ pub static INDEXER_COUNT_BY_NPOI: Lazy<HistogramVec> = Lazy::new(|| {
     m
 });
 
+#[allow(dead_code)]
+pub static EQUIVOCATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "equivocations",
+            "Number of indexers found signing different nPOIs for the same deployment and block",
+        )
+        .namespace("graphcast")
+        .subsystem("poi_radio"),
+        &["deployment"],
+    )
+    .expect("Failed to create equivocations counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register equivocations counter");
+    m
+});
+
+#[allow(dead_code)]
+pub static EVICTED_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "evicted_messages",
+            "Number of remote messages evicted from the cache by the retention policy",
+        )
+        .namespace("graphcast")
+        .subsystem("poi_radio"),
+        &["deployment", "reason"],
+    )
+    .expect("Failed to create evicted_messages counters");
+    prometheus::register(Box::new(m.clone())).expect("Failed to register evicted_messages counter");
+    m
+});
+
+#[allow(dead_code)]
+pub static MESSAGE_DECODE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let m = IntCounterVec::new(
+        Opts::new(
+            "message_decode_failures",
+            "Number of Waku messages that decoded as none of the radio message types expected on their content topic, by expected type",
+        )
+        .namespace("graphcast")
+        .subsystem("poi_radio"),
+        &["message_type"],
+    )
+    .expect("Failed to create message_decode_failures counters");
+    prometheus::register(Box::new(m.clone()))
+        .expect("Failed to register message_decode_failures counter");
+    m
+});
+
 #[allow(dead_code)]
 pub static REGISTRY: Lazy<prometheus::Registry> = Lazy::new(prometheus::Registry::new);
 
@@ -133,6 +182,9 @@ pub fn start_metrics() {
             Box::new(DIVERGING_SUBGRAPHS.clone()),
             Box::new(LOCAL_NPOIS_TO_COMPARE.clone()),
             Box::new(INDEXER_COUNT_BY_NPOI.clone()),
+            Box::new(EQUIVOCATIONS.clone()),
+            Box::new(EVICTED_MESSAGES.clone()),
+            Box::new(MESSAGE_DECODE_FAILURES.clone()),
         ],
     );
 }
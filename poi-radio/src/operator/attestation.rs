@@ -1,38 +1,39 @@
 use async_graphql::{Enum, Error, ErrorExtensions, SimpleObject};
 use autometrics::autometrics;
 use chrono::Utc;
-use num_traits::Zero;
 use serde_derive::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
-    sync::{Arc, Mutex as SyncMutex},
 };
 
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, info, trace, warn};
 
-use graphcast_sdk::{
-    callbook::CallBook,
-    graphcast_agent::message_typing::{get_indexer_stake, BuildMessageError, GraphcastMessage},
-};
+use graphcast_sdk::graphcast_agent::message_typing::{BuildMessageError, GraphcastMessage};
 
 use crate::{
     messages::poi::PublicPoiMessage,
     metrics::{
         ACTIVE_INDEXERS, DIVERGING_SUBGRAPHS, INDEXER_COUNT_BY_NPOI, LOCAL_NPOIS_TO_COMPARE,
     },
-    state::PersistedState,
+    state::StateHandle,
     OperationError,
 };
 
-use super::Notifier;
+use super::{
+    bisection::DivergenceBisection,
+    consensus::{ConsensusPolicy, ConsensusPolicyType},
+    reputation::SenderWeights,
+    stake::{SenderStakes, Stake},
+    Notifier,
+};
 
 /// A wrapper around an attested NPOI, tracks Indexers that have sent it plus their accumulated stake
 #[derive(Clone, Debug, PartialEq, Eq, Hash, SimpleObject, Serialize, Deserialize)]
 pub struct Attestation {
     pub npoi: String,
-    pub stake_weight: i64,
+    pub stake_weight: Stake,
     pub senders: Vec<String>,
     pub sender_group_hash: String,
     pub timestamp: Vec<i64>,
@@ -40,13 +41,18 @@ pub struct Attestation {
 
 #[autometrics]
 impl Attestation {
-    pub fn new(npoi: String, stake_weight: f32, senders: Vec<String>, timestamp: Vec<i64>) -> Self {
+    pub fn new(
+        npoi: String,
+        stake_weight: Stake,
+        senders: Vec<String>,
+        timestamp: Vec<i64>,
+    ) -> Self {
         let addresses = &mut senders.clone();
         sort_addresses(addresses);
         let sender_group_hash = hash_addresses(addresses);
         Attestation {
             npoi,
-            stake_weight: stake_weight as i64,
+            stake_weight,
             senders,
             sender_group_hash,
             timestamp,
@@ -57,7 +63,7 @@ impl Attestation {
     pub fn update(
         base: &Self,
         address: String,
-        stake: f32,
+        stake: Stake,
         timestamp: i64,
     ) -> Result<Self, AttestationError> {
         if base.senders.contains(&address) {
@@ -67,7 +73,7 @@ impl Attestation {
         } else {
             Ok(Self::new(
                 base.npoi.clone(),
-                (base.stake_weight as f32) + stake,
+                &base.stake_weight + &stake,
                 [base.senders.clone(), vec![address]].concat(),
                 [base.timestamp.clone(), vec![timestamp]].concat(),
             ))
@@ -88,7 +94,7 @@ impl fmt::Display for Attestation {
 pub type RemoteAttestationsMap = HashMap<String, HashMap<u64, Vec<Attestation>>>;
 pub type LocalAttestationsMap = HashMap<String, HashMap<u64, Attestation>>;
 
-#[derive(SimpleObject, Debug)]
+#[derive(SimpleObject, Clone, Debug)]
 pub struct AttestationEntry {
     pub deployment: String,
     pub block_number: u64,
@@ -108,10 +114,12 @@ pub fn attestations_to_vec(attestations: &LocalAttestationsMap) -> Vec<Attestati
         .collect()
 }
 
+/// Group messages into attestations weighted by the resolved sender stakes, scaled by sender weights if any
 #[autometrics]
 pub async fn process_ppoi_message(
     messages: Vec<GraphcastMessage<PublicPoiMessage>>,
-    callbook: &CallBook,
+    sender_stakes: &SenderStakes,
+    sender_weights: &SenderWeights,
 ) -> Result<RemoteAttestationsMap, AttestationError> {
     let mut remote_attestations: RemoteAttestationsMap = HashMap::new();
     // Check if there are existing attestations for the block
@@ -126,12 +134,14 @@ pub async fn process_ppoi_message(
         let radio_msg = &msg.payload.clone();
         // Message has passed GraphcastMessage validation, now check for radio validation
         let npoi = radio_msg.payload_content().to_string();
-        let sender_stake =
-            get_indexer_stake(&radio_msg.graph_account.clone(), callbook.graph_network())
-                .await
-                .map_err(|e| {
-                    AttestationError::BuildError(BuildMessageError::FieldDerivations(e))
-                })?;
+        let sender_stake = sender_stakes
+            .get(&radio_msg.graph_account)
+            .cloned()
+            .unwrap_or_default();
+        let sender_stake = match sender_weights.get(&radio_msg.graph_account) {
+            Some(weight) => sender_stake.scale(*weight),
+            None => sender_stake,
+        };
 
         //TODO: update this to utilize update_blocks?
         let blocks = remote_attestations
@@ -207,9 +217,9 @@ pub fn local_comparison_point(
         // Find the attestaion by the smallest block
         let remote_blocks = remote_messages
             .iter()
-            .filter(|m| m.identifier == id.clone())
+            .filter(|m| m.identifier == id)
             .map(|m| m.payload.block_number)
-            .collect::<Vec<u64>>();
+            .collect::<HashSet<u64>>();
         blocks_map
             .iter()
             .filter(|(&block, _)| remote_blocks.contains(&block))
@@ -233,7 +243,7 @@ pub fn update_blocks(
     block_number: u64,
     blocks: &HashMap<u64, Vec<Attestation>>,
     npoi: String,
-    stake: f32,
+    stake: Stake,
     address: String,
     timestamp: i64,
 ) -> HashMap<u64, Vec<Attestation>> {
@@ -253,14 +263,29 @@ pub fn update_blocks(
 
 /// Saves NPOIs that we've generated locally, in order to compare them with remote ones later
 pub fn save_local_attestation(
-    local_attestations: Arc<SyncMutex<LocalAttestationsMap>>,
+    local_attestations: &mut LocalAttestationsMap,
     content: String,
     ipfs_hash: String,
     block_number: u64,
 ) {
-    let attestation = Attestation::new(content, Zero::zero(), vec![], vec![Utc::now().timestamp()]);
+    save_local_attestation_at(
+        local_attestations,
+        content,
+        ipfs_hash,
+        block_number,
+        Utc::now().timestamp(),
+    )
+}
 
-    let mut local_attestations = local_attestations.lock().unwrap();
+/// Saves an NPOI generated locally at a given time, which opens its collect window
+pub fn save_local_attestation_at(
+    local_attestations: &mut LocalAttestationsMap,
+    content: String,
+    ipfs_hash: String,
+    block_number: u64,
+    timestamp: i64,
+) {
+    let attestation = Attestation::new(content, Stake::zero(), vec![], vec![timestamp]);
 
     local_attestations
         .entry(ipfs_hash.clone())
@@ -277,11 +302,10 @@ pub fn save_local_attestation(
 
 /// Clear the expired local attestations after comparing with remote results
 pub fn clear_local_attestation(
-    local_attestations: Arc<SyncMutex<HashMap<String, HashMap<u64, Attestation>>>>,
+    local_attestations: &mut LocalAttestationsMap,
     ipfs_hash: String,
     block_number: u64,
 ) {
-    let mut local_attestations = local_attestations.lock().unwrap();
     let blocks = local_attestations.get(&ipfs_hash);
 
     if let Some(blocks) = blocks {
@@ -295,6 +319,46 @@ pub fn clear_local_attestation(
     };
 }
 
+/// Minimum support the top remote nPOI needs before it is taken as the consensus nPOI
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuorumThreshold {
+    /// Minimum share of the total remote attesting stake backing the top nPOI, from 0 to 1
+    pub min_stake_ratio: f32,
+    /// Minimum number of senders attesting to the top nPOI
+    pub min_senders: usize,
+}
+
+impl QuorumThreshold {
+    pub fn new(min_stake_ratio: f32, min_senders: usize) -> Self {
+        QuorumThreshold {
+            min_stake_ratio,
+            min_senders,
+        }
+    }
+
+    /// Check if the top attestation is backed by enough stake and senders among all remote attestations
+    pub fn is_met(&self, top: &Attestation, attestations: &[Attestation]) -> bool {
+        top.senders.len() >= self.min_senders
+            && stake_share(top, attestations) >= self.min_stake_ratio
+    }
+}
+
+impl Default for QuorumThreshold {
+    fn default() -> Self {
+        QuorumThreshold::new(0.0, 1)
+    }
+}
+
+/// Share of the total attesting stake that backs an attestation, 0 if no stake is attesting
+pub fn stake_share(attestation: &Attestation, attestations: &[Attestation]) -> f32 {
+    let total_stake: Stake = attestations.iter().map(|a| &a.stake_weight).sum();
+    if !total_stake.is_zero() {
+        attestation.stake_weight.share_of(&total_stake)
+    } else {
+        0.0
+    }
+}
+
 /// Tracks results indexed by deployment hash and block number
 #[derive(Enum, Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum ComparisonResultType {
@@ -302,6 +366,10 @@ pub enum ComparisonResultType {
     Divergent,
     Match,
     BuildFailed,
+    /// The top remote nPOI did not reach the configured quorum
+    Inconclusive,
+    /// More than one remote nPOI has the top support under the consensus policy
+    Tie,
 }
 
 /// Keep track of the attestation result for a deployment and block
@@ -312,7 +380,18 @@ pub struct ComparisonResult {
     pub block_number: u64,
     pub result_type: ComparisonResultType,
     pub local_attestation: Option<Attestation>,
+    /// Remote attestations in ascending order of support under the consensus policy.
+    /// Attestations with equal support are ordered by ascending sender_group_hash, so the last one is the consensus candidate
     pub attestations: Vec<Attestation>,
+    /// Earlier blocks probed to locate where a divergence started
+    #[serde(default)]
+    pub bisection: Option<DivergenceBisection>,
+    /// Policy used to pick the consensus nPOI
+    #[serde(default)]
+    pub consensus_policy: ConsensusPolicyType,
+    /// Remote nPOIs tied for the top support in a Tie result, in the same order as attestations
+    #[serde(default)]
+    pub tied_npois: Vec<String>,
 }
 
 impl ComparisonResult {
@@ -338,6 +417,8 @@ impl Display for ComparisonResultType {
                 write!(f, "Matched")
             }
             ComparisonResultType::BuildFailed => write!(f, "Failed to build message"),
+            ComparisonResultType::Inconclusive => write!(f, "Inconclusive"),
+            ComparisonResultType::Tie => write!(f, "Tie"),
         }
     }
 }
@@ -371,7 +452,11 @@ impl Display for ComparisonResult {
                     self.result_type,
                     self.deployment_hash(),
                     self.block()
-                )
+                )?;
+                match &self.bisection {
+                    Some(bisection) => write!(f, ", {bisection}"),
+                    None => Ok(()),
+                }
             }
             ComparisonResultType::Match => {
                 write!(
@@ -389,6 +474,30 @@ impl Display for ComparisonResult {
                 self.deployment_hash(),
                 self.block()
             ),
+            ComparisonResultType::Inconclusive => {
+                let (num_senders, share) = self
+                    .attestations
+                    .last()
+                    .map(|top| (top.senders.len(), stake_share(top, &self.attestations)))
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "{}: deployment {} at block {}, top nPOI backed by {} sender(s) and {:.2}% of attesting stake is below quorum",
+                    self.result_type,
+                    self.deployment_hash(),
+                    self.block(),
+                    num_senders,
+                    share * 100.0
+                )
+            }
+            ComparisonResultType::Tie => write!(
+                f,
+                "{}: deployment {} at block {}, nPOIs {} have equal support",
+                self.result_type,
+                self.deployment_hash(),
+                self.block(),
+                self.tied_npois.join(", ")
+            ),
         }
     }
 }
@@ -401,6 +510,9 @@ impl Clone for ComparisonResult {
             result_type: self.result_type,
             local_attestation: self.local_attestation.clone(),
             attestations: self.attestations.clone(),
+            bisection: self.bisection.clone(),
+            consensus_policy: self.consensus_policy,
+            tied_npois: self.tied_npois.clone(),
         }
     }
 }
@@ -408,13 +520,16 @@ impl Clone for ComparisonResult {
 /// Compares local attestations against remote ones using the attestation stores we populated while processing saved GraphcastMessage messages.
 /// It takes our attestation (NPOI) for a given subgraph on a given block and compares it to the top-attested one from the remote attestations.
 /// The top remote attestation is found by grouping attestations together and increasing their total stake-weight every time we see a new message
-/// with the same NPOI from an Indexer (NOTE: one Indexer can only send 1 attestation per subgraph per block). The attestations are then sorted
-/// and we take the one with the highest total stake-weight.
+/// with the same NPOI from an Indexer (NOTE: one Indexer can only send 1 attestation per subgraph per block). The attestations are then ranked
+/// by the consensus policy, by default taking the one with the highest total stake-weight. If that attestation does not satisfy the quorum threshold,
+/// the comparison is inconclusive.
 pub fn compare_attestations(
     attestation_block: u64,
     remote: RemoteAttestationsMap,
     local: &LocalAttestationsMap,
     ipfs_hash: &str,
+    quorum: &QuorumThreshold,
+    policy: &ConsensusPolicy,
 ) -> ComparisonResult {
     trace!(
         local = tracing::field::debug(&local),
@@ -433,6 +548,9 @@ pub fn compare_attestations(
                 result_type: ComparisonResultType::NotFound,
                 local_attestation: None,
                 attestations: vec![],
+                bisection: None,
+                consensus_policy: policy.policy_type(),
+                tied_npois: vec![],
             };
         }
     };
@@ -446,6 +564,9 @@ pub fn compare_attestations(
                 result_type: ComparisonResultType::NotFound,
                 local_attestation: None,
                 attestations: vec![],
+                bisection: None,
+                consensus_policy: policy.policy_type(),
+                tied_npois: vec![],
             };
         }
     };
@@ -460,6 +581,9 @@ pub fn compare_attestations(
                 result_type: ComparisonResultType::NotFound,
                 local_attestation: Some(local_attestation.clone()),
                 attestations: vec![],
+                bisection: None,
+                consensus_policy: policy.policy_type(),
+                tied_npois: vec![],
             };
         }
     };
@@ -473,12 +597,15 @@ pub fn compare_attestations(
                 result_type: ComparisonResultType::NotFound,
                 local_attestation: Some(local_attestation.clone()),
                 attestations: vec![],
+                bisection: None,
+                consensus_policy: policy.policy_type(),
+                tied_npois: vec![],
             };
         }
     };
 
     let mut remote_attestations = remote_attestations.clone();
-    remote_attestations.sort_by(|a, b| a.stake_weight.partial_cmp(&b.stake_weight).unwrap());
+    policy.rank(&mut remote_attestations);
 
     let sender_gauge = ACTIVE_INDEXERS.with_label_values(&[ipfs_hash]);
     // The value is the total number of senders that are attesting for that subgraph
@@ -494,8 +621,48 @@ pub fn compare_attestations(
         );
     }
 
-    let most_attested_npoi = &remote_attestations.last().unwrap().npoi;
-    if most_attested_npoi == &local_attestation.npoi {
+    let most_attested = remote_attestations.last().unwrap();
+    if !quorum.is_met(most_attested, &remote_attestations) {
+        debug!(
+            ipfs_hash,
+            attestation_block,
+            num_senders = most_attested.senders.len(),
+            stake_share = stake_share(most_attested, &remote_attestations),
+            "Top nPOI did not reach quorum",
+        );
+        return ComparisonResult {
+            deployment: ipfs_hash.to_string(),
+            block_number: attestation_block,
+            result_type: ComparisonResultType::Inconclusive,
+            local_attestation: Some(local_attestation.clone()),
+            attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
+        };
+    }
+
+    let tied_npois = policy.tied_npois(&remote_attestations);
+    if !tied_npois.is_empty() {
+        warn!(
+            ipfs_hash,
+            attestation_block,
+            tied_npois = tracing::field::debug(&tied_npois),
+            "Top nPOIs are tied",
+        );
+        return ComparisonResult {
+            deployment: ipfs_hash.to_string(),
+            block_number: attestation_block,
+            result_type: ComparisonResultType::Tie,
+            local_attestation: Some(local_attestation.clone()),
+            attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois,
+        };
+    }
+
+    if most_attested.npoi == local_attestation.npoi {
         trace!(
             ipfs_hash,
             attestation_block,
@@ -508,6 +675,9 @@ pub fn compare_attestations(
             result_type: ComparisonResultType::Match,
             local_attestation: Some(local_attestation.clone()),
             attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
         }
     } else {
         debug!(
@@ -522,6 +692,9 @@ pub fn compare_attestations(
             result_type: ComparisonResultType::Divergent,
             local_attestation: Some(local_attestation.clone()),
             attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
         }
     }
 }
@@ -530,13 +703,66 @@ pub fn compare_attestations(
 pub fn compare_attestation(
     local: AttestationEntry,
     remote_attestations: Vec<Attestation>,
+    quorum: &QuorumThreshold,
+    policy: &ConsensusPolicy,
 ) -> ComparisonResult {
     let local_attestation = local.attestation;
     let mut remote_attestations = remote_attestations;
-    remote_attestations.sort_by(|a, b| a.stake_weight.partial_cmp(&b.stake_weight).unwrap());
+    policy.rank(&mut remote_attestations);
 
-    let most_attested_npoi = &remote_attestations.last().unwrap().npoi;
-    if most_attested_npoi == &local_attestation.npoi {
+    let most_attested = match remote_attestations.last() {
+        Some(a) => a,
+        None => {
+            return ComparisonResult {
+                deployment: local.deployment.to_string(),
+                block_number: local.block_number,
+                result_type: ComparisonResultType::NotFound,
+                local_attestation: Some(local_attestation),
+                attestations: remote_attestations,
+                bisection: None,
+                consensus_policy: policy.policy_type(),
+                tied_npois: vec![],
+            }
+        }
+    };
+    if !quorum.is_met(most_attested, &remote_attestations) {
+        debug!(
+            block = local.block_number,
+            remote_attestations = tracing::field::debug(&remote_attestations),
+            "Top nPOI did not reach quorum",
+        );
+        return ComparisonResult {
+            deployment: local.deployment.to_string(),
+            block_number: local.block_number,
+            result_type: ComparisonResultType::Inconclusive,
+            local_attestation: Some(local_attestation),
+            attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
+        };
+    }
+
+    let tied_npois = policy.tied_npois(&remote_attestations);
+    if !tied_npois.is_empty() {
+        warn!(
+            block = local.block_number,
+            tied_npois = tracing::field::debug(&tied_npois),
+            "Top nPOIs are tied",
+        );
+        return ComparisonResult {
+            deployment: local.deployment.to_string(),
+            block_number: local.block_number,
+            result_type: ComparisonResultType::Tie,
+            local_attestation: Some(local_attestation),
+            attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois,
+        };
+    }
+
+    if most_attested.npoi == local_attestation.npoi {
         trace!(
             local.block_number,
             remote_attestations = tracing::field::debug(&remote_attestations),
@@ -549,6 +775,9 @@ pub fn compare_attestation(
             result_type: ComparisonResultType::Match,
             local_attestation: Some(local_attestation),
             attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
         }
     } else {
         warn!(
@@ -563,6 +792,9 @@ pub fn compare_attestation(
             result_type: ComparisonResultType::Divergent,
             local_attestation: Some(local_attestation),
             attestations: remote_attestations,
+            bisection: None,
+            consensus_policy: policy.policy_type(),
+            tied_npois: vec![],
         }
     }
 }
@@ -632,12 +864,14 @@ pub async fn process_comparison_results(
     num_topics: usize,
     result_strings: Vec<Result<ComparisonResult, OperationError>>,
     notifier: Notifier,
-    persisted_state: PersistedState,
+    state: StateHandle,
 ) {
     // Generate attestation summary
     let mut match_strings = vec![];
     let mut not_found_strings = vec![];
     let mut divergent_strings = vec![];
+    let mut inconclusive_strings = vec![];
+    let mut tie_strings = vec![];
     let mut cmp_trigger_failed = vec![];
     let mut attestation_failed = vec![];
     let mut cmp_errors = vec![];
@@ -645,9 +879,12 @@ pub async fn process_comparison_results(
     for result in result_strings {
         match result {
             Ok(comparison_result) => {
-                let result_type = persisted_state
-                    .handle_comparison_result(comparison_result.clone(), notifier.clone())
+                let result_type = state
+                    .update_comparison_result(comparison_result.clone())
                     .await;
+                if result_type != ComparisonResultType::NotFound {
+                    notifier.notify(comparison_result.to_string()).await;
+                }
 
                 match result_type {
                     ComparisonResultType::Match => {
@@ -659,6 +896,12 @@ pub async fn process_comparison_results(
                     ComparisonResultType::Divergent => {
                         divergent_strings.push(comparison_result.to_string());
                     }
+                    ComparisonResultType::Inconclusive => {
+                        inconclusive_strings.push(comparison_result.to_string());
+                    }
+                    ComparisonResultType::Tie => {
+                        tie_strings.push(comparison_result.to_string());
+                    }
                     _ => attestation_failed.push(comparison_result.to_string()),
                 }
             }
@@ -674,6 +917,8 @@ pub async fn process_comparison_results(
         num_topics,
         num_active_crosschecks = match_strings.len() + divergent_strings.len(),
         num_attestations_matched = match_strings.len(),
+        num_below_quorum = inconclusive_strings.len(),
+        tied = tracing::field::debug(tie_strings),
         num_topics_inactive = not_found_strings.len(),
         num_waiting_to_compare = cmp_trigger_failed.len(),
         diverged = tracing::field::debug(divergent_strings),
@@ -710,7 +955,7 @@ mod tests {
             42,
             vec![Attestation::new(
                 "default".to_string(),
-                0.0,
+                Stake::zero(),
                 Vec::new(),
                 Vec::new(),
             )],
@@ -719,7 +964,7 @@ mod tests {
             42,
             &blocks,
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             "0xadd3".to_string(),
             1,
         );
@@ -734,13 +979,13 @@ mod tests {
     fn test_sort_sender_addresses_unique() {
         let attestation = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xaac5349585cbbf924026d25a520ffa9e8b51a39b".to_string()],
             vec![1],
         );
         let attestation2 = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xbbc5349585cbbf924026d25a520ffa9e8b51a39b".to_string()],
             vec![1],
         );
@@ -754,7 +999,7 @@ mod tests {
     fn test_sort_sender_addresses() {
         let attestation = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec![
                 "0xaac5349585cbbf924026d25a520ffa9e8b51a39b".to_string(),
                 "0xbbc5349585cbbf924026d25a520ffa9e8b51a39b".to_string(),
@@ -763,7 +1008,7 @@ mod tests {
         );
         let attestation2 = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec![
                 "0xbbc5349585cbbf924026d25a520ffa9e8b51a39b".to_string(),
                 "0xaac5349585cbbf924026d25a520ffa9e8b51a39b".to_string(),
@@ -780,30 +1025,33 @@ mod tests {
     fn test_attestation_sorting() {
         let attestation1 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![0],
         );
 
         let attestation2 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa2".to_string()],
             vec![1],
         );
 
         let attestation3 = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xa3".to_string()],
             vec![2],
         );
 
         let mut attestations = vec![attestation1, attestation2, attestation3];
 
-        attestations.sort_by(|a, b| a.stake_weight.partial_cmp(&b.stake_weight).unwrap());
+        attestations.sort_by(|a, b| a.stake_weight.cmp(&b.stake_weight));
 
-        assert_eq!(attestations.last().unwrap().stake_weight, 1);
+        assert_eq!(
+            attestations.last().unwrap().stake_weight,
+            Stake::from_grt(1)
+        );
         assert_eq!(
             attestations.last().unwrap().senders.first().unwrap(),
             &"0xa3".to_string()
@@ -815,15 +1063,19 @@ mod tests {
     fn test_attestation_update_success() {
         let attestation = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![2],
         );
 
-        let updated_attestation = Attestation::update(&attestation, "0xa2".to_string(), 1.0, 1);
+        let updated_attestation =
+            Attestation::update(&attestation, "0xa2".to_string(), Stake::from_grt(1), 1);
 
         assert!(updated_attestation.is_ok());
-        assert_eq!(updated_attestation.as_ref().unwrap().stake_weight, 1);
+        assert_eq!(
+            updated_attestation.as_ref().unwrap().stake_weight,
+            Stake::from_grt(1)
+        );
         assert_eq!(updated_attestation.unwrap().timestamp, [2, 1]);
     }
 
@@ -831,12 +1083,13 @@ mod tests {
     fn test_attestation_update_fail() {
         let attestation = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![0],
         );
 
-        let updated_attestation = Attestation::update(&attestation, "0xa1".to_string(), 0.0, 0);
+        let updated_attestation =
+            Attestation::update(&attestation, "0xa1".to_string(), Stake::zero(), 0);
 
         assert!(updated_attestation.is_err());
         assert_eq!(
@@ -852,6 +1105,8 @@ mod tests {
             HashMap::new(),
             &HashMap::new(),
             "non-existent-ipfs-hash",
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
         );
 
         assert_eq!(
@@ -870,7 +1125,7 @@ mod tests {
             42,
             vec![Attestation::new(
                 "awesome-npoi".to_string(),
-                0.0,
+                Stake::zero(),
                 vec!["0xa1".to_string()],
                 vec![1],
             )],
@@ -878,7 +1133,12 @@ mod tests {
 
         local_blocks.insert(
             42,
-            Attestation::new("awesome-npoi".to_string(), 0.0, Vec::new(), vec![0]),
+            Attestation::new(
+                "awesome-npoi".to_string(),
+                Stake::zero(),
+                Vec::new(),
+                vec![0],
+            ),
         );
 
         let mut remote_attestations: HashMap<String, HashMap<u64, Vec<Attestation>>> =
@@ -893,6 +1153,8 @@ mod tests {
             remote_attestations,
             &local_attestations,
             "different-awesome-hash",
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
         );
 
         assert_eq!(
@@ -919,6 +1181,8 @@ mod tests {
             remote_attestations,
             &local_attestations,
             "my-awesome-hash",
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
         );
 
         assert_eq!(
@@ -934,13 +1198,18 @@ mod tests {
 
         let remote = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![0],
         );
         remote_blocks.insert(42, vec![remote.clone()]);
 
-        let local = Attestation::new("awesome-npoi".to_string(), 0.0, Vec::new(), vec![0]);
+        let local = Attestation::new(
+            "awesome-npoi".to_string(),
+            Stake::zero(),
+            Vec::new(),
+            vec![0],
+        );
         local_blocks.insert(42, local.clone());
 
         let mut remote_attestations: HashMap<String, HashMap<u64, Vec<Attestation>>> =
@@ -955,6 +1224,8 @@ mod tests {
             remote_attestations,
             &local_attestations,
             "my-awesome-hash",
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
         );
 
         assert_eq!(
@@ -965,30 +1236,173 @@ mod tests {
                 result_type: ComparisonResultType::Match,
                 local_attestation: Some(local),
                 attestations: vec![remote],
+                bisection: None,
+                consensus_policy: ConsensusPolicyType::default(),
+                tied_npois: vec![],
             }
         );
     }
 
+    #[tokio::test]
+    async fn test_compare_attestations_below_quorum() {
+        let mut remote_blocks: HashMap<u64, Vec<Attestation>> = HashMap::new();
+        let mut local_blocks: HashMap<u64, Attestation> = HashMap::new();
+
+        let whale = Attestation::new(
+            "awesome-npoi".to_string(),
+            Stake::from_grt(30),
+            vec!["0xa1".to_string()],
+            vec![0],
+        );
+        let others = Attestation::new(
+            "other-npoi".to_string(),
+            Stake::from_grt(20),
+            vec!["0xa2".to_string()],
+            vec![0],
+        );
+        let third = Attestation::new(
+            "third-npoi".to_string(),
+            Stake::from_grt(20),
+            vec!["0xa3".to_string()],
+            vec![0],
+        );
+        remote_blocks.insert(42, vec![whale, others, third]);
+
+        let local = Attestation::new("other-npoi".to_string(), Stake::zero(), Vec::new(), vec![0]);
+        local_blocks.insert(42, local);
+
+        let mut remote_attestations: HashMap<String, HashMap<u64, Vec<Attestation>>> =
+            HashMap::new();
+        let mut local_attestations: HashMap<String, HashMap<u64, Attestation>> = HashMap::new();
+
+        remote_attestations.insert("my-awesome-hash".to_string(), remote_blocks);
+        local_attestations.insert("my-awesome-hash".to_string(), local_blocks);
+
+        // Top nPOI holds 3/7 of the attesting stake
+        let res = compare_attestations(
+            42,
+            remote_attestations.clone(),
+            &local_attestations,
+            "my-awesome-hash",
+            &QuorumThreshold::new(0.5, 1),
+            &ConsensusPolicy::default(),
+        );
+        assert_eq!(res.result_type, ComparisonResultType::Inconclusive);
+        assert_eq!(
+            res.to_string(),
+            "Inconclusive: deployment my-awesome-hash at block 42, top nPOI backed by 1 sender(s) and 42.86% of attesting stake is below quorum"
+        );
+
+        let res = compare_attestations(
+            42,
+            remote_attestations.clone(),
+            &local_attestations,
+            "my-awesome-hash",
+            &QuorumThreshold::new(0.0, 2),
+            &ConsensusPolicy::default(),
+        );
+        assert_eq!(res.result_type, ComparisonResultType::Inconclusive);
+
+        let res = compare_attestations(
+            42,
+            remote_attestations,
+            &local_attestations,
+            "my-awesome-hash",
+            &QuorumThreshold::new(0.4, 1),
+            &ConsensusPolicy::default(),
+        );
+        assert_eq!(res.result_type, ComparisonResultType::Divergent);
+    }
+
+    #[test]
+    fn test_compare_attestation_tie() {
+        let remote = vec![
+            Attestation::new(
+                "npoi-b".to_string(),
+                Stake::from_grt(2),
+                vec!["0xb1".to_string()],
+                vec![1],
+            ),
+            Attestation::new(
+                "npoi-a".to_string(),
+                Stake::from_grt(2),
+                vec!["0xa1".to_string()],
+                vec![1],
+            ),
+        ];
+        let local = AttestationEntry {
+            deployment: "my-awesome-hash".to_string(),
+            block_number: 42,
+            attestation: Attestation::new("npoi-a".to_string(), Stake::zero(), vec![], vec![0]),
+        };
+
+        let res = compare_attestation(
+            local.clone(),
+            remote.clone(),
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
+        );
+        assert_eq!(res.result_type, ComparisonResultType::Tie);
+        assert_eq!(res.tied_npois.len(), 2);
+        assert_eq!(
+            res.tied_npois.last(),
+            res.attestations.last().map(|a| &a.npoi)
+        );
+
+        // The same attestations in a different order give the same ranking
+        let reversed = compare_attestation(
+            local.clone(),
+            remote.into_iter().rev().collect(),
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::default(),
+        );
+        assert_eq!(reversed.tied_npois, res.tied_npois);
+
+        // Sender count breaks the stake tie
+        let remote = vec![
+            Attestation::new(
+                "npoi-b".to_string(),
+                Stake::from_grt(2),
+                vec!["0xb1".to_string(), "0xb2".to_string()],
+                vec![1, 2],
+            ),
+            Attestation::new(
+                "npoi-a".to_string(),
+                Stake::from_grt(2),
+                vec!["0xa1".to_string()],
+                vec![1],
+            ),
+        ];
+        let res = compare_attestation(
+            local,
+            remote,
+            &QuorumThreshold::default(),
+            &ConsensusPolicy::SenderCount,
+        );
+        assert_eq!(res.result_type, ComparisonResultType::Divergent);
+        assert!(res.tied_npois.is_empty());
+    }
+
     #[tokio::test]
     async fn clear_local_attestation_success() {
         let mut local_blocks: HashMap<u64, Attestation> = HashMap::new();
         let attestation1 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![0],
         );
 
         let attestation2 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa2".to_string()],
             vec![1],
         );
 
         let attestation3 = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xa3".to_string()],
             vec![2],
         );
@@ -1000,19 +1414,12 @@ mod tests {
         let mut local_attestations: HashMap<String, HashMap<u64, Attestation>> = HashMap::new();
         local_attestations.insert("hash".to_string(), local_blocks.clone());
         local_attestations.insert("hash2".to_string(), local_blocks);
-        let local = Arc::new(SyncMutex::new(local_attestations));
-
-        clear_local_attestation(Arc::clone(&local), "hash".to_string(), 43);
-
-        assert_eq!(local.lock().unwrap().get("hash").unwrap().len(), 2);
-        assert!(local
-            .lock()
-            .unwrap()
-            .get("hash")
-            .unwrap()
-            .get(&43)
-            .is_none());
-        assert_eq!(local.lock().unwrap().get("hash2").unwrap().len(), 3);
+
+        clear_local_attestation(&mut local_attestations, "hash".to_string(), 43);
+
+        assert_eq!(local_attestations.get("hash").unwrap().len(), 2);
+        assert!(local_attestations.get("hash").unwrap().get(&43).is_none());
+        assert_eq!(local_attestations.get("hash2").unwrap().len(), 3);
     }
 
     pub fn test_msg_vec() -> Vec<GraphcastMessage<PublicPoiMessage>> {
@@ -1028,6 +1435,7 @@ mod tests {
                 block_number: 42,
                 block_hash: String::from("4dbba1ba9fb18b0034965712598be1368edcf91ae2c551d59462aab578dab9c5"),
                 graph_account: String::from("0xa1"),
+                version: 0,
             },
             signature: String::from("03b197380ab9ee3a9fcaea1301224ad1ff02e9e414275fd79d6ee463b21eb6957af7670a26b0a7f8a6316d95dba8497f2bd67b32b39be07073cf81beff0b37961b"),
         }]
@@ -1038,21 +1446,21 @@ mod tests {
         let mut local_blocks: HashMap<u64, Attestation> = HashMap::new();
         let attestation1 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa1".to_string()],
             vec![2],
         );
 
         let attestation2 = Attestation::new(
             "awesome-npoi".to_string(),
-            0.0,
+            Stake::zero(),
             vec!["0xa2".to_string()],
             vec![4],
         );
 
         let attestation3 = Attestation::new(
             "awesome-npoi".to_string(),
-            1.0,
+            Stake::from_grt(1),
             vec!["0xa3".to_string()],
             vec![6],
         );
@@ -1083,52 +1491,34 @@ mod tests {
 
     #[tokio::test]
     async fn test_save_local_attestation() {
-        let local_attestations = Arc::new(SyncMutex::new(HashMap::new()));
+        let mut local_attestations = HashMap::new();
         save_local_attestation(
-            local_attestations.clone(),
+            &mut local_attestations,
             "npoi-x".to_string(),
             "0xa1".to_string(),
             0,
         );
 
         save_local_attestation(
-            local_attestations.clone(),
+            &mut local_attestations,
             "npoi-y".to_string(),
             "0xa1".to_string(),
             1,
         );
 
         save_local_attestation(
-            local_attestations.clone(),
+            &mut local_attestations,
             "npoi-z".to_string(),
             "0xa2".to_string(),
             2,
         );
 
-        assert!(!local_attestations.lock().unwrap().is_empty());
-        assert!(local_attestations.lock().unwrap().len() == 2);
+        assert!(!local_attestations.is_empty());
+        assert!(local_attestations.len() == 2);
+        assert!(local_attestations.get("0xa1").unwrap().len() == 2);
+        assert!(local_attestations.get("0xa2").unwrap().len() == 1);
         assert!(
             local_attestations
-                .lock()
-                .unwrap()
-                .get("0xa1")
-                .unwrap()
-                .len()
-                == 2
-        );
-        assert!(
-            local_attestations
-                .lock()
-                .unwrap()
-                .get("0xa2")
-                .unwrap()
-                .len()
-                == 1
-        );
-        assert!(
-            local_attestations
-                .lock()
-                .unwrap()
                 .get("0xa1")
                 .unwrap()
                 .get(&0)
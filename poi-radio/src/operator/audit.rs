@@ -0,0 +1,317 @@
+use async_graphql::OutputType;
+use chrono::{DateTime, Utc};
+use ethers_core::types::transaction::eip712::Eip712;
+use prost::Message;
+use serde::Serialize;
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as SyncMutex},
+};
+use tracing::warn;
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::config::Config;
+
+/// Check a received message failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationCheck {
+    /// Payload is of a message version the radio can verify
+    ValidVersion,
+    /// Signature, sender identity and nonce checks of Graphcast
+    CheckMessageValidity,
+    /// Block hash of a POI message against the local Graph node
+    ValidHash,
+    /// Sender of an upgrade message owns the subgraph
+    ValidOwner,
+    /// Payload fields agree with the Graphcast message wrapping them
+    ValidOuter,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Accepted,
+    Rejected {
+        check: ValidationCheck,
+        reason: String,
+    },
+    Sent {
+        message_id: String,
+    },
+    SendFailed {
+        reason: String,
+    },
+}
+
+impl AuditOutcome {
+    pub fn rejected(check: ValidationCheck, reason: impl Display) -> Self {
+        AuditOutcome::Rejected {
+            check,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Outcome of the payload checks following check_message_validity
+    pub fn from_validation<T, E: Display>(result: &Result<T, (ValidationCheck, E)>) -> Self {
+        match result {
+            Ok(_) => AuditOutcome::Accepted,
+            Err((check, e)) => AuditOutcome::rejected(*check, e),
+        }
+    }
+
+    /// Outcome of sending a message to the Graphcast network
+    pub fn from_send<E: Display>(result: &Result<String, E>) -> Self {
+        match result {
+            Ok(message_id) => AuditOutcome::Sent {
+                message_id: message_id.clone(),
+            },
+            Err(e) => AuditOutcome::SendFailed {
+                reason: e.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+struct AuditRecord<'a, T: Serialize> {
+    timestamp: DateTime<Utc>,
+    direction: Direction,
+    message_type: &'a str,
+    identifier: &'a str,
+    nonce: i64,
+    graph_account: &'a str,
+    /// Only known for received messages, the agent signs sent messages internally
+    signature: Option<&'a str>,
+    payload: &'a T,
+    #[serde(flatten)]
+    outcome: &'a AuditOutcome,
+}
+
+/// Append-only JSON Lines record of the messages the radio received and sent.
+/// The file is rotated once it reaches a size or age limit, rotated files are kept next to it
+/// with the time of rotation appended to their name.
+/// Recording is a no-op without an audit log path
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    writer: Option<Arc<SyncMutex<RotatingWriter>>>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl AsRef<Path>, max_bytes: u64, max_age_secs: i64) -> io::Result<Self> {
+        let writer = RotatingWriter::open(path.as_ref(), max_bytes, max_age_secs)?;
+        Ok(AuditLog {
+            writer: Some(Arc::new(SyncMutex::new(writer))),
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        match &config.audit_log_path {
+            Some(path) => AuditLog::new(
+                path,
+                config.audit_log_max_bytes,
+                config.audit_log_rotation_secs,
+            )
+            .unwrap_or_else(|e| panic!("Could not open audit log at {path}: {e}")),
+            None => AuditLog::default(),
+        }
+    }
+
+    /// Record a message received from the network with the outcome of its validation
+    pub fn received<T>(&self, message_type: &str, msg: &GraphcastMessage<T>, outcome: AuditOutcome)
+    where
+        T: Message + Eip712 + Default + Clone + 'static + OutputType + Serialize,
+    {
+        self.write(&AuditRecord {
+            timestamp: Utc::now(),
+            direction: Direction::Received,
+            message_type,
+            identifier: &msg.identifier,
+            nonce: msg.nonce,
+            graph_account: &msg.graph_account,
+            signature: Some(&msg.signature),
+            payload: &msg.payload,
+            outcome: &outcome,
+        })
+    }
+
+    /// Record a message the radio sent, or failed to send
+    pub fn sent<T: Serialize>(
+        &self,
+        message_type: &str,
+        identifier: &str,
+        nonce: i64,
+        graph_account: &str,
+        payload: &T,
+        outcome: AuditOutcome,
+    ) {
+        self.write(&AuditRecord {
+            timestamp: Utc::now(),
+            direction: Direction::Sent,
+            message_type,
+            identifier,
+            nonce,
+            graph_account,
+            signature: None,
+            payload,
+            outcome: &outcome,
+        })
+    }
+
+    fn write<T: Serialize>(&self, record: &AuditRecord<T>) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(err = e.to_string(), "Could not serialize audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = writer.lock().unwrap().append(&line, record.timestamp) {
+            warn!(err = e.to_string(), "Could not write audit record");
+        }
+    }
+}
+
+/// Appends to a file, moving it aside once it grows past max_bytes or gets older than max_age_secs
+#[derive(Debug)]
+struct RotatingWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: DateTime<Utc>,
+    max_bytes: u64,
+    max_age_secs: i64,
+}
+
+impl RotatingWriter {
+    fn open(path: &Path, max_bytes: u64, max_age_secs: i64) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // An existing log keeps its age across restarts where the platform records creation times
+        let opened_at = metadata
+            .created()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+        Ok(RotatingWriter {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            opened_at,
+            max_bytes,
+            max_age_secs,
+        })
+    }
+
+    fn append(&mut self, line: &[u8], now: DateTime<Utc>) -> io::Result<()> {
+        let too_large = self.size + line.len() as u64 > self.max_bytes;
+        let too_old = (now - self.opened_at).num_seconds() >= self.max_age_secs;
+        if self.size > 0 && (too_large || too_old) {
+            self.rotate(now)?;
+        }
+        self.file.write_all(line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        self.file.sync_all()?;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", now.format("%Y%m%dT%H%M%S%.3f")));
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_at = now;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::poi::PublicPoiMessage;
+
+    #[test]
+    fn test_audit_log_rotation() {
+        let dir = std::env::temp_dir().join("poi-radio-test-audit");
+        _ = fs::remove_dir_all(&dir);
+        let path = dir.join("audit.jsonl");
+        let audit = AuditLog::new(&path, 1_000_000, 3600).unwrap();
+
+        let payload = PublicPoiMessage {
+            identifier: String::from("Qm1"),
+            content: String::from("npoi-x"),
+            nonce: 1,
+            network: String::from("goerli"),
+            block_number: 1,
+            block_hash: String::from("0xblahh"),
+            graph_account: String::from("0xa1"),
+            version: 0,
+        };
+        let msg = GraphcastMessage {
+            identifier: String::from("Qm1"),
+            nonce: 1,
+            graph_account: String::from("0xa1"),
+            payload: payload.clone(),
+            signature: String::from("0xsig"),
+        };
+        audit.received(
+            "PublicPoiMessage",
+            &msg,
+            AuditOutcome::rejected(ValidationCheck::ValidHash, "block hash differs"),
+        );
+        audit.sent(
+            "PublicPoiMessage",
+            "Qm1",
+            1,
+            "0xa1",
+            &payload,
+            AuditOutcome::Sent {
+                message_id: String::from("id"),
+            },
+        );
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["direction"], "received");
+        assert_eq!(records[0]["outcome"], "rejected");
+        assert_eq!(records[0]["check"], "valid_hash");
+        assert_eq!(records[0]["payload"]["block_number"], 1);
+        assert_eq!(records[1]["outcome"], "sent");
+        assert_eq!(records[1]["signature"], serde_json::Value::Null);
+
+        // Past the size limit the next record starts a new file
+        let audit = AuditLog::new(&path, 1, 3600).unwrap();
+        audit.received("PublicPoiMessage", &msg, AuditOutcome::Accepted);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        _ = fs::remove_dir_all(&dir);
+    }
+}
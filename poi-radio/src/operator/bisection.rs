@@ -0,0 +1,424 @@
+use async_graphql::SimpleObject;
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::{debug, trace, warn};
+
+use graphcast_sdk::{
+    callbook::CallBook,
+    graphcast_agent::{
+        message_typing::{BuildMessageError, GraphcastMessage},
+        GraphcastAgent, GraphcastAgentError,
+    },
+};
+
+use crate::{
+    messages::poi_request::{PoiRequestMessage, PoiResponseMessage},
+    operator::{
+        attestation::{
+            compare_attestation, Attestation, AttestationEntry, AttestationError,
+            ComparisonResultType, QuorumThreshold,
+        },
+        audit::{AuditLog, AuditOutcome},
+        callbook::CallBookRadioExtensions,
+        consensus::ConsensusPolicy,
+        stake::{epoch_stakes, Stake},
+    },
+    state::StateHandle,
+    OperationError,
+};
+
+/// Where a divergence started, located by probing earlier blocks of a divergent deployment
+#[derive(Clone, Debug, PartialEq, Eq, Hash, SimpleObject, Serialize, Deserialize)]
+pub struct DivergenceBisection {
+    /// Latest probed block where the local nPOI matched the remote consensus
+    pub last_matched_block: Option<u64>,
+    /// Earliest probed block where the local nPOI diverged from the remote consensus
+    pub first_divergent_block: u64,
+    /// True if the two blocks are adjacent, so the first divergent block is exact
+    pub exact: bool,
+}
+
+impl DivergenceBisection {
+    pub fn new(last_matched_block: Option<u64>, first_divergent_block: u64) -> Self {
+        DivergenceBisection {
+            last_matched_block,
+            first_divergent_block,
+            exact: last_matched_block.map(|b| b + 1) == Some(first_divergent_block),
+        }
+    }
+}
+
+impl Display for DivergenceBisection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.last_matched_block {
+            Some(matched) if self.exact => write!(
+                f,
+                "divergence started at block {} (last matched block {})",
+                self.first_divergent_block, matched
+            ),
+            Some(matched) => write!(
+                f,
+                "divergence started between blocks {} and {}",
+                matched, self.first_divergent_block
+            ),
+            None => write!(
+                f,
+                "divergence started at or before block {}",
+                self.first_divergent_block
+            ),
+        }
+    }
+}
+
+/// Result of comparing the local nPOI against peers at a single block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeOutcome {
+    Match,
+    Divergent,
+    /// Not enough information to tell, e.g. no responses or quorum not met
+    Undecided,
+}
+
+/// Binary search between lower_bound and a known divergent block for the first block that diverges.
+/// The lower bound is probed first; if it matches, the range is narrowed until the matched and divergent
+/// blocks are adjacent or a probe is undecided.
+pub async fn bisect<F, Fut>(
+    lower_bound: u64,
+    divergent_block: u64,
+    mut probe: F,
+) -> DivergenceBisection
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = ProbeOutcome>,
+{
+    let mut first_divergent = divergent_block;
+    if lower_bound >= divergent_block {
+        return DivergenceBisection::new(None, first_divergent);
+    }
+
+    let mut last_matched = match probe(lower_bound).await {
+        ProbeOutcome::Match => lower_bound,
+        ProbeOutcome::Divergent => return DivergenceBisection::new(None, lower_bound),
+        ProbeOutcome::Undecided => return DivergenceBisection::new(None, first_divergent),
+    };
+
+    while first_divergent - last_matched > 1 {
+        let block = last_matched + (first_divergent - last_matched) / 2;
+        match probe(block).await {
+            ProbeOutcome::Match => last_matched = block,
+            ProbeOutcome::Divergent => first_divergent = block,
+            ProbeOutcome::Undecided => break,
+        }
+        trace!(last_matched, first_divergent, "Narrowed bisection range");
+    }
+
+    DivergenceBisection::new(Some(last_matched), first_divergent)
+}
+
+/// Bisect a divergent deployment over up to lookback_blocks before the divergent block
+#[allow(clippy::too_many_arguments)]
+pub async fn bisect_divergence(
+    deployment: &str,
+    divergent_block: u64,
+    lookback_blocks: u64,
+    network: &str,
+    collect_duration: i64,
+    quorum: &QuorumThreshold,
+    policy: &ConsensusPolicy,
+    callbook: &CallBook,
+    graphcast_agent: &GraphcastAgent,
+    state: &StateHandle,
+    audit: &AuditLog,
+) -> DivergenceBisection {
+    let lower_bound = divergent_block.saturating_sub(lookback_blocks);
+    debug!(
+        deployment,
+        lower_bound, divergent_block, "Start bisecting divergence"
+    );
+    bisect(lower_bound, divergent_block, |block| {
+        probe_block(
+            deployment,
+            block,
+            network,
+            collect_duration,
+            quorum,
+            policy,
+            callbook,
+            graphcast_agent,
+            state,
+            audit,
+        )
+    })
+    .await
+}
+
+/// Ask peers for their nPOIs at a block and compare them with the local nPOI once the collection window closes
+#[allow(clippy::too_many_arguments)]
+async fn probe_block(
+    deployment: &str,
+    block_number: u64,
+    network: &str,
+    collect_duration: i64,
+    quorum: &QuorumThreshold,
+    policy: &ConsensusPolicy,
+    callbook: &CallBook,
+    graphcast_agent: &GraphcastAgent,
+    state: &StateHandle,
+    audit: &AuditLog,
+) -> ProbeOutcome {
+    let block_hash = match callbook.block_hash(network, block_number).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            warn!(
+                err = tracing::field::debug(&e),
+                block_number, "Failed to query graph node for the block hash to probe"
+            );
+            return ProbeOutcome::Undecided;
+        }
+    };
+    let local_npoi = match callbook
+        .query_poi(
+            deployment.to_string(),
+            block_hash.clone(),
+            block_number.try_into().unwrap(),
+        )
+        .await
+    {
+        Ok(npoi) => npoi,
+        Err(e) => {
+            warn!(
+                err = tracing::field::debug(&e),
+                block_number, "Failed to query local nPOI to probe"
+            );
+            return ProbeOutcome::Undecided;
+        }
+    };
+
+    let nonce = Utc::now().timestamp();
+    let request = PoiRequestMessage::new(
+        deployment.to_string(),
+        nonce,
+        network.to_string(),
+        block_number,
+        block_hash,
+        graphcast_agent.graphcast_identity.graph_account.clone(),
+    );
+    state
+        .open_poi_request(deployment.to_string(), block_number)
+        .await;
+    let sent = graphcast_agent
+        .send_message(deployment, request.clone(), nonce)
+        .await;
+    audit.sent(
+        "PoiRequestMessage",
+        deployment,
+        nonce,
+        &request.graph_account,
+        &request,
+        AuditOutcome::from_send(&sent),
+    );
+    if let Err(e) = sent {
+        warn!(
+            err = tracing::field::debug(&e),
+            "Failed to send nPOI request"
+        );
+        state
+            .take_poi_responses(deployment.to_string(), block_number)
+            .await;
+        return ProbeOutcome::Undecided;
+    }
+
+    sleep(Duration::from_secs(collect_duration.max(0) as u64)).await;
+
+    let responses: Vec<GraphcastMessage<PoiResponseMessage>> = state
+        .take_poi_responses(deployment.to_string(), block_number)
+        .await
+        .into_iter()
+        .filter(|m| policy.admits(&m.graph_account))
+        .collect();
+    let remote_attestations = match process_poi_responses(responses, callbook, state).await {
+        Ok(attestations) => attestations,
+        Err(e) => {
+            warn!(
+                err = tracing::field::debug(&e),
+                "Failed to process nPOI responses"
+            );
+            return ProbeOutcome::Undecided;
+        }
+    };
+
+    let local = AttestationEntry {
+        deployment: deployment.to_string(),
+        block_number,
+        attestation: Attestation::new(local_npoi, Stake::zero(), vec![], vec![nonce]),
+    };
+    let outcome = match compare_attestation(local, remote_attestations, quorum, policy).result_type
+    {
+        ComparisonResultType::Match => ProbeOutcome::Match,
+        ComparisonResultType::Divergent => ProbeOutcome::Divergent,
+        _ => ProbeOutcome::Undecided,
+    };
+    debug!(
+        deployment,
+        block_number,
+        outcome = tracing::field::debug(&outcome),
+        "Probed block"
+    );
+    outcome
+}
+
+/// Group nPOI responses into attestations weighted by the senders' epoch stakes
+pub async fn process_poi_responses(
+    responses: Vec<GraphcastMessage<PoiResponseMessage>>,
+    callbook: &CallBook,
+    state: &StateHandle,
+) -> Result<Vec<Attestation>, AttestationError> {
+    // Responses are sent on request, so they are weighed with the stakes of the current epoch
+    let now = Utc::now().timestamp();
+    let sender_stakes = epoch_stakes(
+        &responses
+            .iter()
+            .map(|m| m.graph_account.clone())
+            .collect::<Vec<String>>(),
+        callbook,
+        state,
+        now,
+        now,
+    )
+    .await
+    .map_err(|e| AttestationError::BuildError(BuildMessageError::FieldDerivations(e)))?;
+    let mut attestations: Vec<Attestation> = vec![];
+    for msg in responses.iter() {
+        let npoi = msg.payload.payload_content();
+        let sender_stake = sender_stakes
+            .get(&msg.graph_account)
+            .cloned()
+            .unwrap_or_default();
+
+        match attestations.iter_mut().find(|a| a.npoi == npoi) {
+            Some(existing_attestation) => {
+                if let Ok(updated_attestation) = Attestation::update(
+                    existing_attestation,
+                    msg.graph_account.clone(),
+                    sender_stake,
+                    msg.nonce,
+                ) {
+                    *existing_attestation = updated_attestation;
+                }
+            }
+            None => attestations.push(Attestation::new(
+                npoi,
+                sender_stake,
+                vec![msg.graph_account.clone()],
+                vec![msg.nonce],
+            )),
+        }
+    }
+    Ok(attestations)
+}
+
+/// Answer a peer's nPOI request with the nPOI from the local graph node
+pub async fn respond_poi_request(
+    request: PoiRequestMessage,
+    graphcast_agent: &GraphcastAgent,
+    audit: &AuditLog,
+) -> Result<String, OperationError> {
+    let content = graphcast_agent
+        .callbook
+        .query_poi(
+            request.identifier.clone(),
+            request.block_hash.clone(),
+            request.block_number.try_into().unwrap(),
+        )
+        .await
+        .map_err(|e| OperationError::Agent(GraphcastAgentError::QueryResponseError(e)))?;
+
+    let nonce = Utc::now().timestamp();
+    let response = PoiResponseMessage::from_request(
+        &request,
+        nonce,
+        content,
+        graphcast_agent.graphcast_identity.graph_account.clone(),
+    );
+    let sent = graphcast_agent
+        .send_message(&request.identifier, response.clone(), nonce)
+        .await;
+    audit.sent(
+        "PoiResponseMessage",
+        &request.identifier,
+        nonce,
+        &response.graph_account,
+        &response,
+        AuditOutcome::from_send(&sent),
+    );
+    sent.map_err(OperationError::Agent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn bisect_with(
+        first_divergent: u64,
+        lower_bound: u64,
+        divergent: u64,
+    ) -> DivergenceBisection {
+        bisect(lower_bound, divergent, |block| async move {
+            if block < first_divergent {
+                ProbeOutcome::Match
+            } else {
+                ProbeOutcome::Divergent
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_bisect_exact() {
+        let bisection = bisect_with(137, 0, 1000).await;
+        assert_eq!(bisection, DivergenceBisection::new(Some(136), 137));
+        assert!(bisection.exact);
+        assert_eq!(
+            bisection.to_string(),
+            "divergence started at block 137 (last matched block 136)"
+        );
+
+        let bisection = bisect_with(1000, 0, 1000).await;
+        assert_eq!(bisection, DivergenceBisection::new(Some(999), 1000));
+    }
+
+    #[tokio::test]
+    async fn test_bisect_diverged_before_lower_bound() {
+        let bisection = bisect_with(10, 500, 1000).await;
+        assert_eq!(bisection, DivergenceBisection::new(None, 500));
+        assert!(!bisection.exact);
+        assert_eq!(
+            bisection.to_string(),
+            "divergence started at or before block 500"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bisect_stops_when_undecided() {
+        let bisection = bisect(0, 1000, |block| async move {
+            match block {
+                0 => ProbeOutcome::Match,
+                500 => ProbeOutcome::Divergent,
+                _ => ProbeOutcome::Undecided,
+            }
+        })
+        .await;
+        assert_eq!(bisection, DivergenceBisection::new(Some(0), 500));
+        assert!(!bisection.exact);
+        assert_eq!(
+            bisection.to_string(),
+            "divergence started between blocks 0 and 500"
+        );
+    }
+}
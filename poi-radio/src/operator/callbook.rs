@@ -1,6 +1,11 @@
 use axum::async_trait;
+use std::collections::HashMap;
 
-use crate::graphql::query_graph_node_poi;
+use crate::graphql::{
+    query_current_epoch, query_graph_node_poi, query_graph_node_version, query_indexer_stake,
+    query_indexer_stakes_at,
+};
+use crate::operator::stake::{EpochStart, Stake};
 use graphcast_sdk::callbook::CallBook;
 use graphcast_sdk::graphql::QueryError;
 
@@ -13,6 +18,22 @@ pub trait CallBookRadioExtensions {
         block_hash: String,
         block_number: i64,
     ) -> Result<String, QueryError>;
+
+    async fn query_indexer_stake(&self, indexer_address: &str) -> Result<Stake, QueryError>;
+
+    async fn query_indexer_stakes_at(
+        &self,
+        indexer_addresses: &[String],
+        block_number: u64,
+    ) -> Result<HashMap<String, Stake>, QueryError>;
+
+    /// Current epoch, `latest` is the latest epoch already seen so its stake block is not searched for again
+    async fn query_current_epoch(
+        &self,
+        latest: Option<EpochStart>,
+    ) -> Result<EpochStart, QueryError>;
+
+    async fn query_graph_node_version(&self) -> Result<String, QueryError>;
 }
 
 #[async_trait]
@@ -31,4 +52,27 @@ impl CallBookRadioExtensions for CallBook {
         )
         .await
     }
+
+    async fn query_indexer_stake(&self, indexer_address: &str) -> Result<Stake, QueryError> {
+        query_indexer_stake(self.graph_network(), indexer_address).await
+    }
+
+    async fn query_indexer_stakes_at(
+        &self,
+        indexer_addresses: &[String],
+        block_number: u64,
+    ) -> Result<HashMap<String, Stake>, QueryError> {
+        query_indexer_stakes_at(self.graph_network(), indexer_addresses, block_number).await
+    }
+
+    async fn query_current_epoch(
+        &self,
+        latest: Option<EpochStart>,
+    ) -> Result<EpochStart, QueryError> {
+        query_current_epoch(self.graph_network(), latest).await
+    }
+
+    async fn query_graph_node_version(&self) -> Result<String, QueryError> {
+        query_graph_node_version(self.graph_node_status()).await
+    }
 }
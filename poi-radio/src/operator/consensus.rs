@@ -0,0 +1,173 @@
+use async_graphql::Enum;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use crate::operator::attestation::Attestation;
+
+/// Rule used to pick the consensus nPOI among remote attestations
+#[derive(
+    clap::ValueEnum, Enum, Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+pub enum ConsensusPolicyType {
+    /// The nPOI backed by the most stake
+    #[default]
+    StakeWeighted,
+    /// The nPOI sent by the most senders, stake only breaks ties
+    SenderCount,
+    /// The nPOI sent by the most trusted senders, messages from other senders are ignored
+    TrustedSet,
+}
+
+impl Display for ConsensusPolicyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsensusPolicyType::StakeWeighted => write!(f, "stake-weighted"),
+            ConsensusPolicyType::SenderCount => write!(f, "sender-count"),
+            ConsensusPolicyType::TrustedSet => write!(f, "trusted-set"),
+        }
+    }
+}
+
+/// Consensus policy applied to remote attestations before comparing them with the local nPOI
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConsensusPolicy {
+    #[default]
+    StakeWeighted,
+    SenderCount,
+    /// Only the listed indexer addresses count towards consensus
+    TrustedSet(HashSet<String>),
+}
+
+impl ConsensusPolicy {
+    /// Trusted set of indexer addresses, compared case-insensitively
+    pub fn trusted_set(senders: &[String]) -> Self {
+        ConsensusPolicy::TrustedSet(senders.iter().map(|s| s.to_lowercase()).collect())
+    }
+
+    pub fn policy_type(&self) -> ConsensusPolicyType {
+        match self {
+            ConsensusPolicy::StakeWeighted => ConsensusPolicyType::StakeWeighted,
+            ConsensusPolicy::SenderCount => ConsensusPolicyType::SenderCount,
+            ConsensusPolicy::TrustedSet(_) => ConsensusPolicyType::TrustedSet,
+        }
+    }
+
+    /// Whether messages from the sender count towards consensus
+    pub fn admits(&self, sender: &str) -> bool {
+        match self {
+            ConsensusPolicy::TrustedSet(trusted) => trusted.contains(&sender.to_lowercase()),
+            _ => true,
+        }
+    }
+
+    /// Compare the support of two attestations under the policy
+    pub fn cmp_support(&self, a: &Attestation, b: &Attestation) -> Ordering {
+        match self {
+            ConsensusPolicy::StakeWeighted => a.stake_weight.cmp(&b.stake_weight),
+            ConsensusPolicy::SenderCount | ConsensusPolicy::TrustedSet(_) => {
+                (a.senders.len(), &a.stake_weight).cmp(&(b.senders.len(), &b.stake_weight))
+            }
+        }
+    }
+
+    /// Sort attestations in ascending order of support, so the consensus candidate is the last one.
+    /// Attestations with equal support are ordered by sender_group_hash, so every radio ranks the same
+    /// attestations identically regardless of the order messages arrived in
+    pub fn rank(&self, attestations: &mut [Attestation]) {
+        attestations.sort_by(|a, b| {
+            self.cmp_support(a, b)
+                .then_with(|| a.sender_group_hash.cmp(&b.sender_group_hash))
+        });
+    }
+
+    /// nPOIs of ranked attestations tied with the top one, in ranked order; empty if the top attestation is unique
+    pub fn tied_npois(&self, ranked: &[Attestation]) -> Vec<String> {
+        let top = match ranked.last() {
+            Some(top) => top,
+            None => return vec![],
+        };
+        let tied: Vec<String> = ranked
+            .iter()
+            .filter(|a| self.cmp_support(a, top) == Ordering::Equal)
+            .map(|a| a.npoi.clone())
+            .collect();
+        if tied.len() > 1 {
+            tied
+        } else {
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operator::stake::Stake;
+
+    fn attestations() -> Vec<Attestation> {
+        vec![
+            Attestation::new(
+                String::from("npoi-whale"),
+                Stake::from_grt(100),
+                vec![String::from("0xa1")],
+                vec![1],
+            ),
+            Attestation::new(
+                String::from("npoi-crowd"),
+                Stake::from_grt(3),
+                vec![String::from("0xb1"), String::from("0xb2")],
+                vec![1, 2],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_policy_rank() {
+        let mut ranked = attestations();
+        ConsensusPolicy::StakeWeighted.rank(&mut ranked);
+        assert_eq!(ranked.last().unwrap().npoi, "npoi-whale");
+
+        ConsensusPolicy::SenderCount.rank(&mut ranked);
+        assert_eq!(ranked.last().unwrap().npoi, "npoi-crowd");
+    }
+
+    #[test]
+    fn test_tie_break_is_deterministic() {
+        let a = Attestation::new(
+            String::from("npoi-a"),
+            Stake::from_grt(5),
+            vec![String::from("0xa1")],
+            vec![1],
+        );
+        let b = Attestation::new(
+            String::from("npoi-b"),
+            Stake::from_grt(5),
+            vec![String::from("0xb1")],
+            vec![1],
+        );
+        let policy = ConsensusPolicy::StakeWeighted;
+        let mut forward = vec![a.clone(), b.clone()];
+        let mut backward = vec![b, a];
+        policy.rank(&mut forward);
+        policy.rank(&mut backward);
+        assert_eq!(forward, backward);
+
+        let tied = policy.tied_npois(&forward);
+        assert_eq!(tied.len(), 2);
+        assert_eq!(tied.last(), forward.last().map(|a| &a.npoi));
+        // Equal sender counts and stake also tie under the sender count policy
+        assert_eq!(ConsensusPolicy::SenderCount.tied_npois(&forward).len(), 2);
+        assert!(policy.tied_npois(&forward[1..]).is_empty());
+    }
+
+    #[test]
+    fn test_trusted_set_admits() {
+        let policy = ConsensusPolicy::trusted_set(&[String::from("0xAB")]);
+        assert_eq!(policy.policy_type(), ConsensusPolicyType::TrustedSet);
+        assert!(policy.admits("0xab"));
+        assert!(!policy.admits("0xcd"));
+        assert!(ConsensusPolicy::StakeWeighted.admits("0xcd"));
+    }
+}
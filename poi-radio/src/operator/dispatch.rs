@@ -0,0 +1,382 @@
+use async_graphql::OutputType;
+use axum::async_trait;
+use ethers_core::types::transaction::eip712::Eip712;
+use prost::Message;
+use serde::Serialize;
+use tracing::{debug, trace};
+
+use graphcast_sdk::graphcast_agent::{
+    message_typing::{check_message_validity, BuildMessageError, GraphcastMessage},
+    GraphcastAgent,
+};
+
+use crate::{
+    metrics::MESSAGE_DECODE_FAILURES,
+    operator::{
+        audit::{AuditLog, AuditOutcome, ValidationCheck},
+        notifier::Notifier,
+    },
+    replay::Recorder,
+    state::StateHandle,
+};
+
+/// Outcome of validating a received message, with the check that failed
+pub type Validation = Result<(), (ValidationCheck, BuildMessageError)>;
+
+/// What handlers of received messages have access to
+#[derive(Clone)]
+pub struct MessageContext {
+    pub agent: &'static GraphcastAgent,
+    pub state: StateHandle,
+    pub notifier: Notifier,
+    pub audit: AuditLog,
+    pub recorder: Recorder,
+    pub graph_node: String,
+    /// Whether to subscribe to the new deployment of announced upgrades
+    pub upgrade_auto_subscribe: bool,
+}
+
+/// What the registry needs from the context handlers run in
+pub trait DispatchContext: Send + Sync {
+    fn audit(&self) -> &AuditLog;
+}
+
+impl DispatchContext for MessageContext {
+    fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+}
+
+/// A radio message type: the payload it decodes to, the checks it goes through and what is done with it
+#[async_trait]
+pub trait RadioMessageHandler<C: DispatchContext = MessageContext>: Send + Sync + 'static {
+    type Payload: Message
+        + Eip712
+        + Default
+        + Clone
+        + 'static
+        + OutputType
+        + Serialize
+        + Send
+        + Sync;
+
+    /// Name of the message type in logs, metrics and the audit log
+    const NAME: &'static str;
+
+    /// Radio-wide content topic the message type is gossiped on, None for the topics of deployments
+    const TOPIC: Option<&'static str> = None;
+
+    /// Every check a decoded message goes through before it is handled
+    async fn validate(&self, msg: &GraphcastMessage<Self::Payload>, context: &C) -> Validation;
+
+    /// Act on a message that passed validation
+    async fn handle(&self, msg: GraphcastMessage<Self::Payload>, context: &C);
+}
+
+/// Signature, sender identity, message age and nonce checks of Graphcast
+pub async fn graphcast_validity<T>(
+    msg: &GraphcastMessage<T>,
+    context: &MessageContext,
+) -> Validation
+where
+    T: Message + Eip712 + Default + Clone + 'static + OutputType,
+{
+    let agent = context.agent;
+    check_message_validity(
+        msg.clone(),
+        &agent.nonces,
+        agent.callbook.clone(),
+        agent.graphcast_identity.graphcast_id.clone(),
+        &agent.id_validation,
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| (ValidationCheck::CheckMessageValidity, e))
+}
+
+/// Rejection of a decoded message, reported only if no other message type accepts the payload
+type Rejection<C> = Box<dyn FnOnce(&C) + Send>;
+
+/// Outcome of handing a payload to one message type
+enum Attempt<C> {
+    /// The payload does not decode to this message type
+    Undecoded,
+    Handled,
+    Rejected(Rejection<C>),
+}
+
+/// Registered message type with its payload type erased
+#[async_trait]
+trait Dispatch<C>: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn topic(&self) -> Option<&'static str>;
+
+    /// Decode, validate and, if valid, handle a payload
+    async fn attempt(&self, payload: &[u8], context: &C) -> Attempt<C>;
+}
+
+struct Registered<H>(H);
+
+#[async_trait]
+impl<C: DispatchContext + 'static, H: RadioMessageHandler<C>> Dispatch<C> for Registered<H> {
+    fn name(&self) -> &'static str {
+        H::NAME
+    }
+
+    fn topic(&self) -> Option<&'static str> {
+        H::TOPIC
+    }
+
+    async fn attempt(&self, payload: &[u8], context: &C) -> Attempt<C> {
+        let msg = match GraphcastMessage::<H::Payload>::decode(payload) {
+            Ok(msg) => msg,
+            Err(e) => {
+                trace!(
+                    message_type = H::NAME,
+                    err = tracing::field::debug(&e),
+                    "Waku message not decoded"
+                );
+                return Attempt::Undecoded;
+            }
+        };
+        trace!(
+            message_type = H::NAME,
+            message = tracing::field::debug(&msg),
+            "Decoded message, now validate",
+        );
+        match self.0.validate(&msg, context).await {
+            Ok(()) => {
+                context
+                    .audit()
+                    .received(H::NAME, &msg, AuditOutcome::Accepted);
+                self.0.handle(msg, context).await;
+                Attempt::Handled
+            }
+            Err((check, e)) => Attempt::Rejected(Box::new(move |context: &C| {
+                debug!(
+                    message_type = H::NAME,
+                    check = tracing::field::debug(check),
+                    err = tracing::field::debug(&e),
+                    "Message failed validation"
+                );
+                context
+                    .audit()
+                    .received(H::NAME, &msg, AuditOutcome::rejected(check, e));
+            })),
+        }
+    }
+}
+
+/// Message types the radio handles. A payload gossiped on the radio-wide topic of a message type is only decoded
+/// as that type. Payloads on the topics of deployments are decoded as each deployment message type in the order
+/// the types were registered, and handled as the first one they decode to and pass the validation of, since
+/// some of these types share a wire layout
+pub struct MessageRegistry<C = MessageContext> {
+    types: Vec<Box<dyn Dispatch<C>>>,
+}
+
+impl<C> Default for MessageRegistry<C> {
+    fn default() -> Self {
+        MessageRegistry { types: vec![] }
+    }
+}
+
+impl<C: DispatchContext + 'static> MessageRegistry<C> {
+    pub fn register<H: RadioMessageHandler<C>>(mut self, handler: H) -> Self {
+        self.types.push(Box::new(Registered(handler)));
+        self
+    }
+
+    /// Message types expected on a content topic. Only the types with a radio-wide topic are tried on that topic,
+    /// and never on deployment topics, so their payloads are told apart by topic rather than by wire layout
+    fn expected_on(&self, topic: &str) -> Vec<&dyn Dispatch<C>> {
+        let radio_wide = self.types.iter().any(|t| t.topic() == Some(topic));
+        self.types
+            .iter()
+            .map(|t| t.as_ref())
+            .filter(|t| match t.topic() {
+                Some(t) => t == topic,
+                None => !radio_wide,
+            })
+            .collect()
+    }
+
+    /// Decode, validate and handle a Waku message payload received on a content topic. Only if none of the types
+    /// expected on the topic decodes the payload, it counts as a decode failure of each of them. If some decode but
+    /// none accepts it, the rejection of the first one is reported
+    pub async fn dispatch(&self, topic: &str, payload: &[u8], context: &C) {
+        let expected = self.expected_on(topic);
+        let mut rejection = None;
+        for message_type in &expected {
+            match message_type.attempt(payload, context).await {
+                Attempt::Handled => return,
+                Attempt::Rejected(report) => {
+                    rejection.get_or_insert(report);
+                }
+                Attempt::Undecoded => {}
+            }
+        }
+        match rejection {
+            Some(report) => report(context),
+            None => {
+                for message_type in expected {
+                    MESSAGE_DECODE_FAILURES
+                        .with_label_values(&[message_type.name()])
+                        .inc();
+                }
+                trace!(topic, "Waku message not decoded, skipped message");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use std::sync::Mutex as SyncMutex;
+
+    use crate::messages::poi::PublicPoiMessage;
+    use crate::messages::upgrade::VersionUpgradeMessage;
+
+    #[derive(Default)]
+    struct TestContext {
+        audit: AuditLog,
+        handled: SyncMutex<Vec<&'static str>>,
+    }
+
+    impl DispatchContext for TestContext {
+        fn audit(&self) -> &AuditLog {
+            &self.audit
+        }
+    }
+
+    fn wallet() -> LocalWallet {
+        "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+            .parse()
+            .unwrap()
+    }
+
+    /// The signature only recovers to the signer under the EIP-712 type it was made for
+    fn signed_by_wallet<T>(msg: &GraphcastMessage<T>) -> Validation
+    where
+        T: Message + Eip712 + Default + Clone + 'static + OutputType,
+    {
+        match msg.recover_sender_address() {
+            Ok(address) if address.eq_ignore_ascii_case(&format!("{:#x}", wallet().address())) => {
+                Ok(())
+            }
+            _ => Err((
+                ValidationCheck::CheckMessageValidity,
+                BuildMessageError::InvalidFields(anyhow::anyhow!("Not signed by the test wallet")),
+            )),
+        }
+    }
+
+    struct PoiHandler;
+
+    #[async_trait]
+    impl RadioMessageHandler<TestContext> for PoiHandler {
+        type Payload = PublicPoiMessage;
+        const NAME: &'static str = "PublicPoiMessage";
+
+        async fn validate(
+            &self,
+            msg: &GraphcastMessage<PublicPoiMessage>,
+            _context: &TestContext,
+        ) -> Validation {
+            signed_by_wallet(msg)
+        }
+
+        async fn handle(&self, _msg: GraphcastMessage<PublicPoiMessage>, context: &TestContext) {
+            context.handled.lock().unwrap().push(Self::NAME);
+        }
+    }
+
+    /// POI messages gossiped on a radio-wide topic
+    struct PoiTopicHandler;
+
+    #[async_trait]
+    impl RadioMessageHandler<TestContext> for PoiTopicHandler {
+        type Payload = PublicPoiMessage;
+        const NAME: &'static str = "PublicPoiTopicMessage";
+        const TOPIC: Option<&'static str> = Some("poi-topic");
+
+        async fn validate(
+            &self,
+            msg: &GraphcastMessage<PublicPoiMessage>,
+            _context: &TestContext,
+        ) -> Validation {
+            signed_by_wallet(msg)
+        }
+
+        async fn handle(&self, _msg: GraphcastMessage<PublicPoiMessage>, context: &TestContext) {
+            context.handled.lock().unwrap().push(Self::NAME);
+        }
+    }
+
+    struct UpgradeHandler;
+
+    #[async_trait]
+    impl RadioMessageHandler<TestContext> for UpgradeHandler {
+        type Payload = VersionUpgradeMessage;
+        const NAME: &'static str = "VersionUpgradeMessage";
+
+        async fn validate(
+            &self,
+            msg: &GraphcastMessage<VersionUpgradeMessage>,
+            _context: &TestContext,
+        ) -> Validation {
+            signed_by_wallet(msg)
+        }
+
+        async fn handle(
+            &self,
+            _msg: GraphcastMessage<VersionUpgradeMessage>,
+            context: &TestContext,
+        ) {
+            context.handled.lock().unwrap().push(Self::NAME);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_shared_wire_layout() {
+        let upgrade = VersionUpgradeMessage::new(
+            String::from("Qm1"),
+            String::from("Qm2"),
+            String::from("0xsubgraph"),
+            1,
+            String::from("goerli"),
+            2,
+            String::from("0xa1"),
+        );
+        let msg = GraphcastMessage::build(
+            &wallet(),
+            upgrade.identifier.clone(),
+            upgrade.graph_account.clone(),
+            upgrade.nonce,
+            upgrade,
+        )
+        .await
+        .unwrap();
+        let payload = msg.encode_to_vec();
+        // The upgrade message decodes as a POI message, which fails validation
+        assert!(GraphcastMessage::<PublicPoiMessage>::decode(payload.as_slice()).is_ok());
+
+        let registry = MessageRegistry::default()
+            .register(PoiHandler)
+            .register(PoiTopicHandler)
+            .register(UpgradeHandler);
+        let context = TestContext::default();
+        registry.dispatch("Qm1", &payload, &context).await;
+        assert_eq!(
+            *context.handled.lock().unwrap(),
+            vec!["VersionUpgradeMessage"]
+        );
+
+        // Payloads on a radio-wide topic are not decoded as deployment message types
+        registry.dispatch("poi-topic", &payload, &context).await;
+        assert_eq!(context.handled.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,335 @@
+use async_graphql::SimpleObject;
+use serde::{Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::messages::{poi::PublicPoiMessage, poi_batch::PublicPoiBatchMessage};
+use crate::operator::consensus::ConsensusPolicy;
+use crate::state::MessageRetention;
+
+/// An indexer that signed different nPOIs for the same deployment and block
+/// The signed messages are kept as evidence
+#[derive(Clone, Debug, Serialize, Deserialize, SimpleObject)]
+pub struct Equivocation {
+    pub deployment: String,
+    pub block_number: u64,
+    pub graph_account: String,
+    pub messages: Vec<GraphcastMessage<PublicPoiMessage>>,
+    /// Signed batches of the messages that were gossiped in a batch, their signature is over the whole batch
+    #[serde(default)]
+    #[graphql(skip)]
+    pub batches: Vec<GraphcastMessage<PublicPoiBatchMessage>>,
+}
+
+impl Equivocation {
+    /// Whether the equivocation covers a message, by deployment, block and sender
+    pub fn covers(&self, msg: &GraphcastMessage<PublicPoiMessage>) -> bool {
+        self.deployment == msg.identifier
+            && self.block_number == msg.payload.block_number
+            && self.graph_account == msg.graph_account
+    }
+
+    /// Keep a batch as evidence unless it is already kept
+    pub fn add_batch(&mut self, batch: GraphcastMessage<PublicPoiBatchMessage>) {
+        if !self.batches.iter().any(|b| b.signature == batch.signature) {
+            self.batches.push(batch);
+        }
+    }
+}
+
+impl Display for Equivocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let npois: HashSet<&str> = self
+            .messages
+            .iter()
+            .map(|m| m.payload.content.as_str())
+            .collect();
+        write!(
+            f,
+            "Equivocation: indexer {} signed {} different nPOIs for deployment {} at block {}, excluded from consensus",
+            self.graph_account,
+            npois.len(),
+            self.deployment,
+            self.block_number
+        )
+    }
+}
+
+/// Deployment, block and sender of an equivocation
+type EquivocationKey = (String, u64, String);
+
+/// Equivocation evidence keyed by deployment, block and sender.
+/// Serialized as a list sorted by key, the layout evidence was persisted in before it was keyed
+#[derive(Clone, Debug, Default)]
+pub struct EquivocationLog {
+    entries: HashMap<EquivocationKey, Equivocation>,
+}
+
+impl EquivocationLog {
+    /// Add evidence, merged into the existing entry for the same deployment, block and sender.
+    /// Returns true if the equivocation was not known before
+    pub fn insert(&mut self, equivocation: Equivocation) -> bool {
+        let key = (
+            equivocation.deployment.clone(),
+            equivocation.block_number,
+            equivocation.graph_account.clone(),
+        );
+        match self.entries.get_mut(&key) {
+            Some(existing) => {
+                for msg in equivocation.messages {
+                    // Entries of a batch share its signature
+                    if !existing.messages.iter().any(|m| {
+                        m.signature == msg.signature && m.payload.content == msg.payload.content
+                    }) {
+                        existing.messages.push(msg);
+                    }
+                }
+                for batch in equivocation.batches {
+                    existing.add_batch(batch);
+                }
+                false
+            }
+            None => {
+                self.entries.insert(key, equivocation);
+                true
+            }
+        }
+    }
+
+    /// All evidence sorted by deployment, block and sender
+    pub fn all(&self) -> Vec<Equivocation> {
+        let mut keys: Vec<&EquivocationKey> = self.entries.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| self.entries[key].clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Evidence past the retention of remote messages: its latest message is older than the retention age,
+    /// or it is beyond the max_per_deployment entries of the latest blocks of its deployment
+    pub fn expired(&self, retention: &MessageRetention, now: i64) -> Vec<EquivocationKey> {
+        let mut expired = vec![];
+        let mut by_deployment: HashMap<&str, Vec<&EquivocationKey>> = HashMap::new();
+        for (key, equivocation) in &self.entries {
+            let latest = equivocation.messages.iter().map(|m| m.nonce).max();
+            match latest {
+                Some(nonce) if now - nonce <= retention.max_age => {
+                    by_deployment.entry(&key.0).or_default().push(key)
+                }
+                _ => expired.push(key.clone()),
+            }
+        }
+        for keys in by_deployment.values_mut() {
+            keys.sort_by(|a, b| b.cmp(a));
+            expired.extend(
+                keys.iter()
+                    .skip(retention.max_per_deployment)
+                    .map(|key| (*key).clone()),
+            );
+        }
+        expired
+    }
+
+    pub fn remove(&mut self, key: &EquivocationKey) -> Option<Equivocation> {
+        self.entries.remove(key)
+    }
+}
+
+impl serde::Serialize for EquivocationLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serde::Serialize::serialize(&self.all(), serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EquivocationLog {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut log = EquivocationLog::default();
+        for equivocation in <Vec<Equivocation> as serde::Deserialize>::deserialize(deserializer)? {
+            log.insert(equivocation);
+        }
+        Ok(log)
+    }
+}
+
+/// Find senders that signed more than one distinct nPOI for the same deployment and block
+pub fn detect_equivocations(messages: &[GraphcastMessage<PublicPoiMessage>]) -> Vec<Equivocation> {
+    let mut by_sender: HashMap<(&str, u64, &str), Vec<&GraphcastMessage<PublicPoiMessage>>> =
+        HashMap::new();
+    for msg in messages {
+        by_sender
+            .entry((
+                msg.identifier.as_str(),
+                msg.payload.block_number,
+                msg.graph_account.as_str(),
+            ))
+            .or_default()
+            .push(msg);
+    }
+
+    let mut equivocations: Vec<Equivocation> = by_sender
+        .into_iter()
+        .filter(|(_, msgs)| {
+            let npois: HashSet<&str> = msgs.iter().map(|m| m.payload.content.as_str()).collect();
+            npois.len() > 1
+        })
+        .map(
+            |((deployment, block_number, graph_account), msgs)| Equivocation {
+                deployment: deployment.to_string(),
+                block_number,
+                graph_account: graph_account.to_string(),
+                messages: msgs.into_iter().cloned().collect(),
+                batches: vec![],
+            },
+        )
+        .collect();
+    equivocations.sort_by(|a, b| {
+        (&a.deployment, a.block_number, &a.graph_account).cmp(&(
+            &b.deployment,
+            b.block_number,
+            &b.graph_account,
+        ))
+    });
+    equivocations
+}
+
+/// Messages that count towards consensus, along with the equivocations found among them.
+/// Senders that signed different nPOIs for a block are left out of that block, as are senders the policy does not admit
+pub fn consensus_messages(
+    messages: Vec<GraphcastMessage<PublicPoiMessage>>,
+    policy: &ConsensusPolicy,
+) -> (Vec<GraphcastMessage<PublicPoiMessage>>, Vec<Equivocation>) {
+    let equivocations = detect_equivocations(&messages);
+    let messages = exclude_equivocators(messages, &equivocations)
+        .into_iter()
+        .filter(|m| policy.admits(&m.graph_account))
+        .collect();
+    (messages, equivocations)
+}
+
+/// Drop the messages of equivocating senders for the blocks they equivocated on
+pub fn exclude_equivocators(
+    messages: Vec<GraphcastMessage<PublicPoiMessage>>,
+    equivocations: &[Equivocation],
+) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+    messages
+        .into_iter()
+        .filter(|msg| !equivocations.iter().any(|e| e.covers(msg)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphcast_sdk::networks::NetworkName;
+
+    fn msg(
+        sender: &str,
+        npoi: &str,
+        block_number: u64,
+        nonce: i64,
+    ) -> GraphcastMessage<PublicPoiMessage> {
+        let hash = String::from("QmHash");
+        GraphcastMessage::new(
+            hash.clone(),
+            nonce,
+            sender.to_string(),
+            PublicPoiMessage::build(
+                hash,
+                npoi.to_string(),
+                nonce,
+                NetworkName::Goerli,
+                block_number,
+                String::from("0xblock"),
+                sender.to_string(),
+            ),
+            String::from("0xsig"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_detect_equivocations() {
+        let messages = vec![
+            msg("0xa1", "npoi-x", 1, 1),
+            msg("0xa1", "npoi-y", 1, 2),
+            // Repeated message with the same nPOI is not an equivocation
+            msg("0xa2", "npoi-x", 1, 1),
+            msg("0xa2", "npoi-x", 1, 2),
+            // Different nPOIs at different blocks
+            msg("0xa3", "npoi-x", 1, 1),
+            msg("0xa3", "npoi-y", 2, 2),
+        ];
+
+        let equivocations = detect_equivocations(&messages);
+        assert_eq!(equivocations.len(), 1);
+        assert_eq!(equivocations[0].graph_account, "0xa1");
+        assert_eq!(equivocations[0].messages.len(), 2);
+        assert_eq!(
+            equivocations[0].to_string(),
+            "Equivocation: indexer 0xa1 signed 2 different nPOIs for deployment QmHash at block 1, excluded from consensus"
+        );
+
+        let remaining = exclude_equivocators(messages.clone(), &equivocations);
+        assert_eq!(remaining.len(), 4);
+        assert!(remaining.iter().all(|m| m.graph_account != "0xa1"));
+
+        let policy = ConsensusPolicy::trusted_set(&[String::from("0xa1"), String::from("0xa2")]);
+        let (admitted, found) = consensus_messages(messages, &policy);
+        assert_eq!(found.len(), 1);
+        assert_eq!(admitted.len(), 2);
+        assert!(admitted.iter().all(|m| m.graph_account == "0xa2"));
+    }
+
+    #[test]
+    fn test_equivocation_log_retention() {
+        let equivocation = |sender: &str, block_number: u64, nonces: [i64; 2]| {
+            detect_equivocations(&[
+                msg(sender, "npoi-x", block_number, nonces[0]),
+                msg(sender, "npoi-y", block_number, nonces[1]),
+            ])
+            .remove(0)
+        };
+        let mut log = EquivocationLog::default();
+        assert!(log.insert(equivocation("0xa1", 1, [10, 90])));
+        // Evidence for the same deployment, block and sender is merged
+        let mut again = equivocation("0xa1", 1, [95, 96]);
+        again.messages[1].payload.content = String::from("npoi-z");
+        assert!(!log.insert(again));
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.all()[0].messages.len(), 3);
+
+        assert!(log.insert(equivocation("0xa1", 2, [91, 92])));
+        assert!(log.insert(equivocation("0xa2", 3, [10, 20])));
+
+        // Persisted as a list, the layout of evidence before it was keyed
+        let json = serde_json::to_value(&log).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 3);
+        let log: EquivocationLog = serde_json::from_value(json).unwrap();
+        assert_eq!(log.len(), 3);
+
+        let retention = MessageRetention {
+            max_age: 50,
+            max_per_deployment: 1,
+        };
+        let mut expired = log.expired(&retention, 100);
+        expired.sort();
+        assert_eq!(
+            expired,
+            vec![
+                (String::from("QmHash"), 1, String::from("0xa1")),
+                (String::from("QmHash"), 3, String::from("0xa2")),
+            ]
+        );
+    }
+}
@@ -1,7 +1,9 @@
+use chrono::Utc;
 use derive_getters::Getters;
+use std::collections::HashSet;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex as SyncMutex,
 };
 use std::time::Duration;
 use tokio::time::{interval, sleep, timeout};
@@ -9,32 +11,50 @@ use tracing::{debug, error, info, trace, warn};
 
 use graphcast_sdk::{
     build_wallet,
-    graphcast_agent::{
-        message_typing::{check_message_validity, GraphcastMessage},
-        waku_handling::WakuHandlingError,
-        GraphcastAgent,
-    },
+    graphcast_agent::GraphcastAgent,
     graphql::client_graph_node::{subgraph_network_blocks, update_network_chainheads},
+    wallet_address,
 };
 
 use crate::chainhead_block_str;
-use crate::messages::poi::PublicPoiMessage;
+use crate::messages::heartbeat::{HeartbeatHandler, HEARTBEAT_TOPIC};
+use crate::messages::poi::PublicPoiHandler;
+use crate::messages::poi_batch::{PoiBatchHandler, POI_BATCH_TOPIC};
+use crate::messages::poi_request::{PoiRequestHandler, PoiResponseHandler};
 
-use crate::messages::upgrade::VersionUpgradeMessage;
+use crate::messages::upgrade::VersionUpgradeHandler;
 use crate::metrics::handle_serve_metrics;
 use crate::operator::attestation::log_gossip_summary;
 use crate::operator::attestation::process_comparison_results;
+use crate::replay::Recorder;
 use crate::server::run_server;
-use crate::state::PersistedState;
+use crate::state::{PersistedState, StateHandle};
+use crate::storage::StateStore;
 use crate::GRAPHCAST_AGENT;
-use crate::{config::Config, metrics::CACHED_MESSAGES};
+use crate::{
+    config::Config,
+    metrics::{CACHED_MESSAGES, EVICTED_MESSAGES},
+};
 
+use self::audit::AuditLog;
+use self::dispatch::{MessageContext, MessageRegistry};
 use self::notifier::Notifier;
 
 pub mod attestation;
+pub mod audit;
+pub mod bisection;
 pub mod callbook;
+pub mod consensus;
+pub mod dispatch;
+pub mod equivocation;
 pub mod notifier;
 pub mod operation;
+pub mod reputation;
+pub mod stake;
+pub mod upgrade;
+
+/// Content topics of radio-wide messages, subscribed to along with the topic of each deployment
+const RADIO_TOPICS: [&str; 2] = [POI_BATCH_TOPIC, HEARTBEAT_TOPIC];
 
 /// Aggregated control flow configurations
 /// Not used currently
@@ -84,10 +104,18 @@ impl ControlFlow {
 #[allow(unused)]
 pub struct RadioOperator {
     config: Config,
-    persisted_state: PersistedState,
+    state: StateHandle,
+    /// Storage the persisted state is saved to, if a persistence file path is set
+    state_store: Option<Arc<dyn StateStore>>,
     graphcast_agent: Arc<GraphcastAgent>,
     notifier: Notifier,
+    /// Record of the messages received and sent
+    audit: AuditLog,
+    /// Record of the inputs consumed, for replay
+    recorder: Recorder,
     control_flow: ControlFlow,
+    /// Deployments with a bisection in progress
+    bisections: Arc<SyncMutex<HashSet<String>>>,
 }
 
 impl RadioOperator {
@@ -95,16 +123,22 @@ impl RadioOperator {
     /// graphcast agent, and control flow
     pub async fn new(config: &Config) -> RadioOperator {
         debug!("Initializing Radio operator");
-        let _wallet = build_wallet(
+        let wallet = build_wallet(
             config
                 .wallet_input()
                 .expect("Operator wallet input invalid"),
         )
         .expect("Radio operator cannot build wallet");
 
+        // With recording on, queries go through a proxy recording their responses
+        let (config, recorder) = Recorder::from_config(config, wallet_address(&wallet)).await;
+        let config = &config;
+
         debug!("Initializing program state");
         // Initialize program state
-        let persisted_state: PersistedState = config.init_radio_state().await;
+        let state_store = config.state_store();
+        let persisted_state: PersistedState = config.init_radio_state(state_store.as_ref()).await;
+        let state = StateHandle::spawn(persisted_state);
 
         debug!("Initializing Graphcast Agent");
         let (agent, receiver) =
@@ -117,103 +151,37 @@ impl RadioOperator {
         _ = GRAPHCAST_AGENT.set(graphcast_agent.clone());
 
         let notifier = Notifier::from_config(config);
+        let audit = AuditLog::from_config(config);
+
+        let registry = MessageRegistry::default()
+            .register(PublicPoiHandler)
+            .register(PoiBatchHandler)
+            .register(VersionUpgradeHandler)
+            .register(PoiRequestHandler::default())
+            .register(PoiResponseHandler)
+            .register(HeartbeatHandler);
+        let context = MessageContext {
+            agent: GRAPHCAST_AGENT
+                .get()
+                .expect("Could not retrieve Graphcast agent"),
+            state: state.clone(),
+            notifier: notifier.clone(),
+            audit: audit.clone(),
+            recorder: recorder.clone(),
+            graph_node: config.graph_node_endpoint().clone(),
+            upgrade_auto_subscribe: config.upgrade_auto_subscribe.unwrap_or(false),
+        };
 
-        let state_ref = persisted_state.clone();
-        let upgrade_notifier = notifier.clone();
-        let graph_node = config.graph_node_endpoint().clone();
-
-        // try message format in order of PublicPOIMessage, VersionUpgradeMessage
         tokio::spawn(async move {
             for msg in receiver {
                 trace!("Decoding waku message into Graphcast Message with Radio specified payload");
-                let agent = GRAPHCAST_AGENT
-                    .get()
-                    .expect("Could not retrieve Graphcast agent");
-                let id_validation = agent.id_validation.clone();
-                let callbook = agent.callbook.clone();
-                let nonces = agent.nonces.clone();
-                let local_sender = agent.graphcast_identity.graphcast_id.clone();
-                if let Ok(msg) = agent.decode::<PublicPoiMessage>(msg.payload()).await {
-                    trace!(
-                        message = tracing::field::debug(&msg),
-                        "Parseable as Public PoI message, now validate",
-                    );
-                    let msg = match check_message_validity(
-                        msg,
-                        &nonces,
-                        callbook.clone(),
-                        local_sender.clone(),
-                        &id_validation,
+                registry
+                    .dispatch(
+                        &msg.content_topic().content_topic_name,
+                        msg.payload(),
+                        &context,
                     )
-                    .await
-                    .map_err(|e| WakuHandlingError::InvalidMessage(e.to_string()))
-                    {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            debug!(
-                                err = tracing::field::debug(e),
-                                "Failed to validate by Graphcast"
-                            );
-                            continue;
-                        }
-                    };
-
-                    let identifier = msg.identifier.clone();
-
-                    let is_valid = msg.payload.validity_check(&msg, &graph_node).await;
-
-                    if is_valid.is_ok() {
-                        state_ref.add_remote_message(msg.clone());
-                        CACHED_MESSAGES.with_label_values(&[&identifier]).set(
-                            state_ref
-                                .remote_messages()
-                                .iter()
-                                .filter(|m| m.identifier == identifier)
-                                .collect::<Vec<&GraphcastMessage<PublicPoiMessage>>>()
-                                .len()
-                                .try_into()
-                                .unwrap(),
-                        );
-                    };
-                } else if let Ok(msg) = agent.decode::<VersionUpgradeMessage>(msg.payload()).await {
-                    trace!(
-                        message = tracing::field::debug(&msg),
-                        "Parseable as Version Upgrade message, now validate",
-                    );
-                    let msg = match check_message_validity(
-                        msg,
-                        &nonces,
-                        callbook.clone(),
-                        local_sender.clone(),
-                        &id_validation,
-                    )
-                    .await
-                    .map_err(|e| WakuHandlingError::InvalidMessage(e.to_string()))
-                    {
-                        Ok(msg) => msg,
-                        Err(e) => {
-                            debug!(
-                                err = tracing::field::debug(e),
-                                "Failed to validate by Graphcast"
-                            );
-                            continue;
-                        }
-                    };
-                    let is_valid = msg.payload.validity_check(&msg, &graph_node).await;
-
-                    if let Ok(payload) = is_valid {
-                        // send notifications to the indexer?
-                        upgrade_notifier.notify(format!(
-                                "Subgraph owner for a deployment has shared version upgrade info:\nold deployment: {}\nnew deployment: {}\nplanned migrate time: {}\nnetwork: {}",
-                                payload.identifier,
-                                payload.new_hash,
-                                payload.migrate_time,
-                                payload.network
-                            )).await;
-                    };
-                } else {
-                    trace!("Waku message not decoded or validated, skipped message",);
-                };
+                    .await;
             }
         });
 
@@ -225,10 +193,14 @@ impl RadioOperator {
 
         RadioOperator {
             config: config.clone(),
-            persisted_state,
+            state,
+            state_store,
             graphcast_agent,
             notifier,
+            audit,
+            recorder,
             control_flow: ControlFlow::new(),
+            bisections: Arc::new(SyncMutex::new(HashSet::new())),
         }
     }
 
@@ -242,10 +214,7 @@ impl RadioOperator {
         }
 
         // Provide generated topics to Graphcast agent
-        let topics = self
-            .config
-            .generate_topics(self.config.indexer_address.clone())
-            .await;
+        let topics = self.content_topics().await;
         debug!(
             topics = tracing::field::debug(&topics),
             "Found content topics for subscription",
@@ -255,13 +224,41 @@ impl RadioOperator {
             .await;
     }
 
+    /// Topics to subscribe to: the deployments to cross-check, including the new deployments of pending upgrades
+    /// if configured, and the topics of radio-wide messages
+    async fn content_topics(&self) -> Vec<String> {
+        let mut topics = self
+            .config
+            .generate_topics(self.config.indexer_address.clone())
+            .await;
+        topics.extend(RADIO_TOPICS.iter().map(|topic| topic.to_string()));
+        if self.config.upgrade_auto_subscribe.unwrap_or(false) {
+            for plan in self.state.latest().upgrade_plans() {
+                if !topics.contains(&plan.new_hash) {
+                    topics.push(plan.new_hash);
+                }
+            }
+        }
+        topics
+    }
+
+    /// Deployments the radio cross-checks, which are its content topics other than the radio-wide ones
+    pub async fn deployments(&self) -> Vec<String> {
+        self.graphcast_agent
+            .content_identifiers()
+            .await
+            .into_iter()
+            .filter(|topic| !RADIO_TOPICS.contains(&topic.as_str()))
+            .collect()
+    }
+
     pub fn graphcast_agent(&self) -> &GraphcastAgent {
         &self.graphcast_agent
     }
 
-    /// Read persisted state at the time of access
-    pub fn state(&self) -> PersistedState {
-        self.persisted_state.clone()
+    /// Handle to the radio state
+    pub fn state(&self) -> &StateHandle {
+        &self.state
     }
 
     /// Radio operations
@@ -278,6 +275,9 @@ impl RadioOperator {
         let mut state_update_interval = interval(Duration::from_secs(60));
         let mut gossip_poi_interval = interval(Duration::from_secs(30));
         let mut comparison_interval = interval(Duration::from_secs(30));
+        let mut heartbeat_interval =
+            interval(Duration::from_secs(self.config.heartbeat_interval.max(1)));
+        let mut upgrade_reminder_interval = interval(Duration::from_secs(60));
 
         let iteration_timeout = Duration::from_secs(180);
         let update_timeout = Duration::from_secs(5);
@@ -291,9 +291,12 @@ impl RadioOperator {
 
         // Initialize Http server with graceful shutdown if configured
         if self.config.server_port().is_some() {
-            let state_ref = &self.persisted_state;
             let config_cloned = self.config.clone();
-            tokio::spawn(run_server(config_cloned, state_ref, running.clone()));
+            tokio::spawn(run_server(
+                config_cloned,
+                self.state.clone(),
+                running.clone(),
+            ));
         }
 
         // Main loop for sending messages, can factor out
@@ -309,7 +312,7 @@ impl RadioOperator {
                     // Update topic subscription
                     let result = timeout(update_timeout,
                         self.graphcast_agent()
-                        .update_content_topics(self.config.generate_topics(self.config.indexer_address.clone()).await)
+                        .update_content_topics(self.content_topics().await)
                     ).await;
 
                     if result.is_err() {
@@ -324,10 +327,29 @@ impl RadioOperator {
                         continue;
                     }
 
-                    // Save cache if path provided
-                    let _ = &self.config.persistence_file_path.as_ref().map(|path| {
-                        self.persisted_state.update_cache(path);
-                    });
+                    // Bound the message cache before saving it
+                    let topics = self.deployments().await;
+                    let evicted = self.state.apply_message_retention(
+                        self.config.message_retention(),
+                        topics,
+                        Utc::now().timestamp(),
+                    ).await;
+                    let snapshot = self.state.snapshot().await;
+                    for ((deployment, reason), count) in evicted {
+                        EVICTED_MESSAGES
+                            .with_label_values(&[&deployment, reason.as_str()])
+                            .inc_by(count as u64);
+                        CACHED_MESSAGES.with_label_values(&[&deployment]).set(
+                            snapshot.remote_messages.deployment_len(&deployment) as i64,
+                        );
+                    }
+
+                    // Save state if a store is configured
+                    if let Some(store) = &self.state_store {
+                        if let Err(e) = store.save(&snapshot) {
+                            error!(err = e.to_string(), "Could not save persisted state");
+                        }
+                    }
                 },
                 _ = gossip_poi_interval.tick() => {
                     if skip_iteration.load(Ordering::SeqCst) {
@@ -365,7 +387,7 @@ impl RadioOperator {
                         // Function takes in an identifier string and make specific queries regarding the identifier
                         // The example here combines a single function provided query endpoint, current block info based on the subgraph's indexing network
                         // Then the function gets sent to agent for making identifier independent queries
-                        let identifiers = self.graphcast_agent.content_identifiers().await;
+                        let identifiers = self.deployments().await;
                         let num_topics = identifiers.len();
                         let blocks_str = chainhead_block_str(&network_chainhead_blocks);
                         info!(
@@ -394,6 +416,29 @@ impl RadioOperator {
                         debug!("gossip_poi completed");
                     }
                 },
+                _ = heartbeat_interval.tick(), if self.config.heartbeat_interval > 0 => {
+                    if skip_iteration.load(Ordering::SeqCst) {
+                        skip_iteration.store(false, Ordering::SeqCst);
+                        continue;
+                    }
+
+                    match timeout(update_timeout, self.send_heartbeat()).await {
+                        Err(_) => warn!("send_heartbeat timed out"),
+                        Ok(Err(e)) => warn!(err = e.to_string(), "Could not send heartbeat"),
+                        Ok(Ok(msg_id)) => debug!(msg_id, "Sent heartbeat"),
+                    }
+                },
+                _ = upgrade_reminder_interval.tick() => {
+                    if skip_iteration.load(Ordering::SeqCst) {
+                        skip_iteration.store(false, Ordering::SeqCst);
+                        continue;
+                    }
+
+                    match timeout(update_timeout, self.remind_upgrades()).await {
+                        Err(_) => warn!("remind_upgrades timed out"),
+                        Ok(reminders) => debug!(reminders, "remind_upgrades completed"),
+                    }
+                },
                 _ = comparison_interval.tick() => {
                     if skip_iteration.load(Ordering::SeqCst) {
                         skip_iteration.store(false, Ordering::SeqCst);
@@ -413,11 +458,11 @@ impl RadioOperator {
                         let network_chainhead_blocks = update_network_chainheads(
                                 indexing_status,
                             );
-                        let identifiers = self.graphcast_agent().content_identifiers().await;
+                        let identifiers = self.deployments().await;
                         let blocks_str = chainhead_block_str(&network_chainhead_blocks);
 
                         trace!(
-                            state = tracing::field::debug(&self.state()),
+                            state = tracing::field::debug(&self.state.latest()),
                             "current state",
                         );
 
@@ -431,7 +476,7 @@ impl RadioOperator {
                             identifiers.len(),
                             comparison_res,
                             self.notifier.clone(),
-                            self.persisted_state.clone()
+                            self.state.clone()
                         )
                     }).await;
 
@@ -440,6 +485,8 @@ impl RadioOperator {
                     } else {
                         debug!("compare_poi completed");
                     }
+
+                    self.bisect_divergences().await;
                 },
                 else => break,
             }
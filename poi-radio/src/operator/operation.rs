@@ -3,8 +3,7 @@ use chrono::Utc;
 use graphcast_sdk::callbook::CallBook;
 use std::cmp::max;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex as SyncMutex};
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 use graphcast_sdk::{
     determine_message_block,
@@ -12,22 +11,36 @@ use graphcast_sdk::{
         message_typing::{BuildMessageError, GraphcastMessage},
         GraphcastAgent, GraphcastAgentError,
     },
+    graphql::client_graph_node::{subgraph_network_blocks, update_network_chainheads},
     networks::NetworkName,
     BlockPointer, NetworkBlockError, NetworkPointer,
 };
 
+use crate::messages::heartbeat::{HeartbeatMessage, HEARTBEAT_TOPIC};
 use crate::messages::poi::PublicPoiMessage;
+use crate::messages::poi_batch::{PublicPoiBatchMessage, POI_BATCH_TOPIC};
 use crate::operator::attestation::process_ppoi_message;
 use crate::{
-    metrics::CACHED_MESSAGES,
+    config::Config,
+    metrics::{CACHED_MESSAGES, EQUIVOCATIONS},
     operator::{
         attestation::{
-            compare_attestations, local_comparison_point, save_local_attestation, Attestation,
-            ComparisonResult,
+            compare_attestations, local_comparison_point, Attestation, ComparisonResult,
+            ComparisonResultType, QuorumThreshold,
         },
+        audit::{AuditLog, AuditOutcome},
+        bisection::bisect_divergence,
         callbook::CallBookRadioExtensions,
+        consensus::ConsensusPolicy,
+        equivocation::consensus_messages,
+        notifier::Notifier,
+        reputation::SenderWeights,
+        stake::{epoch_at, stakes_at_epoch, EpochStart, SenderStakes},
+        upgrade::NewDeploymentSync,
         RadioOperator,
     },
+    replay::Recorder,
+    state::StateHandle,
     OperationError, GRAPHCAST_AGENT,
 };
 
@@ -74,17 +87,20 @@ pub async fn gossip_set_up(
     Ok((network_name, latest_block, message_block))
 }
 
-/// Construct the message and send it to Graphcast network
+/// Construct the message of a deployment's nPOI at the message block, unless the deployment
+/// has not synced to the block yet or the message was already sent
+#[allow(clippy::too_many_arguments)]
 #[autometrics(track_concurrency)]
-pub async fn message_send(
+pub async fn message_build(
     id: String,
-    callbook: CallBook,
+    callbook: &CallBook,
     message_block: u64,
     latest_block: BlockPointer,
     network_name: NetworkName,
-    local_attestations: Arc<SyncMutex<HashMap<String, HashMap<u64, Attestation>>>>,
+    state: &StateHandle,
     graphcast_agent: &GraphcastAgent,
-) -> Result<String, OperationError> {
+    message_version: u32,
+) -> Result<PublicPoiMessage, OperationError> {
     trace!(
         message_block = message_block,
         latest_block = latest_block.number,
@@ -104,13 +120,7 @@ pub async fn message_send(
     };
 
     // Message has already been sent
-    if local_attestations
-        .lock()
-        .unwrap()
-        .get(&id.clone())
-        .and_then(|blocks| blocks.get(&message_block))
-        .is_some()
-    {
+    if state.has_local_attestation(id.clone(), message_block).await {
         let err_msg = format!(
             "Repeated message for deployment {}, skip sending message for block: {}",
             id.clone(),
@@ -147,34 +157,16 @@ pub async fn message_send(
                 .block_hash(&network_name.to_string(), message_block)
                 .await
                 .map_err(OperationError::Query)?;
-            let radio_message = PublicPoiMessage::build(
+            Ok(PublicPoiMessage::build(
                 id.clone(),
-                content.clone(),
+                content,
                 nonce,
                 network_name,
                 message_block,
                 block_hash,
                 graphcast_agent.graphcast_identity.graph_account.clone(),
-            );
-            match graphcast_agent
-                .send_message(&id, radio_message, nonce)
-                .await
-            {
-                Ok(msg_id) => {
-                    save_local_attestation(
-                        local_attestations.clone(),
-                        content.clone(),
-                        id.clone(),
-                        message_block,
-                    );
-                    trace!("save local attestations: {:#?}", local_attestations);
-                    Ok(msg_id)
-                }
-                Err(e) => {
-                    error!(err = tracing::field::debug(&e), "Failed to send message");
-                    Err(OperationError::Agent(e))
-                }
-            }
+            )
+            .with_version(message_version))
         }
         Err(e) => {
             error!(
@@ -188,27 +180,307 @@ pub async fn message_send(
     }
 }
 
+/// Keep the nPOI of a sent message as the local attestation to compare against
+async fn save_sent_attestation(state: &StateHandle, recorder: &Recorder, msg: &PublicPoiMessage) {
+    let saved_at = Utc::now().timestamp();
+    state
+        .save_local_attestation(
+            msg.content.clone(),
+            msg.identifier.clone(),
+            msg.block_number,
+            saved_at,
+        )
+        .await;
+    recorder.local_attestation(saved_at, &msg.identifier, msg.block_number, &msg.content);
+    trace!(
+        deployment = msg.identifier,
+        block = msg.block_number,
+        "Saved local attestation"
+    );
+}
+
+/// Construct the message and send it to Graphcast network
+#[allow(clippy::too_many_arguments)]
+#[autometrics(track_concurrency)]
+pub async fn message_send(
+    id: String,
+    callbook: CallBook,
+    message_block: u64,
+    latest_block: BlockPointer,
+    network_name: NetworkName,
+    state: StateHandle,
+    graphcast_agent: &GraphcastAgent,
+    audit: AuditLog,
+    recorder: Recorder,
+    message_version: u32,
+) -> Result<String, OperationError> {
+    let radio_message = message_build(
+        id.clone(),
+        &callbook,
+        message_block,
+        latest_block,
+        network_name,
+        &state,
+        graphcast_agent,
+        message_version,
+    )
+    .await?;
+    let nonce = radio_message.nonce;
+    let sent = graphcast_agent
+        .send_message(&id, radio_message.clone(), nonce)
+        .await;
+    audit.sent(
+        "PublicPoiMessage",
+        &id,
+        nonce,
+        &radio_message.graph_account,
+        &radio_message,
+        AuditOutcome::from_send(&sent),
+    );
+    match sent {
+        Ok(msg_id) => {
+            save_sent_attestation(&state, &recorder, &radio_message).await;
+            Ok(msg_id)
+        }
+        Err(e) => {
+            error!(err = tracing::field::debug(&e), "Failed to send message");
+            Err(OperationError::Agent(e))
+        }
+    }
+}
+
+/// Send the messages of many deployments in batches of at most `max_batch_size`,
+/// returns the outcome of each deployment
+pub async fn batch_send(
+    messages: Vec<PublicPoiMessage>,
+    max_batch_size: usize,
+    state: &StateHandle,
+    graphcast_agent: &GraphcastAgent,
+    audit: &AuditLog,
+    recorder: &Recorder,
+) -> Vec<Result<String, OperationError>> {
+    let mut results = vec![];
+    for chunk in messages.chunks(max_batch_size.max(1)) {
+        let nonce = Utc::now().timestamp();
+        let batch = PublicPoiBatchMessage::build(
+            nonce,
+            graphcast_agent.graphcast_identity.graph_account.clone(),
+            chunk,
+        );
+        let sent = graphcast_agent
+            .send_message(POI_BATCH_TOPIC, batch.clone(), nonce)
+            .await;
+        audit.sent(
+            "PublicPoiBatchMessage",
+            POI_BATCH_TOPIC,
+            nonce,
+            &batch.graph_account,
+            &batch,
+            AuditOutcome::from_send(&sent),
+        );
+        match sent {
+            Ok(msg_id) => {
+                for msg in chunk {
+                    save_sent_attestation(state, recorder, msg).await;
+                    results.push(Ok(msg_id.clone()));
+                }
+            }
+            Err(e) => {
+                error!(
+                    err = tracing::field::debug(&e),
+                    deployments = batch.len(),
+                    "Failed to send batch"
+                );
+                results.extend(
+                    chunk
+                        .iter()
+                        .map(|_| Err(OperationError::Others(format!("Failed to send batch: {e}")))),
+                );
+            }
+        }
+    }
+    results
+}
+
+/// Compare the cached messages of deployments against local attestations as of `now`,
+/// then drop the messages and attestations each comparison covered
+pub async fn compare_poi(
+    config: &Config,
+    state: &StateHandle,
+    notifier: &Notifier,
+    identifiers: Vec<String>,
+    now: i64,
+) -> Vec<Result<ComparisonResult, OperationError>> {
+    let mut compare_handles = vec![];
+
+    // Additional radio message check happens here since messages are synchronously stored to state cache in msg handler
+    let snapshot = state.snapshot().await;
+    let mut remote_messages = snapshot
+        .valid_ppoi_messages(&identifiers, &config.graph_node_endpoint)
+        .await;
+
+    // Stakes are resolved up front as of the start of the epoch each collect window opened in,
+    // in one bulk query per epoch for the senders missing from the cache
+    let policy = config.consensus();
+    let callbook = config.callbook();
+    let collect_duration: i64 = config.collect_message_duration().to_owned();
+    let local_attestations = snapshot.local_attestations();
+    let mut window_epochs: HashMap<String, u64> = HashMap::new();
+    let mut epoch_senders: HashMap<u64, (EpochStart, Vec<String>)> = HashMap::new();
+    for (id, msgs) in &remote_messages {
+        let window_start =
+            match local_comparison_point(&local_attestations, msgs, id.clone(), collect_duration) {
+                Some((_, window_end)) => window_end - collect_duration,
+                // Nothing to compare yet, reported by message_comparison
+                None => continue,
+            };
+        let epoch = match epoch_at(&callbook, state, window_start, now).await {
+            Ok(epoch) => epoch,
+            Err(e) => {
+                warn!(
+                    err = tracing::field::debug(&e),
+                    "Failed to resolve the epoch of a collect window, skip comparisons"
+                );
+                return vec![Err(OperationError::Query(e))];
+            }
+        };
+        window_epochs.insert(id.clone(), epoch.epoch);
+        epoch_senders
+            .entry(epoch.epoch)
+            .or_insert((epoch, vec![]))
+            .1
+            .extend(
+                msgs.iter()
+                    .filter(|m| policy.admits(&m.graph_account))
+                    .map(|m| m.graph_account.clone()),
+            );
+    }
+    let mut epoch_sender_stakes: HashMap<u64, SenderStakes> = HashMap::new();
+    for (epoch, senders) in epoch_senders.into_values() {
+        match stakes_at_epoch(&senders, epoch, &callbook, state, now).await {
+            Ok(stakes) => {
+                epoch_sender_stakes.insert(epoch.epoch, stakes);
+            }
+            Err(e) => {
+                warn!(
+                    err = tracing::field::debug(&e),
+                    "Failed to resolve sender stakes, skip comparisons"
+                );
+                return vec![Err(OperationError::Query(e))];
+            }
+        }
+    }
+
+    for id in identifiers.clone() {
+        /* Set up */
+        let quorum = config.quorum_threshold();
+        let sender_weights = config.sender_weights(&snapshot);
+        let id_cloned = id.clone();
+        let local_attestations = local_attestations.clone();
+        let sender_stakes = window_epochs
+            .get(&id)
+            .and_then(|epoch| epoch_sender_stakes.get(epoch))
+            .cloned()
+            .unwrap_or_default();
+        let filtered_msg: Vec<GraphcastMessage<PublicPoiMessage>> =
+            remote_messages.remove(&id).unwrap_or_default();
+
+        let (filtered_msg, equivocations) = consensus_messages(filtered_msg, &policy);
+        for equivocation in &equivocations {
+            if state.add_equivocation(equivocation.clone()).await {
+                warn!(
+                    deployment = equivocation.deployment,
+                    block = equivocation.block_number,
+                    graph_account = equivocation.graph_account,
+                    "Detected equivocation",
+                );
+                EQUIVOCATIONS
+                    .with_label_values(&[&equivocation.deployment])
+                    .inc();
+                notifier.notify(equivocation.to_string()).await;
+            }
+        }
+        let policy = policy.clone();
+
+        let compare_handle = tokio::spawn(async move {
+            message_comparison(
+                id_cloned,
+                collect_duration,
+                filtered_msg,
+                local_attestations,
+                quorum,
+                policy,
+                sender_weights,
+                sender_stakes,
+                now,
+            )
+            .await
+        });
+        compare_handles.push(compare_handle);
+    }
+
+    let mut compare_ops = vec![];
+    for handle in compare_handles {
+        let res = handle.await;
+        if let Ok(s) = res {
+            // Skip clean up for comparisonResult for Error and buildFailed
+            match s {
+                Ok(r) => {
+                    compare_ops.push(Ok(r.clone()));
+
+                    /* Clean up cache */
+                    // Only clear the ones matching identifier and block number equal or less
+                    // Retain the msgs with a different identifier, or if their block number is greater
+                    let cached = state
+                        .record_comparison(r.clone(), *config.comparison_history_limit())
+                        .await;
+                    CACHED_MESSAGES
+                        .with_label_values(&[&r.deployment_hash()])
+                        .set(cached as i64);
+                }
+                // Err(OperationError::CompareTrigger(d, b, m)) => {
+                //     trace!(m, "Compare handles");
+                //     self.persisted_state
+                //         .clean_local_attestations(b, d.clone());
+                //     self.persisted_state
+                //         .clean_remote_messages(b, d.clone());
+
+                //     compare_ops.push(Err(OperationError::CompareTrigger(d, b, m).clone_with_inner()));
+                // }
+                Err(e) => {
+                    trace!(err = tracing::field::debug(&e), "Compare handles");
+
+                    compare_ops.push(Err(e.clone_with_inner()));
+                }
+            }
+        }
+    }
+    compare_ops
+}
+
 /// Compare validated messages
 #[allow(clippy::too_many_arguments)]
 #[autometrics(track_concurrency)]
 pub async fn message_comparison(
     id: String,
     collect_window_duration: i64,
-    callbook: CallBook,
     messages: Vec<GraphcastMessage<PublicPoiMessage>>,
     local_attestations: HashMap<String, HashMap<u64, Attestation>>,
+    quorum: QuorumThreshold,
+    policy: ConsensusPolicy,
+    sender_weights: SenderWeights,
+    sender_stakes: SenderStakes,
+    now: i64,
 ) -> Result<ComparisonResult, OperationError> {
-    let time = Utc::now().timestamp();
-
     let (compare_block, collect_window_end) = match local_comparison_point(
         &local_attestations,
         &messages,
         id.clone(),
         collect_window_duration,
     ) {
-        Some((block, window)) if time >= window => (block, window),
+        Some((block, window)) if now >= window => (block, window),
         Some((compare_block, window)) => {
-            let err_msg = format!("Deployment {} comparison not triggered: collecting messages until time {}; currently {time}", id.clone(), window);
+            let err_msg = format!("Deployment {} comparison not triggered: collecting messages until time {}; currently {now}", id.clone(), window);
             debug!(err = err_msg, "Collecting messages",);
             return Err(OperationError::CompareTrigger(
                 id.clone(),
@@ -233,14 +505,15 @@ pub async fn message_comparison(
         .collect();
     debug!(
         deployment_hash = id,
-        time,
+        time = now,
         comparison_time = collect_window_end,
         compare_block,
-        comparison_countdown_seconds = max(0, time - collect_window_end),
+        comparison_countdown_seconds = max(0, now - collect_window_end),
         number_of_messages_matched_to_compare = filter_msg.len(),
         "Comparison state",
     );
-    let remote_attestations_result = process_ppoi_message(filter_msg, &callbook).await;
+    let remote_attestations_result =
+        process_ppoi_message(filter_msg, &sender_stakes, &sender_weights).await;
     let remote_attestations = match remote_attestations_result {
         Ok(remote) => {
             debug!(unique_remote_nPOIs = remote.len(), "Processed messages",);
@@ -254,8 +527,14 @@ pub async fn message_comparison(
             return Err(OperationError::Attestation(err));
         }
     };
-    let comparison_result =
-        compare_attestations(compare_block, remote_attestations, &local_attestations, &id);
+    let comparison_result = compare_attestations(
+        compare_block,
+        remote_attestations,
+        &local_attestations,
+        &id,
+        &quorum,
+        &policy,
+    );
 
     Ok(comparison_result)
 }
@@ -268,6 +547,7 @@ impl RadioOperator {
         subgraph_network_latest_blocks: &HashMap<String, NetworkPointer>,
     ) -> Vec<Result<String, OperationError>> {
         let mut send_handles = vec![];
+        let mut build_handles = vec![];
         for id in identifiers.clone() {
             /* Set up */
             let (network_name, latest_block, message_block) = if let Ok(params) = gossip_set_up(
@@ -288,7 +568,27 @@ impl RadioOperator {
             let id_cloned = id.clone();
 
             let callbook = self.config.callbook();
-            let local_attestations = self.persisted_state.local_attestations.clone();
+            let state = self.state.clone();
+            let message_version = self.config.poi_message_version;
+            // Batched messages are built concurrently and sent once all are built
+            if self.config.max_poi_batch_size.is_some() {
+                build_handles.push(tokio::spawn(async move {
+                    message_build(
+                        id_cloned,
+                        &callbook,
+                        message_block,
+                        latest_block,
+                        network_name,
+                        &state,
+                        GRAPHCAST_AGENT.get().unwrap(),
+                        message_version,
+                    )
+                    .await
+                }));
+                continue;
+            }
+            let audit = self.audit.clone();
+            let recorder = self.recorder.clone();
             let send_handle = tokio::spawn(async move {
                 message_send(
                     id_cloned,
@@ -296,8 +596,11 @@ impl RadioOperator {
                     message_block,
                     latest_block,
                     network_name,
-                    Arc::clone(&local_attestations),
+                    state,
                     GRAPHCAST_AGENT.get().unwrap(),
+                    audit,
+                    recorder,
+                    message_version,
                 )
                 .await
             });
@@ -311,84 +614,196 @@ impl RadioOperator {
                 send_ops.push(s);
             }
         }
+
+        if let Some(max_batch_size) = self.config.max_poi_batch_size {
+            let mut messages = vec![];
+            for handle in build_handles {
+                match handle.await {
+                    Ok(Ok(msg)) => messages.push(msg),
+                    Ok(Err(e)) => send_ops.push(Err(e)),
+                    Err(_) => {}
+                }
+            }
+            send_ops.extend(
+                batch_send(
+                    messages,
+                    max_batch_size,
+                    &self.state,
+                    GRAPHCAST_AGENT.get().unwrap(),
+                    &self.audit,
+                    &self.recorder,
+                )
+                .await,
+            );
+        }
         send_ops
     }
 
+    /// Gossip the versions, deployments and chainheads of the radio
+    pub async fn send_heartbeat(&self) -> Result<String, OperationError> {
+        let network_chainhead_blocks = update_network_chainheads(
+            self.config
+                .callbook()
+                .indexing_statuses()
+                .await
+                .map_err(OperationError::Query)?,
+        );
+        let graph_node_version = match self.config.callbook().query_graph_node_version().await {
+            Ok(version) => version,
+            Err(e) => {
+                warn!(
+                    err = tracing::field::debug(&e),
+                    "Could not query the Graph node version, sending the heartbeat without it"
+                );
+                String::new()
+            }
+        };
+        let nonce = Utc::now().timestamp();
+        let heartbeat = HeartbeatMessage::build(
+            nonce,
+            self.graphcast_agent
+                .graphcast_identity
+                .graph_account
+                .clone(),
+            graph_node_version,
+            self.deployments().await,
+            &network_chainhead_blocks,
+        );
+        let sent = self
+            .graphcast_agent
+            .send_message(HEARTBEAT_TOPIC, heartbeat.clone(), nonce)
+            .await;
+        self.audit.sent(
+            "HeartbeatMessage",
+            HEARTBEAT_TOPIC,
+            nonce,
+            &heartbeat.graph_account,
+            &heartbeat,
+            AuditOutcome::from_send(&sent),
+        );
+        sent.map_err(OperationError::Agent)
+    }
+
+    /// Remind the indexer of upgrade plans whose migrate time is within a reminder lead, with whether the Graph node
+    /// started syncing the new deployment. Returns the number of reminders sent
+    pub async fn remind_upgrades(&self) -> usize {
+        let plans = self
+            .state
+            .take_upgrade_reminders(
+                self.config.upgrade_reminder_leads.clone(),
+                Utc::now().timestamp(),
+            )
+            .await;
+        if plans.is_empty() {
+            return 0;
+        }
+        let subgraph_network_latest_blocks = match self.config.callbook().indexing_statuses().await
+        {
+            Ok(statuses) => Some(subgraph_network_blocks(statuses)),
+            Err(e) => {
+                warn!(
+                    err = tracing::field::debug(&e),
+                    "Could not query indexing statuses, reminding of upgrades without the new deployment status"
+                );
+                None
+            }
+        };
+        let now = Utc::now().timestamp();
+        for plan in &plans {
+            let sync = match &subgraph_network_latest_blocks {
+                Some(blocks) => NewDeploymentSync::of(&plan.new_hash, blocks),
+                None => NewDeploymentSync::Unknown,
+            };
+            self.notifier.notify(plan.reminder(now, &sync)).await;
+        }
+        plans.len()
+    }
+
+    /// Compare the cached messages of deployments against local attestations, recorded for replay
     pub async fn compare_poi(
         &self,
         identifiers: Vec<String>,
     ) -> Vec<Result<ComparisonResult, OperationError>> {
-        let mut compare_handles = vec![];
+        let now = Utc::now().timestamp();
+        self.recorder.comparison(now, &identifiers);
+        compare_poi(&self.config, &self.state, &self.notifier, identifiers, now).await
+    }
 
-        // Additional radio message check happens here since messages are synchronously stored to state cache in msg handler
-        let remote_messages = self
-            .state()
-            .valid_ppoi_messages(&self.config.graph_node_endpoint)
-            .await;
+    /// Spawn a bisection for each divergent deployment that has not been bisected yet
+    pub async fn bisect_divergences(&'static self) {
+        let lookback_blocks = match self.config.bisection_lookback_blocks {
+            Some(blocks) => blocks,
+            None => return,
+        };
+        let divergent_results: Vec<ComparisonResult> = self
+            .state
+            .snapshot()
+            .await
+            .comparison_results()
+            .into_values()
+            .filter(|r| r.result_type == ComparisonResultType::Divergent && r.bisection.is_none())
+            .collect();
+        if divergent_results.is_empty() {
+            return;
+        }
 
-        for id in identifiers.clone() {
-            /* Set up */
-            let collect_duration: i64 = self.config.collect_message_duration().to_owned();
-            let id_cloned = id.clone();
-            let callbook = self.config.callbook();
-            let local_attestations = self.state().local_attestations();
-            let filtered_msg = remote_messages
-                .iter()
-                .filter(|&m| m.identifier == id.clone())
-                .cloned()
-                .collect();
-
-            let compare_handle = tokio::spawn(async move {
-                message_comparison(
-                    id_cloned,
-                    collect_duration,
-                    callbook.clone(),
-                    filtered_msg,
-                    local_attestations,
+        let subgraph_network_latest_blocks = match self.config.callbook().indexing_statuses().await
+        {
+            Ok(res) => subgraph_network_blocks(res),
+            Err(e) => {
+                warn!(
+                    err = tracing::field::debug(&e),
+                    "Could not query indexing statuses for bisection, try again later"
+                );
+                return;
+            }
+        };
+
+        for result in divergent_results {
+            let network = match subgraph_network_latest_blocks.get(&result.deployment) {
+                Some(pointer) => pointer.network.clone(),
+                None => continue,
+            };
+            if !self
+                .bisections
+                .lock()
+                .unwrap()
+                .insert(result.deployment.clone())
+            {
+                continue;
+            }
+
+            tokio::spawn(async move {
+                let bisection = bisect_divergence(
+                    &result.deployment,
+                    result.block_number,
+                    lookback_blocks,
+                    &network,
+                    *self.config.collect_message_duration(),
+                    &self.config.quorum_threshold(),
+                    &self.config.consensus(),
+                    &self.config.callbook(),
+                    self.graphcast_agent(),
+                    &self.state,
+                    &self.audit,
                 )
-                .await
-            });
-            compare_handles.push(compare_handle);
-        }
+                .await;
+                info!(
+                    deployment = result.deployment,
+                    block = result.block_number,
+                    bisection = bisection.to_string(),
+                    "Bisected divergence",
+                );
 
-        let mut compare_ops = vec![];
-        for handle in compare_handles {
-            let res = handle.await;
-            if let Ok(s) = res {
-                // Skip clean up for comparisonResult for Error and buildFailed
-                match s {
-                    Ok(r) => {
-                        compare_ops.push(Ok(r.clone()));
-
-                        /* Clean up cache */
-                        // Only clear the ones matching identifier and block number equal or less
-                        // Retain the msgs with a different identifier, or if their block number is greater
-                        // clear_local_attestation(&mut local_attestations, r.deployment_hash(), r.block());
-                        self.persisted_state
-                            .clean_local_attestations(r.block(), r.deployment_hash());
-                        self.persisted_state
-                            .clean_remote_messages(r.block(), r.deployment_hash());
-                        CACHED_MESSAGES
-                            .with_label_values(&[&r.deployment_hash()])
-                            .set(self.state().remote_messages().len().try_into().unwrap());
-                    }
-                    // Err(OperationError::CompareTrigger(d, b, m)) => {
-                    //     trace!(m, "Compare handles");
-                    //     self.persisted_state
-                    //         .clean_local_attestations(b, d.clone());
-                    //     self.persisted_state
-                    //         .clean_remote_messages(b, d.clone());
-
-                    //     compare_ops.push(Err(OperationError::CompareTrigger(d, b, m).clone_with_inner()));
-                    // }
-                    Err(e) => {
-                        trace!(err = tracing::field::debug(&e), "Compare handles");
-
-                        compare_ops.push(Err(e.clone_with_inner()));
-                    }
+                if let Some(updated) = self
+                    .state
+                    .add_bisection(result.deployment.clone(), result.block_number, bisection)
+                    .await
+                {
+                    self.notifier.notify(updated.to_string()).await;
                 }
-            }
+                self.bisections.lock().unwrap().remove(&result.deployment);
+            });
         }
-        compare_ops
     }
 }
@@ -0,0 +1,187 @@
+use async_graphql::{ComplexObject, SimpleObject};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::operator::attestation::{ComparisonResult, ComparisonResultType};
+
+/// Number of majority comparisons needed before a sender's agreement rate is used to weight its stake
+pub const MIN_AGREEMENT_SAMPLES: u64 = 10;
+
+/// Stake multipliers by sender address, senders not in the map keep their full stake
+pub type SenderWeights = HashMap<String, f32>;
+
+/// Track record of a remote sender across comparisons
+#[derive(Clone, Debug, Default, PartialEq, Eq, SimpleObject, Serialize, Deserialize)]
+#[graphql(complex)]
+pub struct IndexerAgreement {
+    pub address: String,
+    /// Comparisons where the sender attested to an nPOI
+    pub attestations: u64,
+    /// Comparisons with a remote majority (matched or divergent), where the sender attested
+    pub majority_comparisons: u64,
+    /// Comparisons where the sender's nPOI was the stake-weighted majority nPOI
+    pub majority_agreements: u64,
+    /// Comparisons with a local attestation, where the sender attested
+    pub local_comparisons: u64,
+    /// Comparisons where the sender's nPOI matched the local nPOI
+    pub local_agreements: u64,
+    /// Latest message nonce seen from the sender
+    pub last_seen: i64,
+    /// Deployment and block of the latest comparison the sender took part in
+    pub last_deployment: String,
+    pub last_block: u64,
+}
+
+#[ComplexObject]
+impl IndexerAgreement {
+    /// Share of majority comparisons where the sender agreed with the majority
+    async fn majority_agreement_rate(&self) -> Option<f32> {
+        self.majority_rate()
+    }
+
+    /// Share of local comparisons where the sender agreed with the local nPOI
+    async fn local_agreement_rate(&self) -> Option<f32> {
+        rate(self.local_agreements, self.local_comparisons)
+    }
+}
+
+impl IndexerAgreement {
+    pub fn new(address: String) -> Self {
+        IndexerAgreement {
+            address,
+            ..Default::default()
+        }
+    }
+
+    pub fn majority_rate(&self) -> Option<f32> {
+        rate(self.majority_agreements, self.majority_comparisons)
+    }
+
+    /// Stake multiplier for a sender that agreed with the majority less often than the threshold
+    pub fn weight(&self, threshold: f32) -> Option<f32> {
+        if self.majority_comparisons < MIN_AGREEMENT_SAMPLES {
+            return None;
+        }
+        self.majority_rate().filter(|rate| *rate < threshold)
+    }
+}
+
+fn rate(agreements: u64, comparisons: u64) -> Option<f32> {
+    if comparisons == 0 {
+        None
+    } else {
+        Some(agreements as f32 / comparisons as f32)
+    }
+}
+
+/// Fold the senders of a comparison result into their track records
+pub fn update_agreements(
+    agreements: &mut HashMap<String, IndexerAgreement>,
+    result: &ComparisonResult,
+) {
+    // Attestations are ranked by the consensus policy, the last one is the majority nPOI
+    let majority_npoi = match result.result_type {
+        ComparisonResultType::Match | ComparisonResultType::Divergent => {
+            result.attestations.last().map(|a| a.npoi.clone())
+        }
+        _ => None,
+    };
+    let local_npoi = result.local_attestation.as_ref().map(|a| a.npoi.clone());
+
+    for attestation in &result.attestations {
+        for (i, sender) in attestation.senders.iter().enumerate() {
+            let record = agreements
+                .entry(sender.clone())
+                .or_insert_with(|| IndexerAgreement::new(sender.clone()));
+            record.attestations += 1;
+            if let Some(npoi) = &majority_npoi {
+                record.majority_comparisons += 1;
+                if *npoi == attestation.npoi {
+                    record.majority_agreements += 1;
+                }
+            }
+            if let Some(npoi) = &local_npoi {
+                record.local_comparisons += 1;
+                if *npoi == attestation.npoi {
+                    record.local_agreements += 1;
+                }
+            }
+            if let Some(nonce) = attestation.timestamp.get(i) {
+                record.last_seen = record.last_seen.max(*nonce);
+            }
+            record.last_deployment = result.deployment.clone();
+            record.last_block = result.block_number;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operator::{attestation::Attestation, consensus::ConsensusPolicyType, stake::Stake};
+
+    fn result(result_type: ComparisonResultType, local: &str) -> ComparisonResult {
+        ComparisonResult {
+            deployment: String::from("deployment"),
+            block_number: 1,
+            result_type,
+            local_attestation: Some(Attestation::new(
+                local.to_string(),
+                Stake::zero(),
+                vec![],
+                vec![],
+            )),
+            attestations: vec![
+                Attestation::new(
+                    String::from("npoi-b"),
+                    Stake::from_grt(1),
+                    vec![String::from("0xb1")],
+                    vec![3],
+                ),
+                Attestation::new(
+                    String::from("npoi-a"),
+                    Stake::from_grt(5),
+                    vec![String::from("0xa1"), String::from("0xa2")],
+                    vec![1, 2],
+                ),
+            ],
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        }
+    }
+
+    #[test]
+    fn test_update_agreements() {
+        let mut agreements = HashMap::new();
+        update_agreements(
+            &mut agreements,
+            &result(ComparisonResultType::Divergent, "npoi-b"),
+        );
+        update_agreements(
+            &mut agreements,
+            &result(ComparisonResultType::Inconclusive, "npoi-a"),
+        );
+
+        let a2 = agreements.get("0xa2").unwrap();
+        assert_eq!(a2.attestations, 2);
+        assert_eq!((a2.majority_agreements, a2.majority_comparisons), (1, 1));
+        assert_eq!((a2.local_agreements, a2.local_comparisons), (1, 2));
+        assert_eq!(a2.last_seen, 2);
+
+        let b1 = agreements.get("0xb1").unwrap();
+        assert_eq!(b1.majority_rate(), Some(0.0));
+        assert_eq!((b1.local_agreements, b1.local_comparisons), (1, 2));
+        // Not enough samples to be weighted yet
+        assert_eq!(b1.weight(0.5), None);
+    }
+
+    #[test]
+    fn test_agreement_weight() {
+        let mut record = IndexerAgreement::new(String::from("0xb1"));
+        record.majority_comparisons = MIN_AGREEMENT_SAMPLES;
+        record.majority_agreements = 3;
+        assert_eq!(record.weight(0.5), Some(0.3));
+        assert_eq!(record.weight(0.3), None);
+    }
+}
@@ -0,0 +1,456 @@
+use async_graphql::{InputValueError, InputValueResult, Scalar, ScalarType, Value};
+use graphcast_sdk::{callbook::CallBook, graphql::QueryError};
+use num_bigint::{BigUint, ParseBigIntError};
+use num_traits::{ToPrimitive, Zero};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+    future::Future,
+    iter::Sum,
+    ops::Add,
+    str::FromStr,
+};
+use tracing::trace;
+
+use crate::{operator::callbook::CallBookRadioExtensions, state::StateHandle};
+
+/// Number of wei in one GRT
+const WEI_PER_GRT: u64 = 1_000_000_000_000_000_000;
+/// Resolution used when scaling stake by a ratio
+const RATIO_PRECISION: u64 = 1_000_000;
+/// Number of protocol epochs of stake snapshots kept in the persisted state
+pub const STAKE_SNAPSHOT_EPOCHS: usize = 8;
+/// Seconds a cached stake or epoch is used before it is fetched again
+pub const DEFAULT_STAKE_CACHE_TTL: i64 = 300;
+
+/// Stake by sender address
+pub type SenderStakes = HashMap<String, Stake>;
+
+/// Exact amount of staked GRT, denominated in wei (10^-18 GRT)
+/// Serialized as a decimal string so that no precision is lost in persisted state or the API
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Stake(BigUint);
+
+impl Stake {
+    pub fn zero() -> Self {
+        Stake(BigUint::zero())
+    }
+
+    pub fn from_grt(grt: u64) -> Self {
+        Stake(BigUint::from(grt) * WEI_PER_GRT)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Stake scaled by a ratio between 0 and 1, at a resolution of 10^-6
+    pub fn scale(&self, ratio: f32) -> Self {
+        let parts = (ratio.clamp(0.0, 1.0) as f64 * RATIO_PRECISION as f64).round() as u64;
+        Stake(&self.0 * parts / RATIO_PRECISION)
+    }
+
+    /// Share of the total stake, 0 if the total is zero
+    pub fn share_of(&self, total: &Stake) -> f32 {
+        if total.is_zero() {
+            return 0.0;
+        }
+        let scaled = &self.0 * RATIO_PRECISION / &total.0;
+        scaled.to_f64().unwrap_or_default() as f32 / RATIO_PRECISION as f32
+    }
+}
+
+impl Display for Stake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Stake {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BigUint::from_str(s).map(Stake)
+    }
+}
+
+impl<'a> Add<&'a Stake> for &'a Stake {
+    type Output = Stake;
+
+    fn add(self, other: &Stake) -> Stake {
+        Stake(&self.0 + &other.0)
+    }
+}
+
+impl<'a> Sum<&'a Stake> for Stake {
+    fn sum<I: Iterator<Item = &'a Stake>>(iter: I) -> Stake {
+        iter.fold(Stake::zero(), |acc, s| &acc + s)
+    }
+}
+
+impl Serialize for Stake {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Stake as stored in the persisted state
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PersistedStake {
+    Wei(String),
+    /// States persisted before stakes were exact stored whole GRT as a number
+    LegacyGrt(u64),
+}
+
+impl<'de> Deserialize<'de> for Stake {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match PersistedStake::deserialize(deserializer)? {
+            PersistedStake::Wei(wei) => wei.parse().map_err(serde::de::Error::custom),
+            PersistedStake::LegacyGrt(grt) => Ok(Stake::from_grt(grt)),
+        }
+    }
+}
+
+/// Exposed in GraphQL as a decimal string of wei
+#[Scalar]
+impl ScalarType for Stake {
+    fn parse(value: Value) -> InputValueResult<Self> {
+        match &value {
+            Value::String(s) => s.parse().map_err(InputValueError::custom),
+            _ => Err(InputValueError::expected_type(value)),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+/// Stake of an indexer at an epoch, as fetched from the network subgraph
+#[derive(Clone, Debug)]
+struct CachedStake {
+    stake: Stake,
+    epoch: u64,
+    fetched_at: i64,
+}
+
+/// Protocol epoch and the block of the network subgraph chain its stakes are read at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochStart {
+    pub epoch: u64,
+    /// Block the epoch started at, counted on the chain the protocol counts epochs in
+    pub start_block: u64,
+    /// Block the network subgraph is queried at for the stakes of the epoch
+    pub stake_block: u64,
+}
+
+/// Epoch along with the time this radio first saw it as the current epoch
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct SeenEpoch {
+    start: EpochStart,
+    seen_at: i64,
+}
+
+/// Cache of recent epochs and of indexer stakes keyed by address.
+/// Entries are refetched once they are older than the TTL, or when the epoch moves on.
+/// Only the epochs are persisted, so stake snapshots can be matched with the time a collect window opened
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StakeCache {
+    #[serde(skip, default = "default_stake_cache_ttl")]
+    ttl: i64,
+    /// Recent epochs by number, a new epoch is seen at most a TTL after it started
+    epochs: BTreeMap<u64, SeenEpoch>,
+    /// When the current epoch was last fetched
+    #[serde(skip)]
+    epoch_fetched_at: Option<i64>,
+    #[serde(skip)]
+    stakes: HashMap<String, CachedStake>,
+}
+
+fn default_stake_cache_ttl() -> i64 {
+    DEFAULT_STAKE_CACHE_TTL
+}
+
+impl Default for StakeCache {
+    fn default() -> Self {
+        StakeCache::new(DEFAULT_STAKE_CACHE_TTL)
+    }
+}
+
+impl StakeCache {
+    pub fn new(ttl: i64) -> Self {
+        StakeCache {
+            ttl,
+            epochs: BTreeMap::new(),
+            epoch_fetched_at: None,
+            stakes: HashMap::new(),
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: i64) {
+        self.ttl = ttl;
+    }
+
+    fn is_fresh(&self, fetched_at: i64, now: i64) -> bool {
+        now - fetched_at < self.ttl
+    }
+
+    /// Epoch in effect at a time, None once the current epoch was not fetched within the TTL.
+    /// Times before the earliest epoch seen fall into that epoch
+    pub fn epoch(&self, at: i64, now: i64) -> Option<EpochStart> {
+        match self.epoch_fetched_at {
+            Some(fetched_at) if self.is_fresh(fetched_at, now) => {}
+            _ => return None,
+        }
+        self.epoch_seen_at(at)
+    }
+
+    /// Epoch this radio saw as the current one at a time, however long ago it was fetched.
+    /// Times before the earliest epoch seen fall into that epoch
+    pub fn epoch_seen_at(&self, at: i64) -> Option<EpochStart> {
+        self.epochs
+            .values()
+            .rev()
+            .find(|e| e.seen_at <= at)
+            .or_else(|| self.epochs.values().next())
+            .map(|e| e.start)
+    }
+
+    /// Latest epoch seen, whether or not it is still the current one
+    pub fn latest_epoch(&self) -> Option<EpochStart> {
+        self.epochs.values().next_back().map(|e| e.start)
+    }
+
+    /// Record the current epoch. An epoch keeps the stake block and time it was first seen with
+    pub fn set_epoch(&mut self, start: EpochStart, now: i64) {
+        self.epochs.entry(start.epoch).or_insert(SeenEpoch {
+            start,
+            seen_at: now,
+        });
+        while self.epochs.len() > STAKE_SNAPSHOT_EPOCHS {
+            self.epochs.pop_first();
+        }
+        self.epoch_fetched_at = Some(now);
+    }
+
+    /// Stake of an indexer at the epoch, if fetched within the TTL
+    pub fn stake(&self, indexer_address: &str, epoch: u64, now: i64) -> Option<Stake> {
+        self.stakes
+            .get(indexer_address)
+            .filter(|s| s.epoch == epoch && self.is_fresh(s.fetched_at, now))
+            .map(|s| s.stake.clone())
+    }
+
+    pub fn insert(&mut self, indexer_address: String, epoch: u64, stake: Stake, now: i64) {
+        self.stakes.insert(
+            indexer_address,
+            CachedStake {
+                stake,
+                epoch,
+                fetched_at: now,
+            },
+        );
+    }
+}
+
+/// First block in `floor..=head` that has `reached` a point, such as an L1 block, that every later block has reached
+/// as well. Searches back from the head in growing steps and then bisects, as the point is usually recent.
+/// `floor` is a block known not to be past the point, or the point is reached there already
+pub async fn first_block_reaching<F, Fut>(
+    floor: u64,
+    head: u64,
+    mut reached: F,
+) -> Result<u64, QueryError>
+where
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<bool, QueryError>>,
+{
+    let mut high = head;
+    let mut step = 1u64;
+    let mut low = loop {
+        if high <= floor {
+            return Ok(high);
+        }
+        let probe = high.saturating_sub(step).max(floor);
+        if !reached(probe).await? {
+            break probe;
+        }
+        high = probe;
+        step = step.saturating_mul(2);
+    };
+    while high - low > 1 {
+        let mid = low + (high - low) / 2;
+        if reached(mid).await? {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+    Ok(high)
+}
+
+/// Protocol epoch in effect at a time, the current epoch is refetched once the cached one is older than the TTL
+pub async fn epoch_at(
+    callbook: &CallBook,
+    state: &StateHandle,
+    at: i64,
+    now: i64,
+) -> Result<EpochStart, QueryError> {
+    if let Some(epoch) = state.cached_epoch(at, now).await {
+        return Ok(epoch);
+    }
+    let latest = state.latest_epoch().await;
+    let current = callbook.query_current_epoch(latest).await?;
+    state.set_epoch(current, now).await;
+    Ok(state.cached_epoch(at, now).await.unwrap_or(current))
+}
+
+/// Resolve sender stakes as of the start of an epoch.
+/// Pinning stake to the epoch rather than to the latest block means every radio weighs the same messages
+/// identically, and snapshots are kept per (indexer, epoch) so a comparison can be reproduced later.
+/// Stakes missing from the cache are resolved with a single bulk query to the network subgraph,
+/// entries are fresh or stale as of `now`
+pub async fn stakes_at_epoch(
+    senders: &[String],
+    epoch: EpochStart,
+    callbook: &CallBook,
+    state: &StateHandle,
+    now: i64,
+) -> Result<SenderStakes, QueryError> {
+    let (mut stakes, missing) = state
+        .cached_stakes(senders.to_vec(), epoch.epoch, now)
+        .await;
+    if missing.is_empty() {
+        return Ok(stakes);
+    }
+
+    let resolved = callbook
+        .query_indexer_stakes_at(&missing, epoch.stake_block)
+        .await?;
+    trace!(
+        epoch = epoch.epoch,
+        stake_block = epoch.stake_block,
+        senders = missing.len(),
+        "Snapshot sender stakes"
+    );
+    let resolved: SenderStakes = missing
+        .into_iter()
+        .map(|sender| {
+            let stake = resolved.get(&sender).cloned().unwrap_or_default();
+            (sender, stake)
+        })
+        .collect();
+    state
+        .insert_stakes(epoch.epoch, resolved.clone(), now)
+        .await;
+    stakes.extend(resolved);
+    Ok(stakes)
+}
+
+/// Resolve sender stakes as of the start of the epoch in effect at `at`, such as the start of a collect window,
+/// so a comparison finishing after an epoch boundary still weighs its messages with the stakes they were sent with
+pub async fn epoch_stakes(
+    senders: &[String],
+    callbook: &CallBook,
+    state: &StateHandle,
+    at: i64,
+    now: i64,
+) -> Result<SenderStakes, QueryError> {
+    let epoch = epoch_at(callbook, state, at, now).await?;
+    stakes_at_epoch(senders, epoch, callbook, state, now).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stake_exact_arithmetic() {
+        // Two large stakes that collapse to the same f32 value
+        let a: Stake = "123456789012345678901234567".parse().unwrap();
+        let b: Stake = "123456789012345678901234568".parse().unwrap();
+        assert!(a < b);
+        assert_eq!((&a + &b).to_string(), "246913578024691357802469135");
+        assert_eq!(Stake::from_grt(3).to_string(), "3000000000000000000");
+        assert_eq!(
+            Stake::from_grt(10).scale(0.25),
+            Stake::from_grt(5).scale(0.5)
+        );
+        assert_eq!(Stake::from_grt(3).share_of(&Stake::from_grt(4)), 0.75);
+        assert_eq!(Stake::from_grt(3).share_of(&Stake::zero()), 0.0);
+    }
+
+    #[test]
+    fn test_stake_serde_migration() {
+        let stake = Stake::from_grt(42);
+        let json = serde_json::to_string(&stake).unwrap();
+        assert_eq!(json, "\"42000000000000000000\"");
+        assert_eq!(serde_json::from_str::<Stake>(&json).unwrap(), stake);
+        // Legacy whole GRT number
+        assert_eq!(serde_json::from_str::<Stake>("42").unwrap(), stake);
+    }
+
+    #[test]
+    fn test_stake_cache_expiry() {
+        let mut cache = StakeCache::new(60);
+        let epoch = EpochStart {
+            epoch: 7,
+            start_block: 700,
+            stake_block: 700,
+        };
+        assert_eq!(cache.epoch(0, 0), None);
+        cache.set_epoch(epoch, 0);
+        assert_eq!(cache.epoch(0, 59), Some(epoch));
+        assert_eq!(cache.epoch(0, 60), None);
+
+        cache.insert(String::from("0xa1"), 7, Stake::from_grt(1), 0);
+        assert_eq!(cache.stake("0xa1", 7, 59), Some(Stake::from_grt(1)));
+        // Expired, or cached for a different epoch
+        assert_eq!(cache.stake("0xa1", 7, 60), None);
+        assert_eq!(cache.stake("0xa1", 8, 0), None);
+        assert_eq!(cache.stake("0xa2", 7, 0), None);
+    }
+
+    #[test]
+    fn test_epoch_at_time() {
+        let mut cache = StakeCache::new(60);
+        let epoch = |epoch: u64, stake_block: u64| EpochStart {
+            epoch,
+            start_block: epoch * 100,
+            stake_block,
+        };
+        cache.set_epoch(epoch(7, 700), 0);
+        // The stake block of an epoch is the one it was first seen with
+        cache.set_epoch(epoch(7, 750), 50);
+        cache.set_epoch(epoch(8, 800), 100);
+
+        assert_eq!(cache.epoch(-10, 100), Some(epoch(7, 700)));
+        assert_eq!(cache.epoch(99, 100), Some(epoch(7, 700)));
+        assert_eq!(cache.epoch(100, 100), Some(epoch(8, 800)));
+        assert_eq!(cache.epoch(100, 160), None);
+    }
+
+    #[tokio::test]
+    async fn test_first_block_reaching() {
+        // L1 block seen at each L2 block, only set once the network exists
+        let l1_at = |block: u64| (block >= 10).then_some(100 + block / 4);
+        for (floor, head, l1_block, expected) in [
+            (0, 1000, 150, 200),
+            (0, 1000, 100, 10),
+            (0, 1000, 50, 10),
+            (0, 201, 150, 200),
+            (0, 200, 150, 200),
+            (120, 1000, 150, 200),
+            (300, 1000, 150, 300),
+        ] {
+            let found = first_block_reaching(floor, head, |block| async move {
+                Ok(l1_at(block).is_some_and(|l1| l1 >= l1_block))
+            })
+            .await
+            .unwrap();
+            assert_eq!(found, expected, "{floor} {head} {l1_block}");
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use async_graphql::SimpleObject;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use graphcast_sdk::NetworkPointer;
+
+use crate::messages::upgrade::VersionUpgradeMessage;
+
+/// Upgrade of a deployment announced by its subgraph owner, kept until its migrate time passes
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, SimpleObject)]
+pub struct UpgradePlan {
+    /// Deployment being upgraded from
+    pub deployment: String,
+    pub new_hash: String,
+    pub subgraph_id: String,
+    pub network: String,
+    pub migrate_time: i64,
+    /// Subgraph owner that announced the upgrade
+    pub graph_account: String,
+    /// Nonce of the announcement, a later announcement for the same deployment replaces this one
+    pub nonce: i64,
+    /// Seconds before the migrate time of the last reminder sent, None until the first one
+    pub reminded_lead: Option<i64>,
+}
+
+impl From<VersionUpgradeMessage> for UpgradePlan {
+    fn from(msg: VersionUpgradeMessage) -> Self {
+        UpgradePlan {
+            deployment: msg.identifier,
+            new_hash: msg.new_hash,
+            subgraph_id: msg.subgraph_id,
+            network: msg.network,
+            migrate_time: msg.migrate_time,
+            graph_account: msg.graph_account,
+            nonce: msg.nonce,
+            reminded_lead: None,
+        }
+    }
+}
+
+impl UpgradePlan {
+    pub fn is_pending(&self, now: i64) -> bool {
+        self.migrate_time > now
+    }
+
+    /// Shortest reminder lead the migrate time is within and that was not reminded of yet. Longer leads that
+    /// passed in the meantime are skipped, so a plan received shortly before its migrate time gets a single reminder
+    pub fn due_reminder(&self, leads: &[i64], now: i64) -> Option<i64> {
+        let remaining = self.migrate_time - now;
+        leads
+            .iter()
+            .copied()
+            .filter(|lead| remaining <= *lead)
+            .filter(|lead| match self.reminded_lead {
+                Some(reminded) => *lead < reminded,
+                None => true,
+            })
+            .min()
+    }
+
+    /// Notification reminding the indexer of the upgrade
+    pub fn reminder(&self, now: i64, sync: &NewDeploymentSync) -> String {
+        let remaining = self.migrate_time - now;
+        let when = if remaining > 0 {
+            format!("in {} minutes", remaining / 60)
+        } else {
+            String::from("now")
+        };
+        format!(
+            "Subgraph {} migrates from deployment {} to {} {when}\nplanned migrate time: {}\nnetwork: {}\nGraph node: {sync}",
+            self.subgraph_id, self.deployment, self.new_hash, self.migrate_time, self.network
+        )
+    }
+}
+
+/// How far the Graph node got with the new deployment of an upgrade
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NewDeploymentSync {
+    /// Indexing statuses could not be queried
+    Unknown,
+    NotStarted,
+    Syncing {
+        block_number: u64,
+    },
+}
+
+impl NewDeploymentSync {
+    /// Status of a deployment from the latest blocks of the deployments the Graph node indexes
+    pub fn of(deployment: &str, subgraph_network_blocks: &HashMap<String, NetworkPointer>) -> Self {
+        match subgraph_network_blocks.get(deployment) {
+            Some(pointer) => NewDeploymentSync::Syncing {
+                block_number: pointer.block.number,
+            },
+            None => NewDeploymentSync::NotStarted,
+        }
+    }
+}
+
+impl Display for NewDeploymentSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NewDeploymentSync::Unknown => {
+                write!(f, "could not check whether the new deployment is syncing")
+            }
+            NewDeploymentSync::NotStarted => {
+                write!(f, "has not started syncing the new deployment")
+            }
+            NewDeploymentSync::Syncing { block_number } => {
+                write!(f, "syncing the new deployment, at block {block_number}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(migrate_time: i64) -> UpgradePlan {
+        UpgradePlan::from(VersionUpgradeMessage::new(
+            String::from("Qm1"),
+            String::from("Qm2"),
+            String::from("0xsubgraph"),
+            1,
+            String::from("goerli"),
+            migrate_time,
+            String::from("0xa1"),
+        ))
+    }
+
+    #[test]
+    fn test_due_reminder() {
+        let leads = [86400, 3600];
+        let mut plan = plan(100_000);
+
+        assert_eq!(plan.due_reminder(&leads, 0), None);
+        assert_eq!(plan.due_reminder(&leads, 20_000), Some(86400));
+        plan.reminded_lead = Some(86400);
+        assert_eq!(plan.due_reminder(&leads, 20_000), None);
+        assert_eq!(plan.due_reminder(&leads, 97_000), Some(3600));
+        plan.reminded_lead = Some(3600);
+        assert_eq!(plan.due_reminder(&leads, 99_999), None);
+    }
+
+    #[test]
+    fn test_late_plan_reminded_once() {
+        let plan = plan(100_000);
+        // Received half an hour before the migrate time, only the shortest lead is due
+        assert_eq!(plan.due_reminder(&[86400, 3600], 98_200), Some(3600));
+        assert!(plan.is_pending(98_200));
+        assert!(!plan.is_pending(100_000));
+    }
+}
@@ -0,0 +1,453 @@
+use chrono::Utc;
+use serde::Deserializer;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex as SyncMutex},
+};
+use tokio::sync::Mutex as AsyncMutex;
+use tracing::{info, warn};
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::{
+    config::Config,
+    messages::{
+        poi::{validate_poi_message, PublicPoiMessage},
+        poi_batch::{validate_poi_batch, PublicPoiBatchMessage},
+    },
+    operator::{
+        attestation::{save_local_attestation_at, ComparisonResult, LocalAttestationsMap},
+        notifier::Notifier,
+        operation::compare_poi,
+    },
+    state::{PersistedState, StateHandle},
+};
+
+pub mod proxy;
+
+use proxy::{proxied, start_proxy, RecordedResponses, Upstream};
+
+/// Version of the recording format written by this radio
+pub const RECORDING_VERSION: u64 = 1;
+
+/// One line of a recording, events are written in the order the operator consumed them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// First line of every run of the radio that appended to the recording
+    Header {
+        version: u64,
+        recorded_at: i64,
+        /// Graphcast id of the recording radio, its own messages are dropped by validation
+        local_sender: String,
+        /// Configuration of the recording radio, without secrets
+        #[serde(deserialize_with = "recorded_config")]
+        config: Box<Config>,
+    },
+    /// POI message decoded from Waku, before validation
+    Message {
+        at: i64,
+        message: GraphcastMessage<PublicPoiMessage>,
+    },
+    /// Batch of POI messages decoded from Waku, before validation
+    Batch {
+        at: i64,
+        message: GraphcastMessage<PublicPoiBatchMessage>,
+    },
+    /// nPOI the radio generated and sent for a deployment and block
+    LocalAttestation {
+        at: i64,
+        deployment: String,
+        block_number: u64,
+        npoi: String,
+    },
+    /// Comparison run over the deployments the radio was subscribed to
+    Comparison { at: i64, identifiers: Vec<String> },
+    /// Response to a query the radio made to the Graph node or a subgraph
+    Http {
+        at: i64,
+        endpoint: String,
+        request: Value,
+        status: u16,
+        response: String,
+    },
+}
+
+/// Read the recorded config through a JSON value. Arbitrary precision numbers are buffered as maps
+/// within an internally tagged event, which the float fields of the config cannot be read from
+fn recorded_config<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<Config>, D::Error> {
+    let value = <Value as serde::Deserialize>::deserialize(deserializer)?;
+    serde_json::from_value(value)
+        .map(Box::new)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Records the gossip and query responses the operator consumes, for `poi-radio replay`.
+/// Recording is a no-op without a record path
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    file: Option<Arc<SyncMutex<File>>>,
+}
+
+impl Recorder {
+    /// Append to the recording at path, a restarted radio adds a new header and keeps the runs before it
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Some(Arc::new(SyncMutex::new(file))),
+        })
+    }
+
+    /// Start recording if a record path is configured, returns the configuration the radio should run with
+    /// so its queries go through the recording proxy
+    pub async fn from_config(config: &Config, local_sender: String) -> (Config, Recorder) {
+        let path = match &config.record_path {
+            Some(path) => path,
+            None => return (config.clone(), Recorder::default()),
+        };
+        let recorder = Recorder::new(path)
+            .unwrap_or_else(|e| panic!("Could not open recording at {path}: {e}"));
+        recorder.write(&RecordedEvent::Header {
+            version: RECORDING_VERSION,
+            recorded_at: Utc::now().timestamp(),
+            local_sender,
+            config: Box::new(without_secrets(config)),
+        });
+        let addr = start_proxy(Upstream::record(config, recorder.clone()))
+            .await
+            .unwrap_or_else(|e| panic!("Could not start recording proxy: {e}"));
+        info!(path, proxy = addr.to_string(), "Recording radio inputs");
+        (proxied(config, addr), recorder)
+    }
+
+    fn write(&self, event: &RecordedEvent) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(err = e.to_string(), "Could not serialize recorded event");
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = file.lock().unwrap().write_all(&line) {
+            warn!(err = e.to_string(), "Could not write recorded event");
+        }
+    }
+
+    pub fn message(&self, message: &GraphcastMessage<PublicPoiMessage>) {
+        self.write(&RecordedEvent::Message {
+            at: Utc::now().timestamp(),
+            message: message.clone(),
+        })
+    }
+
+    pub fn batch(&self, message: &GraphcastMessage<PublicPoiBatchMessage>) {
+        self.write(&RecordedEvent::Batch {
+            at: Utc::now().timestamp(),
+            message: message.clone(),
+        })
+    }
+
+    pub fn local_attestation(&self, at: i64, deployment: &str, block_number: u64, npoi: &str) {
+        self.write(&RecordedEvent::LocalAttestation {
+            at,
+            deployment: deployment.to_string(),
+            block_number,
+            npoi: npoi.to_string(),
+        })
+    }
+
+    pub fn comparison(&self, at: i64, identifiers: &[String]) {
+        self.write(&RecordedEvent::Comparison {
+            at,
+            identifiers: identifiers.to_vec(),
+        })
+    }
+
+    fn http(&self, endpoint: &str, request: Value, status: u16, response: String) {
+        self.write(&RecordedEvent::Http {
+            at: Utc::now().timestamp(),
+            endpoint: endpoint.to_string(),
+            request,
+            status,
+            response,
+        })
+    }
+}
+
+/// Recordings are meant to be attached to bug reports
+fn without_secrets(config: &Config) -> Config {
+    Config {
+        private_key: None,
+        mnemonic: None,
+        waku_node_key: None,
+        slack_token: None,
+        discord_webhook: None,
+        telegram_token: None,
+        server_admin_token: None,
+        ..config.clone()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("Failed to read recording: {0}")]
+    Io(#[from] io::Error),
+    #[error("Failed to parse line {line} of the recording: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Recording does not start with a header")]
+    MissingHeader,
+    #[error("Recording version {0} is not supported by this radio")]
+    UnsupportedVersion(u64),
+}
+
+/// Read the events of a recording
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedEvent>, ReplayError> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|(i, line)| {
+            serde_json::from_str(&line?).map_err(|source| ReplayError::Parse {
+                line: i + 1,
+                source,
+            })
+        })
+        .collect()
+}
+
+/// Comparison result produced while replaying, with the simulated time of the comparison
+#[derive(Serialize, Debug)]
+pub struct ReplayedComparison {
+    pub at: i64,
+    pub result: ComparisonResult,
+}
+
+/// What a replay produced, along with the inputs it compared
+#[derive(Debug)]
+pub struct Replay {
+    /// Configuration of the recording radio
+    pub config: Config,
+    pub comparisons: Vec<ReplayedComparison>,
+    /// Messages that passed validation, in the order they were received, with batches expanded
+    pub messages: Vec<GraphcastMessage<PublicPoiMessage>>,
+    /// Every local attestation of the recording, comparisons do not clear them from here
+    pub local_attestations: LocalAttestationsMap,
+    /// State at the end of the replay, including the stakes resolved from recorded responses
+    pub state: Arc<PersistedState>,
+}
+
+/// Run of the recording radio, from its header up to the next one
+struct Segment {
+    local_sender: String,
+    config: Config,
+    events: Vec<RecordedEvent>,
+}
+
+/// Split a recording into the runs of the radio, each starting with a header
+fn segments(events: Vec<RecordedEvent>) -> Result<Vec<Segment>, ReplayError> {
+    let mut segments: Vec<Segment> = vec![];
+    for event in events {
+        match event {
+            RecordedEvent::Header {
+                version,
+                local_sender,
+                config,
+                ..
+            } => {
+                if version > RECORDING_VERSION {
+                    return Err(ReplayError::UnsupportedVersion(version));
+                }
+                segments.push(Segment {
+                    local_sender,
+                    config: *config,
+                    events: vec![],
+                });
+            }
+            event => match segments.last_mut() {
+                Some(segment) => segment.events.push(event),
+                None => return Err(ReplayError::MissingHeader),
+            },
+        }
+    }
+    if segments.is_empty() {
+        return Err(ReplayError::MissingHeader);
+    }
+    Ok(segments)
+}
+
+/// Feed a recording through message validation and compare_poi, with the clock set to the time of each event.
+/// Queries are answered with the recorded responses, so no Graph node, Waku node or wallet is needed.
+/// Each run of the radio in the recording is replayed with its own configuration, and starts from the state
+/// the previous run left if the radio persisted its state
+pub async fn replay(events: Vec<RecordedEvent>) -> Result<Replay, ReplayError> {
+    let segments = segments(events)?;
+
+    let mut responses = RecordedResponses::default();
+    for event in segments.iter().flat_map(|segment| &segment.events) {
+        if let RecordedEvent::Http {
+            endpoint,
+            request,
+            status,
+            response,
+            ..
+        } = event
+        {
+            responses.push(endpoint, request, *status, response.clone());
+        }
+    }
+    let addr = start_proxy(Upstream::Replay(SyncMutex::new(responses))).await?;
+
+    let mut recorded_config = Config::default();
+    let mut previous: Option<StateHandle> = None;
+    let mut final_state = Arc::new(PersistedState::new(None, None, None));
+    let mut comparisons = vec![];
+    let mut messages = vec![];
+    let mut local_attestations = HashMap::new();
+    for segment in segments {
+        let config = proxied(&segment.config, addr);
+        let local_sender = segment.local_sender;
+        let state = match (previous.take(), &config.persistence_file_path) {
+            (Some(state), Some(_)) => state,
+            _ => {
+                let mut fresh = PersistedState::new(None, None, None);
+                fresh.set_stake_cache_ttl(config.stake_cache_ttl);
+                StateHandle::spawn(fresh)
+            }
+        };
+        let nonces = Arc::new(AsyncMutex::new(HashMap::new()));
+        let callbook = config.callbook();
+        let notifier = Notifier::from_config(&config);
+
+        for event in segment.events {
+            match event {
+                RecordedEvent::Message { at, message } => {
+                    let is_valid = validate_poi_message(
+                        &message,
+                        &nonces,
+                        &callbook,
+                        &local_sender,
+                        &config.id_validation,
+                        at,
+                    )
+                    .await;
+                    if is_valid.is_ok() {
+                        messages.push(message.clone());
+                        state.add_remote_message(message).await;
+                    }
+                }
+                RecordedEvent::Batch { at, message } => {
+                    let is_valid = validate_poi_batch(
+                        &message,
+                        &nonces,
+                        &callbook,
+                        &local_sender,
+                        &config.id_validation,
+                        at,
+                    )
+                    .await;
+                    if is_valid.is_ok() {
+                        let expanded = message
+                            .payload
+                            .expand_valid(&message, callbook.graph_node_status())
+                            .await;
+                        if !expanded.is_empty() {
+                            state.add_poi_batch(message.clone()).await;
+                        }
+                        for expanded in expanded {
+                            messages.push(expanded.clone());
+                            state.add_remote_message(expanded).await;
+                        }
+                    }
+                }
+                RecordedEvent::LocalAttestation {
+                    at,
+                    deployment,
+                    block_number,
+                    npoi,
+                } => {
+                    save_local_attestation_at(
+                        &mut local_attestations,
+                        npoi.clone(),
+                        deployment.clone(),
+                        block_number,
+                        at,
+                    );
+                    // The collect window opens at the recorded time
+                    state
+                        .save_local_attestation(npoi, deployment, block_number, at)
+                        .await;
+                }
+                RecordedEvent::Comparison { at, identifiers } => {
+                    for result in compare_poi(&config, &state, &notifier, identifiers, at)
+                        .await
+                        .into_iter()
+                        .flatten()
+                    {
+                        state.update_comparison_result(result.clone()).await;
+                        comparisons.push(ReplayedComparison { at, result });
+                    }
+                }
+                RecordedEvent::Header { .. } | RecordedEvent::Http { .. } => {}
+            }
+        }
+        recorded_config = segment.config;
+        final_state = state.snapshot().await;
+        previous = Some(state);
+    }
+    Ok(Replay {
+        config: recorded_config,
+        comparisons,
+        messages,
+        local_attestations,
+        state: final_state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_runs() {
+        let path = std::env::temp_dir().join("poi-radio-test-recording-runs.jsonl");
+        _ = std::fs::remove_file(&path);
+        // A restarted radio appends a run of its own
+        for (run, local_sender) in ["0xa1", "0xa2"].iter().enumerate() {
+            let recorder = Recorder::new(&path).unwrap();
+            recorder.write(&RecordedEvent::Header {
+                version: RECORDING_VERSION,
+                recorded_at: run as i64,
+                local_sender: local_sender.to_string(),
+                config: Box::default(),
+            });
+            recorder.comparison(run as i64, &[String::from("QmHash")]);
+        }
+
+        let events = read_recording(&path).unwrap();
+        assert_eq!(events.len(), 4);
+        let runs = segments(events).unwrap();
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].local_sender, "0xa1");
+        assert_eq!(runs[1].local_sender, "0xa2");
+        assert!(runs.iter().all(|run| run.events.len() == 1));
+
+        assert!(matches!(
+            segments(runs.into_iter().flat_map(|run| run.events).collect()),
+            Err(ReplayError::MissingHeader)
+        ));
+        _ = std::fs::remove_file(path);
+    }
+}
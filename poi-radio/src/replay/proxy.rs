@@ -0,0 +1,234 @@
+use axum::{
+    body::Bytes,
+    extract::{Extension, Path},
+    http::{header, StatusCode},
+    routing::post,
+    Router, Server,
+};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as SyncMutex},
+};
+use tracing::warn;
+
+use crate::config::Config;
+
+use super::Recorder;
+
+const GRAPH_NODE: &str = "graph_node";
+const REGISTRY: &str = "registry";
+const NETWORK: &str = "network";
+
+/// Recorded responses by endpoint and request, served in the order they were recorded.
+/// Once a request has used up its responses the last one keeps being served
+#[derive(Debug, Default)]
+pub struct RecordedResponses {
+    responses: HashMap<(String, String), VecDeque<(u16, String)>>,
+}
+
+impl RecordedResponses {
+    pub fn push(&mut self, endpoint: &str, request: &Value, status: u16, response: String) {
+        self.responses
+            .entry((endpoint.to_string(), request.to_string()))
+            .or_default()
+            .push_back((status, response));
+    }
+
+    fn next(&mut self, endpoint: &str, request: &Value) -> Option<(u16, String)> {
+        let queue = self
+            .responses
+            .get_mut(&(endpoint.to_string(), request.to_string()))?;
+        if queue.len() > 1 {
+            queue.pop_front()
+        } else {
+            queue.front().cloned()
+        }
+    }
+}
+
+/// Where the proxy gets its responses from
+pub enum Upstream {
+    /// Forward to the configured endpoints and record the responses
+    Record {
+        endpoints: HashMap<&'static str, String>,
+        client: reqwest::Client,
+        recorder: Recorder,
+    },
+    /// Answer with recorded responses
+    Replay(SyncMutex<RecordedResponses>),
+}
+
+impl Upstream {
+    pub fn record(config: &Config, recorder: Recorder) -> Self {
+        Upstream::Record {
+            endpoints: HashMap::from([
+                (GRAPH_NODE, config.graph_node_endpoint.clone()),
+                (REGISTRY, config.registry_subgraph.clone()),
+                (NETWORK, config.network_subgraph.clone()),
+            ]),
+            client: reqwest::Client::new(),
+            recorder,
+        }
+    }
+
+    async fn respond(&self, endpoint: &str, body: Bytes) -> (u16, String) {
+        // Requests are compared as JSON so formatting differences do not matter
+        let request = serde_json::from_slice(&body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+        match self {
+            Upstream::Record {
+                endpoints,
+                client,
+                recorder,
+            } => {
+                let url = match endpoints.get(endpoint) {
+                    Some(url) => url,
+                    None => return (404, format!("Unknown endpoint {endpoint}")),
+                };
+                let (status, response) = match forward(client, url, body).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!(
+                            err = e.to_string(),
+                            endpoint, "Recording proxy query failed"
+                        );
+                        (502, e.to_string())
+                    }
+                };
+                recorder.http(endpoint, request, status, response.clone());
+                (status, response)
+            }
+            Upstream::Replay(responses) => responses
+                .lock()
+                .unwrap()
+                .next(endpoint, &request)
+                .unwrap_or_else(|| {
+                    (
+                        502,
+                        format!("No recorded response from {endpoint} for {request}"),
+                    )
+                }),
+        }
+    }
+}
+
+async fn forward(
+    client: &reqwest::Client,
+    url: &str,
+    body: Bytes,
+) -> Result<(u16, String), reqwest::Error> {
+    let response = client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?;
+    let status = response.status().as_u16();
+    Ok((status, response.text().await?))
+}
+
+async fn handle(
+    Extension(upstream): Extension<Arc<Upstream>>,
+    Path(endpoint): Path<String>,
+    body: Bytes,
+) -> (StatusCode, [(header::HeaderName, &'static str); 1], String) {
+    let (status, response) = upstream.respond(&endpoint, body).await;
+    (
+        StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY),
+        [(header::CONTENT_TYPE, "application/json")],
+        response,
+    )
+}
+
+/// Serve the upstream on a local port, returns the address it listens on
+pub async fn start_proxy(upstream: Upstream) -> io::Result<SocketAddr> {
+    let app = Router::new()
+        .route("/:endpoint", post(handle))
+        .layer(Extension(Arc::new(upstream)));
+    let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .map_err(io::Error::other)?
+        .serve(app.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    Ok(addr)
+}
+
+/// Configuration with the Graph node and subgraph endpoints pointed at the proxy
+pub fn proxied(config: &Config, addr: SocketAddr) -> Config {
+    Config {
+        graph_node_endpoint: format!("http://{addr}/{GRAPH_NODE}"),
+        registry_subgraph: format!("http://{addr}/{REGISTRY}"),
+        network_subgraph: format!("http://{addr}/{NETWORK}"),
+        ..config.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphcast_sdk::graphql::client_graph_node::query_graph_node_network_block_hash;
+    use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+    use crate::replay::{read_recording, RecordedEvent};
+
+    #[tokio::test]
+    async fn test_proxy_record_and_replay() {
+        let graph_node = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    serde_json::json!({ "data": { "blockHashFromNumber": "0xblahh" } }),
+                ),
+            )
+            .mount(&graph_node)
+            .await;
+
+        let path = std::env::temp_dir().join("poi-radio-test-recording.jsonl");
+        _ = std::fs::remove_file(&path);
+        let recorder = Recorder::new(&path).unwrap();
+        let config = Config {
+            graph_node_endpoint: graph_node.uri(),
+            ..Config::default()
+        };
+        let addr = start_proxy(Upstream::record(&config, recorder))
+            .await
+            .unwrap();
+        let recording = proxied(&config, addr);
+        let hash = query_graph_node_network_block_hash(&recording.graph_node_endpoint, "goerli", 1)
+            .await
+            .unwrap();
+        assert_eq!(hash, "0xblahh");
+
+        let mut responses = RecordedResponses::default();
+        for event in read_recording(&path).unwrap() {
+            if let RecordedEvent::Http {
+                endpoint,
+                request,
+                status,
+                response,
+                ..
+            } = event
+            {
+                responses.push(&endpoint, &request, status, response);
+            }
+        }
+        let addr = start_proxy(Upstream::Replay(SyncMutex::new(responses)))
+            .await
+            .unwrap();
+        let replaying = proxied(&config, addr);
+        let hash = query_graph_node_network_block_hash(&replaying.graph_node_endpoint, "goerli", 1)
+            .await
+            .unwrap();
+        assert_eq!(hash, "0xblahh");
+        // Never recorded
+        assert!(
+            query_graph_node_network_block_hash(&replaying.graph_node_endpoint, "goerli", 2)
+                .await
+                .is_err()
+        );
+        _ = std::fs::remove_file(path);
+    }
+}
@@ -9,10 +9,10 @@ use crate::{
     config::Config,
     server::{
         model::{build_schema, POIRadioContext},
-        routes::{graphql_handler, graphql_playground, health},
+        routes::{graphql_handler, graphql_playground, health, state_restore, state_snapshot},
     },
     shutdown_signal,
-    state::PersistedState,
+    state::StateHandle,
 };
 
 pub mod model;
@@ -21,30 +21,27 @@ pub mod routes;
 /// Run HTTP server to provide API services
 /// Set up the routes for a radio health endpoint at `/health`
 /// and a versioned GraphQL endpoint at `api/v1/graphql`
+/// With an admin token, state snapshot and restore are served at `api/v1/state`
 /// This function starts a API server at the configured server_host and server_port
-pub async fn run_server(
-    config: Config,
-    persisted_state: &'static PersistedState,
-    running_program: Arc<AtomicBool>,
-) {
+pub async fn run_server(config: Config, state: StateHandle, running_program: Arc<AtomicBool>) {
     if config.server_port().is_none() {
         return;
     }
     let port = config.server_port().unwrap();
-    let context = Arc::new(POIRadioContext::init(config.clone(), persisted_state));
+    let context = Arc::new(POIRadioContext::init(config.clone(), state));
 
     let schema = build_schema(Arc::clone(&context)).await;
 
     debug!("Setting up HTTP service");
 
-    let app = Router::new()
-        .route("/health", get(health))
-        .route(
-            "/api/v1/graphql",
-            get(graphql_playground).post(graphql_handler),
-        )
-        .layer(Extension(schema))
-        .layer(Extension(context));
+    let mut app = Router::new().route("/health", get(health)).route(
+        "/api/v1/graphql",
+        get(graphql_playground).post(graphql_handler),
+    );
+    if config.server_admin_token().is_some() {
+        app = app.route("/api/v1/state", get(state_snapshot).post(state_restore));
+    }
+    let app = app.layer(Extension(schema)).layer(Extension(context));
     let addr = SocketAddr::from_str(&format!("{}:{}", config.server_host(), port))
         .expect("Create address");
 
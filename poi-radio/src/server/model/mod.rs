@@ -2,16 +2,23 @@ use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, S
 use chrono::Utc;
 use std::{collections::HashMap, sync::Arc};
 use thiserror::Error;
+use tracing::warn;
 
 use crate::{
     config::Config,
-    messages::poi::PublicPoiMessage,
+    messages::{heartbeat::HeartbeatMessage, poi::PublicPoiMessage},
     operator::attestation::{
         self, attestations_to_vec, compare_attestation, process_ppoi_message, Attestation,
         AttestationEntry, AttestationError, ComparisonResult, ComparisonResultType,
         LocalAttestationsMap,
     },
-    state::PersistedState,
+    operator::{
+        equivocation::{consensus_messages, Equivocation},
+        reputation::IndexerAgreement,
+        stake::{epoch_stakes, Stake},
+        upgrade::UpgradePlan,
+    },
+    state::StateHandle,
 };
 use graphcast_sdk::{graphcast_agent::message_typing::GraphcastMessage, graphql::QueryError};
 
@@ -31,7 +38,8 @@ impl QueryRoot {
     ) -> Result<Vec<GraphcastMessage<PublicPoiMessage>>, HttpServiceError> {
         let msgs = ctx
             .data_unchecked::<Arc<POIRadioContext>>()
-            .remote_messages_filtered(&identifier, &block);
+            .remote_messages_filtered(&identifier, &block)
+            .await;
         Ok(msgs)
     }
 
@@ -43,13 +51,15 @@ impl QueryRoot {
     ) -> Result<Vec<AttestationEntry>, HttpServiceError> {
         let attestations = ctx
             .data_unchecked::<Arc<POIRadioContext>>()
-            .local_attestations(identifier, block);
+            .local_attestations(identifier, block)
+            .await;
         let filtered = attestations_to_vec(&attestations);
 
         Ok(filtered)
     }
 
     /// Function that optionally takes in identifier and block filters.
+    /// Results where the top remote nPOI is below the configured quorum have the `INCONCLUSIVE` result type
     async fn comparison_results(
         &self,
         ctx: &Context<'_>,
@@ -73,10 +83,142 @@ impl QueryRoot {
     ) -> Result<Option<ComparisonResult>, HttpServiceError> {
         let res = &ctx
             .data_unchecked::<Arc<POIRadioContext>>()
-            .comparison_result(identifier);
+            .comparison_result(identifier)
+            .await;
         Ok(res.clone())
     }
 
+    /// Comparison results of a deployment over time, optionally within an inclusive block range
+    async fn comparison_history(
+        &self,
+        ctx: &Context<'_>,
+        identifier: String,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+        result_type: Option<ComparisonResultType>,
+    ) -> Result<Vec<ComparisonResult>, HttpServiceError> {
+        let res = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .comparison_history(&identifier, from_block, to_block)
+            .into_iter()
+            .filter(|r| result_type.is_none() | (Some(r.result_type) == result_type))
+            .collect();
+        Ok(res)
+    }
+
+    /// Latest matched block of a deployment and the first divergent block since then, from the comparison history
+    async fn divergence_timeline(
+        &self,
+        ctx: &Context<'_>,
+        identifier: String,
+    ) -> Result<DivergenceTimeline, HttpServiceError> {
+        let (last_matched_block, first_divergent_block) = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .divergence_bounds(&identifier);
+        Ok(DivergenceTimeline {
+            deployment: identifier,
+            last_matched_block,
+            first_divergent_block,
+        })
+    }
+
+    /// Evidence of indexers that signed different nPOIs for the same deployment and block
+    async fn equivocations(
+        &self,
+        ctx: &Context<'_>,
+        identifier: Option<String>,
+        graph_account: Option<String>,
+    ) -> Result<Vec<Equivocation>, HttpServiceError> {
+        let res = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .equivocations()
+            .into_iter()
+            .filter(|e| {
+                (identifier.is_none() | (Some(&e.deployment) == identifier.as_ref()))
+                    && (graph_account.is_none()
+                        | (Some(&e.graph_account) == graph_account.as_ref()))
+            })
+            .collect();
+        Ok(res)
+    }
+
+    /// Latest heartbeat of each indexer, optionally only the ones sent since a unix timestamp to list radios online
+    async fn heartbeats(
+        &self,
+        ctx: &Context<'_>,
+        graph_account: Option<String>,
+        since: Option<i64>,
+    ) -> Result<Vec<HeartbeatMessage>, HttpServiceError> {
+        let res = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .heartbeats()
+            .into_iter()
+            .filter(|h| {
+                let account_matches = match &graph_account {
+                    Some(account) => &h.graph_account == account,
+                    None => true,
+                };
+                let recent = match since {
+                    Some(since) => h.nonce >= since,
+                    None => true,
+                };
+                account_matches && recent
+            })
+            .collect();
+        Ok(res)
+    }
+
+    /// Upgrades announced by subgraph owners whose migrate time has not passed, soonest first
+    async fn upgrade_plans(
+        &self,
+        ctx: &Context<'_>,
+        deployment: Option<String>,
+    ) -> Result<Vec<UpgradePlan>, HttpServiceError> {
+        let now = Utc::now().timestamp();
+        let res = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .upgrade_plans()
+            .into_iter()
+            .filter(|plan| {
+                let deployment_matches = match &deployment {
+                    Some(deployment) => {
+                        &plan.deployment == deployment || &plan.new_hash == deployment
+                    }
+                    None => true,
+                };
+                deployment_matches && plan.is_pending(now)
+            })
+            .collect();
+        Ok(res)
+    }
+
+    /// Track record of remote senders, with their agreement rates against the majority and the local nPOI
+    async fn indexers(
+        &self,
+        ctx: &Context<'_>,
+        address: Option<String>,
+    ) -> Result<Vec<IndexerAgreement>, HttpServiceError> {
+        let res = ctx
+            .data_unchecked::<Arc<POIRadioContext>>()
+            .state
+            .latest()
+            .indexer_agreements()
+            .into_iter()
+            .filter(|a| address.is_none() | (Some(&a.address) == address.as_ref()))
+            .collect();
+        Ok(res)
+    }
+
     /// Return the sender ratio for remote attestations, with a "!" for the attestation matching local
     async fn comparison_ratio(
         &self,
@@ -107,7 +249,7 @@ impl QueryRoot {
                     let updateed_attestation = attestation::Attestation::update(
                         &a,
                         local_info.address.clone(),
-                        local_info.stake,
+                        local_info.stake.clone(),
                         Utc::now().timestamp(),
                     );
                     if let Ok(updated_a) = updateed_attestation {
@@ -187,29 +329,29 @@ pub fn stake_weight_str(attestations: &[Attestation], local_npoi: String) -> Str
 
 pub async fn build_schema(ctx: Arc<POIRadioContext>) -> POIRadioSchema {
     Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
-        .data(ctx.persisted_state)
+        .data(ctx.state.clone())
         .finish()
 }
 
 pub struct POIRadioContext {
     pub radio_config: Config,
-    pub persisted_state: &'static PersistedState,
+    pub state: StateHandle,
 }
 
 impl POIRadioContext {
-    pub fn init(radio_config: Config, persisted_state: &'static PersistedState) -> Self {
+    pub fn init(radio_config: Config, state: StateHandle) -> Self {
         Self {
             radio_config,
-            persisted_state,
+            state,
         }
     }
 
-    pub fn local_attestations(
+    pub async fn local_attestations(
         &self,
         identifier: Option<String>,
         block: Option<u64>,
     ) -> LocalAttestationsMap {
-        let attestations = self.persisted_state.local_attestations();
+        let attestations = self.state.latest().local_attestations();
         let mut empty_attestations: LocalAttestationsMap = HashMap::new();
 
         if let Some(deployment) = identifier {
@@ -237,27 +379,30 @@ impl POIRadioContext {
         }
     }
 
-    pub fn remote_messages(&self) -> Vec<GraphcastMessage<PublicPoiMessage>> {
-        self.persisted_state.remote_messages()
+    pub async fn remote_messages(&self) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+        self.state.latest().remote_messages()
     }
 
-    pub fn remote_messages_filtered(
+    pub async fn remote_messages_filtered(
         &self,
         identifier: &Option<String>,
         block: &Option<u64>,
     ) -> Vec<GraphcastMessage<PublicPoiMessage>> {
-        let msgs = self.remote_messages();
-        let filtered = msgs
-            .iter()
-            .cloned()
-            .filter(|message| filter_remote_messages(message, identifier, block))
-            .collect::<Vec<_>>();
-        filtered
+        let snapshot = self.state.latest();
+        let remote_messages = &snapshot.remote_messages;
+        match (identifier, block) {
+            (Some(id), Some(block)) => remote_messages.block(id, *block),
+            (Some(id), None) => remote_messages.deployment(id),
+            (None, _) => remote_messages
+                .all()
+                .into_iter()
+                .filter(|message| filter_remote_messages(message, identifier, block))
+                .collect(),
+        }
     }
 
-    pub fn comparison_result(&self, identifier: String) -> Option<ComparisonResult> {
-        let cmp_results = self.persisted_state.comparison_results();
-        cmp_results.get(&identifier).cloned()
+    pub async fn comparison_result(&self, identifier: String) -> Option<ComparisonResult> {
+        self.state.latest().comparison_result(identifier)
     }
 
     pub async fn comparison_results(
@@ -267,8 +412,9 @@ impl POIRadioContext {
         result_type: Option<ComparisonResultType>,
     ) -> Vec<ComparisonResult> {
         // Simply take from persisted state if block is not specified
+        let snapshot = self.state.latest();
         if block.is_none() {
-            let cmp_results = self.persisted_state.comparison_results();
+            let cmp_results = snapshot.comparison_results();
 
             cmp_results
                 .iter()
@@ -280,26 +426,62 @@ impl POIRadioContext {
                 .collect::<Vec<ComparisonResult>>()
         } else {
             // Calculate for the block if specified
-            let locals = attestations_to_vec(&self.local_attestations(identifier.clone(), block));
+            let locals =
+                attestations_to_vec(&self.local_attestations(identifier.clone(), block).await);
 
             let config = self.radio_config();
 
+            // Stakes are shared between entries of the same epoch through the stake cache
+            let policy = config.consensus();
+            let (msgs, _) = consensus_messages(
+                self.remote_messages_filtered(&identifier, &block).await,
+                &policy,
+            );
+            let senders: Vec<String> = msgs.iter().map(|m| m.graph_account.clone()).collect();
+            let now = Utc::now().timestamp();
+
             let mut res = vec![];
             for entry in locals {
+                // Weighed with the stakes of the epoch the collect window opened in
+                let window_start = entry.attestation.timestamp.first().copied().unwrap_or(now);
+                let sender_stakes = match epoch_stakes(
+                    &senders,
+                    &config.callbook(),
+                    &self.state,
+                    window_start,
+                    now,
+                )
+                .await
+                {
+                    Ok(stakes) => stakes,
+                    Err(e) => {
+                        warn!(
+                            err = tracing::field::debug(&e),
+                            "Failed to resolve sender stakes"
+                        );
+                        return vec![];
+                    }
+                };
                 let deployment_identifier = entry.deployment.clone();
-                let msgs = self.remote_messages_filtered(&identifier, &block);
-                let remote_attestations = process_ppoi_message(msgs, &config.callbook())
-                    .await
-                    .ok()
-                    .and_then(|r| {
-                        r.get(&deployment_identifier)
-                            .and_then(|deployment_attestations| {
-                                deployment_attestations.get(&entry.block_number).cloned()
-                            })
-                    })
-                    .unwrap_or_default();
-
-                let r = compare_attestation(entry, remote_attestations);
+                let msgs = msgs.clone();
+                let remote_attestations =
+                    process_ppoi_message(msgs, &sender_stakes, &config.sender_weights(&snapshot))
+                        .await
+                        .ok()
+                        .and_then(|r| {
+                            r.get(&deployment_identifier)
+                                .and_then(|deployment_attestations| {
+                                    deployment_attestations.get(&entry.block_number).cloned()
+                                })
+                        })
+                        .unwrap_or_default();
+
+                let r = compare_attestation(
+                    entry,
+                    remote_attestations,
+                    &config.quorum_threshold(),
+                    &policy,
+                );
                 if result_type.is_none() | (result_type.unwrap() == r.result_type) {
                     res.push(r);
                 }
@@ -355,10 +537,17 @@ impl CompareRatio {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, SimpleObject)]
+struct DivergenceTimeline {
+    deployment: String,
+    last_matched_block: Option<u64>,
+    first_divergent_block: Option<u64>,
+}
+
 #[derive(Debug, PartialEq, SimpleObject)]
 struct IndexerInfo {
     address: String,
-    stake: f32,
+    stake: Stake,
 }
 
 #[derive(Error, Debug)]
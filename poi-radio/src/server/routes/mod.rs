@@ -1,19 +1,24 @@
 use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
 use axum::{
+    body::Bytes,
     extract::Extension,
-    http::StatusCode,
-    response::{Html, IntoResponse},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
     Json,
 };
 use opentelemetry::trace::TraceContextExt;
 use serde::Serialize;
+use serde_json::json;
 use std::sync::Arc;
-use tracing::{span, trace, Instrument, Level};
+use tracing::{info, span, trace, warn, Instrument, Level};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use super::model::POIRadioContext;
 use crate::server::model::POIRadioSchema;
+use crate::state::merge::{drop_unverified_senders, parse_snapshot};
+use crate::storage::migration::versioned;
+use crate::GRAPHCAST_AGENT;
 
 #[derive(Serialize)]
 struct Health {
@@ -57,3 +62,93 @@ pub(crate) async fn graphql_handler(
         )
         .into()
 }
+
+/// Whether the request bears the admin token, compared in constant time
+fn is_admin(context: &POIRadioContext, headers: &HeaderMap) -> bool {
+    let token = match context.radio_config.server_admin_token() {
+        Some(token) => token,
+        None => return false,
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    bearer.len() == token.len()
+        && bearer
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn error_response(status: StatusCode, error: impl ToString) -> Response {
+    (status, Json(json!({ "error": error.to_string() }))).into_response()
+}
+
+/// Versioned snapshot of the running state, in the format `poi-radio state import` reads
+pub(crate) async fn state_snapshot(
+    headers: HeaderMap,
+    Extension(context): Extension<Arc<POIRadioContext>>,
+) -> Response {
+    if !is_admin(&context, &headers) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+    let snapshot = context.state.snapshot().await;
+    match serde_json::to_value(snapshot.as_ref())
+        .map_err(|e| e.to_string())
+        .and_then(|value| versioned(value).map_err(|e| e.to_string()))
+    {
+        Ok(value) => (StatusCode::OK, Json(value)).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Merge an exported state into the running one, responds with what was added
+pub(crate) async fn state_restore(
+    headers: HeaderMap,
+    Extension(context): Extension<Arc<POIRadioContext>>,
+    body: Bytes,
+) -> Response {
+    if !is_admin(&context, &headers) {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid admin token");
+    }
+    let agent = match GRAPHCAST_AGENT.get() {
+        Some(agent) => agent,
+        None => {
+            return error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Restored messages cannot be verified before the Graphcast agent is running",
+            )
+        }
+    };
+    let mut restored = match parse_snapshot(&body) {
+        Ok(state) => state,
+        Err(e) => {
+            warn!(err = e.to_string(), "Rejected state restore");
+            return error_response(StatusCode::UNPROCESSABLE_ENTITY, e);
+        }
+    };
+    let dropped = drop_unverified_senders(
+        &mut restored,
+        &agent.callbook,
+        &agent.graphcast_identity.graphcast_id,
+        &agent.id_validation,
+    )
+    .await;
+    if !dropped.is_empty() {
+        warn!(
+            messages = tracing::field::debug(&dropped),
+            "Dropped restored messages from unverified senders"
+        );
+    }
+    let summary = context
+        .state
+        .merge(restored, *context.radio_config.comparison_history_limit())
+        .await;
+    info!(
+        summary = tracing::field::debug(&summary),
+        "Merged restored state"
+    );
+    (StatusCode::OK, Json(summary)).into_response()
+}
@@ -0,0 +1,385 @@
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::{
+    config::Config,
+    messages::poi::PublicPoiMessage,
+    operator::{
+        attestation::{ComparisonResultType, LocalAttestationsMap, QuorumThreshold},
+        consensus::{ConsensusPolicy, ConsensusPolicyType},
+        equivocation::consensus_messages,
+        operation::message_comparison,
+        reputation::SenderWeights,
+        stake::SenderStakes,
+    },
+    replay::Replay,
+    state::PersistedState,
+    OperationError,
+};
+
+/// Parameters of the comparison step that a simulation can vary
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ComparisonParameters {
+    pub collect_message_duration: i64,
+    pub quorum_stake_ratio: f32,
+    pub quorum_min_senders: usize,
+    pub consensus_policy: ConsensusPolicyType,
+    pub trusted_senders: Vec<String>,
+    pub sender_agreement_threshold: Option<f32>,
+}
+
+impl ComparisonParameters {
+    pub fn from_config(config: &Config) -> Self {
+        ComparisonParameters {
+            collect_message_duration: config.collect_message_duration,
+            quorum_stake_ratio: config.quorum_stake_ratio,
+            quorum_min_senders: config.quorum_min_senders,
+            consensus_policy: config.consensus_policy,
+            trusted_senders: config.trusted_senders.clone(),
+            sender_agreement_threshold: config.sender_agreement_threshold,
+        }
+    }
+
+    /// Configuration the comparison step runs with, other fields are irrelevant to it
+    fn config(&self) -> Config {
+        Config {
+            collect_message_duration: self.collect_message_duration,
+            quorum_stake_ratio: self.quorum_stake_ratio,
+            quorum_min_senders: self.quorum_min_senders,
+            consensus_policy: self.consensus_policy,
+            trusted_senders: self.trusted_senders.clone(),
+            sender_agreement_threshold: self.sender_agreement_threshold,
+            ..Config::default()
+        }
+    }
+}
+
+impl Display for ComparisonParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "collect_message_duration={} quorum_stake_ratio={} quorum_min_senders={} consensus_policy={}",
+            self.collect_message_duration,
+            self.quorum_stake_ratio,
+            self.quorum_min_senders,
+            self.consensus_policy
+        )?;
+        if self.consensus_policy == ConsensusPolicyType::TrustedSet {
+            write!(f, " trusted_senders={}", self.trusted_senders.join(","))?;
+        }
+        if let Some(threshold) = self.sender_agreement_threshold {
+            write!(f, " sender_agreement_threshold={threshold}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Local attestations and remote messages to compare, with what the comparison step needs to weigh senders
+pub struct SimulationInputs {
+    pub local_attestations: LocalAttestationsMap,
+    pub messages: Vec<GraphcastMessage<PublicPoiMessage>>,
+    /// Sender track records for sender weights, and the epochs and stake snapshots for sender stakes
+    pub state: PersistedState,
+}
+
+impl SimulationInputs {
+    /// Inputs still held in a persisted state. Comparisons clear the messages and attestations they cover,
+    /// so only blocks awaiting comparison are found there
+    pub fn from_state(state: PersistedState) -> Self {
+        SimulationInputs {
+            local_attestations: state.local_attestations(),
+            messages: state.remote_messages(),
+            state,
+        }
+    }
+
+    /// Inputs of a replayed recording, covering every block the recording radio attested to
+    pub fn from_replay(replay: Replay) -> Self {
+        SimulationInputs {
+            local_attestations: replay.local_attestations,
+            messages: replay.messages,
+            state: replay.state.as_ref().clone(),
+        }
+    }
+
+    /// Stake snapshot of the epoch in effect when a collect window opened, the stakes compare_poi weighs
+    /// the messages of the window with. Empty without a snapshot of that epoch
+    fn window_stakes(&self, window_start: i64) -> SenderStakes {
+        self.state
+            .stake_cache
+            .epoch_seen_at(window_start)
+            .and_then(|epoch| self.state.stake_snapshots.get(&epoch.epoch))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Results of a block under the baseline and the alternative parameters
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SimulatedOutcome {
+    pub deployment: String,
+    pub block_number: u64,
+    pub baseline: ComparisonResultType,
+    pub alternative: ComparisonResultType,
+}
+
+impl SimulatedOutcome {
+    pub fn changed(&self) -> bool {
+        self.baseline != self.alternative
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct SimulationReport {
+    pub baseline: ComparisonParameters,
+    pub alternative: ComparisonParameters,
+    /// Outcomes by deployment and block, in ascending order
+    pub outcomes: Vec<SimulatedOutcome>,
+}
+
+impl SimulationReport {
+    pub fn changed(&self) -> impl Iterator<Item = &SimulatedOutcome> {
+        self.outcomes.iter().filter(|o| o.changed())
+    }
+
+    /// Number of outcomes of each result type, under the baseline and the alternative parameters
+    pub fn totals(&self) -> BTreeMap<String, (usize, usize)> {
+        let mut totals: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for outcome in &self.outcomes {
+            totals.entry(outcome.baseline.to_string()).or_default().0 += 1;
+            totals.entry(outcome.alternative.to_string()).or_default().1 += 1;
+        }
+        totals
+    }
+}
+
+impl Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Baseline:    {}", self.baseline)?;
+        writeln!(f, "Alternative: {}", self.alternative)?;
+        writeln!(f, "Changed outcomes ({}):", self.changed().count())?;
+        for outcome in self.changed() {
+            writeln!(
+                f,
+                "  {} at block {}: {} -> {}",
+                outcome.deployment, outcome.block_number, outcome.baseline, outcome.alternative
+            )?;
+        }
+        writeln!(f, "Totals over {} blocks:", self.outcomes.len())?;
+        for (result_type, (baseline, alternative)) in self.totals() {
+            writeln!(f, "  {result_type}: {baseline} -> {alternative}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare every locally attested block under the baseline and the alternative parameters
+pub async fn simulate(
+    inputs: &SimulationInputs,
+    baseline: ComparisonParameters,
+    alternative: ComparisonParameters,
+) -> SimulationReport {
+    let baseline_results = compare_blocks(inputs, &baseline).await;
+    let alternative_results = compare_blocks(inputs, &alternative).await;
+    let outcomes = baseline_results
+        .into_iter()
+        .zip(alternative_results)
+        .map(
+            |(((deployment, block_number), baseline), (_, alternative))| SimulatedOutcome {
+                deployment,
+                block_number,
+                baseline,
+                alternative,
+            },
+        )
+        .collect();
+    SimulationReport {
+        baseline,
+        alternative,
+        outcomes,
+    }
+}
+
+/// Result type of each locally attested block, the way compare_poi would determine it once its collect window closed
+async fn compare_blocks(
+    inputs: &SimulationInputs,
+    parameters: &ComparisonParameters,
+) -> BTreeMap<(String, u64), ComparisonResultType> {
+    let config = parameters.config();
+    let quorum: QuorumThreshold = config.quorum_threshold();
+    let policy: ConsensusPolicy = config.consensus();
+    let sender_weights: SenderWeights = config.sender_weights(&inputs.state);
+
+    let mut messages_by_block: HashMap<(&str, u64), Vec<GraphcastMessage<PublicPoiMessage>>> =
+        HashMap::new();
+    for msg in &inputs.messages {
+        messages_by_block
+            .entry((&msg.identifier, msg.payload.block_number))
+            .or_default()
+            .push(msg.clone());
+    }
+
+    let mut results = BTreeMap::new();
+    for (deployment, blocks) in &inputs.local_attestations {
+        for (block_number, attestation) in blocks {
+            let messages = messages_by_block
+                .get(&(deployment.as_str(), *block_number))
+                .cloned()
+                .unwrap_or_default();
+            let (messages, _) = consensus_messages(messages, &policy);
+            // Each block is compared on its own, message_comparison otherwise picks the earliest block
+            let local_attestations = HashMap::from([(
+                deployment.clone(),
+                HashMap::from([(*block_number, attestation.clone())]),
+            )]);
+            let result = message_comparison(
+                deployment.clone(),
+                config.collect_message_duration,
+                messages,
+                local_attestations,
+                quorum,
+                policy.clone(),
+                sender_weights.clone(),
+                inputs.window_stakes(attestation.timestamp.first().copied().unwrap_or_default()),
+                i64::MAX,
+            )
+            .await;
+            let result_type = match result {
+                Ok(result) => result.result_type,
+                // No remote message for the block
+                Err(OperationError::CompareTrigger(..)) => ComparisonResultType::NotFound,
+                Err(_) => ComparisonResultType::BuildFailed,
+            };
+            results.insert((deployment.clone(), *block_number), result_type);
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operator::attestation::save_local_attestation_at;
+    use crate::operator::stake::{EpochStart, Stake};
+
+    fn message(
+        block: u64,
+        sender: &str,
+        npoi: &str,
+        nonce: i64,
+    ) -> GraphcastMessage<PublicPoiMessage> {
+        GraphcastMessage {
+            identifier: String::from("Qm1"),
+            nonce,
+            graph_account: sender.to_string(),
+            payload: PublicPoiMessage {
+                identifier: String::from("Qm1"),
+                content: npoi.to_string(),
+                nonce,
+                network: String::from("goerli"),
+                block_number: block,
+                block_hash: String::from("0xblahh"),
+                graph_account: sender.to_string(),
+                version: 0,
+            },
+            signature: format!("{block}-{sender}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulate_alternative_parameters() {
+        let mut local = HashMap::new();
+        save_local_attestation_at(
+            &mut local,
+            String::from("npoi-a"),
+            String::from("Qm1"),
+            10,
+            0,
+        );
+        save_local_attestation_at(
+            &mut local,
+            String::from("npoi-a"),
+            String::from("Qm1"),
+            20,
+            0,
+        );
+        save_local_attestation_at(
+            &mut local,
+            String::from("npoi-a"),
+            String::from("Qm1"),
+            30,
+            0,
+        );
+        // The windows opened in epoch 1, stakes moved to the small senders in epoch 2
+        let mut state = PersistedState::new(None, None, None);
+        for (epoch, seen_at, stakes) in [(1, 0, [100, 1, 1]), (2, 100, [1, 100, 100])] {
+            state.set_epoch(
+                EpochStart {
+                    epoch,
+                    start_block: epoch * 100,
+                    stake_block: epoch * 100,
+                },
+                seen_at,
+            );
+            for (sender, grt) in ["0xa1", "0xa2", "0xa3"].iter().zip(stakes) {
+                state.add_stake_snapshot(epoch, sender.to_string(), Stake::from_grt(grt));
+            }
+        }
+        let inputs = SimulationInputs {
+            local_attestations: local,
+            messages: vec![
+                // Block 10: one large sender agrees, two small senders diverge
+                message(10, "0xa1", "npoi-a", 10),
+                message(10, "0xa2", "npoi-b", 10),
+                message(10, "0xa3", "npoi-b", 10),
+                // Block 20: the only message arrives late
+                message(20, "0xa1", "npoi-a", 200),
+            ],
+            state,
+        };
+        let baseline = ComparisonParameters {
+            collect_message_duration: 300,
+            quorum_stake_ratio: 0.0,
+            quorum_min_senders: 1,
+            consensus_policy: ConsensusPolicyType::StakeWeighted,
+            trusted_senders: vec![],
+            sender_agreement_threshold: None,
+        };
+        let alternative = ComparisonParameters {
+            collect_message_duration: 60,
+            consensus_policy: ConsensusPolicyType::SenderCount,
+            ..baseline.clone()
+        };
+
+        let report = simulate(&inputs, baseline, alternative).await;
+        assert_eq!(
+            report.outcomes,
+            vec![
+                SimulatedOutcome {
+                    deployment: String::from("Qm1"),
+                    block_number: 10,
+                    baseline: ComparisonResultType::Match,
+                    alternative: ComparisonResultType::Divergent,
+                },
+                SimulatedOutcome {
+                    deployment: String::from("Qm1"),
+                    block_number: 20,
+                    baseline: ComparisonResultType::Match,
+                    alternative: ComparisonResultType::NotFound,
+                },
+                SimulatedOutcome {
+                    deployment: String::from("Qm1"),
+                    block_number: 30,
+                    baseline: ComparisonResultType::NotFound,
+                    alternative: ComparisonResultType::NotFound,
+                },
+            ]
+        );
+        assert_eq!(report.changed().count(), 2);
+        assert_eq!(report.totals().get("Matched"), Some(&(2, 0)));
+        assert_eq!(report.totals().get("NotFound"), Some(&(1, 2)));
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{debug, trace};
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::messages::{
+    heartbeat::HeartbeatMessage, poi::PublicPoiMessage, poi_batch::PublicPoiBatchMessage,
+    poi_request::PoiResponseMessage,
+};
+use crate::operator::{
+    attestation::{ComparisonResult, ComparisonResultType},
+    bisection::DivergenceBisection,
+    equivocation::Equivocation,
+    stake::{EpochStart, SenderStakes},
+    upgrade::UpgradePlan,
+};
+
+use super::{merge::MergeSummary, EvictionReason, MessageRetention, PersistedState};
+
+/// Pending commands before senders wait for the state task to catch up
+const COMMAND_BUFFER: usize = 1024;
+
+/// Longest time a change waits before it shows up in the latest published snapshot
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+type Reply<T> = oneshot::Sender<T>;
+
+/// Commands and queries processed by the state task, in the order they were sent
+enum StateCommand {
+    AddRemoteMessage(GraphcastMessage<PublicPoiMessage>, Reply<usize>),
+    AddPoiBatch(GraphcastMessage<PublicPoiBatchMessage>),
+    OpenPoiRequest(String, u64),
+    AddPoiResponse(GraphcastMessage<PoiResponseMessage>),
+    TakePoiResponses(
+        String,
+        u64,
+        Reply<Vec<GraphcastMessage<PoiResponseMessage>>>,
+    ),
+    HasLocalAttestation(String, u64, Reply<bool>),
+    SaveLocalAttestation {
+        npoi: String,
+        deployment: String,
+        block_number: u64,
+        timestamp: i64,
+    },
+    AddEquivocation(Equivocation, Reply<bool>),
+    AddHeartbeat(HeartbeatMessage),
+    AddUpgradePlan(UpgradePlan, Reply<bool>),
+    /// Mark the upgrade reminders due as sent, then drop the plans whose migrate time passed
+    TakeUpgradeReminders {
+        leads: Vec<i64>,
+        now: i64,
+        reply: Reply<Vec<UpgradePlan>>,
+    },
+    /// Fold a finished comparison into the history and agreements, then drop the messages and attestations it covered
+    RecordComparison {
+        result: ComparisonResult,
+        history_limit: usize,
+        reply: Reply<usize>,
+    },
+    UpdateComparisonResult(ComparisonResult, Reply<ComparisonResultType>),
+    AddBisection {
+        deployment: String,
+        block_number: u64,
+        bisection: DivergenceBisection,
+        reply: Reply<Option<ComparisonResult>>,
+    },
+    ApplyMessageRetention {
+        retention: MessageRetention,
+        topics: Vec<String>,
+        now: i64,
+        reply: Reply<HashMap<(String, EvictionReason), usize>>,
+    },
+    CachedEpoch {
+        at: i64,
+        now: i64,
+        reply: Reply<Option<EpochStart>>,
+    },
+    LatestEpoch(Reply<Option<EpochStart>>),
+    SetEpoch(EpochStart, i64),
+    CachedStakes {
+        senders: Vec<String>,
+        epoch: u64,
+        now: i64,
+        reply: Reply<(SenderStakes, Vec<String>)>,
+    },
+    InsertStakes {
+        epoch: u64,
+        stakes: SenderStakes,
+        now: i64,
+    },
+    /// Add what a restored state knows and the running one does not
+    Merge {
+        state: Box<PersistedState>,
+        history_limit: usize,
+        reply: Reply<MergeSummary>,
+    },
+    Snapshot(Reply<Arc<PersistedState>>),
+}
+
+/// Handle to the radio state, owned by a single task that applies commands one at a time.
+/// Reads go through snapshots, clones of the state that share its unchanged fields, so they never hold up writers
+#[derive(Clone)]
+pub struct StateHandle {
+    commands: mpsc::Sender<StateCommand>,
+    snapshots: watch::Receiver<Arc<PersistedState>>,
+}
+
+impl StateHandle {
+    /// Hand the state over to a new task, which runs until every handle is dropped
+    pub fn spawn(state: PersistedState) -> StateHandle {
+        let (commands, receiver) = mpsc::channel(COMMAND_BUFFER);
+        let (publisher, snapshots) = watch::channel(Arc::new(state.clone()));
+        let actor = StateActor {
+            state,
+            dirty: false,
+            publisher,
+        };
+        tokio::spawn(actor.run(receiver));
+        StateHandle {
+            commands,
+            snapshots,
+        }
+    }
+
+    async fn send(&self, command: StateCommand) {
+        self.commands
+            .send(command)
+            .await
+            .expect("State task stopped");
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> StateCommand) -> T {
+        let (reply, response) = oneshot::channel();
+        self.send(command(reply)).await;
+        response.await.expect("State task dropped the request")
+    }
+
+    /// Snapshot including every command sent before
+    pub async fn snapshot(&self) -> Arc<PersistedState> {
+        self.request(StateCommand::Snapshot).await
+    }
+
+    /// Most recently published snapshot, at most a second behind, readable without waiting on the state task
+    pub fn latest(&self) -> Arc<PersistedState> {
+        self.snapshots.borrow().clone()
+    }
+
+    /// Add a remote message, returns the number of messages cached for its deployment
+    pub async fn add_remote_message(&self, msg: GraphcastMessage<PublicPoiMessage>) -> usize {
+        self.request(|reply| StateCommand::AddRemoteMessage(msg, reply))
+            .await
+    }
+
+    pub async fn add_poi_batch(&self, msg: GraphcastMessage<PublicPoiBatchMessage>) {
+        self.send(StateCommand::AddPoiBatch(msg)).await
+    }
+
+    pub async fn open_poi_request(&self, deployment: String, block_number: u64) {
+        self.send(StateCommand::OpenPoiRequest(deployment, block_number))
+            .await
+    }
+
+    pub async fn add_poi_response(&self, msg: GraphcastMessage<PoiResponseMessage>) {
+        self.send(StateCommand::AddPoiResponse(msg)).await
+    }
+
+    pub async fn take_poi_responses(
+        &self,
+        deployment: String,
+        block_number: u64,
+    ) -> Vec<GraphcastMessage<PoiResponseMessage>> {
+        self.request(|reply| StateCommand::TakePoiResponses(deployment, block_number, reply))
+            .await
+    }
+
+    pub async fn has_local_attestation(&self, deployment: String, block_number: u64) -> bool {
+        self.request(|reply| StateCommand::HasLocalAttestation(deployment, block_number, reply))
+            .await
+    }
+
+    pub async fn save_local_attestation(
+        &self,
+        npoi: String,
+        deployment: String,
+        block_number: u64,
+        timestamp: i64,
+    ) {
+        self.send(StateCommand::SaveLocalAttestation {
+            npoi,
+            deployment,
+            block_number,
+            timestamp,
+        })
+        .await
+    }
+
+    /// Returns true if the equivocation was not known before
+    pub async fn add_equivocation(&self, equivocation: Equivocation) -> bool {
+        self.request(|reply| StateCommand::AddEquivocation(equivocation, reply))
+            .await
+    }
+
+    /// Keep a heartbeat if it is the latest of its indexer
+    pub async fn add_heartbeat(&self, heartbeat: HeartbeatMessage) {
+        self.send(StateCommand::AddHeartbeat(heartbeat)).await
+    }
+
+    /// Returns true if the plan is the latest announced for its deployment
+    pub async fn add_upgrade_plan(&self, plan: UpgradePlan) -> bool {
+        self.request(|reply| StateCommand::AddUpgradePlan(plan, reply))
+            .await
+    }
+
+    /// Upgrade plans with a reminder due, which are not returned again for the same reminder lead
+    pub async fn take_upgrade_reminders(&self, leads: Vec<i64>, now: i64) -> Vec<UpgradePlan> {
+        self.request(|reply| StateCommand::TakeUpgradeReminders { leads, now, reply })
+            .await
+    }
+
+    /// Record a finished comparison, returns the number of messages still cached for its deployment
+    pub async fn record_comparison(&self, result: ComparisonResult, history_limit: usize) -> usize {
+        self.request(|reply| StateCommand::RecordComparison {
+            result,
+            history_limit,
+            reply,
+        })
+        .await
+    }
+
+    /// Returns the result type now reported for the deployment
+    pub async fn update_comparison_result(&self, result: ComparisonResult) -> ComparisonResultType {
+        self.request(|reply| StateCommand::UpdateComparisonResult(result, reply))
+            .await
+    }
+
+    pub async fn add_bisection(
+        &self,
+        deployment: String,
+        block_number: u64,
+        bisection: DivergenceBisection,
+    ) -> Option<ComparisonResult> {
+        self.request(|reply| StateCommand::AddBisection {
+            deployment,
+            block_number,
+            bisection,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn apply_message_retention(
+        &self,
+        retention: MessageRetention,
+        topics: Vec<String>,
+        now: i64,
+    ) -> HashMap<(String, EvictionReason), usize> {
+        self.request(|reply| StateCommand::ApplyMessageRetention {
+            retention,
+            topics,
+            now,
+            reply,
+        })
+        .await
+    }
+
+    /// Epoch in effect at a time, None once the current epoch has to be fetched again
+    pub async fn cached_epoch(&self, at: i64, now: i64) -> Option<EpochStart> {
+        self.request(|reply| StateCommand::CachedEpoch { at, now, reply })
+            .await
+    }
+
+    /// Latest epoch seen, to tell whether the current one is new
+    pub async fn latest_epoch(&self) -> Option<EpochStart> {
+        self.request(StateCommand::LatestEpoch).await
+    }
+
+    pub async fn set_epoch(&self, start: EpochStart, now: i64) {
+        self.send(StateCommand::SetEpoch(start, now)).await
+    }
+
+    pub async fn cached_stakes(
+        &self,
+        senders: Vec<String>,
+        epoch: u64,
+        now: i64,
+    ) -> (SenderStakes, Vec<String>) {
+        self.request(|reply| StateCommand::CachedStakes {
+            senders,
+            epoch,
+            now,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn insert_stakes(&self, epoch: u64, stakes: SenderStakes, now: i64) {
+        self.send(StateCommand::InsertStakes { epoch, stakes, now })
+            .await
+    }
+
+    /// Merge a restored state into the running one, returns what was added
+    pub async fn merge(&self, state: PersistedState, history_limit: usize) -> MergeSummary {
+        self.request(|reply| StateCommand::Merge {
+            state: Box::new(state),
+            history_limit,
+            reply,
+        })
+        .await
+    }
+}
+
+/// Sole owner of the radio state
+struct StateActor {
+    state: PersistedState,
+    /// Whether the state changed since the last published snapshot
+    dirty: bool,
+    publisher: watch::Sender<Arc<PersistedState>>,
+}
+
+impl StateActor {
+    async fn run(mut self, mut commands: mpsc::Receiver<StateCommand>) {
+        let mut publish_interval = interval(SNAPSHOT_INTERVAL);
+        publish_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => self.handle(command),
+                    None => break,
+                },
+                _ = publish_interval.tick() => {
+                    self.publish();
+                }
+            }
+        }
+        self.publish();
+        debug!("State task stopped");
+    }
+
+    /// Publish a snapshot if the state changed since the last one, returns the latest snapshot
+    fn publish(&mut self) -> Arc<PersistedState> {
+        if self.dirty {
+            self.publisher.send_replace(Arc::new(self.state.clone()));
+            self.dirty = false;
+        }
+        self.publisher.borrow().clone()
+    }
+
+    fn handle(&mut self, command: StateCommand) {
+        let state = &mut self.state;
+        // Replies are dropped silently if the requester stopped waiting
+        match command {
+            StateCommand::Snapshot(reply) => {
+                _ = reply.send(self.publish());
+                return;
+            }
+            StateCommand::HasLocalAttestation(deployment, block_number, reply) => {
+                _ = reply.send(state.has_local_attestation(&deployment, block_number));
+                return;
+            }
+            StateCommand::CachedEpoch { at, now, reply } => {
+                _ = reply.send(state.cached_epoch(at, now));
+                return;
+            }
+            StateCommand::LatestEpoch(reply) => {
+                _ = reply.send(state.latest_epoch());
+                return;
+            }
+            StateCommand::CachedStakes {
+                senders,
+                epoch,
+                now,
+                reply,
+            } => {
+                _ = reply.send(state.cached_stakes(&senders, epoch, now));
+                return;
+            }
+            StateCommand::AddRemoteMessage(msg, reply) => {
+                _ = reply.send(state.add_remote_message(msg));
+            }
+            StateCommand::AddPoiBatch(msg) => {
+                state.add_poi_batch(msg);
+            }
+            StateCommand::OpenPoiRequest(deployment, block_number) => {
+                state.open_poi_request(deployment, block_number)
+            }
+            StateCommand::AddPoiResponse(msg) => state.add_poi_response(msg),
+            StateCommand::TakePoiResponses(deployment, block_number, reply) => {
+                _ = reply.send(state.take_poi_responses(deployment, block_number));
+            }
+            StateCommand::SaveLocalAttestation {
+                npoi,
+                deployment,
+                block_number,
+                timestamp,
+            } => state.save_local_attestation(npoi, deployment, block_number, timestamp),
+            StateCommand::AddHeartbeat(heartbeat) => {
+                state.add_heartbeat(heartbeat);
+            }
+            StateCommand::AddUpgradePlan(plan, reply) => {
+                _ = reply.send(state.add_upgrade_plan(plan));
+            }
+            StateCommand::TakeUpgradeReminders { leads, now, reply } => {
+                _ = reply.send(state.take_upgrade_reminders(&leads, now));
+            }
+            StateCommand::AddEquivocation(equivocation, reply) => {
+                _ = reply.send(state.add_equivocation(equivocation));
+            }
+            StateCommand::RecordComparison {
+                result,
+                history_limit,
+                reply,
+            } => {
+                let deployment = result.deployment.clone();
+                let block_number = result.block_number;
+                state.update_indexer_agreements(&result);
+                state.add_comparison_history(result, history_limit);
+                // Only clear the ones matching identifier and block number equal or less
+                state.clean_local_attestations(block_number, deployment.clone());
+                state.clean_remote_messages(block_number, deployment.clone());
+                _ = reply.send(state.remote_messages.deployment_len(&deployment));
+            }
+            StateCommand::UpdateComparisonResult(result, reply) => {
+                _ = reply.send(state.update_comparison_result(result));
+            }
+            StateCommand::AddBisection {
+                deployment,
+                block_number,
+                bisection,
+                reply,
+            } => {
+                _ = reply.send(state.add_bisection(&deployment, block_number, bisection));
+            }
+            StateCommand::ApplyMessageRetention {
+                retention,
+                topics,
+                now,
+                reply,
+            } => {
+                _ = reply.send(state.apply_message_retention(&retention, &topics, now));
+            }
+            StateCommand::SetEpoch(start, now) => state.set_epoch(start, now),
+            StateCommand::InsertStakes { epoch, stakes, now } => {
+                state.insert_stakes(epoch, stakes, now)
+            }
+            StateCommand::Merge {
+                state: restored,
+                history_limit,
+                reply,
+            } => {
+                _ = reply.send(state.merge(&restored, history_limit));
+            }
+        }
+        trace!("State updated");
+        self.dirty = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(deployment: &str, block: u64) -> GraphcastMessage<PublicPoiMessage> {
+        GraphcastMessage {
+            identifier: deployment.to_string(),
+            nonce: block as i64,
+            graph_account: String::from("0xa1"),
+            payload: PublicPoiMessage {
+                identifier: deployment.to_string(),
+                content: String::from("npoi-x"),
+                nonce: block as i64,
+                network: String::from("goerli"),
+                block_number: block,
+                block_hash: String::from("0xblahh"),
+                graph_account: String::from("0xa1"),
+                version: 0,
+            },
+            signature: format!("{deployment}-{block}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_handle_snapshots() {
+        let state = StateHandle::spawn(PersistedState::new(None, None, None));
+        assert_eq!(state.add_remote_message(message("Qm1", 1)).await, 1);
+        assert_eq!(state.add_remote_message(message("Qm1", 2)).await, 2);
+        state
+            .save_local_attestation(String::from("npoi-x"), String::from("Qm1"), 1, 0)
+            .await;
+        assert!(state.has_local_attestation(String::from("Qm1"), 1).await);
+
+        let snapshot = state.snapshot().await;
+        assert_eq!(snapshot.remote_messages().len(), 2);
+        assert!(snapshot.local_attestation(String::from("Qm1"), 1).is_some());
+
+        // Snapshots are not affected by later commands
+        state.add_remote_message(message("Qm2", 1)).await;
+        assert_eq!(snapshot.remote_messages().len(), 2);
+        assert_eq!(state.snapshot().await.remote_messages().len(), 3);
+        assert_eq!(state.latest().remote_messages().len(), 3);
+
+        state.open_poi_request(String::from("Qm1"), 1).await;
+        assert!(state
+            .take_poi_responses(String::from("Qm1"), 1)
+            .await
+            .is_empty());
+    }
+}
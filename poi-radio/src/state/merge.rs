@@ -0,0 +1,455 @@
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+use graphcast_sdk::{
+    callbook::CallBook,
+    graphcast_agent::message_typing::{BuildMessageError, IdentityValidation},
+};
+use serde_derive::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::messages::poi::PublicPoiMessage;
+use crate::storage::{migration::migrate, StorageError};
+
+use super::PersistedState;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("Invalid state: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+/// What a restore added to the running state
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    pub local_attestations: usize,
+    pub remote_messages: usize,
+    /// Deployments whose latest comparison result was replaced by a later block
+    pub comparison_results: usize,
+    pub comparison_history: usize,
+    pub indexer_agreements: usize,
+    pub equivocations: usize,
+    pub stake_snapshots: usize,
+    /// Indexers whose heartbeat was missing here or replaced by a later one
+    pub heartbeats: usize,
+    /// Deployments whose upgrade plan was missing here or replaced by a later announcement
+    pub upgrade_plans: usize,
+}
+
+/// Parse an exported state, at any schema version this radio can migrate, and check it is consistent
+pub fn parse_snapshot(bytes: &[u8]) -> Result<PersistedState, RestoreError> {
+    let value = migrate(serde_json::from_slice(bytes).map_err(StorageError::from)?)?;
+    let state: PersistedState = serde_json::from_value(value).map_err(StorageError::from)?;
+    let problems = state.problems();
+    if problems.is_empty() {
+        Ok(state)
+    } else {
+        Err(RestoreError::Invalid(problems))
+    }
+}
+
+/// Drop the remote messages of a restored state whose signer is not a valid sender for the graph account they claim,
+/// under the same identity validation as gossiped messages. Returns the signatures of the dropped messages
+pub async fn drop_unverified_senders(
+    state: &mut PersistedState,
+    callbook: &CallBook,
+    local_sender: &str,
+    id_validation: &IdentityValidation,
+) -> Vec<String> {
+    let (registry, network) = (callbook.graphcast_registry(), callbook.graph_network());
+    let mut dropped = HashSet::new();
+    let mut verified = HashSet::new();
+    for msg in state.remote_messages.all() {
+        if verified.contains(&msg.signature) || dropped.contains(&msg.signature) {
+            continue;
+        }
+        // Messages of a batch are signed as part of the batch
+        let valid = match state.poi_batch(&msg) {
+            Some(batch) => batch
+                .valid_sender(registry, network, local_sender.to_string(), id_validation)
+                .await
+                .is_ok(),
+            None => msg
+                .valid_sender(registry, network, local_sender.to_string(), id_validation)
+                .await
+                .is_ok(),
+        };
+        if valid {
+            verified.insert(msg.signature);
+        } else {
+            dropped.insert(msg.signature);
+        }
+    }
+    if !dropped.is_empty() {
+        Arc::make_mut(&mut state.remote_messages).retain(|msg| !dropped.contains(&msg.signature));
+    }
+    dropped.into_iter().collect()
+}
+
+impl PersistedState {
+    /// Inconsistencies that would trip up the radio if the state was merged in
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (deployment, blocks) in self.local_attestations.iter() {
+            for (block, attestation) in blocks {
+                // Collect windows open at the first timestamp
+                if attestation.timestamp.is_empty() {
+                    problems.push(format!(
+                        "Local attestation of {deployment} at block {block} has no timestamp"
+                    ));
+                }
+            }
+        }
+        for msg in self.remote_messages.all() {
+            // Whether the signer may speak for the graph account is checked against the network before merging
+            let signer = match self.poi_batches.get(&msg.signature) {
+                Some(batch) if !batch.payload.carries(batch, &msg) => {
+                    Err(BuildMessageError::InvalidFields(anyhow::anyhow!(
+                        "Not an entry of the batch with its signature"
+                    )))
+                }
+                Some(batch) => batch
+                    .payload
+                    .valid_outer(batch)
+                    .and_then(|_| batch.recover_sender_address()),
+                None => msg.recover_sender_address(),
+            };
+            if let Err(e) = msg
+                .payload
+                .valid_version()
+                .and_then(|payload| payload.valid_outer(&msg))
+                .and(signer)
+            {
+                problems.push(format!(
+                    "Remote message {} from {}: {e}",
+                    msg.signature, msg.graph_account
+                ));
+            }
+        }
+        for (deployment, result) in self.comparison_results.iter() {
+            if &result.deployment != deployment {
+                problems.push(format!(
+                    "Comparison result of {} stored under {deployment}",
+                    result.deployment
+                ));
+            }
+        }
+        for (deployment, blocks) in self.comparison_history.iter() {
+            for (block, result) in blocks {
+                if &result.deployment != deployment || result.block_number != *block {
+                    problems.push(format!(
+                        "Comparison result of {} at block {} stored under {deployment} at block {block}",
+                        result.deployment, result.block_number
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Add what another state knows and this one does not. Entries present in both are kept as they are here,
+    /// except the latest comparison result of a deployment, the latest heartbeat of an indexer and the upgrade plan
+    /// of a deployment, which are replaced by later ones
+    pub fn merge(&mut self, other: &PersistedState, history_limit: usize) -> MergeSummary {
+        let mut summary = MergeSummary::default();
+
+        for (deployment, blocks) in other.local_attestations.iter() {
+            for (block, attestation) in blocks {
+                if !self.has_local_attestation(deployment, *block) {
+                    Arc::make_mut(&mut self.local_attestations)
+                        .entry(deployment.clone())
+                        .or_default()
+                        .insert(*block, attestation.clone());
+                    summary.local_attestations += 1;
+                }
+            }
+        }
+
+        // Messages of a batch share its signature
+        let key = |msg: &GraphcastMessage<PublicPoiMessage>| {
+            (
+                msg.signature.clone(),
+                msg.identifier.clone(),
+                msg.payload.block_number,
+            )
+        };
+        let mut known: HashSet<(String, String, u64)> =
+            self.remote_messages.all().iter().map(key).collect();
+        for msg in other.remote_messages() {
+            if known.insert(key(&msg)) {
+                if let Some(batch) = other.poi_batch(&msg) {
+                    self.add_poi_batch(batch.clone());
+                }
+                self.add_remote_message(msg);
+                summary.remote_messages += 1;
+            }
+        }
+
+        for (deployment, result) in other.comparison_results.iter() {
+            let is_later = match self.comparison_results.get(deployment) {
+                Some(existing) => existing.block_number < result.block_number,
+                None => true,
+            };
+            if is_later {
+                self.add_comparison_result(result.clone());
+                summary.comparison_results += 1;
+            }
+        }
+
+        for (deployment, blocks) in other.comparison_history.iter() {
+            for (block, result) in blocks {
+                let is_known = self
+                    .comparison_history
+                    .get(deployment)
+                    .is_some_and(|blocks| blocks.contains_key(block));
+                if !is_known {
+                    self.add_comparison_history(result.clone(), history_limit);
+                    summary.comparison_history += 1;
+                }
+            }
+        }
+
+        for (address, agreement) in other.indexer_agreements.iter() {
+            if !self.indexer_agreements.contains_key(address) {
+                Arc::make_mut(&mut self.indexer_agreements)
+                    .insert(address.clone(), agreement.clone());
+                summary.indexer_agreements += 1;
+            }
+        }
+
+        for equivocation in other.equivocations() {
+            if self.add_equivocation(equivocation) {
+                summary.equivocations += 1;
+            }
+        }
+
+        for (epoch, stakes) in other.stake_snapshots.iter() {
+            for (address, stake) in stakes {
+                if self.stake_snapshot(*epoch, address).is_none() {
+                    self.add_stake_snapshot(*epoch, address.clone(), stake.clone());
+                    summary.stake_snapshots += 1;
+                }
+            }
+        }
+
+        for heartbeat in other.heartbeats() {
+            if self.add_heartbeat(heartbeat) {
+                summary.heartbeats += 1;
+            }
+        }
+
+        for plan in other.upgrade_plans() {
+            if self.add_upgrade_plan(plan) {
+                summary.upgrade_plans += 1;
+            }
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+    use crate::messages::poi_batch::PublicPoiBatchMessage;
+    use crate::operator::attestation::{ComparisonResult, ComparisonResultType};
+    use crate::operator::consensus::ConsensusPolicyType;
+    use crate::operator::stake::Stake;
+    use crate::storage::migration::versioned;
+
+    const SIGNER_KEYS: [&str; 2] = [
+        "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d",
+    ];
+
+    fn message_payload(block: u64, sender: &str) -> PublicPoiMessage {
+        PublicPoiMessage {
+            identifier: String::from("Qm1"),
+            content: String::from("npoi-remote"),
+            nonce: block as i64,
+            network: String::from("goerli"),
+            block_number: block,
+            block_hash: String::from("0xblahh"),
+            graph_account: sender.to_string(),
+            version: 0,
+        }
+    }
+
+    async fn message(block: u64, signer: usize) -> GraphcastMessage<PublicPoiMessage> {
+        let wallet: LocalWallet = SIGNER_KEYS[signer].parse().unwrap();
+        let sender = format!("{:#x}", wallet.address());
+        let payload = message_payload(block, &sender);
+        GraphcastMessage::build(&wallet, String::from("Qm1"), sender, block as i64, payload)
+            .await
+            .unwrap()
+    }
+
+    fn result(block_number: u64, result_type: ComparisonResultType) -> ComparisonResult {
+        ComparisonResult {
+            deployment: String::from("Qm1"),
+            block_number,
+            result_type,
+            local_attestation: None,
+            attestations: vec![],
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_merges_snapshot() {
+        let mut running = PersistedState::new(None, None, None);
+        running.save_local_attestation(String::from("npoi-running"), String::from("Qm1"), 10, 0);
+        running.add_remote_message(message(10, 0).await);
+        running.add_comparison_result(result(5, ComparisonResultType::Match));
+        running.add_stake_snapshot(1, String::from("0xa1"), Stake::from_grt(1));
+
+        let mut restored = PersistedState::new(None, None, None);
+        restored.save_local_attestation(String::from("npoi-restored"), String::from("Qm1"), 10, 0);
+        restored.save_local_attestation(String::from("npoi-restored"), String::from("Qm1"), 20, 0);
+        restored.add_remote_message(message(10, 0).await);
+        restored.add_remote_message(message(20, 1).await);
+        restored.add_comparison_result(result(8, ComparisonResultType::Divergent));
+        restored.add_comparison_history(result(8, ComparisonResultType::Divergent), 10);
+        restored.add_stake_snapshot(1, String::from("0xa1"), Stake::from_grt(2));
+        restored.add_stake_snapshot(1, String::from("0xa2"), Stake::from_grt(2));
+
+        let bytes =
+            serde_json::to_vec(&versioned(serde_json::to_value(&restored).unwrap()).unwrap())
+                .unwrap();
+        let summary = running.merge(&parse_snapshot(&bytes).unwrap(), 10);
+        assert_eq!(
+            summary,
+            MergeSummary {
+                local_attestations: 1,
+                remote_messages: 1,
+                comparison_results: 1,
+                comparison_history: 1,
+                indexer_agreements: 0,
+                equivocations: 0,
+                stake_snapshots: 1,
+                heartbeats: 0,
+                upgrade_plans: 0,
+            }
+        );
+        // Entries known to both keep the running value
+        assert_eq!(
+            running
+                .local_attestation(String::from("Qm1"), 10)
+                .unwrap()
+                .npoi,
+            "npoi-running"
+        );
+        assert_eq!(running.stake_snapshot(1, "0xa1"), Some(Stake::from_grt(1)));
+        assert_eq!(running.remote_messages().len(), 2);
+        assert_eq!(
+            running
+                .comparison_result(String::from("Qm1"))
+                .unwrap()
+                .block_number,
+            8
+        );
+        // Merging again adds nothing
+        assert_eq!(running.merge(&restored, 10), MergeSummary::default());
+    }
+
+    #[tokio::test]
+    async fn test_restore_rejects_invalid_snapshot() {
+        let mut state = PersistedState::new(None, None, None);
+        let mut msg = message(10, 0).await;
+        msg.payload.graph_account = String::from("0xa2");
+        state.add_remote_message(msg);
+        let mut msg = message(20, 0).await;
+        msg.signature = String::from("20-0xa1");
+        state.add_remote_message(msg);
+        let bytes = serde_json::to_vec(&state).unwrap();
+        assert!(matches!(
+            parse_snapshot(&bytes),
+            Err(RestoreError::Invalid(problems)) if problems.len() == 2
+        ));
+        assert!(matches!(
+            parse_snapshot(b"[]"),
+            Err(RestoreError::Storage(StorageError::Migration(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_restore_drops_unverified_senders() {
+        let mut state = PersistedState::new(None, None, None);
+        let own = message(10, 0).await;
+        let remote = message(10, 1).await;
+        state.add_remote_message(own.clone());
+        state.add_remote_message(remote.clone());
+
+        // Messages signed by this radio are not accepted from a restore either
+        let callbook = CallBook::new(String::new(), String::new(), None);
+        let dropped = drop_unverified_senders(
+            &mut state,
+            &callbook,
+            &own.recover_sender_address().unwrap(),
+            &IdentityValidation::ValidAddress,
+        )
+        .await;
+        assert_eq!(dropped, vec![own.signature]);
+        assert_eq!(
+            state
+                .remote_messages()
+                .into_iter()
+                .map(|msg| msg.signature)
+                .collect::<Vec<_>>(),
+            vec![remote.signature]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_checks_batch_entries() {
+        let wallet: LocalWallet = SIGNER_KEYS[0].parse().unwrap();
+        let sender = format!("{:#x}", wallet.address());
+        let entries: Vec<PublicPoiMessage> = ["Qm1", "Qm2"]
+            .into_iter()
+            .map(|deployment| PublicPoiMessage {
+                identifier: deployment.to_string(),
+                ..message_payload(10, &sender)
+            })
+            .collect();
+        let batch = PublicPoiBatchMessage::build(10, sender.clone(), &entries);
+        let batch = GraphcastMessage::build(&wallet, batch.identifier.clone(), sender, 10, batch)
+            .await
+            .unwrap();
+        let mut restored = PersistedState::new(None, None, None);
+        restored.add_poi_batch(batch.clone());
+        for msg in batch.payload.expand(&batch) {
+            restored.add_remote_message(msg);
+        }
+
+        // Entries sharing the batch signature are all merged, along with the batch
+        let bytes = serde_json::to_vec(&restored).unwrap();
+        let mut running = PersistedState::new(None, None, None);
+        let summary = running.merge(&parse_snapshot(&bytes).unwrap(), 10);
+        assert_eq!(summary.remote_messages, 2);
+        assert_eq!(running.poi_batches.len(), 1);
+        let dropped = drop_unverified_senders(
+            &mut running,
+            &CallBook::new(String::new(), String::new(), None),
+            "0x0",
+            &IdentityValidation::ValidAddress,
+        )
+        .await;
+        assert!(dropped.is_empty());
+
+        // An entry the batch does not carry cannot borrow its signature
+        let mut forged = batch.payload.expand(&batch).remove(0);
+        forged.payload.content = String::from("npoi-forged");
+        restored.add_remote_message(forged);
+        let bytes = serde_json::to_vec(&restored).unwrap();
+        assert!(matches!(
+            parse_snapshot(&bytes),
+            Err(RestoreError::Invalid(problems)) if problems.len() == 1
+        ));
+    }
+}
@@ -0,0 +1,1128 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::panic;
+use std::panic::PanicInfo;
+use std::sync::Arc;
+use tracing::{debug, trace};
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::messages::cache::RemoteMessages;
+use crate::messages::heartbeat::HeartbeatMessage;
+use crate::messages::poi_batch::PublicPoiBatchMessage;
+use crate::messages::poi_request::PoiResponseMessage;
+use crate::operator::attestation::{
+    clear_local_attestation, save_local_attestation_at, ComparisonResult, ComparisonResultType,
+    LocalAttestationsMap,
+};
+use crate::operator::bisection::DivergenceBisection;
+use crate::operator::equivocation::{Equivocation, EquivocationLog};
+use crate::operator::reputation::{update_agreements, IndexerAgreement, SenderWeights};
+use crate::operator::stake::{EpochStart, SenderStakes, Stake, StakeCache, STAKE_SNAPSHOT_EPOCHS};
+use crate::operator::upgrade::UpgradePlan;
+use crate::storage::StateStore;
+use crate::RADIO_OPERATOR;
+
+use crate::{messages::poi::PublicPoiMessage, operator::attestation::Attestation};
+
+pub mod actor;
+pub mod merge;
+pub mod summary;
+
+pub use actor::StateHandle;
+pub use summary::StateSummary;
+
+// Fields are shared with the clones of the state and copied on their first change after cloning,
+// so publishing a snapshot of the state does not copy it
+type Local = Arc<LocalAttestationsMap>;
+type Remote = Arc<RemoteMessages>;
+type ComparisonResults = Arc<HashMap<String, ComparisonResult>>;
+type ComparisonHistory = Arc<HashMap<String, BTreeMap<u64, ComparisonResult>>>;
+type IndexerAgreements = Arc<HashMap<String, IndexerAgreement>>;
+type Equivocations = Arc<EquivocationLog>;
+type StakeSnapshots = Arc<BTreeMap<u64, HashMap<String, Stake>>>;
+type StakeCaches = Arc<StakeCache>;
+type Heartbeats = Arc<HashMap<String, HeartbeatMessage>>;
+type UpgradePlans = Arc<HashMap<String, UpgradePlan>>;
+type PoiBatches = Arc<HashMap<String, GraphcastMessage<PublicPoiBatchMessage>>>;
+type PoiResponses = Arc<HashMap<(String, u64), Vec<GraphcastMessage<PoiResponseMessage>>>>;
+
+/// Bounds on the remote messages kept in the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageRetention {
+    /// Seconds a message is kept after its nonce timestamp
+    pub max_age: i64,
+    pub max_per_deployment: usize,
+}
+
+/// Why a remote message was evicted from the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    Age,
+    Count,
+    Unsubscribed,
+}
+
+impl EvictionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionReason::Age => "age",
+            EvictionReason::Count => "count",
+            EvictionReason::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PersistedState {
+    pub local_attestations: Local,
+    pub remote_messages: Remote,
+    pub comparison_results: ComparisonResults,
+    /// Comparison results by deployment and block, bounded per deployment
+    #[serde(default)]
+    pub comparison_history: ComparisonHistory,
+    /// Track record of remote senders by address
+    #[serde(default)]
+    pub indexer_agreements: IndexerAgreements,
+    /// Evidence of indexers signing different nPOIs for the same deployment and block
+    #[serde(default)]
+    pub equivocations: Equivocations,
+    /// Indexer stakes at the start of recent protocol epochs, keyed by epoch and indexer address
+    #[serde(default)]
+    pub stake_snapshots: StakeSnapshots,
+    /// Latest heartbeat of each indexer, keyed by graph account
+    #[serde(default)]
+    pub heartbeats: Heartbeats,
+    /// Pending upgrades announced by subgraph owners, keyed by the deployment they upgrade from
+    #[serde(default)]
+    pub upgrade_plans: UpgradePlans,
+    /// Signed batches the cached remote messages were expanded from, keyed by signature
+    #[serde(default)]
+    pub poi_batches: PoiBatches,
+    /// Recently fetched stakes shared by comparisons and the API, of which only the epochs seen are persisted
+    #[serde(default)]
+    pub stake_cache: StakeCaches,
+    /// Responses to in-flight nPOI requests keyed by deployment and block, only relevant while the radio is running
+    #[serde(skip)]
+    pub poi_responses: PoiResponses,
+}
+
+impl PersistedState {
+    pub fn new(
+        local: Option<Local>,
+        remote: Option<Remote>,
+        comparison_results: Option<ComparisonResults>,
+    ) -> PersistedState {
+        PersistedState {
+            local_attestations: local.unwrap_or_default(),
+            remote_messages: remote.unwrap_or_default(),
+            comparison_results: comparison_results.unwrap_or_default(),
+            comparison_history: Arc::default(),
+            indexer_agreements: Arc::default(),
+            equivocations: Arc::default(),
+            stake_snapshots: Arc::default(),
+            heartbeats: Arc::default(),
+            upgrade_plans: Arc::default(),
+            poi_batches: Arc::default(),
+            stake_cache: Arc::default(),
+            poi_responses: Arc::default(),
+        }
+    }
+
+    /// Getter for local_attestations
+    pub fn local_attestations(&self) -> HashMap<String, HashMap<u64, Attestation>> {
+        self.local_attestations.as_ref().clone()
+    }
+
+    /// Getter for one local_attestation
+    pub fn local_attestation(&self, deployment: String, block_number: u64) -> Option<Attestation> {
+        match self.local_attestations.get(&deployment) {
+            None => None,
+            Some(blocks_map) => blocks_map.get(&block_number).cloned(),
+        }
+    }
+
+    /// Getter for remote_messages
+    pub fn remote_messages(&self) -> Vec<GraphcastMessage<PublicPoiMessage>> {
+        self.remote_messages.all()
+    }
+
+    /// Getter for comparison_results
+    pub fn comparison_results(&self) -> HashMap<String, ComparisonResult> {
+        self.comparison_results.as_ref().clone()
+    }
+
+    /// Getter for comparison result
+    pub fn comparison_result(&self, deployment: String) -> Option<ComparisonResult> {
+        self.comparison_results.get(&deployment).cloned()
+    }
+
+    /// Getter for the comparison history of a deployment within an inclusive block range, ordered by block
+    pub fn comparison_history(
+        &self,
+        deployment: &str,
+        from_block: Option<u64>,
+        to_block: Option<u64>,
+    ) -> Vec<ComparisonResult> {
+        let from_block = from_block.unwrap_or(u64::MIN);
+        let to_block = to_block.unwrap_or(u64::MAX);
+        if from_block > to_block {
+            return vec![];
+        }
+        self.comparison_history
+            .get(deployment)
+            .map(|blocks| {
+                blocks
+                    .range(from_block..=to_block)
+                    .map(|(_, result)| result.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Latest matched block of a deployment and the first divergent block after it from the comparison history
+    /// The divergent block is None if the deployment has not diverged since it last matched
+    pub fn divergence_bounds(&self, deployment: &str) -> (Option<u64>, Option<u64>) {
+        let blocks = match self.comparison_history.get(deployment) {
+            Some(blocks) => blocks,
+            None => return (None, None),
+        };
+        let last_matched_block = blocks
+            .iter()
+            .rev()
+            .find(|(_, r)| r.result_type == ComparisonResultType::Match)
+            .map(|(block, _)| *block);
+        let first_divergent_block = blocks
+            .range(last_matched_block.map_or(u64::MIN, |b| b + 1)..)
+            .find(|(_, r)| r.result_type == ComparisonResultType::Divergent)
+            .map(|(block, _)| *block);
+        (last_matched_block, first_divergent_block)
+    }
+
+    /// Getter for indexer_agreements, ordered by address
+    pub fn indexer_agreements(&self) -> Vec<IndexerAgreement> {
+        let mut agreements: Vec<IndexerAgreement> =
+            self.indexer_agreements.values().cloned().collect();
+        agreements.sort_by(|a, b| a.address.cmp(&b.address));
+        agreements
+    }
+
+    /// Getter for equivocations
+    pub fn equivocations(&self) -> Vec<Equivocation> {
+        self.equivocations.all()
+    }
+
+    /// Latest heartbeat of every indexer, sorted by graph account
+    pub fn heartbeats(&self) -> Vec<HeartbeatMessage> {
+        let mut heartbeats: Vec<HeartbeatMessage> = self.heartbeats.values().cloned().collect();
+        heartbeats.sort_by(|a, b| a.graph_account.cmp(&b.graph_account));
+        heartbeats
+    }
+
+    /// Keep a heartbeat unless a later one of the same indexer is known, returns true if it was kept
+    pub fn add_heartbeat(&mut self, heartbeat: HeartbeatMessage) -> bool {
+        let is_latest = match self.heartbeats.get(&heartbeat.graph_account) {
+            Some(existing) => existing.nonce < heartbeat.nonce,
+            None => true,
+        };
+        if is_latest {
+            Arc::make_mut(&mut self.heartbeats).insert(heartbeat.graph_account.clone(), heartbeat);
+        }
+        is_latest
+    }
+
+    /// Pending upgrade plans, sorted by migrate time
+    pub fn upgrade_plans(&self) -> Vec<UpgradePlan> {
+        let mut plans: Vec<UpgradePlan> = self.upgrade_plans.values().cloned().collect();
+        plans.sort_by(|a, b| (a.migrate_time, &a.deployment).cmp(&(b.migrate_time, &b.deployment)));
+        plans
+    }
+
+    /// Keep an upgrade plan unless a later announcement for the same deployment is known, returns true if it was kept
+    pub fn add_upgrade_plan(&mut self, plan: UpgradePlan) -> bool {
+        let is_latest = match self.upgrade_plans.get(&plan.deployment) {
+            Some(existing) => existing.nonce < plan.nonce,
+            None => true,
+        };
+        if is_latest {
+            Arc::make_mut(&mut self.upgrade_plans).insert(plan.deployment.clone(), plan);
+        }
+        is_latest
+    }
+
+    /// Plans with a reminder due, recorded as reminded. Plans whose migrate time passed are dropped afterwards
+    pub fn take_upgrade_reminders(&mut self, leads: &[i64], now: i64) -> Vec<UpgradePlan> {
+        let plans = Arc::make_mut(&mut self.upgrade_plans);
+        let mut due = vec![];
+        for plan in plans.values_mut() {
+            if let Some(lead) = plan.due_reminder(leads, now) {
+                plan.reminded_lead = Some(lead);
+                due.push(plan.clone());
+            }
+        }
+        plans.retain(|_, plan| plan.is_pending(now));
+        due.sort_by(|a, b| (a.migrate_time, &a.deployment).cmp(&(b.migrate_time, &b.deployment)));
+        due
+    }
+
+    /// Stake multipliers for senders that agreed with the majority less often than the threshold
+    pub fn sender_weights(&self, threshold: f32) -> SenderWeights {
+        self.indexer_agreements
+            .iter()
+            .filter_map(|(address, record)| {
+                record
+                    .weight(threshold)
+                    .map(|weight| (address.clone(), weight))
+            })
+            .collect()
+    }
+
+    /// Add message to remote_messages, returns the number of messages cached for its deployment
+    /// Generalize PublicPoiMessage
+    pub fn add_remote_message(&mut self, msg: GraphcastMessage<PublicPoiMessage>) -> usize {
+        trace!(msg = tracing::field::debug(&msg), "adding remote message");
+        Arc::make_mut(&mut self.remote_messages).insert(msg)
+    }
+
+    /// Keep a batch the cached messages carrying its signature were expanded from
+    pub fn add_poi_batch(&mut self, msg: GraphcastMessage<PublicPoiBatchMessage>) {
+        if !self.poi_batches.contains_key(&msg.signature) {
+            Arc::make_mut(&mut self.poi_batches).insert(msg.signature.clone(), msg);
+        }
+    }
+
+    /// Batch a cached message was expanded from, None for messages gossiped on their own
+    pub fn poi_batch(
+        &self,
+        msg: &GraphcastMessage<PublicPoiMessage>,
+    ) -> Option<&GraphcastMessage<PublicPoiBatchMessage>> {
+        self.poi_batches
+            .get(&msg.signature)
+            .filter(|batch| batch.payload.carries(batch, msg))
+    }
+
+    /// Drop the batches no cached message was expanded from anymore
+    fn prune_poi_batches(&mut self) {
+        let signatures: HashSet<String> = self
+            .remote_messages
+            .all()
+            .into_iter()
+            .map(|msg| msg.signature)
+            .collect();
+        if self
+            .poi_batches
+            .keys()
+            .any(|signature| !signatures.contains(signature))
+        {
+            Arc::make_mut(&mut self.poi_batches)
+                .retain(|signature, _| signatures.contains(signature));
+        }
+    }
+
+    /// Whether an nPOI was already attested locally for a deployment at a block
+    pub fn has_local_attestation(&self, deployment: &str, block_number: u64) -> bool {
+        self.local_attestations
+            .get(deployment)
+            .is_some_and(|blocks| blocks.contains_key(&block_number))
+    }
+
+    /// Save an nPOI generated locally at a given time, to compare it with remote ones later
+    pub fn save_local_attestation(
+        &mut self,
+        npoi: String,
+        deployment: String,
+        block_number: u64,
+        timestamp: i64,
+    ) {
+        save_local_attestation_at(
+            Arc::make_mut(&mut self.local_attestations),
+            npoi,
+            deployment,
+            block_number,
+            timestamp,
+        );
+    }
+
+    /// Start collecting poi_responses for a deployment at a block
+    pub fn open_poi_request(&mut self, deployment: String, block_number: u64) {
+        Arc::make_mut(&mut self.poi_responses)
+            .entry((deployment, block_number))
+            .or_default();
+    }
+
+    /// Add message to poi_responses if a request for its deployment and block is open
+    pub fn add_poi_response(&mut self, msg: GraphcastMessage<PoiResponseMessage>) {
+        let key = (msg.identifier.clone(), msg.payload.block_number);
+        match Arc::make_mut(&mut self.poi_responses).get_mut(&key) {
+            Some(responses) => {
+                trace!(msg = tracing::field::debug(&msg), "adding poi response");
+                responses.push(msg)
+            }
+            None => trace!(
+                msg = tracing::field::debug(&msg),
+                "No open request for poi response, skipped"
+            ),
+        }
+    }
+
+    /// Close the request for a deployment at a block and return the poi_responses collected
+    pub fn take_poi_responses(
+        &mut self,
+        deployment: String,
+        block_number: u64,
+    ) -> Vec<GraphcastMessage<PoiResponseMessage>> {
+        Arc::make_mut(&mut self.poi_responses)
+            .remove(&(deployment, block_number))
+            .unwrap_or_default()
+    }
+
+    /// Add equivocation evidence, merged into the existing entry for the same deployment, block and sender.
+    /// The batches the messages were expanded from are added to the evidence
+    /// Returns true if the equivocation was not known before
+    pub fn add_equivocation(&mut self, mut equivocation: Equivocation) -> bool {
+        let batches: Vec<_> = equivocation
+            .messages
+            .iter()
+            .filter_map(|msg| self.poi_batch(msg).cloned())
+            .collect();
+        for batch in batches {
+            equivocation.add_batch(batch);
+        }
+        Arc::make_mut(&mut self.equivocations).insert(equivocation)
+    }
+
+    /// Getter for the stake snapshot of an indexer at an epoch
+    pub fn stake_snapshot(&self, epoch: u64, indexer_address: &str) -> Option<Stake> {
+        self.stake_snapshots
+            .get(&epoch)
+            .and_then(|stakes| stakes.get(indexer_address).cloned())
+    }
+
+    /// Set how long cached stakes are used before they are fetched again
+    pub fn set_stake_cache_ttl(&mut self, ttl: i64) {
+        Arc::make_mut(&mut self.stake_cache).set_ttl(ttl);
+    }
+
+    /// Cached epoch in effect at a time, None once the current epoch expired from the cache
+    pub fn cached_epoch(&self, at: i64, now: i64) -> Option<EpochStart> {
+        self.stake_cache.epoch(at, now)
+    }
+
+    /// Latest epoch in the cache, even once it has to be fetched again
+    pub fn latest_epoch(&self) -> Option<EpochStart> {
+        self.stake_cache.latest_epoch()
+    }
+
+    /// Cache the current epoch
+    pub fn set_epoch(&mut self, start: EpochStart, now: i64) {
+        Arc::make_mut(&mut self.stake_cache).set_epoch(start, now);
+    }
+
+    /// Stakes of the senders at an epoch from the cache or the persisted snapshots of the epoch,
+    /// along with the senders missing from both
+    pub fn cached_stakes(
+        &self,
+        senders: &[String],
+        epoch: u64,
+        now: i64,
+    ) -> (SenderStakes, Vec<String>) {
+        let mut stakes = SenderStakes::new();
+        let mut missing: Vec<String> = vec![];
+        for sender in senders {
+            if stakes.contains_key(sender) || missing.contains(sender) {
+                continue;
+            }
+            match self
+                .stake_cache
+                .stake(sender, epoch, now)
+                .or_else(|| self.stake_snapshot(epoch, sender))
+            {
+                Some(stake) => {
+                    stakes.insert(sender.clone(), stake);
+                }
+                None => missing.push(sender.clone()),
+            }
+        }
+        (stakes, missing)
+    }
+
+    /// Cache stakes resolved at an epoch and keep them as snapshots of that epoch
+    pub fn insert_stakes(&mut self, epoch: u64, stakes: SenderStakes, now: i64) {
+        for (sender, stake) in stakes {
+            Arc::make_mut(&mut self.stake_cache).insert(sender.clone(), epoch, stake.clone(), now);
+            self.add_stake_snapshot(epoch, sender, stake);
+        }
+    }
+
+    /// Add a stake snapshot, keeping only the most recent epochs
+    pub fn add_stake_snapshot(&mut self, epoch: u64, indexer_address: String, stake: Stake) {
+        let snapshots = Arc::make_mut(&mut self.stake_snapshots);
+        snapshots
+            .entry(epoch)
+            .or_default()
+            .insert(indexer_address, stake);
+        while snapshots.len() > STAKE_SNAPSHOT_EPOCHS {
+            snapshots.pop_first();
+        }
+    }
+
+    /// Update indexer_agreements with the senders of a comparison result
+    pub fn update_indexer_agreements(&mut self, comparison_result: &ComparisonResult) {
+        update_agreements(
+            Arc::make_mut(&mut self.indexer_agreements),
+            comparison_result,
+        );
+    }
+
+    /// Add entry to comparison_history, keeping at most limit blocks per deployment
+    pub fn add_comparison_history(&mut self, comparison_result: ComparisonResult, limit: usize) {
+        let blocks = Arc::make_mut(&mut self.comparison_history)
+            .entry(comparison_result.deployment.clone())
+            .or_default();
+        blocks.insert(comparison_result.block_number, comparison_result);
+        while blocks.len() > limit {
+            blocks.pop_first();
+        }
+    }
+
+    /// Attach a bisection to the comparison result it was started from
+    /// Skipped if the deployment has since moved on to a different result
+    pub fn add_bisection(
+        &mut self,
+        deployment: &str,
+        block_number: u64,
+        bisection: DivergenceBisection,
+    ) -> Option<ComparisonResult> {
+        if let Some(result) = Arc::make_mut(&mut self.comparison_history)
+            .get_mut(deployment)
+            .and_then(|blocks| blocks.get_mut(&block_number))
+        {
+            result.bisection = Some(bisection.clone());
+        }
+
+        match Arc::make_mut(&mut self.comparison_results).get_mut(deployment) {
+            Some(result)
+                if result.block_number == block_number
+                    && result.result_type == ComparisonResultType::Divergent =>
+            {
+                result.bisection = Some(bisection);
+                Some(result.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Add entry to comparison_results
+    pub fn add_comparison_result(&mut self, comparison_result: ComparisonResult) {
+        let deployment = comparison_result.clone().deployment;
+
+        Arc::make_mut(&mut self.comparison_results).insert(deployment, comparison_result);
+    }
+
+    /// Remote messages of the deployments that pass the validity check, by deployment
+    pub async fn valid_ppoi_messages(
+        &self,
+        deployments: &[String],
+        graph_node_endpoint: &str,
+    ) -> HashMap<String, Vec<GraphcastMessage<PublicPoiMessage>>> {
+        let mut valid_messages = HashMap::new();
+
+        for deployment in deployments {
+            let mut valid = vec![];
+            for message in self.remote_messages.deployment(deployment) {
+                let is_valid = message
+                    .payload
+                    .validity_check(&message, graph_node_endpoint)
+                    .await;
+
+                if is_valid.is_ok() {
+                    valid.push(message);
+                }
+            }
+            valid_messages.insert(deployment.clone(), valid);
+        }
+
+        valid_messages
+    }
+
+    /// Keep a new comparison result unless it would hide a more relevant current one,
+    /// returns the result type now reported for the deployment
+    pub fn update_comparison_result(
+        &mut self,
+        new_comparison_result: ComparisonResult,
+    ) -> ComparisonResultType {
+        let results = Arc::make_mut(&mut self.comparison_results);
+        let deployment = &new_comparison_result.deployment;
+
+        let current_result = results.get(deployment).cloned();
+
+        if !results.contains_key(deployment) {
+            results.insert(deployment.clone(), new_comparison_result.clone());
+            new_comparison_result.result_type
+        } else {
+            match &current_result {
+                Some(current_result)
+                    if current_result.result_type != new_comparison_result.result_type
+                        && new_comparison_result.result_type != ComparisonResultType::NotFound =>
+                {
+                    results.insert(deployment.clone(), new_comparison_result.clone());
+                    new_comparison_result.result_type
+                }
+                Some(current_result) => {
+                    if let ComparisonResultType::Match | ComparisonResultType::NotFound =
+                        new_comparison_result.result_type
+                    {
+                        results.insert(deployment.clone(), new_comparison_result.clone());
+                    }
+                    current_result.result_type
+                }
+                None => {
+                    results.insert(deployment.clone(), new_comparison_result.clone());
+                    new_comparison_result.result_type
+                }
+            }
+        }
+    }
+
+    /// Clean remote_messages
+    pub fn clean_remote_messages(&mut self, block_number: u64, deployment: String) {
+        let removed =
+            Arc::make_mut(&mut self.remote_messages).remove_before(&deployment, block_number);
+        trace!(deployment, block_number, removed, "cleaned remote messages");
+    }
+
+    /// Evict remote messages for deployments outside of topics, older than the retention age,
+    /// or beyond the per deployment limit (oldest received first). Equivocation evidence is evicted under the same
+    /// age and per deployment limits.
+    /// Returns the number of evicted messages by deployment and reason
+    pub fn apply_message_retention(
+        &mut self,
+        retention: &MessageRetention,
+        topics: &[String],
+        now: i64,
+    ) -> HashMap<(String, EvictionReason), usize> {
+        let topics: HashSet<&String> = topics.iter().collect();
+        let mut evicted = HashMap::new();
+        let remote_messages = Arc::make_mut(&mut self.remote_messages);
+
+        for deployment in remote_messages.deployments() {
+            if topics.contains(&deployment) {
+                let removed = remote_messages
+                    .retain_deployment(&deployment, |msg| now - msg.nonce <= retention.max_age);
+                evicted.insert((deployment, EvictionReason::Age), removed);
+            } else {
+                let removed = remote_messages.retain_deployment(&deployment, |_| false);
+                evicted.insert((deployment, EvictionReason::Unsubscribed), removed);
+            }
+        }
+        for (deployment, removed) in remote_messages.truncate(retention.max_per_deployment) {
+            evicted.insert((deployment, EvictionReason::Count), removed);
+        }
+        evicted.retain(|_, removed| *removed > 0);
+        self.prune_poi_batches();
+
+        // Evidence is kept as long as the messages it is made of would be
+        let expired = self.equivocations.expired(retention, now);
+        if !expired.is_empty() {
+            let equivocations = Arc::make_mut(&mut self.equivocations);
+            for key in &expired {
+                equivocations.remove(key);
+            }
+            debug!(
+                evicted = expired.len(),
+                remaining = self.equivocations.len(),
+                "Evicted equivocations"
+            );
+        }
+
+        if !evicted.is_empty() {
+            debug!(
+                evicted = tracing::field::debug(&evicted),
+                remaining = self.remote_messages.len(),
+                "Evicted remote messages"
+            );
+        }
+        evicted
+    }
+
+    /// Clean local_attestations
+    // TODO: Refactor with attestations operations
+    pub fn clean_local_attestations(&mut self, block_number: u64, ipfs_hash: String) {
+        clear_local_attestation(
+            Arc::make_mut(&mut self.local_attestations),
+            ipfs_hash,
+            block_number,
+        )
+    }
+}
+
+// TODO: panic hook for updating the cache file before exiting the program
+/// Set up panic hook to store persisted state
+pub fn panic_hook(store: Arc<dyn StateStore>) {
+    panic::set_hook(Box::new(move |panic_info| {
+        panic_cache(panic_info, store.as_ref())
+    }));
+}
+
+pub fn panic_cache(panic_info: &PanicInfo<'_>, store: &dyn StateStore) {
+    // The operator is not set yet if the panic happened during initialization
+    if let Some(operator) = RADIO_OPERATOR.get() {
+        if let Err(e) = store.save(&operator.state().latest()) {
+            eprintln!("Could not save persisted state: {e}");
+        }
+    }
+    // Log panic information and program state
+    eprintln!("Panic occurred! Panic info: {:?}", panic_info);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use graphcast_sdk::networks::NetworkName;
+    use std::fs::remove_file;
+
+    use crate::messages::upgrade::VersionUpgradeMessage;
+    use crate::operator::attestation::{save_local_attestation, ComparisonResultType};
+    use crate::operator::consensus::ConsensusPolicyType;
+    use crate::operator::equivocation::detect_equivocations;
+    use crate::operator::stake::Stake;
+    use crate::storage::JsonFileStore;
+
+    /// Tests for saving and loading the state with the JSON file store
+    #[tokio::test]
+    async fn test_state_cache() {
+        let path = "test-state.json";
+        _ = remove_file(path);
+        let store = JsonFileStore::new(path);
+        assert!(store.load().unwrap().is_none());
+
+        let mut local_attestations = HashMap::new();
+        let mut messages = RemoteMessages::default();
+        let mut comparison_results = HashMap::new();
+
+        save_local_attestation(
+            &mut local_attestations,
+            "npoi-x".to_string(),
+            "0xa1".to_string(),
+            0,
+        );
+
+        save_local_attestation(
+            &mut local_attestations,
+            "npoi-y".to_string(),
+            "0xa1".to_string(),
+            1,
+        );
+
+        save_local_attestation(
+            &mut local_attestations,
+            "npoi-z".to_string(),
+            "0xa2".to_string(),
+            2,
+        );
+
+        let test_comparison_result = ComparisonResult {
+            deployment: "test_deployment".to_string(),
+            block_number: 42,
+            result_type: ComparisonResultType::Match,
+            local_attestation: None,
+            attestations: vec![],
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        };
+        comparison_results.insert("test_deployment".to_string(), test_comparison_result);
+
+        let hash: String = "QmWECgZdP2YMcV9RtKU41GxcdW8EGYqMNoG98ubu5RGN6U".to_string();
+        let content: String =
+            "0xa6008cea5905b8b7811a68132feea7959b623188e2d6ee3c87ead7ae56dd0eae".to_string();
+        let nonce: i64 = 123321;
+        let block_number: u64 = 0;
+        let block_hash: String = "0xblahh".to_string();
+        let radio_msg = PublicPoiMessage::build(
+            hash.clone(),
+            content,
+            nonce,
+            NetworkName::Goerli,
+            block_number,
+            block_hash,
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+        );
+        let sig: String = "4be6a6b7f27c4086f22e8be364cbdaeddc19c1992a42b08cbe506196b0aafb0a68c8c48a730b0e3155f4388d7cc84a24b193d091c4a6a4e8cd6f1b305870fae61b".to_string();
+        let msg = GraphcastMessage::new(
+            hash,
+            nonce,
+            String::from("0xe9a1cabd57700b17945fd81feefba82340d9568f"),
+            radio_msg,
+            sig,
+        )
+        .expect("Shouldn't get here since the message is purposefully constructed for testing");
+        messages.insert(msg);
+
+        let state = PersistedState::new(
+            Some(Arc::new(local_attestations)),
+            Some(Arc::new(messages)),
+            Some(Arc::new(comparison_results)),
+        );
+        store.save(&state).unwrap();
+
+        let state = store.load().unwrap().unwrap();
+        assert_eq!(state.remote_messages.len(), 1);
+        assert!(!state.local_attestations.is_empty());
+        assert!(state.local_attestations.len() == 2);
+        assert!(state.local_attestations.get("0xa1").unwrap().len() == 2);
+        assert!(state.local_attestations.get("0xa2").unwrap().len() == 1);
+        assert!(
+            state
+                .local_attestations
+                .get("0xa1")
+                .unwrap()
+                .get(&0)
+                .unwrap()
+                .npoi
+                == *"npoi-x"
+        );
+
+        assert_eq!(state.comparison_results.len(), 1);
+        assert_eq!(
+            state
+                .comparison_results
+                .get("test_deployment")
+                .unwrap()
+                .block_number,
+            42
+        );
+        assert_eq!(
+            state
+                .comparison_results
+                .get("test_deployment")
+                .unwrap()
+                .result_type,
+            ComparisonResultType::Match
+        );
+
+        _ = remove_file(path);
+    }
+
+    #[test]
+    fn update_comparison_result_new_deployment() {
+        let mut state = PersistedState::new(None, None, None);
+
+        let new_result = ComparisonResult {
+            deployment: String::from("new_deployment"),
+            block_number: 1,
+            result_type: ComparisonResultType::Match,
+            local_attestation: None,
+            attestations: Vec::new(),
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        };
+
+        state.update_comparison_result(new_result);
+
+        assert!(state
+            .comparison_results
+            .contains_key(&String::from("new_deployment")));
+    }
+
+    #[test]
+    fn update_comparison_result_change_result_type() {
+        let mut state = PersistedState::new(None, None, None);
+
+        let old_result = ComparisonResult {
+            deployment: String::from("existing_deployment"),
+            block_number: 1,
+            result_type: ComparisonResultType::Match,
+            local_attestation: None,
+            attestations: Vec::new(),
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        };
+
+        let new_result = ComparisonResult {
+            deployment: String::from("existing_deployment"),
+            block_number: 1,
+            result_type: ComparisonResultType::Divergent,
+            local_attestation: None,
+            attestations: Vec::new(),
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        };
+
+        state.add_comparison_result(old_result);
+        state.update_comparison_result(new_result);
+
+        let result = state
+            .comparison_results
+            .get(&String::from("existing_deployment"))
+            .unwrap();
+        assert_eq!(result.result_type, ComparisonResultType::Divergent);
+    }
+
+    #[test]
+    fn stake_snapshots_retention() {
+        let mut state = PersistedState::new(None, None, None);
+        for epoch in 0..=STAKE_SNAPSHOT_EPOCHS as u64 {
+            state.add_stake_snapshot(epoch, String::from("0xa1"), Stake::from_grt(epoch));
+        }
+        assert_eq!(state.stake_snapshot(0, "0xa1"), None);
+        assert_eq!(state.stake_snapshot(1, "0xa1"), Some(Stake::from_grt(1)));
+        assert_eq!(state.stake_snapshot(1, "0xa2"), None);
+
+        let json = serde_json::to_string(&state).unwrap();
+        let loaded: PersistedState = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            loaded.stake_snapshot(STAKE_SNAPSHOT_EPOCHS as u64, "0xa1"),
+            Some(Stake::from_grt(STAKE_SNAPSHOT_EPOCHS as u64))
+        );
+    }
+
+    #[test]
+    fn cached_stakes_read_snapshots() {
+        let mut state = PersistedState::new(None, None, None);
+        state.add_stake_snapshot(3, String::from("0xa1"), Stake::from_grt(7));
+        let start = EpochStart {
+            epoch: 3,
+            start_block: 300,
+            stake_block: 300,
+        };
+        state.set_epoch(start, 50);
+        let json = serde_json::to_string(&state).unwrap();
+        let loaded: PersistedState = serde_json::from_str(&json).unwrap();
+        // The epochs seen are kept, the cached epoch has to be fetched again
+        assert_eq!(loaded.stake_cache.epoch_seen_at(60), Some(start));
+        assert_eq!(loaded.cached_epoch(60, 60), None);
+
+        let senders = vec![String::from("0xa1"), String::from("0xa2")];
+        let (stakes, missing) = loaded.cached_stakes(&senders, 3, 0);
+        assert_eq!(stakes.get("0xa1"), Some(&Stake::from_grt(7)));
+        assert_eq!(missing, vec![String::from("0xa2")]);
+        let (stakes, missing) = loaded.cached_stakes(&senders, 4, 0);
+        assert!(stakes.is_empty());
+        assert_eq!(missing.len(), 2);
+    }
+
+    #[test]
+    fn remote_message_retention() {
+        let message = |deployment: &str, nonce: i64| GraphcastMessage {
+            identifier: deployment.to_string(),
+            nonce,
+            graph_account: String::from("0xa1"),
+            payload: PublicPoiMessage {
+                identifier: deployment.to_string(),
+                content: String::from("npoi-x"),
+                nonce,
+                network: String::from("goerli"),
+                block_number: nonce as u64,
+                block_hash: String::from("0xblahh"),
+                graph_account: String::from("0xa1"),
+                version: 0,
+            },
+            signature: format!("{deployment}-{nonce}"),
+        };
+        let mut state = PersistedState::new(None, None, None);
+        for nonce in [10, 96, 97, 98, 99] {
+            state.add_remote_message(message("Qm1", nonce));
+        }
+        state.add_remote_message(message("Qm2", 99));
+
+        let retention = MessageRetention {
+            max_age: 50,
+            max_per_deployment: 3,
+        };
+        let evicted = state.apply_message_retention(&retention, &[String::from("Qm1")], 100);
+        assert_eq!(evicted.len(), 3);
+        assert_eq!(evicted[&(String::from("Qm1"), EvictionReason::Age)], 1);
+        assert_eq!(evicted[&(String::from("Qm1"), EvictionReason::Count)], 1);
+        assert_eq!(
+            evicted[&(String::from("Qm2"), EvictionReason::Unsubscribed)],
+            1
+        );
+        let nonces: Vec<i64> = state.remote_messages().iter().map(|m| m.nonce).collect();
+        assert_eq!(nonces, vec![97, 98, 99]);
+
+        assert!(state
+            .apply_message_retention(&retention, &[String::from("Qm1")], 100)
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn poi_batches_kept_with_their_messages() {
+        let wallet: LocalWallet =
+            "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+                .parse()
+                .unwrap();
+        let sender = format!("{:#x}", wallet.address());
+        let payload = |deployment: &str, npoi: &str| {
+            PublicPoiMessage::new(
+                deployment.to_string(),
+                npoi.to_string(),
+                100,
+                String::from("goerli"),
+                10,
+                String::from("0xblahh"),
+                sender.clone(),
+            )
+        };
+        let batch = PublicPoiBatchMessage::build(
+            100,
+            sender.clone(),
+            &[payload("Qm1", "npoi-x"), payload("Qm2", "npoi-x")],
+        );
+        let batch = GraphcastMessage::build(
+            &wallet,
+            batch.identifier.clone(),
+            sender.clone(),
+            100,
+            batch,
+        )
+        .await
+        .unwrap();
+        let single = GraphcastMessage::build(
+            &wallet,
+            String::from("Qm1"),
+            sender.clone(),
+            100,
+            payload("Qm1", "npoi-y"),
+        )
+        .await
+        .unwrap();
+
+        let mut state = PersistedState::new(None, None, None);
+        state.add_poi_batch(batch.clone());
+        for msg in batch.payload.expand(&batch) {
+            state.add_remote_message(msg);
+        }
+        state.add_remote_message(single.clone());
+
+        // Evidence of an entry of the batch comes with the batch it was signed in
+        let equivocations = detect_equivocations(&state.remote_messages.deployment("Qm1"));
+        assert_eq!(equivocations.len(), 1);
+        assert!(state.add_equivocation(equivocations[0].clone()));
+        let evidence = &state.equivocations()[0];
+        assert_eq!(evidence.messages.len(), 2);
+        assert_eq!(evidence.batches.len(), 1);
+        assert_eq!(evidence.batches[0].signature, batch.signature);
+        assert!(evidence.batches[0]
+            .payload
+            .carries(&evidence.batches[0], &evidence.messages[0]));
+        assert!(state.poi_batch(&single).is_none());
+
+        // Batches are dropped along with the last of their messages
+        let retention = MessageRetention {
+            max_age: 50,
+            max_per_deployment: 3,
+        };
+        state.apply_message_retention(&retention, &[String::from("Qm1")], 100);
+        assert_eq!(state.poi_batches.len(), 1);
+        state.apply_message_retention(&retention, &[], 100);
+        assert!(state.poi_batches.is_empty());
+    }
+
+    #[test]
+    fn comparison_history_retention_and_bounds() {
+        let mut state = PersistedState::new(None, None, None);
+        let result_types = [
+            ComparisonResultType::Divergent,
+            ComparisonResultType::Match,
+            ComparisonResultType::Match,
+            ComparisonResultType::Inconclusive,
+            ComparisonResultType::Divergent,
+            ComparisonResultType::Divergent,
+        ];
+        for (i, result_type) in result_types.into_iter().enumerate() {
+            state.add_comparison_history(
+                ComparisonResult {
+                    deployment: String::from("deployment"),
+                    block_number: (i as u64) * 10,
+                    result_type,
+                    local_attestation: None,
+                    attestations: Vec::new(),
+                    bisection: None,
+                    consensus_policy: ConsensusPolicyType::default(),
+                    tied_npois: vec![],
+                },
+                5,
+            );
+        }
+
+        // Oldest block got evicted
+        let history = state.comparison_history("deployment", None, None);
+        assert_eq!(history.len(), 5);
+        assert_eq!(history.first().unwrap().block_number, 10);
+        assert_eq!(
+            state
+                .comparison_history("deployment", Some(15), Some(40))
+                .iter()
+                .map(|r| r.block_number)
+                .collect::<Vec<u64>>(),
+            vec![20, 30, 40]
+        );
+        assert!(state
+            .comparison_history("deployment", Some(40), Some(15))
+            .is_empty());
+        assert!(state.comparison_history("other", None, None).is_empty());
+
+        assert_eq!(state.divergence_bounds("deployment"), (Some(20), Some(40)));
+        assert_eq!(state.divergence_bounds("other"), (None, None));
+    }
+
+    #[test]
+    fn test_upgrade_plan_reminders() {
+        let mut state = PersistedState::new(None, None, None);
+        let plan = |nonce: i64, migrate_time: i64| {
+            UpgradePlan::from(VersionUpgradeMessage::new(
+                String::from("Qm1"),
+                String::from("Qm2"),
+                String::from("0xsubgraph"),
+                nonce,
+                String::from("goerli"),
+                migrate_time,
+                String::from("0xa1"),
+            ))
+        };
+        assert!(state.add_upgrade_plan(plan(2, 10_000)));
+        // An earlier announcement does not replace a later one
+        assert!(!state.add_upgrade_plan(plan(1, 20_000)));
+        assert_eq!(state.upgrade_plans()[0].migrate_time, 10_000);
+
+        let leads = [3600];
+        assert!(state.take_upgrade_reminders(&leads, 0).is_empty());
+        let due = state.take_upgrade_reminders(&leads, 7_000);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].reminded_lead, Some(3600));
+        assert!(state.take_upgrade_reminders(&leads, 8_000).is_empty());
+
+        // Dropped once the migrate time passed
+        assert!(state.take_upgrade_reminders(&leads, 10_000).is_empty());
+        assert!(state.upgrade_plans().is_empty());
+    }
+
+    #[test]
+    fn load_legacy_state() {
+        // State persisted before exact stakes, comparison history and indexer agreements
+        let legacy = r#"{
+            "local_attestations": {"0xa1": {"1": {"npoi": "npoi-x", "stake_weight": 0, "senders": [], "sender_group_hash": "", "timestamp": [1]}}},
+            "remote_messages": [],
+            "comparison_results": {"0xa1": {
+                "deployment": "0xa1",
+                "block_number": 1,
+                "result_type": "Match",
+                "local_attestation": null,
+                "attestations": [{"npoi": "npoi-x", "stake_weight": 42, "senders": ["0xb1"], "sender_group_hash": "", "timestamp": [1]}]
+            }}
+        }"#;
+        let state: PersistedState = serde_json::from_str(legacy).unwrap();
+
+        let result = state.comparison_result(String::from("0xa1")).unwrap();
+        assert_eq!(result.attestations[0].stake_weight, Stake::from_grt(42));
+        assert!(result.bisection.is_none());
+        assert!(state.comparison_history("0xa1", None, None).is_empty());
+        assert!(state.indexer_agreements().is_empty());
+    }
+}
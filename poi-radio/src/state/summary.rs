@@ -0,0 +1,236 @@
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+
+use crate::operator::attestation::ComparisonResultType;
+
+use super::PersistedState;
+
+/// Overview of a persisted state, for operators inspecting it outside of a running radio
+#[derive(Serialize, Debug, PartialEq)]
+pub struct StateSummary {
+    pub deployments: Vec<DeploymentSummary>,
+    /// Blocks with a local attestation that has not been compared yet
+    pub awaiting_comparison: Vec<PendingBlock>,
+    pub divergent_results: Vec<DivergentResult>,
+    /// Cached remote messages by sender address
+    pub messages_by_sender: BTreeMap<String, usize>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DeploymentSummary {
+    pub deployment: String,
+    pub local_attestations: usize,
+    pub remote_messages: usize,
+    /// Block and type of the latest comparison result
+    pub latest_result: Option<(u64, ComparisonResultType)>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PendingBlock {
+    pub deployment: String,
+    pub block_number: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DivergentResult {
+    pub deployment: String,
+    pub block_number: u64,
+    pub local_npoi: Option<String>,
+    /// nPOI with the most support among remote attestations
+    pub remote_npoi: Option<String>,
+}
+
+impl StateSummary {
+    pub fn new(state: &PersistedState) -> StateSummary {
+        let local_attestations = state.local_attestations();
+        let comparison_results = state.comparison_results();
+        let remote_messages = state.remote_messages();
+
+        let deployments: BTreeSet<String> = local_attestations
+            .keys()
+            .cloned()
+            .chain(state.remote_messages.deployments())
+            .chain(comparison_results.keys().cloned())
+            .collect();
+        let deployments = deployments
+            .into_iter()
+            .map(|deployment| DeploymentSummary {
+                local_attestations: local_attestations.get(&deployment).map_or(0, |b| b.len()),
+                remote_messages: state.remote_messages.deployment_len(&deployment),
+                latest_result: comparison_results
+                    .get(&deployment)
+                    .map(|result| (result.block_number, result.result_type)),
+                deployment,
+            })
+            .collect();
+
+        let mut awaiting_comparison: Vec<PendingBlock> = local_attestations
+            .iter()
+            .flat_map(|(deployment, blocks)| {
+                blocks.keys().map(|block_number| PendingBlock {
+                    deployment: deployment.clone(),
+                    block_number: *block_number,
+                })
+            })
+            .collect();
+        awaiting_comparison
+            .sort_by(|a, b| (&a.deployment, a.block_number).cmp(&(&b.deployment, b.block_number)));
+
+        let mut divergent_results: Vec<DivergentResult> = comparison_results
+            .into_values()
+            .filter(|result| result.result_type == ComparisonResultType::Divergent)
+            .map(|result| DivergentResult {
+                deployment: result.deployment,
+                block_number: result.block_number,
+                local_npoi: result.local_attestation.map(|a| a.npoi),
+                remote_npoi: result.attestations.last().map(|a| a.npoi.clone()),
+            })
+            .collect();
+        divergent_results.sort_by(|a, b| a.deployment.cmp(&b.deployment));
+
+        let mut messages_by_sender = BTreeMap::new();
+        for msg in remote_messages {
+            *messages_by_sender.entry(msg.graph_account).or_insert(0) += 1;
+        }
+
+        StateSummary {
+            deployments,
+            awaiting_comparison,
+            divergent_results,
+            messages_by_sender,
+        }
+    }
+}
+
+impl Display for StateSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Deployments ({}):", self.deployments.len())?;
+        for d in &self.deployments {
+            let latest = d
+                .latest_result
+                .map_or(String::from("none"), |(block, result_type)| {
+                    format!("{result_type} at block {block}")
+                });
+            writeln!(
+                f,
+                "  {}: {} local attestations, {} remote messages, latest result {}",
+                d.deployment, d.local_attestations, d.remote_messages, latest
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Blocks awaiting comparison ({}):",
+            self.awaiting_comparison.len()
+        )?;
+        for pending in &self.awaiting_comparison {
+            writeln!(
+                f,
+                "  {} at block {}",
+                pending.deployment, pending.block_number
+            )?;
+        }
+
+        writeln!(f, "Divergent results ({}):", self.divergent_results.len())?;
+        for result in &self.divergent_results {
+            writeln!(
+                f,
+                "  {} at block {}: local nPOI {}, remote nPOI {}",
+                result.deployment,
+                result.block_number,
+                result.local_npoi.as_deref().unwrap_or("none"),
+                result.remote_npoi.as_deref().unwrap_or("none"),
+            )?;
+        }
+
+        writeln!(f, "Messages by sender ({}):", self.messages_by_sender.len())?;
+        for (sender, count) in &self.messages_by_sender {
+            writeln!(f, "  {sender}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+    use crate::messages::poi::PublicPoiMessage;
+    use crate::operator::attestation::{Attestation, ComparisonResult};
+    use crate::operator::consensus::ConsensusPolicyType;
+    use crate::operator::stake::Stake;
+
+    fn message(deployment: &str, block: u64, sender: &str) -> GraphcastMessage<PublicPoiMessage> {
+        GraphcastMessage {
+            identifier: deployment.to_string(),
+            nonce: block as i64,
+            graph_account: sender.to_string(),
+            payload: PublicPoiMessage {
+                identifier: deployment.to_string(),
+                content: String::from("npoi-remote"),
+                nonce: block as i64,
+                network: String::from("goerli"),
+                block_number: block,
+                block_hash: String::from("0xblahh"),
+                graph_account: sender.to_string(),
+                version: 0,
+            },
+            signature: format!("{deployment}-{block}-{sender}"),
+        }
+    }
+
+    #[test]
+    fn test_state_summary() {
+        let mut state = PersistedState::new(None, None, None);
+        state.save_local_attestation(String::from("npoi-local"), String::from("Qm1"), 20, 0);
+        state.add_remote_message(message("Qm1", 20, "0xa1"));
+        state.add_remote_message(message("Qm2", 10, "0xa1"));
+        state.add_remote_message(message("Qm2", 10, "0xa2"));
+        state.add_comparison_result(ComparisonResult {
+            deployment: String::from("Qm2"),
+            block_number: 10,
+            result_type: ComparisonResultType::Divergent,
+            local_attestation: Some(Attestation::new(
+                String::from("npoi-local"),
+                Stake::zero(),
+                vec![],
+                vec![],
+            )),
+            attestations: vec![Attestation::new(
+                String::from("npoi-remote"),
+                Stake::zero(),
+                vec![String::from("0xa1"), String::from("0xa2")],
+                vec![],
+            )],
+            bisection: None,
+            consensus_policy: ConsensusPolicyType::default(),
+            tied_npois: vec![],
+        });
+
+        let summary = StateSummary::new(&state);
+        assert_eq!(summary.deployments.len(), 2);
+        assert_eq!(summary.deployments[0].local_attestations, 1);
+        assert_eq!(summary.deployments[0].latest_result, None);
+        assert_eq!(
+            summary.deployments[1].latest_result,
+            Some((10, ComparisonResultType::Divergent))
+        );
+        assert_eq!(
+            summary.awaiting_comparison,
+            vec![PendingBlock {
+                deployment: String::from("Qm1"),
+                block_number: 20,
+            }]
+        );
+        assert_eq!(
+            summary.divergent_results[0].remote_npoi.as_deref(),
+            Some("npoi-remote")
+        );
+        assert_eq!(
+            summary.messages_by_sender,
+            BTreeMap::from([(String::from("0xa1"), 2), (String::from("0xa2"), 1)])
+        );
+    }
+}
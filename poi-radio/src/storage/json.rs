@@ -0,0 +1,147 @@
+use chrono::Utc;
+use serde_json::Value;
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+use crate::state::PersistedState;
+
+use super::migration::{migrate, versioned};
+use super::{StateStore, StorageError};
+
+/// Stores the whole state as one versioned JSON file, rewritten on every save.
+/// Writes go to a temporary file that replaces the state file only once complete,
+/// and files that cannot be read back are quarantined next to it instead of being overwritten
+#[derive(Clone, Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+}
+
+impl JsonFileStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        JsonFileStore {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Move the state file aside so the radio can start over without losing it
+    fn quarantine(&self) -> Result<PathBuf, StorageError> {
+        let mut quarantine = self.path.clone().into_os_string();
+        quarantine.push(format!(".corrupt-{}", Utc::now().format("%Y%m%dT%H%M%S")));
+        let quarantine = PathBuf::from(quarantine);
+        fs::rename(&self.path, &quarantine)?;
+        Ok(quarantine)
+    }
+
+    /// Read and migrate the state file without modifying it, None if it does not exist
+    pub fn read(&self) -> Result<Option<PersistedState>, StorageError> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let value = migrate(serde_json::from_slice::<Value>(&contents)?)?;
+        Ok(Some(serde_json::from_value(value)?))
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn load(&self) -> Result<Option<PersistedState>, StorageError> {
+        match self.read() {
+            Ok(state) => Ok(state),
+            // Missing permissions or similar, nothing wrong with the file itself
+            Err(e @ StorageError::Io(_)) => Err(e),
+            // Written by a newer radio, leave it for that version to read
+            Err(e @ StorageError::UnsupportedVersion(_)) => Err(e),
+            Err(e) => {
+                let path = self.quarantine()?;
+                warn!(
+                    err = e.to_string(),
+                    path = tracing::field::debug(&path),
+                    "Quarantined unreadable persisted state"
+                );
+                Err(StorageError::Quarantined {
+                    path,
+                    reason: e.to_string(),
+                })
+            }
+        }
+    }
+
+    fn save(&self, state: &PersistedState) -> Result<(), StorageError> {
+        let state_json = serde_json::to_vec(&versioned(serde_json::to_value(state)?)?)?;
+        write_atomic(&self.path, &state_json)
+    }
+}
+
+/// Replace the file at path with contents, so a crash leaves either the old or the new file in place
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), StorageError> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // Persist the rename itself, not supported on every platform
+    if let Ok(dir) = File::open(parent) {
+        _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::migration::{SCHEMA_VERSION, SCHEMA_VERSION_KEY};
+
+    #[test]
+    fn test_json_store_quarantines_unparseable_state() {
+        let dir = std::env::temp_dir().join("poi-radio-test-quarantine");
+        _ = fs::remove_dir_all(&dir);
+        let path = dir.join("state.json");
+        let store = JsonFileStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "{not json").unwrap();
+        let quarantine = match store.load() {
+            Err(StorageError::Quarantined { path, .. }) => path,
+            other => panic!("Expected quarantined state, got {other:?}"),
+        };
+        assert_eq!(fs::read_to_string(quarantine).unwrap(), "{not json");
+        assert!(store.load().unwrap().is_none());
+
+        store.save(&PersistedState::new(None, None, None)).unwrap();
+        assert!(store.load().unwrap().is_some());
+        let saved: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved[SCHEMA_VERSION_KEY], SCHEMA_VERSION);
+        _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_json_store_keeps_newer_state() {
+        let dir = std::env::temp_dir().join("poi-radio-test-newer-state");
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let newer = format!("{{\"{SCHEMA_VERSION_KEY}\": {}}}", SCHEMA_VERSION + 1);
+        fs::write(&path, &newer).unwrap();
+
+        let store = JsonFileStore::new(&path);
+        assert!(matches!(
+            store.load(),
+            Err(StorageError::UnsupportedVersion(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+        _ = fs::remove_dir_all(&dir);
+    }
+}
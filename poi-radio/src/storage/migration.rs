@@ -0,0 +1,88 @@
+use serde_json::{Map, Value};
+
+use super::StorageError;
+
+/// Version of the persisted state layout written by this radio
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Key of the schema version in the persisted state document
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Upgrades a state document by one version
+type Migration = fn(Map<String, Value>) -> Result<Map<String, Value>, StorageError>;
+
+/// Migrations by the version they upgrade from, a document at version N goes through MIGRATIONS[N..]
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [v0_to_v1];
+
+/// Schema version of a state document, documents written before versioning are version 0
+pub fn schema_version(state: &Map<String, Value>) -> Result<u64, StorageError> {
+    match state.get(SCHEMA_VERSION_KEY) {
+        None => Ok(0),
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| StorageError::Migration(format!("Invalid schema version {version}"))),
+    }
+}
+
+/// Bring a state document to the current schema version
+pub fn migrate(value: Value) -> Result<Value, StorageError> {
+    let mut state = match value {
+        Value::Object(state) => state,
+        _ => {
+            return Err(StorageError::Migration(String::from(
+                "Persisted state is not a JSON object",
+            )))
+        }
+    };
+    let version = schema_version(&state)?;
+    if version > SCHEMA_VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        state = migration(state)?;
+    }
+    state.insert(SCHEMA_VERSION_KEY.to_string(), SCHEMA_VERSION.into());
+    Ok(Value::Object(state))
+}
+
+/// Stamp a state document with the current schema version
+pub fn versioned(value: Value) -> Result<Value, StorageError> {
+    match value {
+        Value::Object(mut state) => {
+            state.insert(SCHEMA_VERSION_KEY.to_string(), SCHEMA_VERSION.into());
+            Ok(Value::Object(state))
+        }
+        _ => Err(StorageError::Migration(String::from(
+            "Persisted state is not a JSON object",
+        ))),
+    }
+}
+
+/// Unversioned state files only lack the version, fields added since then have defaults
+fn v0_to_v1(state: Map<String, Value>) -> Result<Map<String, Value>, StorageError> {
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_versions() {
+        let legacy = json!({ "local_attestations": {}, "remote_messages": [] });
+        let migrated = migrate(legacy).unwrap();
+        assert_eq!(migrated[SCHEMA_VERSION_KEY], json!(SCHEMA_VERSION));
+        assert_eq!(migrated["remote_messages"], json!([]));
+
+        let newer = json!({ SCHEMA_VERSION_KEY: SCHEMA_VERSION + 1 });
+        assert!(matches!(
+            migrate(newer),
+            Err(StorageError::UnsupportedVersion(v)) if v == SCHEMA_VERSION + 1
+        ));
+        assert!(matches!(
+            migrate(json!([])),
+            Err(StorageError::Migration(_))
+        ));
+    }
+}
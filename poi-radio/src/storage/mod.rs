@@ -0,0 +1,63 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::state::PersistedState;
+
+pub mod json;
+pub mod migration;
+pub mod sqlite;
+
+pub use json::JsonFileStore;
+pub use sqlite::SqliteStore;
+
+/// Backend used to persist the radio state
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageBackend {
+    /// The whole state as a single JSON file
+    #[default]
+    Json,
+    /// An embedded SQLite database, written incrementally
+    Sqlite,
+}
+
+/// Persistent storage for the radio state
+pub trait StateStore: Send + Sync {
+    /// Read the stored state, None if nothing has been stored yet
+    fn load(&self) -> Result<Option<PersistedState>, StorageError>;
+
+    /// Write the current state
+    fn save(&self, state: &PersistedState) -> Result<(), StorageError>;
+
+    /// Write a state in place of whatever is stored, including what was not loaded by this store
+    fn replace(&self, state: &PersistedState) -> Result<(), StorageError> {
+        self.save(state)
+    }
+}
+
+/// Open the store of a backend at a path
+pub fn open_store(
+    backend: StorageBackend,
+    path: &str,
+) -> Result<Arc<dyn StateStore>, StorageError> {
+    match backend {
+        StorageBackend::Json => Ok(Arc::new(JsonFileStore::new(path))),
+        StorageBackend::Sqlite => Ok(Arc::new(SqliteStore::open(path)?)),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Failed to access storage: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to serialize or parse state: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("SQLite failure: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Failed to migrate state: {0}")]
+    Migration(String),
+    #[error("State schema version {0} is newer than this radio supports")]
+    UnsupportedVersion(u64),
+    #[error("Unreadable state moved to {}: {reason}", path.display())]
+    Quarantined { path: PathBuf, reason: String },
+}
@@ -0,0 +1,717 @@
+use rusqlite::{params_from_iter, types::Value, Connection, Transaction};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use tracing::trace;
+
+use graphcast_sdk::graphcast_agent::message_typing::GraphcastMessage;
+
+use crate::{
+    messages::{poi::PublicPoiMessage, poi_batch::PublicPoiBatchMessage},
+    operator::attestation::{Attestation, ComparisonResult},
+    state::PersistedState,
+};
+
+use super::{StateStore, StorageError};
+
+/// Local attestations, remote messages and the batches they came in, comparison results and the comparison history
+/// get a row each, with their main fields as columns so the database can be queried from outside the radio. The remaining state is stored as
+/// one JSON document per field.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS local_attestations (
+    id TEXT PRIMARY KEY,
+    deployment TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    npoi TEXT NOT NULL,
+    attestation TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS remote_messages (
+    id TEXT PRIMARY KEY,
+    deployment TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    graph_account TEXT NOT NULL,
+    nonce INTEGER NOT NULL,
+    npoi TEXT NOT NULL,
+    message TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS comparison_results (
+    id TEXT PRIMARY KEY,
+    deployment TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    result_type TEXT NOT NULL,
+    result TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS state_entries (
+    id TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+";
+
+/// Move the comparison history out of its JSON document into a row per deployment and block
+const COMPARISON_HISTORY_TABLE: &str = "
+CREATE TABLE comparison_history (
+    id TEXT PRIMARY KEY,
+    deployment TEXT NOT NULL,
+    block_number INTEGER NOT NULL,
+    result_type TEXT NOT NULL,
+    result TEXT NOT NULL
+);
+CREATE INDEX comparison_history_deployment_block ON comparison_history (deployment, block_number);
+INSERT INTO comparison_history (id, deployment, block_number, result_type, result)
+    SELECT deployment.key || '/' || block.key, deployment.key, CAST(block.key AS INTEGER),
+        json_extract(block.value, '$.result_type'), block.value
+    FROM state_entries, json_each(state_entries.value) AS deployment, json_each(deployment.value) AS block
+    WHERE state_entries.id = 'comparison_history';
+DELETE FROM state_entries WHERE id = 'comparison_history';
+";
+
+/// Keep the batches remote messages were expanded from. Messages of a batch share its signature,
+/// so remote messages are keyed by signature, deployment and block
+const POI_BATCHES_TABLE: &str = "
+CREATE TABLE poi_batches (
+    id TEXT PRIMARY KEY,
+    graph_account TEXT NOT NULL,
+    nonce INTEGER NOT NULL,
+    message TEXT NOT NULL
+);
+UPDATE remote_messages SET id = id || '/' || deployment || '/' || block_number;
+";
+
+/// Schema migrations by the version they upgrade from, tracked in the database user_version
+const MIGRATIONS: [&str; 3] = [SCHEMA, COMPARISON_HISTORY_TABLE, POI_BATCHES_TABLE];
+
+/// Bring the database schema to the latest version in one transaction
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let version: u64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() as u64 {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    let tx = conn.transaction()?;
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as u64)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// A table keyed by a text id, the last column holds the JSON document the row is loaded from
+struct Table {
+    name: &'static str,
+    columns: &'static [&'static str],
+}
+
+const LOCAL_ATTESTATIONS: Table = Table {
+    name: "local_attestations",
+    columns: &["deployment", "block_number", "npoi", "attestation"],
+};
+const REMOTE_MESSAGES: Table = Table {
+    name: "remote_messages",
+    columns: &[
+        "deployment",
+        "block_number",
+        "graph_account",
+        "nonce",
+        "npoi",
+        "message",
+    ],
+};
+const POI_BATCHES: Table = Table {
+    name: "poi_batches",
+    columns: &["graph_account", "nonce", "message"],
+};
+const COMPARISON_RESULTS: Table = Table {
+    name: "comparison_results",
+    columns: &["deployment", "block_number", "result_type", "result"],
+};
+const COMPARISON_HISTORY: Table = Table {
+    name: "comparison_history",
+    columns: &["deployment", "block_number", "result_type", "result"],
+};
+const STATE_ENTRIES: Table = Table {
+    name: "state_entries",
+    columns: &["value"],
+};
+const TABLES: [&Table; 6] = [
+    &LOCAL_ATTESTATIONS,
+    &REMOTE_MESSAGES,
+    &POI_BATCHES,
+    &COMPARISON_RESULTS,
+    &COMPARISON_HISTORY,
+    &STATE_ENTRIES,
+];
+
+/// Digest of the last written values by row id
+type Digests = HashMap<String, u64>;
+
+/// Rows to write and ids of the rows to delete to bring a table up to date
+#[derive(Default)]
+struct Changes {
+    upserts: Vec<(String, Vec<Value>)>,
+    deletes: Vec<String>,
+}
+
+/// What the database holds as of the last save or load
+struct Written {
+    state: PersistedState,
+    /// Digests of the state entries, which are rewritten as a whole whenever their field changes
+    entries: Digests,
+}
+
+/// Stores the state in an embedded SQLite database. Only rows that changed since the last save are written,
+/// and they are found by comparing the state with the one last saved, so saving a large state neither rewrites nor
+/// serializes it as a whole
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    written: Mutex<Written>,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut conn = Connection::open(path)?;
+        // Let outside readers query the database while the radio writes to it
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        migrate(&mut conn)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            written: Mutex::new(Written {
+                state: PersistedState::new(None, None, None),
+                entries: Digests::new(),
+            }),
+        })
+    }
+
+    /// Write the rows that changed since the last save or load, returns the number of rows written or deleted
+    pub fn save_changes(&self, state: &PersistedState) -> Result<usize, StorageError> {
+        self.write(state, false)
+    }
+
+    /// Write the state, either the rows that changed since the last save or load, or every row
+    /// after deleting the ones the database holds
+    fn write(&self, state: &PersistedState, replace: bool) -> Result<usize, StorageError> {
+        let mut written = self.written.lock().unwrap();
+        let (changes, entries) = if replace {
+            state_changes(
+                &PersistedState::new(None, None, None),
+                state,
+                &Digests::new(),
+            )?
+        } else {
+            state_changes(&written.state, state, &written.entries)?
+        };
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction()?;
+        let mut count = 0;
+        for (table, changes) in TABLES.iter().zip(changes) {
+            if replace {
+                count += tx.execute(&format!("DELETE FROM {}", table.name), [])?;
+            }
+            count += apply_changes(&tx, table, changes)?;
+        }
+        tx.commit()?;
+
+        // Cloning shares the fields with the state, the next save skips the ones that are still shared
+        *written = Written {
+            state: state.clone(),
+            entries,
+        };
+        trace!(changes = count, "Saved state to SQLite");
+        Ok(count)
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load(&self) -> Result<Option<PersistedState>, StorageError> {
+        let mut state = PersistedState::new(None, None, None);
+        let mut entries = Digests::new();
+        let mut empty = true;
+        {
+            let conn = self.conn.lock().unwrap();
+
+            let mut stmt = conn
+                .prepare("SELECT deployment, block_number, attestation FROM local_attestations")?;
+            let mut rows = stmt.query([])?;
+            let local_attestations = Arc::make_mut(&mut state.local_attestations);
+            while let Some(row) = rows.next()? {
+                let deployment: String = row.get(0)?;
+                let block_number: i64 = row.get(1)?;
+                let attestation: Attestation = serde_json::from_str(&row.get::<_, String>(2)?)?;
+                local_attestations
+                    .entry(deployment)
+                    .or_default()
+                    .insert(block_number as u64, attestation);
+                empty = false;
+            }
+
+            let mut stmt = conn.prepare("SELECT message FROM remote_messages ORDER BY rowid")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let msg: GraphcastMessage<PublicPoiMessage> =
+                    serde_json::from_str(&row.get::<_, String>(0)?)?;
+                state.add_remote_message(msg);
+                empty = false;
+            }
+
+            let mut stmt = conn.prepare("SELECT message FROM poi_batches")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let msg: GraphcastMessage<PublicPoiBatchMessage> =
+                    serde_json::from_str(&row.get::<_, String>(0)?)?;
+                state.add_poi_batch(msg);
+                empty = false;
+            }
+
+            let mut stmt = conn.prepare("SELECT result FROM comparison_results")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let result: ComparisonResult = serde_json::from_str(&row.get::<_, String>(0)?)?;
+                state.add_comparison_result(result);
+                empty = false;
+            }
+
+            let mut stmt = conn.prepare("SELECT result FROM comparison_history")?;
+            let mut rows = stmt.query([])?;
+            let comparison_history = Arc::make_mut(&mut state.comparison_history);
+            while let Some(row) = rows.next()? {
+                let result: ComparisonResult = serde_json::from_str(&row.get::<_, String>(0)?)?;
+                comparison_history
+                    .entry(result.deployment.clone())
+                    .or_default()
+                    .insert(result.block_number, result);
+                empty = false;
+            }
+
+            let mut stmt = conn.prepare("SELECT id, value FROM state_entries")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let value: String = row.get(1)?;
+                match id.as_str() {
+                    "indexer_agreements" => {
+                        state.indexer_agreements = serde_json::from_str(&value)?
+                    }
+                    "equivocations" => state.equivocations = serde_json::from_str(&value)?,
+                    "stake_snapshots" => state.stake_snapshots = serde_json::from_str(&value)?,
+                    "stake_cache" => state.stake_cache = serde_json::from_str(&value)?,
+                    "heartbeats" => state.heartbeats = serde_json::from_str(&value)?,
+                    "upgrade_plans" => state.upgrade_plans = serde_json::from_str(&value)?,
+                    _ => continue,
+                }
+                entries.insert(id, digest(&[Value::Text(value)]));
+                empty = false;
+            }
+        }
+        if empty {
+            return Ok(None);
+        }
+
+        // The loaded rows are what the database holds, so the next save only writes changes made after loading
+        *self.written.lock().unwrap() = Written {
+            state: state.clone(),
+            entries,
+        };
+        Ok(Some(state))
+    }
+
+    fn save(&self, state: &PersistedState) -> Result<(), StorageError> {
+        self.save_changes(state).map(|_| ())
+    }
+
+    fn replace(&self, state: &PersistedState) -> Result<(), StorageError> {
+        self.write(state, true).map(|_| ())
+    }
+}
+
+/// Changes of every table in the order of TABLES between the written state and the current one,
+/// along with the digests of the state entries once written
+fn state_changes(
+    written: &PersistedState,
+    state: &PersistedState,
+    entry_digests: &Digests,
+) -> Result<([Changes; 6], Digests), StorageError> {
+    let mut local_changes = Changes::default();
+    if !Arc::ptr_eq(&written.local_attestations, &state.local_attestations) {
+        local_changes = diff(
+            local_rows(written),
+            local_rows(state),
+            |(deployment, block_number, attestation)| {
+                Ok(vec![
+                    Value::Text(deployment.clone()),
+                    Value::Integer(block_number as i64),
+                    Value::Text(attestation.npoi.clone()),
+                    Value::Text(serde_json::to_string(attestation)?),
+                ])
+            },
+        )?;
+    }
+
+    let mut remote_changes = Changes::default();
+    if !Arc::ptr_eq(&written.remote_messages, &state.remote_messages) {
+        let (added, dropped) = state
+            .remote_messages
+            .changes_since(&written.remote_messages);
+        remote_changes.deletes = dropped.into_iter().map(remote_message_id).collect();
+        for msg in added {
+            remote_changes.upserts.push((
+                remote_message_id(msg),
+                vec![
+                    Value::Text(msg.identifier.clone()),
+                    Value::Integer(msg.payload.block_number as i64),
+                    Value::Text(msg.graph_account.clone()),
+                    Value::Integer(msg.nonce),
+                    Value::Text(msg.payload.content.clone()),
+                    Value::Text(serde_json::to_string(&msg)?),
+                ],
+            ));
+        }
+    }
+
+    // Batches do not change once kept, they are only added and dropped
+    let mut batch_changes = Changes::default();
+    if !Arc::ptr_eq(&written.poi_batches, &state.poi_batches) {
+        for (signature, msg) in state.poi_batches.iter() {
+            if !written.poi_batches.contains_key(signature) {
+                batch_changes.upserts.push((
+                    signature.clone(),
+                    vec![
+                        Value::Text(msg.graph_account.clone()),
+                        Value::Integer(msg.nonce),
+                        Value::Text(serde_json::to_string(msg)?),
+                    ],
+                ));
+            }
+        }
+        batch_changes.deletes = written
+            .poi_batches
+            .keys()
+            .filter(|signature| !state.poi_batches.contains_key(*signature))
+            .cloned()
+            .collect();
+    }
+
+    let mut result_changes = Changes::default();
+    if !Arc::ptr_eq(&written.comparison_results, &state.comparison_results) {
+        result_changes = diff(result_rows(written), result_rows(state), result_row)?;
+    }
+
+    let mut history_changes = Changes::default();
+    if !Arc::ptr_eq(&written.comparison_history, &state.comparison_history) {
+        history_changes = diff(history_rows(written), history_rows(state), result_row)?;
+    }
+
+    // Small enough to be rewritten whole, but only serialized once their field changed
+    let mut entry_changes = Changes::default();
+    let mut entries = entry_digests.clone();
+    let mut entry = |id: &str, unchanged: bool, value: Result<String, serde_json::Error>| {
+        if unchanged && entry_digests.contains_key(id) {
+            return Ok::<(), StorageError>(());
+        }
+        let values = vec![Value::Text(value?)];
+        let value_digest = digest(&values);
+        if entry_digests.get(id) != Some(&value_digest) {
+            entry_changes.upserts.push((id.to_string(), values));
+        }
+        entries.insert(id.to_string(), value_digest);
+        Ok(())
+    };
+    entry(
+        "indexer_agreements",
+        Arc::ptr_eq(&written.indexer_agreements, &state.indexer_agreements),
+        serde_json::to_string(&state.indexer_agreements),
+    )?;
+    entry(
+        "equivocations",
+        Arc::ptr_eq(&written.equivocations, &state.equivocations),
+        serde_json::to_string(&state.equivocations),
+    )?;
+    entry(
+        "stake_snapshots",
+        Arc::ptr_eq(&written.stake_snapshots, &state.stake_snapshots),
+        serde_json::to_string(&state.stake_snapshots),
+    )?;
+    entry(
+        "stake_cache",
+        Arc::ptr_eq(&written.stake_cache, &state.stake_cache),
+        serde_json::to_string(&state.stake_cache),
+    )?;
+    entry(
+        "heartbeats",
+        Arc::ptr_eq(&written.heartbeats, &state.heartbeats),
+        serde_json::to_string(&state.heartbeats),
+    )?;
+    entry(
+        "upgrade_plans",
+        Arc::ptr_eq(&written.upgrade_plans, &state.upgrade_plans),
+        serde_json::to_string(&state.upgrade_plans),
+    )?;
+
+    Ok((
+        [
+            local_changes,
+            remote_changes,
+            batch_changes,
+            result_changes,
+            history_changes,
+            entry_changes,
+        ],
+        entries,
+    ))
+}
+
+/// Row id of a remote message, messages of a batch share its signature
+fn remote_message_id(msg: &GraphcastMessage<PublicPoiMessage>) -> String {
+    format!(
+        "{}/{}/{}",
+        msg.signature, msg.identifier, msg.payload.block_number
+    )
+}
+
+/// Local attestations by row id
+fn local_rows(state: &PersistedState) -> Vec<(String, (&String, u64, &Attestation))> {
+    state
+        .local_attestations
+        .iter()
+        .flat_map(|(deployment, blocks)| {
+            blocks.iter().map(move |(block_number, attestation)| {
+                (
+                    format!("{deployment}/{block_number}"),
+                    (deployment, *block_number, attestation),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Latest comparison results by row id
+fn result_rows(state: &PersistedState) -> Vec<(String, &ComparisonResult)> {
+    state
+        .comparison_results
+        .iter()
+        .map(|(deployment, result)| (deployment.clone(), result))
+        .collect()
+}
+
+/// Comparison history by row id
+fn history_rows(state: &PersistedState) -> Vec<(String, &ComparisonResult)> {
+    state
+        .comparison_history
+        .iter()
+        .flat_map(|(deployment, blocks)| {
+            blocks
+                .iter()
+                .map(move |(block_number, result)| (format!("{deployment}/{block_number}"), result))
+        })
+        .collect()
+}
+
+fn result_row(result: &ComparisonResult) -> Result<Vec<Value>, StorageError> {
+    Ok(vec![
+        Value::Text(result.deployment.clone()),
+        Value::Integer(result.block_number as i64),
+        Value::Text(format!("{:?}", result.result_type)),
+        Value::Text(serde_json::to_string(result)?),
+    ])
+}
+
+/// Rows of the entries that were added or differ since the written version of a table and the ids of the entries
+/// gone since then. Only the entries that differ are serialized
+fn diff<T: PartialEq + Copy>(
+    written: Vec<(String, T)>,
+    current: Vec<(String, T)>,
+    row: impl Fn(T) -> Result<Vec<Value>, StorageError>,
+) -> Result<Changes, StorageError> {
+    let mut written: HashMap<String, T> = written.into_iter().collect();
+    let mut changes = Changes::default();
+    for (id, value) in current {
+        if written.remove(&id) != Some(value) {
+            changes.upserts.push((id, row(value)?));
+        }
+    }
+    changes.deletes = written.into_keys().collect();
+    Ok(changes)
+}
+
+fn digest(values: &[Value]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for value in values {
+        match value {
+            Value::Integer(i) => i.hash(&mut hasher),
+            Value::Text(s) => s.hash(&mut hasher),
+            _ => {}
+        }
+    }
+    hasher.finish()
+}
+
+/// Delete the rows that are gone then upsert the changed ones, returns the number of changes
+fn apply_changes(tx: &Transaction, table: &Table, changes: Changes) -> Result<usize, StorageError> {
+    let mut upsert = tx.prepare_cached(&format!(
+        "INSERT OR REPLACE INTO {} (id, {}) VALUES (?{})",
+        table.name,
+        table.columns.join(", "),
+        ", ?".repeat(table.columns.len())
+    ))?;
+    let mut delete = tx.prepare_cached(&format!("DELETE FROM {} WHERE id = ?1", table.name))?;
+
+    let count = changes.deletes.len() + changes.upserts.len();
+    for id in changes.deletes {
+        delete.execute([id])?;
+    }
+    for (id, values) in changes.upserts {
+        upsert.execute(params_from_iter(
+            std::iter::once(Value::Text(id)).chain(values),
+        ))?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operator::{attestation::ComparisonResultType, stake::Stake};
+    use std::collections::BTreeMap;
+
+    fn state_with_attestations(npois: &[&str]) -> PersistedState {
+        let mut state = PersistedState::new(None, None, None);
+        let mut blocks = HashMap::new();
+        for (i, npoi) in npois.iter().enumerate() {
+            blocks.insert(
+                i as u64,
+                Attestation::new(npoi.to_string(), Stake::zero(), vec![], vec![i as i64]),
+            );
+        }
+        Arc::make_mut(&mut state.local_attestations).insert(String::from("QmHash"), blocks);
+        state
+    }
+
+    fn comparison_result(block_number: u64) -> ComparisonResult {
+        ComparisonResult {
+            deployment: String::from("QmHash"),
+            block_number,
+            result_type: ComparisonResultType::Match,
+            local_attestation: None,
+            attestations: vec![],
+            bisection: None,
+            consensus_policy: Default::default(),
+            tied_npois: vec![],
+        }
+    }
+
+    #[test]
+    fn test_sqlite_store_incremental_save() {
+        let path = std::env::temp_dir().join("poi-radio-test-incremental-state.sqlite");
+        _ = fs::remove_file(&path);
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(store.load().unwrap().is_none());
+
+        let state = state_with_attestations(&["npoi-0", "npoi-1", "npoi-2"]);
+        // Three attestations and the six state entries
+        assert_eq!(store.save_changes(&state).unwrap(), 9);
+        assert_eq!(store.save_changes(&state).unwrap(), 0);
+
+        // One changed attestation and one removed
+        let state = state_with_attestations(&["npoi-0", "npoi-x"]);
+        assert_eq!(store.save_changes(&state).unwrap(), 2);
+
+        // A freshly opened store picks up where the last one left off
+        let store = Arc::new(SqliteStore::open(&path).unwrap());
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.local_attestations(), state.local_attestations());
+        assert_eq!(store.save_changes(&loaded).unwrap(), 0);
+
+        // Each compared block is a row of its own
+        let mut state = loaded;
+        state.add_comparison_history(comparison_result(1), 2);
+        assert_eq!(store.save_changes(&state).unwrap(), 1);
+        state.add_comparison_history(comparison_result(2), 2);
+        state.add_comparison_history(comparison_result(3), 2);
+        assert_eq!(store.save_changes(&state).unwrap(), 3);
+
+        // Batches are written once
+        let batch = PublicPoiBatchMessage::build(1, String::from("0xa1"), &[]);
+        state.add_poi_batch(GraphcastMessage {
+            identifier: batch.identifier.clone(),
+            nonce: 1,
+            graph_account: String::from("0xa1"),
+            payload: batch,
+            signature: String::from("0xsig"),
+        });
+        assert_eq!(store.save_changes(&state).unwrap(), 1);
+        let store = SqliteStore::open(&path).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert!(loaded.poi_batches.contains_key("0xsig"));
+        assert_eq!(store.save_changes(&loaded).unwrap(), 0);
+        _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_store_replace() {
+        let path = std::env::temp_dir().join("poi-radio-test-replace-state.sqlite");
+        _ = fs::remove_file(&path);
+        let store = SqliteStore::open(&path).unwrap();
+        store
+            .save(&state_with_attestations(&["npoi-0", "npoi-1", "npoi-2"]))
+            .unwrap();
+
+        // A store that has not loaded the rows still deletes them
+        let store = SqliteStore::open(&path).unwrap();
+        let state = state_with_attestations(&["npoi-x"]);
+        store.replace(&state).unwrap();
+        assert_eq!(store.save_changes(&state).unwrap(), 0);
+        let loaded = SqliteStore::open(&path).unwrap().load().unwrap().unwrap();
+        assert_eq!(loaded.local_attestations(), state.local_attestations());
+        _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_store_migrates_comparison_history() {
+        let path = std::env::temp_dir().join("poi-radio-test-migrate-history.sqlite");
+        _ = fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(SCHEMA).unwrap();
+            conn.pragma_update(None, "user_version", 1).unwrap();
+            let history = HashMap::from([(
+                String::from("QmHash"),
+                BTreeMap::from([(1, comparison_result(1)), (2, comparison_result(2))]),
+            )]);
+            conn.execute(
+                "INSERT INTO state_entries (id, value) VALUES ('comparison_history', ?1)",
+                [serde_json::to_string(&history).unwrap()],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(
+            loaded
+                .comparison_history
+                .get("QmHash")
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&1, &2]
+        );
+        let (rows, result_type): (i64, String) = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*), MIN(result_type) FROM comparison_history WHERE deployment = 'QmHash'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((rows, result_type.as_str()), (2, "Match"));
+        _ = fs::remove_file(&path);
+    }
+}
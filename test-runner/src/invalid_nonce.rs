@@ -1,4 +1,4 @@
-use poi_radio::state::PersistedState;
+use poi_radio::storage::{JsonFileStore, StateStore};
 use test_utils::{
     config::{test_config, TestSenderConfig},
     setup, teardown,
@@ -33,7 +33,10 @@ pub async fn invalid_nonce_test() {
 
     sleep(Duration::from_secs(89)).await;
 
-    let persisted_state = PersistedState::load_cache(&store_path);
+    let persisted_state = JsonFileStore::new(&store_path)
+        .load()
+        .expect("Persisted state is readable")
+        .expect("Radio persisted its state");
     debug!("persisted state {:?}", persisted_state);
 
     teardown(process_manager, &store_path);
@@ -1,4 +1,4 @@
-use poi_radio::state::PersistedState;
+use poi_radio::storage::{JsonFileStore, StateStore};
 use test_utils::{
     config::{test_config, TestSenderConfig},
     messages_are_equal, payloads_are_equal, setup, teardown,
@@ -37,7 +37,10 @@ pub async fn send_and_receive_test() {
 
     sleep(Duration::from_secs(85)).await;
 
-    let persisted_state = PersistedState::load_cache(&store_path);
+    let persisted_state = JsonFileStore::new(&store_path)
+        .load()
+        .expect("Persisted state is readable")
+        .expect("Radio persisted its state");
     debug!("persisted state {:?}", persisted_state);
 
     teardown(process_manager, &store_path);
@@ -1,4 +1,7 @@
-use poi_radio::{operator::attestation::ComparisonResultType, state::PersistedState};
+use poi_radio::{
+    operator::attestation::ComparisonResultType,
+    storage::{JsonFileStore, StateStore},
+};
 use test_utils::{
     config::{test_config, TestSenderConfig},
     setup, teardown,
@@ -33,7 +36,10 @@ pub async fn poi_match_test() {
 
     sleep(Duration::from_secs(550)).await;
 
-    let persisted_state = PersistedState::load_cache(&store_path);
+    let persisted_state = JsonFileStore::new(&store_path)
+        .load()
+        .expect("Persisted state is readable")
+        .expect("Radio persisted its state");
     debug!("persisted state {:?}", persisted_state);
 
     let comparison_results = persisted_state.comparison_results();
@@ -1,4 +1,4 @@
-use poi_radio::state::PersistedState;
+use poi_radio::storage::{JsonFileStore, StateStore};
 use test_utils::{
     config::{test_config, TestSenderConfig},
     setup, teardown,
@@ -42,7 +42,10 @@ pub async fn topics_test() {
     sleep(Duration::from_secs(89)).await;
 
     // can be sure that file path is set to Some (after test_config()
-    let persisted_state = PersistedState::load_cache(&config.persistence_file_path.unwrap());
+    let persisted_state = JsonFileStore::new(&config.persistence_file_path.unwrap())
+        .load()
+        .expect("Persisted state is readable")
+        .expect("Radio persisted its state");
     debug!(
         local_attestations = tracing::field::debug(&persisted_state.local_attestations()),
         remote_messages = tracing::field::debug(&persisted_state.remote_messages()),
@@ -108,7 +111,10 @@ pub async fn topics_test() {
 
     tokio::time::sleep(Duration::from_secs(50)).await;
 
-    let persisted_state = PersistedState::load_cache(&store_path);
+    let persisted_state = JsonFileStore::new(&store_path)
+        .load()
+        .expect("Persisted state is readable")
+        .expect("Radio persisted its state");
     debug!("persisted state {:?}", persisted_state);
 
     let remote_messages = persisted_state.remote_messages();